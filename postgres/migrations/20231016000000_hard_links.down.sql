@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS objects_hlink_group_idx;
+
+ALTER TABLE objects
+    DROP COLUMN IF EXISTS hlink_group;
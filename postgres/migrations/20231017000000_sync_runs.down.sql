@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS sync_runs CASCADE;
+
+DROP TYPE IF EXISTS sync_result CASCADE;
@@ -0,0 +1,20 @@
+CREATE TYPE sync_result AS ENUM ('running', 'success', 'failed', 'aborted');
+CREATE TABLE sync_runs
+(
+    id             INTEGER GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    namespace      TEXT           NOT NULL, -- not a reference, runs may fail before the repository is created
+    revision       INTEGER REFERENCES revisions (revision) ON DELETE SET NULL,
+    started_at     timestamptz(0) NOT NULL,
+    finished_at    timestamptz(0),
+    result         sync_result    NOT NULL,
+    error          TEXT,
+    bytes_read     BIGINT,        -- rsync stats
+    bytes_written  BIGINT,
+    transfer_count BIGINT,        -- transfer plan
+    transfer_bytes BIGINT,
+    delta_count    BIGINT,
+    delta_bytes    BIGINT,
+    copied_count   BIGINT,        -- entries copied from live revisions
+    CONSTRAINT finished_if_not_running CHECK ((finished_at IS NULL) = (result = 'running'))
+);
+CREATE INDEX sync_runs_namespace_idx ON sync_runs (namespace, started_at);
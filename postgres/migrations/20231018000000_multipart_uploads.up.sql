@@ -0,0 +1,8 @@
+CREATE TABLE multipart_uploads
+(
+    upload_id  TEXT PRIMARY KEY,
+    namespace  TEXT           NOT NULL,
+    key        TEXT           NOT NULL,
+    created_at timestamptz(0) NOT NULL
+);
+CREATE INDEX multipart_uploads_namespace_key_idx ON multipart_uploads (namespace, key);
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS objects_blake2b_idx;
@@ -0,0 +1,2 @@
+-- Looked up before uploading a blob, to skip blobs already in storage.
+CREATE INDEX objects_blake2b_idx ON objects USING hash (blake2b) WHERE blake2b IS NOT NULL;
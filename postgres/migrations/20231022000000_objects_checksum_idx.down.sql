@@ -0,0 +1 @@
+DROP INDEX IF EXISTS objects_checksum_idx;
@@ -0,0 +1,2 @@
+-- Looked up in checksum mode to copy renamed files instead of transferring them again.
+CREATE INDEX objects_checksum_idx ON objects USING hash (checksum) WHERE checksum IS NOT NULL;
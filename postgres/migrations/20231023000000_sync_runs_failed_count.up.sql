@@ -0,0 +1,2 @@
+ALTER TABLE sync_runs
+    ADD COLUMN failed_count BIGINT; -- files left out of the revision after failing checksum verification twice
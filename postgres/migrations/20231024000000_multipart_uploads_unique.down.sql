@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS multipart_uploads_namespace_key_idx;
+CREATE INDEX multipart_uploads_namespace_key_idx ON multipart_uploads (namespace, key);
@@ -0,0 +1,10 @@
+-- At most one upload of each key is recorded, so that it's never resumed by two uploads at once.
+-- Older duplicates are forgotten, and aborted as unrecorded uploads later.
+DELETE
+FROM multipart_uploads a
+    USING multipart_uploads b
+WHERE a.namespace = b.namespace
+  AND a.key = b.key
+  AND (a.created_at, a.upload_id) < (b.created_at, b.upload_id);
+DROP INDEX IF EXISTS multipart_uploads_namespace_key_idx;
+CREATE UNIQUE INDEX multipart_uploads_namespace_key_idx ON multipart_uploads (namespace, key);
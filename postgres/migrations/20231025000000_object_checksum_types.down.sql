@@ -0,0 +1,3 @@
+ALTER TABLE objects
+    DROP COLUMN IF EXISTS checksum_type;
+DROP TYPE IF EXISTS checksum_type;
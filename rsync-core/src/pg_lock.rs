@@ -50,6 +50,30 @@ impl PgLock {
             conn: Some(conn),
         })
     }
+    /// Try to acquire the lock without waiting.
+    ///
+    /// Returns `None` if the lock is held by another session.
+    ///
+    /// # Errors
+    /// Returns an error if there's a problem acquiring the lock.
+    pub async fn try_lock<C: AsMut<PgConnection>>(
+        &self,
+        mut conn: C,
+    ) -> eyre::Result<Option<PgLockGuard<C>>> {
+        let query = if self.shared {
+            "SELECT pg_try_advisory_lock_shared($1)"
+        } else {
+            "SELECT pg_try_advisory_lock($1)"
+        };
+        let acquired: bool = sqlx::query_scalar(query)
+            .bind(self.key)
+            .fetch_one(conn.as_mut())
+            .await?;
+        Ok(acquired.then(|| PgLockGuard {
+            lock: self,
+            conn: Some(conn),
+        }))
+    }
 }
 
 impl<'lock, C: AsMut<PgConnection>> PgLockGuard<'lock, C> {
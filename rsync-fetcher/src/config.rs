@@ -0,0 +1,499 @@
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use bytesize::ByteSize;
+use doku::Document;
+use eyre::{bail, ensure, Result, WrapErr};
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::Figment;
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::warn;
+use url::Url;
+
+use rsync_core::s3::S3Opts;
+use rsync_core::utils::parse_ensure_end_slash;
+
+use crate::consts::DEFAULT_FAILURE_WINDOW;
+use crate::opts::{Concurrency, RsyncOpts, SyncOpts};
+use crate::plan::Compare;
+use crate::rsync::bwlimit::RateLimiter;
+use crate::rsync::filter::FilterRules;
+use crate::rsync::progress_display::ProgressOpts;
+use crate::source::{de_urls, validate_sources, Source};
+
+/// Fetches rsync repositories to S3.
+#[derive(Debug, Clone, Serialize, Deserialize, Document)]
+pub struct Config {
+    /// PostgreSQL database url.
+    #[doku(example = "postgres://postgres@localhost:5432/rsync-sjtug")]
+    pub database_url: String,
+    /// Temporary directory.
+    #[doku(as = "String", example = "/tmp")]
+    pub tmp_path: PathBuf,
+    /// Daemon options.
+    pub daemon: DaemonOpts,
+    /// Repositories to sync.
+    pub repos: Vec<Repo>,
+}
+
+/// Options of the daemon mode.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Document)]
+pub struct DaemonOpts {
+    /// Max number of repositories to sync at the same time.
+    #[doku(example = "4")]
+    pub max_concurrent: usize,
+    /// Delay each scheduled sync by a random duration up to this many seconds.
+    ///
+    /// Avoids hitting upstreams at the same time.
+    #[doku(example = "60")]
+    pub jitter: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            database_url: String::from("postgres://postgres@localhost:5432/rsync-sjtug"),
+            tmp_path: PathBuf::from("/tmp"),
+            daemon: DaemonOpts {
+                max_concurrent: 4,
+                jitter: 60,
+            },
+            repos: vec![],
+        }
+    }
+}
+
+/// A repository to sync.
+#[derive(Debug, Clone, Serialize, Deserialize, Document)]
+pub struct Repo {
+    /// Metadata namespace. Need to be unique for each repository.
+    ///
+    /// Also used to select repositories to sync on the command line.
+    #[doku(example = "debian")]
+    pub namespace: String,
+    /// Rsync remote url.
+    ///
+    /// Either `rsync://[user@]host[:port]/module/path` for rsync daemons, or
+    /// `ssh://[user@]host[:port]/path` for remote shell access.
+    ///
+    /// May be a list of equivalent upstreams, which are tried in order of their recorded health.
+    #[serde(deserialize_with = "de_urls")]
+    #[doku(as = "String", example = "rsync://ftp.debian.org/debian/")]
+    pub src: Vec<Url>,
+    /// Other sources merged into the repository, each mounted at a path of it.
+    ///
+    /// Entries of `src` under the path are excluded. Filter rules apply to every source,
+    /// relative to its own root.
+    #[serde(default)]
+    pub merge: Vec<Source>,
+    /// File containing the password of the rsync daemon.
+    #[serde(default)]
+    #[doku(as = "String", example = "/etc/rsync-fetcher/debian.secret")]
+    pub password_file: Option<PathBuf>,
+    /// Remote shell used for `ssh://` urls.
+    #[serde(default = "default_rsh")]
+    #[doku(example = "ssh")]
+    pub rsh: String,
+    /// Program to run on the remote host for `ssh://` urls.
+    #[serde(default = "default_rsync_path")]
+    #[doku(example = "rsync")]
+    pub rsync_path: String,
+    /// Spawn this command as the rsync server instead of connecting to the remote.
+    #[serde(default)]
+    #[doku(example = "rsync")]
+    pub command: Option<String>,
+    /// Filter rules, following the syntax of rsync's `--filter`.
+    #[serde(default)]
+    #[doku(example = "- *.iso")]
+    pub filters: Vec<String>,
+    /// S3 storage.
+    pub s3: S3Target,
+    /// Sync the repository every this many seconds in daemon mode.
+    #[serde(default)]
+    #[doku(example = "3600")]
+    pub interval: Option<u64>,
+    /// Sync the repository on this cron schedule (UTC) in daemon mode.
+    ///
+    /// Either 5 fields (`min hour day month weekday`), or 6-7 fields with seconds and years.
+    #[serde(default)]
+    #[doku(example = "0 */4 * * *")]
+    pub cron: Option<String>,
+    /// Receive file list incrementally (protocol 30+).
+    #[serde(default)]
+    #[doku(example = "false")]
+    pub inc_recursive: bool,
+    /// Compress file data during transfer.
+    #[serde(default)]
+    #[doku(example = "false")]
+    pub compress: bool,
+    /// Preserve hard links.
+    #[serde(default)]
+    #[doku(example = "false")]
+    pub hard_links: bool,
+    /// Compare regular files by whole-file checksums instead of mtime.
+    #[serde(default)]
+    #[doku(example = "false")]
+    pub checksum: bool,
+    /// Compare regular files by size only, ignoring mtime.
+    #[serde(default)]
+    #[doku(example = "false")]
+    pub size_only: bool,
+    /// Consider mtimes equal if they differ by at most this many seconds.
+    #[serde(default)]
+    #[doku(example = "0")]
+    pub modify_window: u32,
+    /// Resume an interrupted sync.
+    #[serde(default)]
+    #[doku(example = "false")]
+    pub resume: bool,
+    /// Limit the rate of data received from the rsync server per second.
+    #[serde(default)]
+    #[doku(as = "String", example = "10MiB")]
+    pub bwlimit: Option<ByteSize>,
+    /// Limit the rate of data uploaded to and downloaded from S3 per second.
+    #[serde(default)]
+    #[doku(as = "String", example = "10MiB")]
+    pub s3_bwlimit: Option<ByteSize>,
+    /// Concurrency of S3 transfers.
+    #[serde(default)]
+    pub concurrency: Concurrency,
+    /// Also check blobs known from previous revisions exist in S3 before skipping their uploads.
+    #[serde(default)]
+    #[doku(example = "false")]
+    pub stat_known_blobs: bool,
+    /// Refuse to publish the new revision if its file count or total size shrank by more than
+    /// this percentage compared to the previous live revision.
+    #[serde(default = "default_max_shrink")]
+    #[doku(example = "50")]
+    pub max_shrink: u8,
+    /// Trace files in the module containing the time of the last upstream update.
+    #[serde(default)]
+    #[doku(example = "project/trace/master")]
+    pub trace_files: Vec<String>,
+    /// Refuse to publish the revision if any trace file is older than this many hours.
+    #[serde(default)]
+    #[doku(example = "48")]
+    pub trace_max_age: Option<u32>,
+    /// Forget failures of an upstream url after this many hours when ranking urls.
+    #[serde(default = "default_failure_window")]
+    #[doku(example = "6")]
+    pub failure_window: u32,
+}
+
+/// S3 storage of a repository.
+#[derive(Debug, Clone, Serialize, Deserialize, Document)]
+pub struct S3Target {
+    /// S3 endpoint url.
+    ///
+    /// To specify credentials, use environment variables:
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+    #[doku(example = "https://s3.example.com")]
+    pub url: String,
+    /// S3 storage region.
+    #[doku(example = "us-east-1")]
+    pub region: String,
+    /// S3 storage bucket.
+    #[doku(example = "mirror")]
+    pub bucket: String,
+    /// S3 storage prefix.
+    #[serde(deserialize_with = "de_ensure_end_slash")]
+    #[doku(example = "debian/")]
+    pub prefix: String,
+}
+
+fn de_ensure_end_slash<'de, D>(de: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(de)?;
+    Ok(parse_ensure_end_slash(&s).expect("infallible"))
+}
+
+/// Parse a cron expression. Standard 5-field expressions are accepted besides the ones with
+/// seconds.
+pub fn parse_cron(expr: &str) -> Result<cron::Schedule> {
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {expr}")
+    } else {
+        expr.to_string()
+    };
+    Ok(expr.parse()?)
+}
+
+fn default_rsh() -> String {
+    String::from("ssh")
+}
+
+fn default_rsync_path() -> String {
+    String::from("rsync")
+}
+
+const fn default_max_shrink() -> u8 {
+    50
+}
+
+const fn default_failure_window() -> u32 {
+    DEFAULT_FAILURE_WINDOW
+}
+
+pub fn load_config(conf_path: &Path) -> Result<Config> {
+    // There's a TOCTOU problem here, but it's not a big deal.
+    let _conf = std::fs::read_to_string(conf_path)
+        .wrap_err_with(|| format!("Failed to read config file: {}", conf_path.display()))?;
+
+    let opts: Config = Figment::new()
+        .merge(Serialized::defaults(Config::default()))
+        .merge(Toml::file(conf_path))
+        .merge(Env::raw().only(&["database_url"]))
+        .extract()?;
+
+    Ok(opts)
+}
+
+pub fn validate_config(opts: &Config) -> Result<()> {
+    if opts.repos.is_empty() {
+        warn!("No repositories configured");
+    }
+
+    if !opts.repos.iter().map(|repo| &repo.namespace).all_unique() {
+        bail!("Repository namespace must be unique");
+    }
+
+    ensure!(
+        opts.daemon.max_concurrent > 0,
+        "daemon.max_concurrent must be positive"
+    );
+
+    for repo in &opts.repos {
+        let namespace = &repo.namespace;
+        ensure!(
+            repo.max_shrink <= 100,
+            "max_shrink of {namespace} must be a percentage"
+        );
+        let Concurrency {
+            upload_conn,
+            download_conn,
+            basis_buffer_limit,
+        } = repo.concurrency;
+        ensure!(
+            upload_conn > 0 && download_conn > 0 && basis_buffer_limit > 0,
+            "concurrency of {namespace} must be positive"
+        );
+        ensure!(
+            !(repo.checksum && repo.size_only),
+            "only one of checksum and size_only can be set for {namespace}"
+        );
+        ensure!(
+            repo.modify_window == 0 || !(repo.checksum || repo.size_only),
+            "modify_window of {namespace} has no effect with checksum or size_only"
+        );
+        ensure!(
+            repo.interval.is_none() || repo.cron.is_none(),
+            "only one of interval and cron can be set for {namespace}"
+        );
+        ensure!(
+            repo.interval != Some(0),
+            "interval of {namespace} must be positive"
+        );
+        if let Some(cron) = &repo.cron {
+            parse_cron(cron).wrap_err_with(|| format!("invalid cron of {namespace}"))?;
+        }
+        validate_sources(&sources(repo))
+            .wrap_err_with(|| format!("invalid sources of {namespace}"))?;
+    }
+
+    Ok(())
+}
+
+/// Repositories of the given namespaces, or all repositories if none is given.
+pub fn select_repos<'a>(config: &'a Config, namespaces: &[String]) -> Result<Vec<&'a Repo>> {
+    if let Some(unknown) = namespaces
+        .iter()
+        .find(|ns| !config.repos.iter().any(|repo| repo.namespace == **ns))
+    {
+        bail!("repository {unknown} not found in config");
+    }
+    Ok(config
+        .repos
+        .iter()
+        .filter(|repo| namespaces.is_empty() || namespaces.contains(&repo.namespace))
+        .collect())
+}
+
+/// Sources of the repository, starting with `src` mounted at the root.
+fn sources(repo: &Repo) -> Vec<Source> {
+    iter::once(Source::root(repo.src.clone()))
+        .chain(repo.merge.iter().cloned())
+        .collect()
+}
+
+/// Options to sync the repository.
+pub fn sync_opts(
+    repo: &Repo,
+    config: &Config,
+    progress: &ProgressOpts,
+    dry_run: bool,
+) -> Result<SyncOpts> {
+    let namespace = &repo.namespace;
+    let mut filters = FilterRules::default();
+    for rule in &repo.filters {
+        filters
+            .add_rule(rule.as_bytes())
+            .wrap_err_with(|| format!("invalid filter rule {rule:?} of {namespace}"))?;
+    }
+    Ok(SyncOpts {
+        sources: sources(repo),
+        namespace: namespace.clone(),
+        s3: S3Opts {
+            region: repo.s3.region.clone(),
+            url: repo.s3.url.clone(),
+            bucket: repo.s3.bucket.clone(),
+        },
+        s3_prefix: repo.s3.prefix.clone(),
+        s3_bwlimit: RateLimiter::new(repo.s3_bwlimit.map_or(0, |limit| limit.as_u64())),
+        reload_s3_bwlimit: repo.s3_bwlimit.is_none(),
+        concurrency: repo.concurrency,
+        stat_known_blobs: repo.stat_known_blobs,
+        compare: Compare::from_opts(repo.checksum, repo.size_only, repo.modify_window),
+        resume: repo.resume,
+        dry_run,
+        max_shrink: repo.max_shrink,
+        trace_files: repo.trace_files.clone(),
+        trace_max_age: repo.trace_max_age,
+        failure_window: repo.failure_window,
+        tmp_path: config.tmp_path.clone(),
+        progress: progress.clone(),
+        rsync: RsyncOpts {
+            filters,
+            bwlimit: RateLimiter::new(repo.bwlimit.map_or(0, |limit| limit.as_u64())),
+            reload_bwlimit: repo.bwlimit.is_none(),
+            inc_recursive: repo.inc_recursive && !dry_run,
+            compress: repo.compress,
+            hard_links: repo.hard_links,
+            checksum: repo.checksum,
+            rsh: repo.rsh.clone(),
+            rsync_path: repo.rsync_path.clone(),
+            command: repo.command.clone(),
+            password_file: repo.password_file.clone(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::providers::{Format, Serialized, Toml};
+    use figment::Figment;
+
+    use crate::config::{parse_cron, select_repos, sync_opts, validate_config, Config};
+    use crate::rsync::progress_display::ProgressOpts;
+
+    const CONFIG: &str = r#"
+        database_url = "postgres://localhost/rsync"
+
+        [[repos]]
+        namespace = "debian"
+        src = "rsync://ftp.debian.org/debian/"
+        filters = ["- *.iso"]
+        s3 = { url = "https://s3.example.com", region = "us-east-1", bucket = "mirror", prefix = "debian" }
+        concurrency = { upload_conn = 32 }
+        interval = 3600
+        merge = [{ path = "security", src = "rsync://security.debian.org/debian-security/" }]
+
+        [[repos]]
+        namespace = "fedora"
+        src = ["rsync://dl.fedoraproject.org/fedora-enchilada/", "rsync://mirrors.kernel.org/fedora-enchilada/"]
+        s3 = { url = "https://s3.example.com", region = "us-east-1", bucket = "mirror", prefix = "fedora/" }
+        cron = "0 */4 * * *"
+    "#;
+
+    fn config() -> Config {
+        Figment::new()
+            .merge(Serialized::defaults(Config::default()))
+            .merge(Toml::string(CONFIG))
+            .extract()
+            .expect("config")
+    }
+
+    #[test]
+    fn must_select_repos() {
+        let config = config();
+        validate_config(&config).expect("valid");
+
+        let all: Vec<_> = select_repos(&config, &[])
+            .expect("select")
+            .into_iter()
+            .map(|repo| {
+                sync_opts(repo, &config, &ProgressOpts::default(), false).expect("sync opts")
+            })
+            .collect();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].sources.len(), 2);
+        assert_eq!(all[0].sources[1].mount_path(), "security");
+        assert_eq!(all[1].sources.len(), 1);
+        assert_eq!(all[0].sources[0].src.len(), 1);
+        assert_eq!(all[1].sources[0].src.len(), 2);
+        assert_eq!(all[0].s3_prefix, "debian/");
+        assert_eq!(all[0].concurrency.upload_conn, 32);
+        assert_eq!(all[0].concurrency.download_conn, 8);
+        assert_eq!(all[1].max_shrink, 50);
+        assert_eq!(all[1].rsync.rsh, "ssh");
+
+        let fedora = select_repos(&config, &["fedora".to_string()]).expect("select");
+        assert_eq!(fedora.len(), 1);
+        assert_eq!(fedora[0].namespace, "fedora");
+        assert!(
+            sync_opts(fedora[0], &config, &ProgressOpts::default(), true)
+                .expect("sync opts")
+                .dry_run
+        );
+
+        assert!(select_repos(&config, &["arch".to_string()]).is_err());
+    }
+
+    #[test]
+    fn must_reject_duplicate_namespace() {
+        let mut config = config();
+        config.repos[1].namespace = "debian".to_string();
+        assert!(validate_config(&config).is_err());
+    }
+
+    #[test]
+    fn must_reject_invalid_schedule() {
+        let mut both = config();
+        both.repos[0].cron = Some("0 * * * *".to_string());
+        assert!(validate_config(&both).is_err());
+
+        let mut invalid = config();
+        invalid.repos[1].cron = Some("every hour".to_string());
+        assert!(validate_config(&invalid).is_err());
+    }
+
+    #[test]
+    fn must_reject_conflicting_compare() {
+        let mut both = config();
+        both.repos[0].checksum = true;
+        both.repos[0].size_only = true;
+        assert!(validate_config(&both).is_err());
+
+        let mut window = config();
+        window.repos[0].modify_window = 2;
+        validate_config(&window).expect("valid");
+        window.repos[0].size_only = true;
+        assert!(validate_config(&window).is_err());
+    }
+
+    #[test]
+    fn must_reject_invalid_merge() {
+        let mut config = config();
+        config.repos[0].merge[0].path = "/".to_string();
+        assert!(validate_config(&config).is_err());
+    }
+
+    #[test]
+    fn must_parse_cron() {
+        assert!(parse_cron("0 */4 * * *").is_ok());
+        assert!(parse_cron("30 0 */4 * * *").is_ok());
+        assert!(parse_cron("0 */4 * *").is_err());
+    }
+}
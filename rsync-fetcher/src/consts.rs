@@ -1,7 +1,15 @@
-/// Max number of concurrent connections for uploading to S3.
+/// Default max number of concurrent connections for uploading to S3.
 pub const UPLOAD_CONN: usize = 8;
-/// Max number of concurrent connections for downloading from S3.
+/// Default max number of concurrent connections for downloading from S3.
 pub const DOWNLOAD_CONN: usize = UPLOAD_CONN;
-/// Max number of basis buffer files allowed to be open, or to be pending at the same time.
+/// Default max number of basis buffer files allowed to be open, or to be pending at the same time.
 /// NOTE: therefore, at most `BASIS_BUFFER_LIMIT * 2` buffer files can be exist at the same time.
 pub const BASIS_BUFFER_LIMIT: usize = UPLOAD_CONN * 2;
+/// Files transferred in full and not larger than this are received in memory. Larger ones are
+/// streamed to S3.
+pub const IN_MEMORY_LIMIT: u64 = 4 * 1024 * 1024;
+/// Max number of received chunks of a streamed file buffered for its upload task. Chunks are up
+/// to 128 KiB.
+pub const STREAM_CHUNK_LIMIT: usize = 64;
+/// Default number of hours after which a failure of an upstream url no longer demotes it.
+pub const DEFAULT_FAILURE_WINDOW: u32 = 6;
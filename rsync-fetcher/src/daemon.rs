@@ -0,0 +1,228 @@
+//! Daemon mode which syncs repositories on their schedules.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use eyre::{Result, WrapErr};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use opendal::Operator;
+use rand::Rng;
+use sqlx::PgPool;
+use tracing::{error, info, warn};
+
+use rsync_core::s3::build_operator;
+
+use crate::config::{parse_cron, DaemonOpts, Repo};
+use crate::opts::SyncOpts;
+use crate::sync::try_sync;
+use crate::utils::Shutdown;
+
+/// When to sync a repository.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Schedule of the repository, if any.
+    pub fn from_repo(repo: &Repo) -> Result<Option<Self>> {
+        Ok(match (&repo.interval, &repo.cron) {
+            (Some(secs), _) => Some(Self::Interval(Duration::seconds(
+                i64::try_from(*secs).wrap_err("interval too large")?,
+            ))),
+            (None, Some(cron)) => Some(Self::Cron(Box::new(parse_cron(cron)?))),
+            (None, None) => None,
+        })
+    }
+
+    /// The first time to sync after the daemon starts.
+    fn first(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            // Repositories synced on interval are synced immediately.
+            Self::Interval(_) => now,
+            Self::Cron(schedule) => Self::next_cron(schedule, now),
+        }
+    }
+
+    /// The next time to sync after a sync which was due at `due` started at `now`.
+    ///
+    /// Ticks missed while the sync is running are skipped.
+    fn next(&self, due: DateTime<Utc>, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Interval(interval) => {
+                let next = due + *interval;
+                if next < now {
+                    now + *interval
+                } else {
+                    next
+                }
+            }
+            Self::Cron(schedule) => Self::next_cron(schedule, now),
+        }
+    }
+
+    fn next_cron(schedule: &cron::Schedule, after: DateTime<Utc>) -> DateTime<Utc> {
+        schedule
+            .after(&after)
+            .next()
+            // The schedule may end in a specific year.
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+}
+
+/// A repository scheduled in the daemon.
+pub struct ScheduledRepo {
+    pub sync: SyncOpts,
+    pub schedule: Schedule,
+}
+
+/// Run scheduled syncs until SIGINT or SIGTERM is received.
+pub async fn daemon(
+    opts: DaemonOpts,
+    repos: Vec<ScheduledRepo>,
+    pool: Arc<PgPool>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let operators = build_operators(&repos)?;
+    let mut shutdown_rx = shutdown.clone();
+    let max_jitter = i64::try_from(opts.jitter).wrap_err("jitter too large")?;
+    // (time the sync is due without jitter, time to start the sync)
+    let with_jitter = |due: DateTime<Utc>| {
+        let jitter = Duration::seconds(rand::thread_rng().gen_range(0..=max_jitter));
+        (due, due.checked_add_signed(jitter).unwrap_or(due))
+    };
+
+    let now = Utc::now();
+    let mut due: Vec<_> = repos
+        .iter()
+        .map(|repo| with_jitter(repo.schedule.first(now)))
+        .collect();
+    let mut running = vec![false; repos.len()];
+    let mut tasks = FuturesUnordered::new();
+
+    info!(repos = repos.len(), "daemon started.");
+    loop {
+        let now = Utc::now();
+        for (idx, repo) in repos.iter().enumerate() {
+            if tasks.len() >= opts.max_concurrent {
+                break;
+            }
+            let (scheduled, start) = due[idx];
+            if running[idx] || start > now {
+                continue;
+            }
+
+            info!(namespace = repo.sync.namespace, "starting scheduled sync.");
+            running[idx] = true;
+            due[idx] = with_jitter(repo.schedule.next(scheduled, now));
+            tasks.push(
+                try_sync(&repo.sync, &pool, &operators[idx], shutdown)
+                    .map(move |result| (idx, result)),
+            );
+        }
+
+        // Wait for a running sync to finish, or for the next sync to be due if there's room.
+        let next_start = (tasks.len() < opts.max_concurrent)
+            .then(|| {
+                due.iter()
+                    .zip(&running)
+                    .filter(|(_, running)| !**running)
+                    .map(|((_, start), _)| *start)
+                    .min()
+            })
+            .flatten();
+        let sleep = next_start.map_or(std::time::Duration::MAX, |start| {
+            (start - now).to_std().unwrap_or_default()
+        });
+        tokio::select! {
+            Some((idx, result)) = tasks.next() => {
+                running[idx] = false;
+                let (next, _) = due[idx];
+                log_result(&repos[idx].sync.namespace, next, result);
+            }
+            () = tokio::time::sleep(sleep) => {}
+            () = shutdown_rx.recv() => break,
+        }
+    }
+
+    // Running syncs receive the signal too, and are recorded as aborted.
+    info!(
+        running = tasks.len(),
+        "shutting down, waiting for running syncs."
+    );
+    while let Some((idx, result)) = tasks.next().await {
+        let (next, _) = due[idx];
+        log_result(&repos[idx].sync.namespace, next, result);
+    }
+    Ok(())
+}
+
+fn log_result(namespace: &str, next: DateTime<Utc>, result: Result<bool>) {
+    match result {
+        Ok(true) => info!(namespace, %next, "scheduled sync done."),
+        Ok(false) => warn!(
+            namespace, %next,
+            "namespace is locked by another operation, skipped."
+        ),
+        Err(e) => error!(namespace, %next, ?e, "scheduled sync failed."),
+    }
+}
+
+/// Build S3 operators of the repositories, sharing one operator among repositories of the same
+/// bucket.
+fn build_operators(repos: &[ScheduledRepo]) -> Result<Vec<Operator>> {
+    let mut operators = HashMap::new();
+    repos
+        .iter()
+        .map(|repo| {
+            let s3 = &repo.sync.s3;
+            let key = (s3.url.clone(), s3.region.clone(), s3.bucket.clone());
+            if let Some(operator) = operators.get(&key) {
+                return Ok(Operator::clone(operator));
+            }
+            let operator = build_operator(s3)
+                .wrap_err_with(|| format!("invalid S3 options of {}", repo.sync.namespace))?;
+            operators.insert(key, operator.clone());
+            Ok(operator)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::config::parse_cron;
+    use crate::daemon::Schedule;
+
+    #[test]
+    fn must_schedule_interval() {
+        let schedule = Schedule::Interval(Duration::hours(1));
+        let now = Utc.with_ymd_and_hms(2023, 10, 6, 4, 0, 0).unwrap();
+        assert_eq!(schedule.first(now), now);
+        // Started on time.
+        assert_eq!(schedule.next(now, now), now + Duration::hours(1));
+        // Started late, but still before the next tick.
+        assert_eq!(
+            schedule.next(now, now + Duration::minutes(10)),
+            now + Duration::hours(1)
+        );
+        // Missed ticks are skipped.
+        assert_eq!(
+            schedule.next(now, now + Duration::minutes(90)),
+            now + Duration::minutes(150)
+        );
+    }
+
+    #[test]
+    fn must_schedule_cron() {
+        let schedule = Schedule::Cron(Box::new(parse_cron("0 */4 * * *").unwrap()));
+        let now = Utc.with_ymd_and_hms(2023, 10, 6, 5, 30, 0).unwrap();
+        let expected = Utc.with_ymd_and_hms(2023, 10, 6, 8, 0, 0).unwrap();
+        assert_eq!(schedule.first(now), expected);
+        assert_eq!(schedule.next(now, now), expected);
+    }
+}
@@ -0,0 +1,131 @@
+//! Health of upstream urls.
+//!
+//! Equivalent urls of a source are tried in order of their health recorded in Postgres by past
+//! syncs, so a sync falls back to another upstream when one is down, and prefers fresh and fast
+//! upstreams otherwise.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use url::Url;
+
+/// Recorded health of an upstream url.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Health {
+    /// Latency of the last successful handshake in milliseconds.
+    pub handshake_ms: Option<i32>,
+    /// When a revision synced from the url was last published.
+    pub last_success: Option<DateTime<Utc>>,
+    /// When a sync last failed because of the url.
+    pub last_failure: Option<DateTime<Utc>>,
+    /// Oldest trace timestamp of the last revision synced from the url.
+    pub freshness: Option<DateTime<Utc>>,
+}
+
+impl Health {
+    /// Whether the url failed after its last success, and the failure is newer than `since`.
+    fn failing(&self, since: DateTime<Utc>) -> bool {
+        match (self.last_failure, self.last_success) {
+            (Some(failure), _) if failure < since => false,
+            (Some(failure), Some(success)) => failure > success,
+            (failure, _) => failure.is_some(),
+        }
+    }
+}
+
+/// Order urls by health.
+///
+/// Urls failed after their last success go last, unless the failure is older than `since`, so
+/// that an upstream recovered from an outage is preferred again. Otherwise, fresher urls go first,
+/// then the ones with lower handshake latency. Ties, e.g. urls never tried, keep their given
+/// order.
+pub fn rank_urls(urls: &[Url], health: &HashMap<String, Health>, since: DateTime<Utc>) -> Vec<Url> {
+    let mut urls = urls.to_vec();
+    urls.sort_by_key(|url| {
+        health
+            .get(url.as_str())
+            .map_or((false, Reverse(None), i32::MAX), |health| {
+                (
+                    health.failing(since),
+                    Reverse(health.freshness),
+                    health.handshake_ms.unwrap_or(i32::MAX),
+                )
+            })
+    });
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::{Duration, TimeZone, Utc};
+    use url::Url;
+
+    use crate::health::{rank_urls, Health};
+
+    #[test]
+    fn must_rank_urls() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 21, 0, 0, 0).unwrap();
+        let urls: Vec<Url> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|host| format!("rsync://{host}/debian/").parse().unwrap())
+            .collect();
+        let health = HashMap::from([
+            (
+                // Down since its last success.
+                urls[0].to_string(),
+                Health {
+                    handshake_ms: Some(10),
+                    last_success: Some(now - Duration::hours(2)),
+                    last_failure: Some(now - Duration::hours(1)),
+                    freshness: Some(now),
+                },
+            ),
+            (
+                // Recovered.
+                urls[1].to_string(),
+                Health {
+                    handshake_ms: Some(200),
+                    last_success: Some(now),
+                    last_failure: Some(now - Duration::hours(1)),
+                    freshness: Some(now - Duration::hours(6)),
+                },
+            ),
+            (
+                urls[2].to_string(),
+                Health {
+                    handshake_ms: Some(50),
+                    last_success: Some(now),
+                    last_failure: None,
+                    freshness: Some(now - Duration::hours(6)),
+                },
+            ),
+            (
+                urls[3].to_string(),
+                Health {
+                    handshake_ms: Some(500),
+                    last_success: Some(now),
+                    last_failure: None,
+                    freshness: Some(now),
+                },
+            ),
+        ]);
+        let ranked = |since| -> Vec<_> {
+            rank_urls(&urls, &health, since)
+                .iter()
+                .map(|url| url.host_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(ranked(now - Duration::hours(6)), ["d", "c", "b", "e", "a"]);
+        // The failure of a has expired.
+        assert_eq!(
+            ranked(now - Duration::minutes(30)),
+            ["a", "d", "c", "b", "e"]
+        );
+
+        // Never tried urls keep their order.
+        assert_eq!(rank_urls(&urls, &HashMap::new(), now), urls);
+    }
+}
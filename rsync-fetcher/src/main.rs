@@ -9,35 +9,40 @@
 
 use std::sync::Arc;
 
-use chrono::Utc;
-use eyre::Result;
-use futures::FutureExt;
+use bytesize::ByteSize;
+use eyre::{bail, Result};
+use itertools::Itertools;
+use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
-use tokio::sync::mpsc;
-use tracing::info;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
 use rsync_core::logging::{init_color_eyre, init_logger};
 use rsync_core::logging::{LogFormat, LogTarget};
-use rsync_core::pg::{
-    change_revision_status, create_revision, ensure_repository, insert_task, RevisionStatus,
-};
-use rsync_core::pg_lock::PgLock;
-use rsync_core::s3::{build_operator, S3Opts};
+use rsync_core::s3::build_operator;
 
-use crate::pg::{
-    analyse_objects, create_fl_table, drop_fl_table, insert_file_list_to_db, update_parent_ids,
-};
-use crate::plan::diff_and_apply;
-use crate::rsync::{finalize, start_handshake, TaskBuilders};
-use crate::utils::{flatten_err, plan_stat};
+use crate::config::Config;
+use crate::daemon::daemon;
+use crate::opts::Command;
+use crate::probe::probe;
+use crate::rsync::bwlimit::RateLimiter;
+use crate::sync::{sync, Aborted};
+use crate::utils::Shutdown;
 
+mod config;
 mod consts;
+mod daemon;
+mod health;
 mod opts;
 mod pg;
 mod plan;
+mod probe;
 mod rsync;
+mod source;
+mod sync;
 #[cfg(test)]
 mod tests;
+mod trace;
 mod utils;
 
 #[tokio::main]
@@ -46,108 +51,145 @@ async fn main() -> Result<()> {
     init_logger(LogTarget::Stderr, LogFormat::Human);
 
     drop(dotenvy::dotenv());
-    let (opts, rsync_opts) = opts::parse();
-    let s3_opts = S3Opts::from(&opts);
-    let namespace = &opts.namespace;
+    let (pg_url, repos) = match opts::parse()? {
+        Command::GenerateConfig => {
+            println!(
+                "{}",
+                doku::to_toml_fmt::<Config>(&doku::toml::Formatting {
+                    enums_style: doku::toml::EnumsStyle::Commented,
+                    ..Default::default()
+                })
+            );
+            return Ok(());
+        }
+        Command::Probe {
+            urls,
+            rsync,
+            trace_files,
+        } => {
+            for url in &urls {
+                probe(url, &rsync, &trace_files).await?;
+            }
+            return Ok(());
+        }
+        Command::Sync { pg_url, repos } => (pg_url, repos),
+        Command::Daemon {
+            pg_url,
+            opts,
+            repos,
+        } => {
+            // Each sync holds a few connections at the same time.
+            #[allow(clippy::cast_possible_truncation)]
+            let pool = PgPoolOptions::new()
+                .max_connections((opts.max_concurrent * 8) as u32)
+                .connect(&pg_url)
+                .await?;
+            tokio::spawn(reload_bwlimits(
+                repos
+                    .iter()
+                    .filter(|repo| repo.sync.rsync.reload_bwlimit)
+                    .map(|repo| repo.sync.rsync.bwlimit.clone())
+                    .collect(),
+                repos
+                    .iter()
+                    .filter(|repo| repo.sync.reload_s3_bwlimit)
+                    .map(|repo| repo.sync.s3_bwlimit.clone())
+                    .collect(),
+            ));
+            return daemon(opts, repos, Arc::new(pool), &Shutdown::install()?).await;
+        }
+    };
+
+    let pool = Arc::new(PgPool::connect(&pg_url).await?);
+    let shutdown = Shutdown::install()?;
+    tokio::spawn(reload_bwlimits(
+        repos
+            .iter()
+            .filter(|repo| repo.rsync.reload_bwlimit)
+            .map(|repo| repo.rsync.bwlimit.clone())
+            .collect(),
+        repos
+            .iter()
+            .filter(|repo| repo.reload_s3_bwlimit)
+            .map(|repo| repo.s3_bwlimit.clone())
+            .collect(),
+    ));
+
+    if let [repo] = &repos[..] {
+        return sync(repo, &pool, &build_operator(&repo.s3)?, &shutdown).await;
+    }
+
+    // Failure of a repository shouldn't stop others from syncing.
+    let mut failed = vec![];
+    for repo in &repos {
+        info!(namespace = repo.namespace, "syncing repository.");
+        let result = match build_operator(&repo.s3) {
+            Ok(s3) => sync(repo, &pool, &s3, &shutdown).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if e.is::<Aborted>() {
+                return Err(e);
+            }
+            error!(namespace = repo.namespace, ?e, "sync failed");
+            failed.push(&repo.namespace);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("failed to sync {}", failed.iter().join(", "));
+    }
 
-    let pool = Arc::new(PgPool::connect(&opts.pg_url).await?);
-    let mut db_conn = pool.acquire().await?;
-    // Shared lock table structure to prevent schema changes.
-    let system_lock = PgLock::new_shared("_system");
-    let system_guard = system_lock.lock(pool.acquire().await?).await?;
-    // Exclusively lock namespace to ensure only one write operation(fetch, migrate, gc) is running
-    // on the namespace.
-    let ns_lock = PgLock::new_exclusive(namespace);
-    let ns_guard = ns_lock.lock(pool.acquire().await?).await?;
-
-    let s3 = build_operator(&s3_opts)?;
-
-    let handshake = start_handshake(&opts.src).await?;
-    let mut conn = handshake.finalize(&rsync_opts.filters).await?;
-
-    info!("fetching file list from rsync server.");
-    let file_list = Arc::new(conn.recv_file_list().await?);
-    create_fl_table(namespace, &mut db_conn).await?;
-    insert_file_list_to_db(namespace, &file_list, &mut db_conn).await?;
-
-    info!("generating transfer plan.");
-    ensure_repository(namespace, &mut db_conn).await?;
-    let revision = create_revision(namespace, RevisionStatus::Partial, &mut db_conn).await?;
-    // Run queries to
-    // 1. diff files to be transferred
-    // 2. copy unchanged entries from live indices to partial index
-    // 3. copy directories and symlinks from file list to partial index.
-    let (copied, transfer_items) = diff_and_apply(namespace, revision, &*pool).await?;
-    info!(
-        downloads=%transfer_items.len(),
-        copied,
-        "transfer plan generated."
-    );
-
-    let (pg_tx, pg_rx) = mpsc::channel(10240);
-    let insert_handle = tokio::spawn({
-        let pool = pool.clone();
-        async move { insert_task(revision, pg_rx, &*pool).await }
-    });
-
-    // Start the transfer. The transfer model is basically the same as the original rsync impl.
-    let TaskBuilders {
-        downloader,
-        generator,
-        receiver,
-        uploader,
-        progress,
-    } = conn.into_task_builders(
-        s3.clone(),
-        opts.s3_prefix,
-        pg_tx,
-        file_list.clone(),
-        &opts.tmp_path,
-    )?;
-    let stat = plan_stat(&file_list, &transfer_items);
-    info!(?stat, "transfer plan stat.");
-    progress.set_length(stat.total_bytes);
-    let transfer_items = Arc::new(transfer_items);
-    let ((), mut generator, mut receiver, ()) = tokio::try_join!(
-        tokio::spawn({
-            let transfer_items = transfer_items.clone();
-            async move { downloader.tasks(&transfer_items).await }
-        })
-        .map(flatten_err),
-        tokio::spawn({
-            let transfer_items = transfer_items.clone();
-            async move { generator.generate_task(&transfer_items).await }
-        })
-        .map(flatten_err),
-        tokio::spawn(receiver.recv_task()).map(flatten_err),
-        tokio::spawn(uploader.upload_tasks()).map(flatten_err),
-    )?;
-    progress.finalize().await?;
-
-    info!("waiting for database insertion to finish.");
-    insert_handle.await??;
-
-    info!("generating index for directory listing.");
-    analyse_objects(&mut db_conn).await?;
-    update_parent_ids(revision, &mut db_conn).await?;
-
-    info!("committing transfer.");
-    change_revision_status(
-        revision,
-        RevisionStatus::Live,
-        Some(Utc::now()),
-        &mut db_conn,
-    )
-    .await?;
-
-    // Finalize rsync connection.
-    let stats = finalize(&mut *generator, &mut *receiver).await?;
-    info!(?stats, "transfer stats");
-
-    // Finalize db.
-    drop_fl_table(namespace, &mut db_conn).await?;
-    system_guard.unlock().await?;
-    ns_guard.unlock().await?;
+    Ok(())
+}
 
+/// Reload bandwidth limits of repositories without their own limits from `.env` on SIGHUP.
+async fn reload_bwlimits(rsync: Vec<RateLimiter>, s3: Vec<RateLimiter>) -> Result<()> {
+    let mut hup = signal(SignalKind::hangup())?;
+    while hup.recv().await.is_some() {
+        let vars = match dotenvy::dotenv_iter() {
+            Ok(vars) => vars,
+            Err(e) => {
+                warn!(?e, "failed to read .env, bandwidth limits unchanged");
+                continue;
+            }
+        };
+        // Limits missing from `.env` are lifted. Invalid ones are left unchanged.
+        let mut limits = [
+            ("RSYNC_BWLIMIT", &rsync, Some(ByteSize::b(0))),
+            ("S3_BWLIMIT", &s3, Some(ByteSize::b(0))),
+        ];
+        for var in vars {
+            let (key, value) = match var {
+                Ok(var) => var,
+                Err(e) => {
+                    warn!(?e, "invalid line in .env");
+                    continue;
+                }
+            };
+            let Some((_, _, limit)) = limits.iter_mut().find(|(name, ..)| *name == key) else {
+                continue;
+            };
+            *limit = match value.parse::<ByteSize>() {
+                Ok(parsed) => Some(parsed),
+                Err(e) => {
+                    warn!(key, value, e, "invalid bandwidth limit");
+                    None
+                }
+            };
+        }
+        for (key, limiters, limit) in limits {
+            let Some(limit) = limit else {
+                continue;
+            };
+            if limit.as_u64() == 0 {
+                info!(key, "bandwidth limit lifted");
+            } else {
+                info!(key, %limit, "bandwidth limit reloaded");
+            }
+            for limiter in limiters {
+                limiter.set_rate(limit.as_u64());
+            }
+        }
+    }
     Ok(())
 }
@@ -1,96 +1,483 @@
 use std::ffi::OsString;
+use std::iter;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
-use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use bytesize::ByteSize;
+use clap::builder::RangedU64ValueParser;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use doku::Document;
+use eyre::{Result, WrapErr};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 use url::Url;
 
 use rsync_core::s3::S3Opts;
 use rsync_core::utils::parse_ensure_end_slash;
 
-use crate::rsync::filter::Rule;
+use crate::config::{
+    load_config, select_repos, sync_opts, validate_config, Config, DaemonOpts, Repo,
+};
+use crate::consts::{BASIS_BUFFER_LIMIT, DEFAULT_FAILURE_WINDOW, DOWNLOAD_CONN, UPLOAD_CONN};
+use crate::daemon::{Schedule, ScheduledRepo};
+use crate::plan::Compare;
+use crate::rsync::bwlimit::RateLimiter;
+use crate::rsync::filter::{Action, FilterRules};
+use crate::rsync::progress_display::{ProgressFormat, ProgressOpts};
+use crate::source::{validate_sources, Source};
 
 #[derive(Parser)]
 pub struct Opts {
+    /// Config file defining repositories to sync.
+    ///
+    /// Options of repositories given on the command line override those in the file. S3 storage
+    /// and filter rules can only be set in the file.
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "src", "namespace", "s3_url", "s3_region", "s3_bucket", "s3_prefix", "filter",
+            "exclude", "include", "exclude_from", "include_from"
+        ]
+    )]
+    pub config: Option<PathBuf>,
+    /// Namespace of a repository in the config file to sync. Can be given multiple times.
+    ///
+    /// All repositories in the config file are synced if not given.
+    #[clap(long, requires = "config", action = ArgAction::Append)]
+    pub repo: Vec<String>,
+    /// Keep running and sync repositories in the config file on their `interval` or `cron`
+    /// schedules.
+    #[clap(long, requires = "config", conflicts_with = "dry_run")]
+    pub daemon: bool,
+    /// Generate a default config file to stdout.
+    #[clap(long)]
+    pub generate_config: bool,
+    /// Print the motd and module list of `--src` if it names no module, e.g. `rsync://host/`.
+    /// Otherwise, print the protocol version, auth requirement, file count and total size of the
+    /// module, and mtimes of `--trace-file`s.
+    ///
+    /// Nothing is uploaded or written to the database.
+    #[clap(long, requires = "src")]
+    pub probe: bool,
     /// Rsync remote url.
+    ///
+    /// Either `rsync://[user@]host[:port]/module/path` for rsync daemons, or
+    /// `ssh://[user@]host[:port]/path` for remote shell access.
+    ///
+    /// Can be given multiple times for equivalent upstreams. They are tried in order of their
+    /// recorded health, and the next one is used if an upstream fails.
+    #[clap(
+        long,
+        action = ArgAction::Append,
+        required_unless_present_any = ["config", "generate_config"]
+    )]
+    pub src: Vec<Url>,
+    /// Merge another source into the repository, mounted at the given path, e.g.
+    /// `security=rsync://security.debian.org/debian-security/`. Can be given multiple times.
+    ///
+    /// Equivalent upstreams of the source are separated by commas. Entries of `--src` under the
+    /// path are excluded. Filter rules apply to every source, relative to its own root.
+    #[clap(long, value_parser = Source::parse_merge, action = ArgAction::Append, requires = "src")]
+    pub merge: Vec<Source>,
+    /// File containing the password of the rsync daemon.
+    ///
+    /// Takes precedence over `RSYNC_PASSWORD`.
+    #[clap(long)]
+    pub password_file: Option<PathBuf>,
+    /// Remote shell used for `ssh://` urls.
+    #[clap(long, env = "RSYNC_RSH", default_value = "ssh")]
+    pub rsh: String,
+    /// Program to run on the remote host for `ssh://` urls.
+    #[clap(long, default_value = "rsync")]
+    pub rsync_path: String,
+    /// Spawn this command as the rsync server instead of connecting to the remote, and talk over
+    /// its stdin/stdout. Server arguments are appended to it, and the path of `--src` is used as
+    /// the source path.
+    ///
+    /// E.g. `--command rsync --src file:///srv/mirror/` syncs from a local `rsync --server`.
     #[clap(long)]
-    pub src: Url,
+    pub command: Option<String>,
     /// S3 endpoint url.
     /// For specifying authentication, use environment variables:
     /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
-    #[clap(long)]
-    pub s3_url: String,
+    #[clap(long, required_unless_present_any = ["config", "generate_config", "probe"])]
+    pub s3_url: Option<String>,
     /// S3 storage region.
-    #[clap(long)]
-    pub s3_region: String,
+    #[clap(long, required_unless_present_any = ["config", "generate_config", "probe"])]
+    pub s3_region: Option<String>,
     /// S3 storage bucket.
-    #[clap(long)]
-    pub s3_bucket: String,
+    #[clap(long, required_unless_present_any = ["config", "generate_config", "probe"])]
+    pub s3_bucket: Option<String>,
     /// S3 storage prefix.
-    #[clap(long, value_parser = parse_ensure_end_slash)]
-    pub s3_prefix: String,
+    #[clap(long, value_parser = parse_ensure_end_slash, required_unless_present_any = ["config", "generate_config", "probe"])]
+    pub s3_prefix: Option<String>,
     /// Postgres database URL.
-    #[clap(long, env = "DATABASE_URL")]
-    pub pg_url: String,
+    #[clap(long, env = "DATABASE_URL", required_unless_present_any = ["config", "generate_config", "probe"])]
+    pub pg_url: Option<String>,
     /// Metadata namespace. Need to be unique for each repository.
-    #[clap(long)]
-    pub namespace: String,
+    #[clap(long, required_unless_present_any = ["config", "generate_config", "probe"])]
+    pub namespace: Option<String>,
+    /// Add a filter rule, e.g. `- *.iso`, `+ */`, `merge /etc/mirror/debian.rules`, or
+    /// `dir-merge,- .rsync-filter`.
+    ///
+    /// Rules follow the syntax of rsync's `--filter`. Together with `--exclude`, `--include`,
+    /// `--exclude-from` and `--include-from`, rules take effect in the order they are given.
+    #[clap(long, action = ArgAction::Append)]
+    pub filter: Vec<OsString>,
     /// Exclude files matching given pattern.
     #[clap(long, action = ArgAction::Append)]
     pub exclude: Vec<OsString>,
     /// Include files matching given pattern.
     #[clap(long, action = ArgAction::Append)]
     pub include: Vec<OsString>,
+    /// Read exclude patterns from file, one per line.
+    #[clap(long, action = ArgAction::Append)]
+    pub exclude_from: Vec<PathBuf>,
+    /// Read include patterns from file, one per line.
+    #[clap(long, action = ArgAction::Append)]
+    pub include_from: Vec<PathBuf>,
     /// Disable delta transfer.
     #[clap(long)]
     pub no_delta: bool,
+    /// Receive file list incrementally (protocol 30+).
+    ///
+    /// The file list is streamed into the database directory by directory, and transfer starts
+    /// before the whole list is received. Recommended for very large modules.
+    #[clap(long)]
+    pub inc_recursive: bool,
+    /// Compress file data during transfer.
+    ///
+    /// Recommended for bandwidth-bound upstreams with text-heavy repositories.
+    #[clap(long)]
+    pub compress: bool,
+    /// Preserve hard links.
+    ///
+    /// Hard-linked files are recorded as links of each other instead of separate files. The
+    /// upstream tracks every file it sends to find them, which costs memory and time on large
+    /// modules.
+    #[clap(short = 'H', long)]
+    pub hard_links: bool,
+    /// Compare regular files by whole-file checksums instead of mtime.
+    ///
+    /// Checksums are requested in the file list and stored with objects. Useful for upstreams
+    /// which rewrite mtimes on every publish, or keep mtimes across edits. The upstream reads
+    /// every file to compute checksums, which makes the file list much slower to receive.
+    #[clap(short, long)]
+    pub checksum: bool,
+    /// Compare regular files by size only, ignoring mtime.
+    #[clap(long, conflicts_with = "checksum")]
+    pub size_only: bool,
+    /// Consider mtimes equal if they differ by at most this many seconds.
+    ///
+    /// Useful for upstreams on FAT, which stores mtimes in 2-second units, or whose mtimes
+    /// jitter.
+    #[clap(long, default_value_t = 0, conflicts_with_all = ["checksum", "size_only"])]
+    pub modify_window: u32,
+    /// Resume an interrupted sync.
+    ///
+    /// Reopens the newest partial revision of the namespace if it is newer than the live one.
+    /// Files already uploaded to it are kept, and only missing or changed files are transferred.
+    #[clap(long)]
+    pub resume: bool,
+    /// Show what would be transferred without uploading anything or creating a revision.
+    ///
+    /// The file list is always received upfront in this mode, regardless of `--inc-recursive`.
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Limit the rate of data received from the rsync server, e.g. `10MiB` per second.
+    ///
+    /// Send SIGHUP to reload `RSYNC_BWLIMIT` from `.env` at runtime.
+    #[clap(long, env = "RSYNC_BWLIMIT")]
+    pub bwlimit: Option<ByteSize>,
+    /// Limit the rate of data uploaded to and downloaded from S3, e.g. `10MiB` per second.
+    ///
+    /// Send SIGHUP to reload `S3_BWLIMIT` from `.env` at runtime.
+    #[clap(long, env = "S3_BWLIMIT")]
+    pub s3_bwlimit: Option<ByteSize>,
+    /// Max number of concurrent connections for uploading to S3.
+    #[clap(long, default_value_t = UPLOAD_CONN, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub upload_conn: usize,
+    /// Max number of concurrent connections for downloading basis files from S3.
+    #[clap(long, default_value_t = DOWNLOAD_CONN, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub download_conn: usize,
+    /// Max number of basis files allowed to be open, or to be pending at the same time.
+    #[clap(long, default_value_t = BASIS_BUFFER_LIMIT, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub basis_buffer_limit: usize,
+    /// Also check blobs known from previous revisions exist in S3 before skipping their uploads.
+    ///
+    /// By default, blobs referenced by any revision of the namespace are trusted to exist.
+    #[clap(long)]
+    pub stat_known_blobs: bool,
+    /// Refuse to publish the new revision if its file count or total size shrank by more than
+    /// this percentage compared to the previous live revision.
+    ///
+    /// The revision is left partial instead. Set to 100 to disable the check.
+    #[clap(long, default_value_t = 50, value_parser = RangedU64ValueParser::<u8>::new().range(0..=100))]
+    pub max_shrink: u8,
+    /// Trace file in the module containing the time of the last upstream update, e.g.
+    /// `project/trace/master` or `lastsync`.
+    ///
+    /// The revision is not published if any trace file is older than that of the live revision.
+    #[clap(long, action = ArgAction::Append)]
+    pub trace_file: Vec<String>,
+    /// Refuse to publish the revision if any trace file is older than this many hours.
+    #[clap(long, requires = "trace_file")]
+    pub trace_max_age: Option<u32>,
+    /// Forget failures of an upstream url after this many hours when ranking urls.
+    ///
+    /// A url failed within this window is tried after the others, until it succeeds again.
+    #[clap(long, default_value_t = DEFAULT_FAILURE_WINDOW)]
+    pub failure_window: u32,
+    /// How to report progress.
+    ///
+    /// `json` emits a JSON line every second with the phase, file and byte counts, number of
+    /// files being uploaded and ETA.
+    #[clap(long, value_enum, default_value_t)]
+    pub progress: ProgressFormat,
+    /// Write JSON progress to this unix socket instead of stderr.
+    #[clap(long)]
+    pub progress_socket: Option<PathBuf>,
     /// Temporary directory.
     #[clap(long, default_value = "/tmp")]
     pub tmp_path: PathBuf,
 }
 
-impl From<&Opts> for S3Opts {
-    fn from(opts: &Opts) -> Self {
+/// Concurrency of S3 transfers.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Document)]
+#[serde(default)]
+pub struct Concurrency {
+    /// Max number of concurrent connections for uploading to S3.
+    #[doku(example = "8")]
+    pub upload_conn: usize,
+    /// Max number of concurrent connections for downloading basis files from S3.
+    #[doku(example = "8")]
+    pub download_conn: usize,
+    /// Max number of basis files allowed to be open, or to be pending at the same time.
+    #[doku(example = "16")]
+    pub basis_buffer_limit: usize,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
         Self {
-            region: opts.s3_region.clone(),
-            url: opts.s3_url.clone(),
-            bucket: opts.s3_bucket.clone(),
+            upload_conn: UPLOAD_CONN,
+            download_conn: DOWNLOAD_CONN,
+            basis_buffer_limit: BASIS_BUFFER_LIMIT,
         }
     }
 }
 
+/// Options to sync a repository.
+#[derive(Debug, Clone)]
+pub struct SyncOpts {
+    /// Sources of the repository. The first one is mounted at the root.
+    pub sources: Vec<Source>,
+    pub namespace: String,
+    pub s3: S3Opts,
+    pub s3_prefix: String,
+    pub s3_bwlimit: RateLimiter,
+    /// Whether `s3_bwlimit` follows `S3_BWLIMIT` in `.env` on SIGHUP.
+    pub reload_s3_bwlimit: bool,
+    pub concurrency: Concurrency,
+    pub stat_known_blobs: bool,
+    pub compare: Compare,
+    pub resume: bool,
+    pub dry_run: bool,
+    pub max_shrink: u8,
+    pub trace_files: Vec<String>,
+    pub trace_max_age: Option<u32>,
+    pub failure_window: u32,
+    pub tmp_path: PathBuf,
+    pub progress: ProgressOpts,
+    pub rsync: RsyncOpts,
+}
+
+/// What to do, as given on the command line.
+pub enum Command {
+    /// Print a default config file.
+    GenerateConfig,
+    /// Probe upstreams.
+    Probe {
+        urls: Vec<Url>,
+        rsync: RsyncOpts,
+        trace_files: Vec<String>,
+    },
+    /// Sync repositories one by one.
+    Sync {
+        pg_url: String,
+        repos: Vec<SyncOpts>,
+    },
+    /// Sync repositories on their schedules.
+    Daemon {
+        pg_url: String,
+        opts: DaemonOpts,
+        repos: Vec<ScheduledRepo>,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub struct RsyncOpts {
-    pub filters: Vec<Rule>,
+    pub filters: FilterRules,
+    pub bwlimit: RateLimiter,
+    /// Whether `bwlimit` follows `RSYNC_BWLIMIT` in `.env` on SIGHUP.
+    pub reload_bwlimit: bool,
+    pub inc_recursive: bool,
+    pub compress: bool,
+    pub hard_links: bool,
+    pub checksum: bool,
+    pub rsh: String,
+    pub rsync_path: String,
+    pub command: Option<String>,
+    pub password_file: Option<PathBuf>,
+}
+
+fn format_error(e: clap::Error) -> clap::Error {
+    let mut cmd = Opts::command();
+    e.format(&mut cmd)
+}
+
+/// Options which override those in the config file if given on the command line.
+const OVERRIDES: &[&str] = &[
+    "pg_url",
+    "tmp_path",
+    "password_file",
+    "rsh",
+    "rsync_path",
+    "command",
+    "inc_recursive",
+    "compress",
+    "hard_links",
+    "checksum",
+    "size_only",
+    "modify_window",
+    "resume",
+    "bwlimit",
+    "s3_bwlimit",
+    "upload_conn",
+    "download_conn",
+    "basis_buffer_limit",
+    "stat_known_blobs",
+    "max_shrink",
+    "trace_file",
+    "trace_max_age",
+    "failure_window",
+];
+
+/// Options in `OVERRIDES` given on the command line.
+fn given_overrides(matches: &ArgMatches) -> Vec<&'static str> {
+    OVERRIDES
+        .iter()
+        .copied()
+        .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+        .collect()
 }
 
-impl From<&Opts> for RsyncOpts {
-    fn from(opts: &Opts) -> Self {
-        let mut filters = Vec::new();
-        for pattern in &opts.exclude {
-            filters.push(Rule::Exclude(pattern.clone()));
+/// Override options in the config file with the `given` ones on the command line.
+fn override_config(config: &mut Config, opts: &Opts, given: &[&str]) {
+    let given = |id| given.contains(&id);
+    if given("pg_url") {
+        config.database_url = opts.pg_url.clone().expect("pg_url");
+    }
+    if given("tmp_path") {
+        config.tmp_path = opts.tmp_path.clone();
+    }
+    for repo in &mut config.repos {
+        if given("password_file") {
+            repo.password_file = opts.password_file.clone();
+        }
+        if given("rsh") {
+            repo.rsh = opts.rsh.clone();
+        }
+        if given("rsync_path") {
+            repo.rsync_path = opts.rsync_path.clone();
+        }
+        if given("command") {
+            repo.command = opts.command.clone();
         }
-        for pattern in &opts.include {
-            filters.push(Rule::Include(pattern.clone()));
+        repo.inc_recursive |= opts.inc_recursive;
+        repo.compress |= opts.compress;
+        repo.hard_links |= opts.hard_links;
+        repo.resume |= opts.resume;
+        repo.stat_known_blobs |= opts.stat_known_blobs;
+        // Ways to compare files given on the command line replace the one in the file.
+        if given("checksum") || given("size_only") || given("modify_window") {
+            repo.checksum = opts.checksum;
+            repo.size_only = opts.size_only;
+            repo.modify_window = opts.modify_window;
+        }
+        if given("bwlimit") {
+            repo.bwlimit = opts.bwlimit;
+        }
+        if given("s3_bwlimit") {
+            repo.s3_bwlimit = opts.s3_bwlimit;
+        }
+        if given("upload_conn") {
+            repo.concurrency.upload_conn = opts.upload_conn;
+        }
+        if given("download_conn") {
+            repo.concurrency.download_conn = opts.download_conn;
+        }
+        if given("basis_buffer_limit") {
+            repo.concurrency.basis_buffer_limit = opts.basis_buffer_limit;
+        }
+        if given("max_shrink") {
+            repo.max_shrink = opts.max_shrink;
+        }
+        if given("trace_file") {
+            repo.trace_files = opts.trace_file.clone();
+        }
+        if given("trace_max_age") {
+            repo.trace_max_age = opts.trace_max_age;
+        }
+        if given("failure_window") {
+            repo.failure_window = opts.failure_window;
         }
-        Self { filters }
     }
 }
 
-fn format_error(e: clap::Error) -> clap::Error {
-    let mut cmd = Opts::command();
-    e.format(&mut cmd)
+/// Options to sync a repository in the config file.
+///
+/// Bandwidth limits not set for the repository are read from `RSYNC_BWLIMIT` and `S3_BWLIMIT`,
+/// and follow them on SIGHUP.
+fn repo_sync_opts(
+    repo: &Repo,
+    config: &Config,
+    progress: &ProgressOpts,
+    opts: &Opts,
+) -> Result<SyncOpts> {
+    let sync = sync_opts(repo, config, progress, opts.dry_run)?;
+    if sync.rsync.reload_bwlimit {
+        sync.rsync
+            .bwlimit
+            .set_rate(opts.bwlimit.map_or(0, |limit| limit.as_u64()));
+    }
+    if sync.reload_s3_bwlimit {
+        sync.s3_bwlimit
+            .set_rate(opts.s3_bwlimit.map_or(0, |limit| limit.as_u64()));
+    }
+    Ok(sync)
+}
+
+/// A filter option given on the command line.
+enum FilterArg<'a> {
+    Filter(&'a OsString),
+    Pattern(&'a OsString, Action),
+    PatternsFrom(&'a PathBuf, Action),
 }
 
-pub fn parse() -> (Opts, RsyncOpts) {
+pub fn parse() -> Result<Command> {
     let mut matches = Opts::command().get_matches();
 
-    let exclude_indices = matches
-        .indices_of("exclude")
-        .map_or(vec![], Iterator::collect);
-    let include_indices = matches
-        .indices_of("include")
-        .map_or(vec![], Iterator::collect);
+    let indices_of = |id| matches.indices_of(id).map_or(vec![], Iterator::collect);
+    let filter_indices: Vec<usize> = indices_of("filter");
+    let exclude_indices: Vec<usize> = indices_of("exclude");
+    let include_indices: Vec<usize> = indices_of("include");
+    let exclude_from_indices: Vec<usize> = indices_of("exclude_from");
+    let include_from_indices: Vec<usize> = indices_of("include_from");
+
+    let given = given_overrides(&matches);
 
     let res = Opts::from_arg_matches_mut(&mut matches).map_err(format_error);
 
@@ -103,24 +490,224 @@ pub fn parse() -> (Opts, RsyncOpts) {
         }
     };
 
-    let exclude_patterns = opts
-        .exclude
-        .clone()
-        .into_iter()
-        .map(Rule::Exclude)
-        .zip(exclude_indices);
-    let include_patterns = opts
-        .include
-        .clone()
-        .into_iter()
-        .map(Rule::Include)
-        .zip(include_indices);
-
-    let filters = exclude_patterns
-        .chain(include_patterns)
+    if opts.generate_config {
+        return Ok(Command::GenerateConfig);
+    }
+    let progress = ProgressOpts {
+        format: if opts.progress_socket.is_some() {
+            ProgressFormat::Json
+        } else {
+            opts.progress
+        },
+        socket: opts.progress_socket.clone(),
+    };
+    if let Some(path) = &opts.config {
+        let mut config = load_config(path)?;
+        override_config(&mut config, &opts, &given);
+        validate_config(&config)?;
+        let selected = select_repos(&config, &opts.repo)?;
+        if !opts.daemon {
+            return Ok(Command::Sync {
+                pg_url: config.database_url.clone(),
+                repos: selected
+                    .into_iter()
+                    .map(|repo| repo_sync_opts(repo, &config, &progress, &opts))
+                    .collect::<Result<_>>()?,
+            });
+        }
+
+        let mut repos = vec![];
+        for repo in selected {
+            if let Some(schedule) = Schedule::from_repo(repo)? {
+                repos.push(ScheduledRepo {
+                    sync: repo_sync_opts(repo, &config, &progress, &opts)?,
+                    schedule,
+                });
+            } else {
+                warn!(
+                    namespace = repo.namespace,
+                    "no schedule, not synced by daemon"
+                );
+            }
+        }
+        return Ok(Command::Daemon {
+            pg_url: config.database_url.clone(),
+            opts: config.daemon,
+            repos,
+        });
+    }
+
+    // Rules take effect in the order they are given, regardless of the option used.
+    let filter_args = opts
+        .filter
+        .iter()
+        .map(FilterArg::Filter)
+        .zip(filter_indices)
+        .chain(
+            opts.exclude
+                .iter()
+                .map(|p| FilterArg::Pattern(p, Action::Exclude))
+                .zip(exclude_indices),
+        )
+        .chain(
+            opts.include
+                .iter()
+                .map(|p| FilterArg::Pattern(p, Action::Include))
+                .zip(include_indices),
+        )
+        .chain(
+            opts.exclude_from
+                .iter()
+                .map(|p| FilterArg::PatternsFrom(p, Action::Exclude))
+                .zip(exclude_from_indices),
+        )
+        .chain(
+            opts.include_from
+                .iter()
+                .map(|p| FilterArg::PatternsFrom(p, Action::Include))
+                .zip(include_from_indices),
+        )
         .sorted_by_key(|(_, i)| *i)
-        .map(|(p, _)| p)
+        .map(|(arg, _)| arg);
+
+    let mut filters = FilterRules::default();
+    for arg in filter_args {
+        match arg {
+            FilterArg::Filter(rule) => filters
+                .add_rule(rule.as_bytes())
+                .wrap_err_with(|| format!("invalid filter rule {rule:?}"))?,
+            FilterArg::Pattern(pattern, action) => filters
+                .add_pattern(pattern.as_bytes(), action)
+                .wrap_err_with(|| format!("invalid pattern {pattern:?}"))?,
+            FilterArg::PatternsFrom(path, action) => filters.add_patterns_from(path, action)?,
+        }
+    }
+
+    let rsync = RsyncOpts {
+        filters,
+        bwlimit: RateLimiter::new(opts.bwlimit.map_or(0, |limit| limit.as_u64())),
+        reload_bwlimit: true,
+        inc_recursive: opts.inc_recursive && !opts.dry_run,
+        compress: opts.compress,
+        hard_links: opts.hard_links,
+        checksum: opts.checksum,
+        rsh: opts.rsh,
+        rsync_path: opts.rsync_path,
+        command: opts.command,
+        password_file: opts.password_file,
+    };
+    if opts.probe {
+        return Ok(Command::Probe {
+            urls: opts.src,
+            rsync,
+            trace_files: opts.trace_file,
+        });
+    }
+    // Required options are ensured by clap.
+    let sources: Vec<_> = iter::once(Source::root(opts.src))
+        .chain(opts.merge)
         .collect();
+    validate_sources(&sources)?;
+    let sync = SyncOpts {
+        sources,
+        namespace: opts.namespace.expect("namespace"),
+        s3: S3Opts {
+            region: opts.s3_region.expect("s3_region"),
+            url: opts.s3_url.expect("s3_url"),
+            bucket: opts.s3_bucket.expect("s3_bucket"),
+        },
+        s3_prefix: opts.s3_prefix.expect("s3_prefix"),
+        s3_bwlimit: RateLimiter::new(opts.s3_bwlimit.map_or(0, |limit| limit.as_u64())),
+        reload_s3_bwlimit: true,
+        concurrency: Concurrency {
+            upload_conn: opts.upload_conn,
+            download_conn: opts.download_conn,
+            basis_buffer_limit: opts.basis_buffer_limit,
+        },
+        stat_known_blobs: opts.stat_known_blobs,
+        compare: Compare::from_opts(opts.checksum, opts.size_only, opts.modify_window),
+        resume: opts.resume,
+        dry_run: opts.dry_run,
+        max_shrink: opts.max_shrink,
+        trace_files: opts.trace_file,
+        trace_max_age: opts.trace_max_age,
+        failure_window: opts.failure_window,
+        tmp_path: opts.tmp_path,
+        progress,
+        rsync,
+    };
+    Ok(Command::Sync {
+        pg_url: opts.pg_url.expect("pg_url"),
+        repos: vec![sync],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytesize::ByteSize;
+    use clap::{CommandFactory, FromArgMatches};
+    use figment::providers::{Format, Serialized, Toml};
+    use figment::Figment;
+
+    use crate::config::Config;
+    use crate::opts::{given_overrides, override_config, Opts};
+
+    const CONFIG: &str = r#"
+        [[repos]]
+        namespace = "debian"
+        src = "rsync://ftp.debian.org/debian/"
+        s3 = { url = "https://s3.example.com", region = "us-east-1", bucket = "mirror", prefix = "debian/" }
+        size_only = true
+        bwlimit = "1MiB"
+        concurrency = { upload_conn = 32 }
+    "#;
 
-    (opts, RsyncOpts { filters })
+    #[test]
+    fn must_override_config() {
+        let mut matches = Opts::command()
+            .try_get_matches_from([
+                "rsync-fetcher",
+                "--config",
+                "config.toml",
+                "--checksum",
+                "--resume",
+                "--bwlimit",
+                "10MiB",
+                "--download-conn",
+                "4",
+            ])
+            .expect("parse");
+        let given = given_overrides(&matches);
+        let opts = Opts::from_arg_matches_mut(&mut matches).expect("opts");
+
+        let mut config: Config = Figment::new()
+            .merge(Serialized::defaults(Config::default()))
+            .merge(Toml::string(CONFIG))
+            .extract()
+            .expect("config");
+        override_config(&mut config, &opts, &given);
+
+        let repo = &config.repos[0];
+        assert!(repo.checksum);
+        assert!(!repo.size_only);
+        assert!(repo.resume);
+        assert!(!repo.compress);
+        assert_eq!(repo.bwlimit, Some(ByteSize::mib(10)));
+        assert_eq!(repo.concurrency.upload_conn, 32);
+        assert_eq!(repo.concurrency.download_conn, 4);
+        assert_eq!(repo.max_shrink, 50);
+    }
+
+    #[test]
+    fn must_reject_storage_with_config() {
+        assert!(Opts::command()
+            .try_get_matches_from([
+                "rsync-fetcher",
+                "--config",
+                "config.toml",
+                "--s3-prefix",
+                "a/"
+            ])
+            .is_err());
+    }
 }
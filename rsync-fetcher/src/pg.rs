@@ -1,13 +1,17 @@
+use std::collections::HashMap;
+
 use chrono::{DateTime, Utc};
-use eyre::Result;
+use eyre::{eyre, Result};
 use itertools::multizip;
 use sqlx::{Acquire, Postgres};
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument};
 
 use rsync_core::pg::INSERT_CHUNK_SIZE;
 
+use crate::health::Health;
 use crate::rsync::file_list::FileEntry;
-use crate::utils::namespace_as_table;
+use crate::rsync::stats::Stats;
+use crate::utils::{namespace_as_table, PlannedTransfer};
 
 #[instrument(skip(db))]
 pub async fn create_fl_table<'a>(
@@ -64,18 +68,59 @@ pub async fn drop_fl_table<'a>(
     Ok(())
 }
 
+/// Insert file list entries to the file list table, and analyse it.
+///
+/// Indices and hard link groups of entries are offset by `idx_offset`, see `source::idx_offset`.
 #[instrument(skip(db, file_list))]
 pub async fn insert_file_list_to_db<'a>(
     namespace: &str,
     file_list: &[FileEntry],
+    idx_offset: u32,
+    db: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = db.acquire().await?;
+    append_file_list_to_db(namespace, file_list, idx_offset, &mut *conn).await?;
+    analyse_fl_table(namespace, &mut *conn).await?;
+
+    info!(len = file_list.len(), "inserted filelist to db");
+    Ok(())
+}
+
+/// Insert file list entries to the file list table without analysing it.
+///
+/// Used when the file list is received in chunks.
+#[instrument(skip(db, file_list), fields(len = file_list.len()))]
+pub async fn append_file_list_to_db<'a>(
+    namespace: &str,
+    file_list: &[FileEntry],
+    idx_offset: u32,
     db: impl Acquire<'a, Database = Postgres>,
 ) -> Result<()> {
     // Make sure the namespace is a valid table name.
     let ns_table = namespace_as_table(namespace);
 
     // TODO doubled memory usage, can be optimised if OOM
-    let (mut names, mut lens, mut mtimes, mut modes, mut targets, mut ids) =
-        (vec![], vec![], vec![], vec![], vec![], vec![]);
+    let (
+        mut names,
+        mut lens,
+        mut mtimes,
+        mut modes,
+        mut targets,
+        mut hlinks,
+        mut sums,
+        mut sum_types,
+        mut ids,
+    ) = (
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+    );
     #[allow(clippy::cast_possible_wrap)]
     for entry in file_list {
         let FileEntry {
@@ -84,6 +129,8 @@ pub async fn insert_file_list_to_db<'a>(
             modify_time,
             mode,
             link_target,
+            hlink_group,
+            checksum,
             idx,
         } = entry;
         names.push(name.clone());
@@ -91,18 +138,24 @@ pub async fn insert_file_list_to_db<'a>(
         mtimes.push(DateTime::<Utc>::from(*modify_time));
         modes.push(*mode as i32);
         targets.push(link_target.clone());
-        ids.push(*idx as i32);
+        hlinks.push(hlink_group.map(|group| i64::from(group + idx_offset)));
+        sums.push(checksum.map(|checksum| checksum.sum.to_vec()));
+        sum_types.push(checksum.map(|checksum| checksum.kind.file_checksum_name()));
+        ids.push((*idx + idx_offset) as i32);
     }
 
     let mut txn = db.begin().await?;
 
     let mut affected = 0u64;
-    for (ns, ls, ms, mos, ts, is) in multizip((
+    for (ns, ls, ms, mos, ts, hs, cs, cts, is) in multizip((
         names.chunks(INSERT_CHUNK_SIZE),
         lens.chunks(INSERT_CHUNK_SIZE),
         mtimes.chunks(INSERT_CHUNK_SIZE),
         modes.chunks(INSERT_CHUNK_SIZE),
         targets.chunks(INSERT_CHUNK_SIZE),
+        hlinks.chunks(INSERT_CHUNK_SIZE),
+        sums.chunks(INSERT_CHUNK_SIZE),
+        sum_types.chunks(INSERT_CHUNK_SIZE),
         ids.chunks(INSERT_CHUNK_SIZE),
     )) {
         let result = sqlx::query(
@@ -114,18 +167,32 @@ pub async fn insert_file_list_to_db<'a>(
         .bind(ms)
         .bind(mos)
         .bind(ts)
+        .bind(hs)
+        .bind(cs)
+        .bind(cts)
         .bind(is)
         .execute(&mut *txn)
         .await?;
         affected += result.rows_affected();
     }
+    txn.commit().await?;
+
+    debug!(affected, "inserted filelist to db");
+    Ok(())
+}
+
+#[instrument(skip(db))]
+pub async fn analyse_fl_table<'a>(
+    namespace: &str,
+    db: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    // Make sure the namespace is a valid table name.
+    let ns_table = namespace_as_table(namespace);
 
+    let mut conn = db.acquire().await?;
     sqlx::query(&format!("ANALYZE {ns_table}_fl"))
-        .execute(&mut *txn)
+        .execute(&mut *conn)
         .await?;
-    txn.commit().await?;
-
-    info!(affected, "inserted filelist to db");
     Ok(())
 }
 
@@ -148,17 +215,451 @@ pub async fn update_parent_ids<'a>(
     Ok(())
 }
 
+/// Record hard link groups of the file list to objects of the given revision.
+#[instrument(skip(conn))]
+pub async fn update_hard_links<'a>(
+    namespace: &str,
+    rev: i32,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    // Make sure the namespace is a valid table name.
+    let ns_table = namespace_as_table(namespace);
+
+    let mut conn = conn.acquire().await?;
+    let result = sqlx::query(
+        &include_str!("../../sqls/update_hard_links.sql")
+            .replace("rsync_filelist", &format!("{ns_table}_fl")),
+    )
+    .bind(rev)
+    .execute(&mut *conn)
+    .await?;
+    debug!(affected = result.rows_affected(), "updated hard links");
+    Ok(())
+}
+
+/// Record whole-file checksums of the file list to objects of the given revision, since
+/// transferred objects are inserted without them.
+#[instrument(skip(conn))]
+pub async fn update_checksums<'a>(
+    namespace: &str,
+    rev: i32,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    // Make sure the namespace is a valid table name.
+    let ns_table = namespace_as_table(namespace);
+
+    let mut conn = conn.acquire().await?;
+    let result = sqlx::query(
+        &include_str!("../../sqls/update_checksums.sql")
+            .replace("rsync_filelist", &format!("{ns_table}_fl")),
+    )
+    .bind(rev)
+    .execute(&mut *conn)
+    .await?;
+    debug!(affected = result.rows_affected(), "updated checksums");
+    Ok(())
+}
+
+/// Find the newest partial revision which is newer than any live revision, if any.
+#[instrument(skip(conn))]
+pub async fn resumable_revision<'a>(
+    namespace: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Option<i32>> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        sqlx::query_file_scalar!("../sqls/resumable_revision.sql", namespace)
+            .fetch_optional(&mut *conn)
+            .await?,
+    )
+}
+
+/// Reopen a partial revision to resume it.
+///
+/// Directories and symlinks are removed, since they are copied from the file list again. Regular
+/// files are kept, so that files already uploaded are not transferred again.
+#[instrument(skip(conn))]
+pub async fn resume_revision<'a>(
+    rev: i32,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = conn.acquire().await?;
+    let result = sqlx::query_file!("../sqls/resume_revision.sql", rev)
+        .execute(&mut *conn)
+        .await?;
+    debug!(
+        deleted = result.rows_affected(),
+        "reopened partial revision"
+    );
+    Ok(())
+}
+
+/// Delete objects of the given revision which are no longer in the file list.
+///
+/// Only needed when resuming a partial revision, which may contain files removed upstream since.
+#[instrument(skip(conn))]
+pub async fn delete_removed_objects<'a>(
+    namespace: &str,
+    rev: i32,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    // Make sure the namespace is a valid table name.
+    let ns_table = namespace_as_table(namespace);
+
+    let mut conn = conn.acquire().await?;
+    let result = sqlx::query(
+        &include_str!("../../sqls/delete_removed_objects.sql")
+            .replace("rsync_filelist", &format!("{ns_table}_fl")),
+    )
+    .bind(rev)
+    .execute(&mut *conn)
+    .await?;
+    info!(deleted = result.rows_affected(), "deleted removed objects");
+    Ok(())
+}
+
+/// Number of objects and their total size in the given revision.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RevisionSize {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Get the size of the given revision.
+#[instrument(skip(conn))]
+pub async fn revision_size<'a>(
+    rev: i32,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<RevisionSize> {
+    let mut conn = conn.acquire().await?;
+    let row = sqlx::query_file!("../sqls/revision_size.sql", rev)
+        .fetch_one(&mut *conn)
+        .await?;
+    #[allow(clippy::cast_sign_loss)]
+    let size = RevisionSize {
+        count: row.count as u64,
+        bytes: row.bytes as u64,
+    };
+    Ok(size)
+}
+
+/// Find the latest live revision of the namespace, if any.
+#[instrument(skip(conn))]
+pub async fn latest_live_revision<'a>(
+    namespace: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Option<i32>> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        // Shared with the gateway, which also needs the creation time.
+        sqlx::query_file!("../sqls/latest_live_revision.sql", namespace)
+            .map(|row| row.revision)
+            .fetch_optional(&mut *conn)
+            .await?,
+    )
+}
+
+/// Get the blake2b hash of a regular file in the given revision, if it exists.
+#[instrument(skip(conn))]
+pub async fn regular_file_hash<'a>(
+    rev: i32,
+    filename: &[u8],
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Option<[u8; 20]>> {
+    let mut conn = conn.acquire().await?;
+    let hash = sqlx::query_file_scalar!("../sqls/regular_file_hash.sql", rev, filename)
+        .fetch_optional(&mut *conn)
+        .await?;
+    hash.map(|hash| {
+        <[u8; 20]>::try_from(hash).map_err(|hash| {
+            eyre!(
+                "invalid blake2b hash of {} in revision {rev}: {} bytes",
+                String::from_utf8_lossy(filename),
+                hash.len()
+            )
+        })
+    })
+    .transpose()
+}
+
+/// Get the mtime of a regular file in the given revision, if it exists.
+#[instrument(skip(conn))]
+pub async fn regular_file_mtime<'a>(
+    rev: i32,
+    filename: &[u8],
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Option<DateTime<Utc>>> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        sqlx::query_file_scalar!("../sqls/regular_file_mtime.sql", rev, filename)
+            .fetch_optional(&mut *conn)
+            .await?,
+    )
+}
+
+/// Result of a sync run.
+#[derive(sqlx::Type, Debug, Copy, Clone, Eq, PartialEq)]
+#[sqlx(type_name = "sync_result", rename_all = "lowercase")]
+pub enum SyncResult {
+    Running,
+    Success,
+    Failed,
+    Aborted,
+}
+
+/// Statistics collected during a sync run, recorded in `sync_runs` when it finishes.
+///
+/// Fields are filled as the sync progresses, so a failed run records whatever it had reached.
+#[derive(Debug, Default)]
+pub struct RunStats {
+    pub revision: Option<i32>,
+    pub plan: Option<PlannedTransfer>,
+    pub copied: Option<u64>,
+    /// Files left out of the revision after failing checksum verification twice.
+    pub failed: Option<u64>,
+    pub rsync: Option<Stats>,
+}
+
+/// Record the start of a sync run, returning its id.
+///
+/// Runs of the namespace still marked as running are marked as aborted, because the caller holds
+/// the namespace lock and they must have been interrupted.
+#[instrument(skip(conn))]
+pub async fn start_sync_run<'a>(
+    namespace: &str,
+    started_at: DateTime<Utc>,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<i32> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        sqlx::query_file_scalar!("../sqls/start_sync_run.sql", namespace, started_at)
+            .fetch_one(&mut *conn)
+            .await?,
+    )
+}
+
+/// Record the result and statistics of a sync run.
+#[instrument(skip(stats, conn))]
+pub async fn finish_sync_run<'a>(
+    id: i32,
+    result: SyncResult,
+    error: Option<&str>,
+    stats: &RunStats,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    #[allow(clippy::cast_possible_wrap)]
+    let to_i64 = |n: u64| n as i64;
+    let mut conn = conn.acquire().await?;
+    sqlx::query_file!(
+        "../sqls/finish_sync_run.sql",
+        id,
+        Utc::now(),
+        result as _,
+        error,
+        stats.revision,
+        stats.rsync.map(|stats| stats.read),
+        stats.rsync.map(|stats| stats.written),
+        stats.plan.map(|plan| to_i64(plan.total_count)),
+        stats.plan.map(|plan| to_i64(plan.total_bytes)),
+        stats.plan.map(|plan| to_i64(plan.partial_count)),
+        stats.plan.map(|plan| to_i64(plan.partial_bytes)),
+        stats.copied.map(to_i64),
+        stats.failed.map(to_i64)
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Whether the blob is referenced by any live or partial revision of the namespace, which means it
+/// has been uploaded to storage. Blobs only referenced by stale revisions may be collected by gc.
+#[instrument(skip(conn))]
+pub async fn blob_exists<'a>(
+    namespace: &str,
+    blake2b: &[u8; 20],
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<bool> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        sqlx::query_file_scalar!("../sqls/blob_exists.sql", namespace, &blake2b[..])
+            .fetch_one(&mut *conn)
+            .await?,
+    )
+}
+
+/// Record a multipart upload, so that it can be resumed or aborted later.
+#[instrument(skip(conn))]
+pub async fn insert_multipart_upload<'a>(
+    namespace: &str,
+    key: &str,
+    upload_id: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = conn.acquire().await?;
+    sqlx::query_file!(
+        "../sqls/insert_multipart_upload.sql",
+        upload_id,
+        namespace,
+        key
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Claim the recorded multipart upload of the key, if any, by forgetting it.
+///
+/// Only one caller gets the upload, so that it's never resumed twice at once.
+#[instrument(skip(conn))]
+pub async fn claim_multipart_upload<'a>(
+    namespace: &str,
+    key: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Option<String>> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        sqlx::query_file_scalar!("../sqls/claim_multipart_upload.sql", namespace, key)
+            .fetch_optional(&mut *conn)
+            .await?,
+    )
+}
+
+/// All recorded multipart uploads of the namespace, as `(key, upload_id)`.
+#[instrument(skip(conn))]
+pub async fn list_multipart_uploads<'a>(
+    namespace: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Vec<(String, String)>> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        sqlx::query_file!("../sqls/list_multipart_uploads.sql", namespace)
+            .map(|row| (row.key, row.upload_id))
+            .fetch_all(&mut *conn)
+            .await?,
+    )
+}
+
+/// Whether the multipart upload is recorded by any namespace.
+#[instrument(skip(conn))]
+pub async fn multipart_upload_exists<'a>(
+    upload_id: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<bool> {
+    let mut conn = conn.acquire().await?;
+    Ok(
+        sqlx::query_file_scalar!("../sqls/multipart_upload_exists.sql", upload_id)
+            .fetch_one(&mut *conn)
+            .await?,
+    )
+}
+
+/// Forget a completed or aborted multipart upload.
+#[instrument(skip(conn))]
+pub async fn delete_multipart_upload<'a>(
+    upload_id: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = conn.acquire().await?;
+    sqlx::query_file!("../sqls/delete_multipart_upload.sql", upload_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Recorded health of the upstream urls, keyed by url.
+#[instrument(skip(conn))]
+pub async fn upstream_health<'a>(
+    urls: &[String],
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<HashMap<String, Health>> {
+    let mut conn = conn.acquire().await?;
+    let rows = sqlx::query_file!("../sqls/upstream_health.sql", urls)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.url,
+                Health {
+                    handshake_ms: row.handshake_ms,
+                    last_success: row.last_success,
+                    last_failure: row.last_failure,
+                    freshness: row.freshness,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Record the latency of a successful handshake with the upstream.
+#[instrument(skip(conn))]
+pub async fn record_handshake<'a>(
+    url: &str,
+    handshake_ms: i32,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = conn.acquire().await?;
+    sqlx::query_file!("../sqls/record_handshake.sql", url, handshake_ms)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Record a failure of the upstream, e.g. a failed handshake or a disconnect during transfer.
+#[instrument(skip(conn))]
+pub async fn record_upstream_failure<'a>(
+    url: &str,
+    error: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = conn.acquire().await?;
+    sqlx::query_file!("../sqls/record_upstream_failure.sql", url, error)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Record that a revision synced from the upstream is published, with the oldest trace
+/// timestamp of the upstream if known.
+#[instrument(skip(conn))]
+pub async fn record_upstream_success<'a>(
+    url: &str,
+    freshness: Option<DateTime<Utc>>,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = conn.acquire().await?;
+    sqlx::query_file!("../sqls/record_upstream_success.sql", url, freshness)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(clippy::cast_possible_wrap, clippy::cast_possible_truncation)]
 
     mod db_required {
+        use std::time::UNIX_EPOCH;
+
+        use chrono::{TimeZone, Utc};
         use sqlx::PgPool;
 
-        use rsync_core::pg::{create_revision, ensure_repository, FileType, RevisionStatus};
-        use rsync_core::tests::generate_random_namespace;
+        use rsync_core::metadata::Metadata;
+        use rsync_core::pg::{
+            change_revision_status, create_revision, ensure_repository, FileType, RevisionStatus,
+        };
+        use rsync_core::tests::{generate_random_namespace, insert_to_revision};
 
-        use crate::pg::update_parent_ids;
+        use crate::pg::{
+            blob_exists, claim_multipart_upload, create_fl_table, delete_multipart_upload,
+            delete_removed_objects, finish_sync_run, insert_file_list_to_db,
+            insert_multipart_upload, list_multipart_uploads, multipart_upload_exists,
+            record_handshake, record_upstream_failure, record_upstream_success, regular_file_hash,
+            regular_file_mtime, resumable_revision, resume_revision, start_sync_run,
+            update_parent_ids, upstream_health, RunStats, SyncResult,
+        };
+        use crate::rsync::file_list::FileEntry;
 
         #[sqlx::test(migrations = "../tests/migrations")]
         async fn must_update_parent_ids(pool: PgPool) {
@@ -241,5 +742,319 @@ mod tests {
                 );
             }
         }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_record_sync_runs(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+            let namespace = generate_random_namespace();
+            let result = |id: i32| {
+                sqlx::query_scalar::<_, SyncResult>("SELECT result FROM sync_runs WHERE id = $1")
+                    .bind(id)
+                    .fetch_one(&pool)
+            };
+
+            let interrupted = start_sync_run(&namespace, Utc::now(), &mut conn)
+                .await
+                .expect("start");
+            assert_eq!(result(interrupted).await.unwrap(), SyncResult::Running);
+
+            // A new run marks the interrupted one as aborted.
+            let id = start_sync_run(&namespace, Utc::now(), &mut conn)
+                .await
+                .expect("start");
+            assert_eq!(result(interrupted).await.unwrap(), SyncResult::Aborted);
+
+            let stats = RunStats {
+                copied: Some(42),
+                failed: Some(1),
+                ..Default::default()
+            };
+            finish_sync_run(id, SyncResult::Failed, Some("boom"), &stats, &mut conn)
+                .await
+                .expect("finish");
+            let (error, copied, failed): (Option<String>, Option<i64>, Option<i64>) =
+                sqlx::query_as(
+                    "SELECT error, copied_count, failed_count FROM sync_runs WHERE id = $1",
+                )
+                .bind(id)
+                .fetch_one(&pool)
+                .await
+                .expect("select");
+            assert_eq!(result(id).await.unwrap(), SyncResult::Failed);
+            assert_eq!(error.as_deref(), Some("boom"));
+            assert_eq!(copied, Some(42));
+            assert_eq!(failed, Some(1));
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_find_existing_blob(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+
+            let namespace = generate_random_namespace();
+            let other = generate_random_namespace();
+            for namespace in [&namespace, &other] {
+                ensure_repository(namespace, &mut conn)
+                    .await
+                    .expect("create repo");
+            }
+            for (status, blob) in [(RevisionStatus::Partial, 1u8), (RevisionStatus::Stale, 3)] {
+                let rev = create_revision(&namespace, status, &mut conn)
+                    .await
+                    .expect("create rev");
+                sqlx::query(
+                    "INSERT INTO objects (revision, filename, len, modify_time, type, blake2b) \
+                    VALUES ($1, 'a', 0, now(), 'regular', $2)",
+                )
+                .bind(rev)
+                .bind(&[blob; 20][..])
+                .execute(&mut *conn)
+                .await
+                .expect("insert");
+            }
+
+            assert!(blob_exists(&namespace, &[1; 20], &mut conn).await.unwrap());
+            assert!(!blob_exists(&namespace, &[2; 20], &mut conn).await.unwrap());
+            // Blobs of stale revisions may be removed by gc at any time.
+            assert!(!blob_exists(&namespace, &[3; 20], &mut conn).await.unwrap());
+            // Blobs are looked up in the namespace only, which may use another prefix.
+            assert!(!blob_exists(&other, &[1; 20], &mut conn).await.unwrap());
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_find_resumable_revision(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+            let namespace = generate_random_namespace();
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("create repo");
+            assert_eq!(
+                resumable_revision(&namespace, &mut conn).await.unwrap(),
+                None
+            );
+
+            let old = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create rev");
+            let newer = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create rev");
+            assert_eq!(
+                resumable_revision(&namespace, &mut conn).await.unwrap(),
+                Some(newer)
+            );
+
+            // Partial revisions older than the live one are not resumed.
+            let live = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create rev");
+            change_revision_status(live, RevisionStatus::Live, Some(Utc::now()), &mut conn)
+                .await
+                .expect("publish");
+            assert_eq!(
+                resumable_revision(&namespace, &mut conn).await.unwrap(),
+                None
+            );
+            assert!(old < newer && newer < live);
+
+            // Partial revisions of other namespaces are not resumed.
+            let other = generate_random_namespace();
+            ensure_repository(&other, &mut conn)
+                .await
+                .expect("create repo");
+            create_revision(&other, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create rev");
+            assert_eq!(
+                resumable_revision(&namespace, &mut conn).await.unwrap(),
+                None
+            );
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_clean_resumed_revision(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+            let namespace = generate_random_namespace();
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("create repo");
+            let rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create rev");
+            insert_to_revision(
+                rev,
+                &[
+                    (b"kept".to_vec(), Metadata::regular(0, UNIX_EPOCH, [1; 20])),
+                    (
+                        b"removed".to_vec(),
+                        Metadata::regular(0, UNIX_EPOCH, [2; 20]),
+                    ),
+                    (b"dir".to_vec(), Metadata::directory(0, UNIX_EPOCH)),
+                ],
+                &mut conn,
+            )
+            .await;
+            let filenames = || {
+                sqlx::query_scalar::<_, Vec<u8>>(
+                    "SELECT filename FROM objects WHERE revision = $1 ORDER BY filename",
+                )
+                .bind(rev)
+                .fetch_all(&pool)
+            };
+
+            // Directories are copied from the file list again.
+            resume_revision(rev, &mut conn).await.expect("resume");
+            assert_eq!(
+                filenames().await.unwrap(),
+                [b"kept".to_vec(), b"removed".to_vec()]
+            );
+
+            // Files removed upstream since the revision was interrupted are deleted.
+            create_fl_table(&namespace, &mut conn)
+                .await
+                .expect("create table");
+            insert_file_list_to_db(
+                &namespace,
+                &[
+                    FileEntry::regular("kept".into(), 0, UNIX_EPOCH, 0),
+                    FileEntry::regular("new".into(), 0, UNIX_EPOCH, 1),
+                ],
+                0,
+                &mut conn,
+            )
+            .await
+            .expect("insert file list");
+            delete_removed_objects(&namespace, rev, &mut conn)
+                .await
+                .expect("delete");
+            assert_eq!(filenames().await.unwrap(), [b"kept".to_vec()]);
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_find_regular_file(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+
+            let namespace = generate_random_namespace();
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("create repo");
+            let rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create rev");
+            let mtime = Utc.with_ymd_and_hms(2023, 10, 6, 4, 46, 48).unwrap();
+            for (filename, kind, blake2b) in [
+                ("a", "regular", Some(&[1u8; 20][..])),
+                ("b", "directory", None),
+            ] {
+                sqlx::query(
+                    "INSERT INTO objects (revision, filename, len, modify_time, type, blake2b) \
+                    VALUES ($1, $2, 0, $3, $4::filetype, $5)",
+                )
+                .bind(rev)
+                .bind(filename.as_bytes())
+                .bind(mtime)
+                .bind(kind)
+                .bind(blake2b)
+                .execute(&mut *conn)
+                .await
+                .expect("insert");
+            }
+
+            let hash = regular_file_hash(rev, b"a", &mut conn).await.unwrap();
+            assert_eq!(hash, Some([1; 20]));
+            assert_eq!(regular_file_hash(rev, b"b", &mut conn).await.unwrap(), None);
+
+            let found = regular_file_mtime(rev, b"a", &mut conn).await.unwrap();
+            assert_eq!(found, Some(mtime));
+            assert_eq!(
+                regular_file_mtime(rev, b"b", &mut conn).await.unwrap(),
+                None
+            );
+            assert_eq!(
+                regular_file_mtime(rev, b"x", &mut conn).await.unwrap(),
+                None
+            );
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_record_multipart_uploads(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+            let namespace = generate_random_namespace();
+            let other = generate_random_namespace();
+
+            for (namespace, key, upload_id) in [
+                (&namespace, "k", "u1"),
+                (&namespace, "l", "u2"),
+                (&other, "k", "u3"),
+            ] {
+                insert_multipart_upload(namespace, key, upload_id, &mut conn)
+                    .await
+                    .expect("insert");
+            }
+            // Only one upload of each key is recorded.
+            assert!(insert_multipart_upload(&namespace, "k", "u4", &mut conn)
+                .await
+                .is_err());
+
+            let mut uploads = list_multipart_uploads(&namespace, &mut conn).await.unwrap();
+            uploads.sort();
+            assert_eq!(
+                uploads,
+                [
+                    ("k".to_string(), "u1".to_string()),
+                    ("l".to_string(), "u2".to_string())
+                ]
+            );
+
+            // Uploads of any namespace are known.
+            assert!(multipart_upload_exists("u3", &mut conn).await.unwrap());
+            assert!(!multipart_upload_exists("u4", &mut conn).await.unwrap());
+
+            // An upload is claimed once.
+            let found = claim_multipart_upload(&namespace, "k", &mut conn)
+                .await
+                .unwrap();
+            assert_eq!(found.as_deref(), Some("u1"));
+            let found = claim_multipart_upload(&namespace, "k", &mut conn)
+                .await
+                .unwrap();
+            assert_eq!(found, None);
+            assert!(multipart_upload_exists("u3", &mut conn).await.unwrap());
+
+            delete_multipart_upload("u2", &mut conn).await.unwrap();
+            assert!(!multipart_upload_exists("u2", &mut conn).await.unwrap());
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_record_upstream_health(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+            let url = "rsync://a/debian/";
+            let freshness = Utc.with_ymd_and_hms(2023, 10, 21, 0, 0, 0).unwrap();
+
+            record_handshake(url, 42, &mut conn).await.unwrap();
+            record_upstream_failure(url, "boom", &mut conn)
+                .await
+                .unwrap();
+            let health = upstream_health(&[url.to_string()], &mut conn)
+                .await
+                .unwrap();
+            assert_eq!(health[url].handshake_ms, Some(42));
+            assert!(health[url].last_failure.is_some());
+            assert!(health[url].last_success.is_none());
+
+            record_upstream_success(url, Some(freshness), &mut conn)
+                .await
+                .unwrap();
+            // Freshness is kept if unknown.
+            record_upstream_success(url, None, &mut conn).await.unwrap();
+            let health = upstream_health(
+                &[url.to_string(), "rsync://b/debian/".to_string()],
+                &mut conn,
+            )
+            .await
+            .unwrap();
+            assert_eq!(health.len(), 1);
+            assert_eq!(health[url].freshness, Some(freshness));
+            assert!(health[url].last_success.is_some());
+        }
     }
 }
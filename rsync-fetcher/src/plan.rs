@@ -4,12 +4,75 @@
 //! 1. No need to use partial stale to handle stale files caused by previous partial transfer, because we will mark those revisions as STALE.
 //! 2. All previous live revisions are considered, not just the latest one.
 
-use crate::utils::namespace_as_table;
+use std::future::{ready, Future};
+use std::ops::RangeInclusive;
+
 use eyre::Result;
+use futures::future::try_join_all;
 use sqlx::postgres::PgRow;
-use sqlx::{Acquire, Error, FromRow, Postgres, Row};
+use sqlx::{Acquire, Error, FromRow, PgPool, Postgres, Row};
 use tap::Tap;
-use tracing::{info, instrument};
+use tokio::sync::mpsc;
+use tracing::{debug, info, instrument};
+
+use rsync_core::pg::{create_revision, ensure_repository, RevisionStatus};
+
+use crate::pg::{
+    analyse_fl_table, append_file_list_to_db, create_fl_table, insert_file_list_to_db,
+};
+use crate::rsync::file_list::{FileEntry, InFlightFiles};
+use crate::rsync::progress_display::ProgressDisplay;
+use crate::source::SOURCE_IDX_STRIDE;
+use crate::utils::{namespace_as_table, plan_stat, PlannedTransfer};
+
+/// How regular files in the file list are compared with objects of previous revisions.
+///
+/// Both diff queries take the same policy, so that a file is never both copied and transferred.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Compare {
+    /// Len and mtime match, with mtimes differing by at most `modify_window` seconds.
+    Mtime { modify_window: u32 },
+    /// Only len matches.
+    SizeOnly,
+    /// Len and whole-file checksum match. Objects without checksums never match.
+    ///
+    /// Objects of other filenames match too, so that renamed files are copied instead of
+    /// transferred again.
+    Checksum,
+}
+
+impl Default for Compare {
+    fn default() -> Self {
+        Self::Mtime { modify_window: 0 }
+    }
+}
+
+impl Compare {
+    /// Policy of the given options. Checksums take precedence over sizes.
+    pub const fn from_opts(checksum: bool, size_only: bool, modify_window: u32) -> Self {
+        if checksum {
+            Self::Checksum
+        } else if size_only {
+            Self::SizeOnly
+        } else {
+            Self::Mtime { modify_window }
+        }
+    }
+    /// Name of the policy in diff queries.
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Mtime { .. } => "mtime",
+            Self::SizeOnly => "size_only",
+            Self::Checksum => "checksum",
+        }
+    }
+    fn modify_window(self) -> i64 {
+        match self {
+            Self::Mtime { modify_window } => i64::from(modify_window),
+            Self::SizeOnly | Self::Checksum => 0,
+        }
+    }
+}
 
 /// Diff local and remote file list.
 ///
@@ -18,14 +81,80 @@ use tracing::{info, instrument};
 pub async fn diff_and_apply<'a>(
     namespace: &'a str,
     target_revision: i32,
+    compare: Compare,
     db: impl Acquire<'a, Database = Postgres> + Clone,
 ) -> Result<(u64, Vec<TransferItem>)> {
+    let (download, affected) = apply_after(
+        namespace,
+        target_revision,
+        compare,
+        db.clone(),
+        diff_changed_or_remote_only(namespace, 0..=u32::MAX, compare, db),
+    )
+    .await?;
+    Ok((affected, download))
+}
+
+/// Diff local and remote file list without changing anything.
+///
+/// All queries of `diff_and_apply` run in a transaction against a throwaway revision, and the
+/// transaction is rolled back afterwards.
+///
+/// Returns a list of files that need to be transferred, and count of rows that would be copied from
+/// previous revisions.
+#[instrument(skip(pool, file_list))]
+pub async fn diff_dry_run(
+    namespace: &str,
+    file_list: &[FileEntry],
+    compare: Compare,
+    pool: &PgPool,
+) -> Result<(u64, Vec<TransferItem>)> {
+    let mut txn = pool.begin().await?;
+    create_fl_table(namespace, &mut txn).await?;
+    insert_file_list_to_db(namespace, file_list, 0, &mut txn).await?;
+    ensure_repository(namespace, &mut txn).await?;
+    let revision = create_revision(namespace, RevisionStatus::Partial, &mut txn).await?;
+
+    // Diff first so that it doesn't see the copies.
+    let download = diff_changed_or_remote_only(namespace, 0..=u32::MAX, compare, &mut txn).await?;
+    let affected = diff_unchanged_and_copy(namespace, revision, compare, &mut txn).await?
+        + copy_directories(namespace, revision, &mut txn).await?
+        + copy_symlinks(namespace, revision, &mut txn).await?;
+
+    txn.rollback().await?;
+    Ok((affected, download))
+}
+
+/// Copy unchanged files, directories and symlinks from the file list to the new revision.
+///
+/// Returns count of rows copied from previous revisions.
+#[instrument(skip(db))]
+pub async fn apply_unchanged<'a>(
+    namespace: &'a str,
+    target_revision: i32,
+    compare: Compare,
+    db: impl Acquire<'a, Database = Postgres> + Clone,
+) -> Result<u64> {
+    let ((), affected) =
+        apply_after(namespace, target_revision, compare, db, ready(Ok(()))).await?;
+    Ok(affected)
+}
+
+/// Copy unchanged entries to the new revision while running `diff`. Side effects are committed
+/// after `diff` completes, so that it doesn't see them.
+async fn apply_after<'a, T>(
+    namespace: &'a str,
+    target_revision: i32,
+    compare: Compare,
+    db: impl Acquire<'a, Database = Postgres> + Clone,
+    diff: impl Future<Output = Result<T>>,
+) -> Result<(T, u64)> {
     let mut unchanged_txn = db.clone().begin().await?;
     let mut dir_txn = db.clone().begin().await?;
-    let mut link_txn = db.clone().begin().await?;
-    let (download, unchanged_affected, dir_affected, link_affected) = tokio::try_join!(
-        diff_changed_or_remote_only(namespace, db),
-        diff_unchanged_and_copy(namespace, target_revision, &mut unchanged_txn),
+    let mut link_txn = db.begin().await?;
+    let (diff_result, unchanged_affected, dir_affected, link_affected) = tokio::try_join!(
+        diff,
+        diff_unchanged_and_copy(namespace, target_revision, compare, &mut unchanged_txn),
         copy_directories(namespace, target_revision, &mut dir_txn),
         copy_symlinks(namespace, target_revision, &mut link_txn),
     )?;
@@ -35,7 +164,147 @@ pub async fn diff_and_apply<'a>(
     dir_txn.commit().await?;
     link_txn.commit().await?;
 
-    Ok((unchanged_affected + dir_affected + link_affected, download))
+    Ok((
+        diff_result,
+        unchanged_affected + dir_affected + link_affected,
+    ))
+}
+
+/// Transfer plan of a batch of file lists.
+#[derive(Debug, Clone, Default)]
+pub struct PlanBatch {
+    /// Number of file lists in this batch.
+    pub flists: usize,
+    pub items: Vec<TransferItem>,
+}
+
+/// Transfer of a source to plan.
+pub struct SourcePlan {
+    /// Offset of indices of the source in the file list table.
+    pub idx_offset: u32,
+    /// Initial file list.
+    pub initial: Vec<FileEntry>,
+    /// File lists of subdirectories received during transfer.
+    pub flist_rx: mpsc::UnboundedReceiver<Vec<FileEntry>>,
+    pub plan_tx: mpsc::UnboundedSender<PlanBatch>,
+    pub in_flight: InFlightFiles,
+}
+
+/// Plan transfer of file lists received with incremental recursion.
+///
+/// File lists are inserted into the database and diffed in batches as they arrive, and the plan
+/// of each batch is sent to the generator of its source. After all file lists of all sources are
+/// received, unchanged entries are copied to the new revision.
+///
+/// Returns count of rows copied from previous revisions, and stats of the whole plan.
+#[instrument(skip_all)]
+pub async fn plan_incremental(
+    namespace: &str,
+    target_revision: i32,
+    compare: Compare,
+    pool: &PgPool,
+    sources: Vec<SourcePlan>,
+    pb: ProgressDisplay,
+) -> Result<(u64, PlannedTransfer)> {
+    let stat = try_join_all(
+        sources
+            .into_iter()
+            .map(|source| plan_source_incremental(namespace, compare, pool, source, &pb)),
+    )
+    .await?
+    .into_iter()
+    .fold(PlannedTransfer::default(), |acc, stat| acc + stat);
+    info!(?stat, "all file lists received");
+
+    analyse_fl_table(namespace, pool).await?;
+    let copied = apply_unchanged(namespace, target_revision, compare, pool).await?;
+    Ok((copied, stat))
+}
+
+/// Plan transfer of file lists of a source as they arrive.
+async fn plan_source_incremental(
+    namespace: &str,
+    compare: Compare,
+    pool: &PgPool,
+    source: SourcePlan,
+    pb: &ProgressDisplay,
+) -> Result<PlannedTransfer> {
+    let SourcePlan {
+        idx_offset,
+        initial,
+        mut flist_rx,
+        plan_tx,
+        in_flight,
+    } = source;
+    let mut stat = PlannedTransfer::default();
+    let mut next = Some(vec![initial]);
+    loop {
+        let mut flists = match next.take() {
+            Some(flists) => flists,
+            None => match flist_rx.recv().await {
+                Some(flist) => vec![flist],
+                None => break,
+            },
+        };
+        // Take all file lists available to reduce round trips.
+        while let Ok(flist) = flist_rx.try_recv() {
+            flists.push(flist);
+        }
+
+        let batch_len = flists.len();
+        let entries: Vec<_> = flists.into_iter().flatten().collect();
+        let items = if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+            append_file_list_to_db(namespace, &entries, idx_offset, pool).await?;
+            let idx_range = first.idx + idx_offset..=last.idx + idx_offset;
+            let items = diff_changed_or_remote_only(namespace, idx_range, compare, pool).await?;
+            let items = items_of_source(&items, idx_offset);
+
+            let mut entries = entries.into_iter().peekable();
+            for item in &items {
+                #[allow(clippy::cast_sign_loss)]
+                let idx = item.idx as u32;
+                // Both entries and items are sorted by idx.
+                while entries.next_if(|entry| entry.idx < idx).is_some() {}
+                let entry = entries
+                    .next_if(|entry| entry.idx == idx)
+                    .expect("planned entry");
+                in_flight.insert(entry);
+            }
+
+            let batch_stat = plan_stat(&in_flight, &items);
+            pb.inc_planned(batch_stat.total_count, batch_stat.total_bytes);
+            stat = stat + batch_stat;
+            items
+        } else {
+            vec![]
+        };
+
+        debug!(flists = batch_len, items = items.len(), "planned batch");
+        plan_tx.send(PlanBatch {
+            flists: batch_len,
+            items,
+        })?;
+    }
+    Ok(stat)
+}
+
+/// Items of the source at `idx_offset`, with indices of its own file list, sorted by index.
+pub fn items_of_source(items: &[TransferItem], idx_offset: u32) -> Vec<TransferItem> {
+    #[allow(clippy::cast_possible_wrap)]
+    let (start, end) = (
+        idx_offset as i32,
+        (idx_offset + (SOURCE_IDX_STRIDE - 1)) as i32,
+    );
+    let mut items: Vec<_> = items
+        .iter()
+        .filter(|item| (start..=end).contains(&item.idx))
+        .map(|item| TransferItem {
+            idx: item.idx - start,
+            blake2b: item.blake2b,
+        })
+        .collect();
+    items.sort_unstable();
+    items
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -60,11 +329,15 @@ impl FromRow<'_, PgRow> for TransferItem {
 
 /// Diff local and remote file list, and return a list of files
 /// 1. only on remote
-/// 2. filename exists on remote and local, but with no entry in local with len and mtime match
+/// 2. filename exists on remote and local, but with no entry in local matching under `compare`
 ///    (in which case returns blake2b of newest revision with same filename).
+///
+/// In checksum mode, files matching an entry of another filename are not returned.
 #[instrument(skip(db))]
 async fn diff_changed_or_remote_only<'a>(
     namespace: &str,
+    idx_range: RangeInclusive<u32>,
+    compare: Compare,
     db: impl Acquire<'a, Database = Postgres>,
 ) -> Result<Vec<TransferItem>> {
     // Make sure the namespace is a valid table name.
@@ -75,14 +348,22 @@ async fn diff_changed_or_remote_only<'a>(
             .replace("rsync_filelist", &format!("{ns_table}_fl")),
     )
     .bind(namespace)
+    .bind(i32::try_from(*idx_range.start()).unwrap_or(i32::MAX))
+    .bind(i32::try_from(*idx_range.end()).unwrap_or(i32::MAX))
+    .bind(compare.name())
+    .bind(compare.modify_window())
     .fetch_all(&mut *db.acquire().await?)
     .await?
     .tap(|items| info!(len = items.len(), "changed or remote only files")))
 }
 
 /// Diff local and remote file list, get a list of files existing on both sides but unchanged
-/// (i.e. filename, len and mtime match), and copy them to the new revision. In case of multiple
-/// matching entries, the one with the newest revision is used.
+/// (i.e. filename matches, and other fields match under `compare`), and copy them to the new
+/// revision with the remote mtime. In case of multiple matching entries, the one with the newest
+/// revision is used.
+///
+/// In checksum mode, files matching an entry of another filename are copied too, with entries of
+/// the same filename preferred.
 ///
 /// # Note
 ///
@@ -91,6 +372,7 @@ async fn diff_changed_or_remote_only<'a>(
 async fn diff_unchanged_and_copy<'a>(
     namespace: &'a str,
     target_revision: i32,
+    compare: Compare,
     db: impl Acquire<'a, Database = Postgres>,
 ) -> Result<u64> {
     // Make sure the namespace is a valid table name.
@@ -102,6 +384,8 @@ async fn diff_unchanged_and_copy<'a>(
     )
     .bind(namespace)
     .bind(target_revision)
+    .bind(compare.name())
+    .bind(compare.modify_window())
     .execute(&mut *db.acquire().await?)
     .await?;
     let affected = result.rows_affected();
@@ -163,6 +447,30 @@ async fn copy_symlinks<'a>(
 
 #[cfg(test)]
 mod tests {
+    use crate::plan::{items_of_source, TransferItem};
+    use crate::source::idx_offset;
+
+    #[test]
+    fn must_split_items_by_source() {
+        let item = |idx, blake2b| TransferItem { idx, blake2b };
+        #[allow(clippy::cast_possible_wrap)]
+        let (second, third) = (idx_offset(1) as i32, idx_offset(2) as i32);
+        let items = [
+            item(second + 3, Some([1; 20])),
+            item(5, None),
+            item(third, None),
+            item(second + 1, None),
+            item(2, None),
+        ];
+        assert_eq!(items_of_source(&items, 0), [item(2, None), item(5, None)]);
+        assert_eq!(
+            items_of_source(&items, idx_offset(1)),
+            [item(1, None), item(3, Some([1; 20]))]
+        );
+        assert_eq!(items_of_source(&items, idx_offset(2)), [item(0, None)]);
+        assert!(items_of_source(&items, idx_offset(3)).is_empty());
+    }
+
     mod db_required {
         use std::time::{Duration, UNIX_EPOCH};
 
@@ -177,9 +485,13 @@ mod tests {
         use rsync_core::tests::generate_random_namespace;
         use rsync_core::tests::insert_to_revision;
 
+        use tokio::sync::mpsc;
+
         use crate::pg::{create_fl_table, insert_file_list_to_db};
-        use crate::plan::{diff_and_apply, TransferItem};
-        use crate::rsync::file_list::FileEntry;
+        use crate::plan::{diff_and_apply, plan_incremental, Compare, SourcePlan, TransferItem};
+        use crate::rsync::checksum::ChecksumType;
+        use crate::rsync::file_list::{FileChecksum, FileEntry, InFlightFiles};
+        use crate::rsync::progress_display::{ProgressDisplay, ProgressOpts};
 
         async fn assert_entry_eq<'a>(
             source_revision: i32,
@@ -247,7 +559,7 @@ mod tests {
             let namespace = generate_random_namespace();
 
             create_fl_table(&namespace, db).await.expect("create table");
-            insert_file_list_to_db(&namespace, remote, &mut conn)
+            insert_file_list_to_db(&namespace, remote, 0, &mut conn)
                 .await
                 .expect("insert file list");
 
@@ -276,9 +588,10 @@ mod tests {
             let target_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
                 .await
                 .expect("create target");
-            let (inserted, mut downloads) = diff_and_apply(&namespace, target_rev, db)
-                .await
-                .expect("diff and apply");
+            let (inserted, mut downloads) =
+                diff_and_apply(&namespace, target_rev, Compare::default(), db)
+                    .await
+                    .expect("diff and apply");
 
             let expected_dir_link: Vec<_> = remote
                 .iter()
@@ -472,5 +785,360 @@ mod tests {
             )
             .await;
         }
+
+        /// Plan the remote file list against a live revision, returning count of copied rows and
+        /// sorted downloads.
+        async fn plan_against_live(
+            remote: &[FileEntry],
+            live: &[(Vec<u8>, Metadata)],
+            compare: Compare,
+            db: &PgPool,
+        ) -> (u64, Vec<TransferItem>) {
+            let mut conn = db.acquire().await.expect("pool");
+            let namespace = generate_random_namespace();
+
+            create_fl_table(&namespace, db).await.expect("create table");
+            insert_file_list_to_db(&namespace, remote, 0, &mut conn)
+                .await
+                .expect("insert file list");
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("ensure repository");
+            let live_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create live");
+            insert_to_revision(live_rev, live, &mut conn).await;
+            change_revision_status(
+                live_rev,
+                RevisionStatus::Live,
+                Some(DateTime::from(UNIX_EPOCH)),
+                &mut conn,
+            )
+            .await
+            .expect("change live status");
+
+            let target_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create target");
+            let (copied, mut downloads) = diff_and_apply(&namespace, target_rev, compare, db)
+                .await
+                .expect("diff and apply");
+            downloads.sort();
+            (copied, downloads)
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_plan_with_modify_window(pool: PgPool) {
+            let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+            let (copied, downloads) = plan_against_live(
+                &[
+                    FileEntry::regular("a".into(), 1, at(12), 0),
+                    FileEntry::regular("b".into(), 1, at(8), 1),
+                    FileEntry::regular("c".into(), 1, at(13), 2),
+                    FileEntry::regular("d".into(), 2, at(10), 3),
+                ],
+                &[
+                    ("a".into(), Metadata::regular(1, at(10), [0; 20])),
+                    ("b".into(), Metadata::regular(1, at(10), [1; 20])),
+                    ("c".into(), Metadata::regular(1, at(10), [2; 20])),
+                    ("d".into(), Metadata::regular(1, at(10), [3; 20])),
+                ],
+                Compare::Mtime { modify_window: 2 },
+                &pool,
+            )
+            .await;
+            assert_eq!(copied, 2); // a and b are within the window
+            assert_eq!(
+                downloads,
+                [
+                    TransferItem::new(2, Some([2; 20])), // time differs too much
+                    TransferItem::new(3, Some([3; 20])), // len differs
+                ]
+            );
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_plan_size_only(pool: PgPool) {
+            let (copied, downloads) = plan_against_live(
+                &[
+                    FileEntry::regular("a".into(), 1, UNIX_EPOCH + Duration::from_secs(100), 0),
+                    FileEntry::regular("b".into(), 2, UNIX_EPOCH, 1),
+                ],
+                &[
+                    ("a".into(), Metadata::regular(1, UNIX_EPOCH, [0; 20])),
+                    ("b".into(), Metadata::regular(1, UNIX_EPOCH, [1; 20])),
+                ],
+                Compare::SizeOnly,
+                &pool,
+            )
+            .await;
+            assert_eq!(copied, 1);
+            assert_eq!(downloads, [TransferItem::new(1, Some([1; 20]))]);
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_plan_with_checksum(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("pool");
+            let namespace = generate_random_namespace();
+            let with_sum = |name: &str, modify_time, sum, idx| FileEntry {
+                checksum: Some(FileChecksum {
+                    kind: ChecksumType::Md5,
+                    sum: [sum; 16],
+                }),
+                ..FileEntry::regular(name.into(), 1, modify_time, idx)
+            };
+            let remote = [
+                with_sum("a", UNIX_EPOCH + Duration::from_secs(1), 1, 0),
+                with_sum("b", UNIX_EPOCH, 2, 1),
+                with_sum("c", UNIX_EPOCH, 3, 2),
+                with_sum("d", UNIX_EPOCH, 4, 3),
+            ];
+            create_fl_table(&namespace, &pool)
+                .await
+                .expect("create table");
+            insert_file_list_to_db(&namespace, &remote, 0, &mut conn)
+                .await
+                .expect("insert file list");
+
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("ensure repository");
+            let live_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create live");
+            insert_to_revision(
+                live_rev,
+                &[
+                    ("a".into(), Metadata::regular(1, UNIX_EPOCH, [0; 20])),
+                    ("b".into(), Metadata::regular(1, UNIX_EPOCH, [1; 20])),
+                    ("c".into(), Metadata::regular(1, UNIX_EPOCH, [2; 20])),
+                    ("d".into(), Metadata::regular(1, UNIX_EPOCH, [3; 20])),
+                ],
+                &mut conn,
+            )
+            .await;
+            // c is synced before checksum mode is enabled, and d from an upstream using MD4.
+            for (name, sum, kind) in [("a", 1u8, "md5"), ("b", 9, "md5"), ("d", 4, "md4")] {
+                sqlx::query(
+                    "UPDATE objects SET checksum = $1, checksum_type = $2::checksum_type \
+                     WHERE revision = $3 AND filename = $4",
+                )
+                .bind(&[sum; 16][..])
+                .bind(kind)
+                .bind(live_rev)
+                .bind(name.as_bytes())
+                .execute(&mut *conn)
+                .await
+                .expect("update checksum");
+            }
+            change_revision_status(
+                live_rev,
+                RevisionStatus::Live,
+                Some(DateTime::from(UNIX_EPOCH)),
+                &mut conn,
+            )
+            .await
+            .expect("change live status");
+
+            let target_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create target");
+            let (copied, mut downloads) =
+                diff_and_apply(&namespace, target_rev, Compare::Checksum, &pool)
+                    .await
+                    .expect("diff and apply");
+            downloads.sort();
+            assert_eq!(copied, 1);
+            assert_eq!(
+                downloads,
+                [
+                    TransferItem::new(1, Some([1; 20])), // checksum differs
+                    TransferItem::new(2, Some([2; 20])), // no checksum
+                    TransferItem::new(3, Some([3; 20])), // checksum of another algorithm
+                ]
+            );
+
+            // Unchanged content with a new mtime.
+            let (modify_time, checksum, checksum_type): (DateTime<Utc>, Vec<u8>, String) =
+                sqlx::query_as(
+                    "SELECT modify_time, checksum, checksum_type::TEXT FROM objects \
+                     WHERE revision = $1 AND filename = 'a'",
+                )
+                .bind(target_rev)
+                .fetch_one(&mut *conn)
+                .await
+                .expect("query");
+            assert_eq!(modify_time, DateTime::<Utc>::from(remote[0].modify_time));
+            assert_eq!(checksum, [1; 16]);
+            assert_eq!(checksum_type, "md5");
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_plan_renamed_with_checksum(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("pool");
+            let namespace = generate_random_namespace();
+            let with_sum = |name: &str, len, sum, idx| FileEntry {
+                checksum: Some(FileChecksum {
+                    kind: ChecksumType::Md5,
+                    sum: [sum; 16],
+                }),
+                ..FileEntry::regular(name.into(), len, UNIX_EPOCH, idx)
+            };
+            let remote = [
+                with_sum("a-renamed", 1, 1, 0),
+                with_sum("b-renamed", 2, 2, 1), // len differs
+                with_sum("c", 1, 3, 2),
+            ];
+            create_fl_table(&namespace, &pool)
+                .await
+                .expect("create table");
+            insert_file_list_to_db(&namespace, &remote, 0, &mut conn)
+                .await
+                .expect("insert file list");
+
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("ensure repository");
+            let live_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create live");
+            insert_to_revision(
+                live_rev,
+                &[
+                    ("a".into(), Metadata::regular(1, UNIX_EPOCH, [1; 20])),
+                    ("b".into(), Metadata::regular(1, UNIX_EPOCH, [2; 20])),
+                    ("c".into(), Metadata::regular(1, UNIX_EPOCH, [3; 20])),
+                    ("d".into(), Metadata::regular(1, UNIX_EPOCH, [4; 20])),
+                ],
+                &mut conn,
+            )
+            .await;
+            // d has the same content as c.
+            for (name, sum) in [("a", 1u8), ("b", 2), ("c", 3), ("d", 3)] {
+                sqlx::query(
+                    "UPDATE objects SET checksum = $1, checksum_type = 'md5' \
+                     WHERE revision = $2 AND filename = $3",
+                )
+                .bind(&[sum; 16][..])
+                .bind(live_rev)
+                .bind(name.as_bytes())
+                .execute(&mut *conn)
+                .await
+                .expect("update checksum");
+            }
+            change_revision_status(
+                live_rev,
+                RevisionStatus::Live,
+                Some(DateTime::from(UNIX_EPOCH)),
+                &mut conn,
+            )
+            .await
+            .expect("change live status");
+
+            let target_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create target");
+            let (copied, downloads) =
+                diff_and_apply(&namespace, target_rev, Compare::Checksum, &pool)
+                    .await
+                    .expect("diff and apply");
+            assert_eq!(copied, 2);
+            assert_eq!(downloads, [TransferItem::new(1, None)]);
+
+            let copied: Vec<(Vec<u8>, Vec<u8>)> = sqlx::query_as(
+                "SELECT filename, blake2b FROM objects WHERE revision = $1 ORDER BY filename",
+            )
+            .bind(target_rev)
+            .fetch_all(&mut *conn)
+            .await
+            .expect("query");
+            assert_eq!(
+                copied,
+                [
+                    (b"a-renamed".to_vec(), vec![1; 20]),
+                    (b"c".to_vec(), vec![3; 20]), // same filename preferred
+                ]
+            );
+        }
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_plan_incrementally_in_batches(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("pool");
+            let namespace = generate_random_namespace();
+            create_fl_table(&namespace, &pool)
+                .await
+                .expect("create table");
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("ensure repository");
+            let live_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create live");
+            insert_to_revision(
+                live_rev,
+                &[("a".into(), Metadata::regular(1, UNIX_EPOCH, [0; 20]))],
+                &mut conn,
+            )
+            .await;
+            change_revision_status(
+                live_rev,
+                RevisionStatus::Live,
+                Some(DateTime::from(UNIX_EPOCH)),
+                &mut conn,
+            )
+            .await
+            .expect("change live status");
+            let target_rev = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create target");
+
+            // File lists already received are planned in one batch with the initial one.
+            let (flist_tx, flist_rx) = mpsc::unbounded_channel();
+            flist_tx
+                .send(vec![
+                    FileEntry::regular("d/b".into(), 2, UNIX_EPOCH, 2),
+                    FileEntry::regular("d/c".into(), 3, UNIX_EPOCH, 3),
+                ])
+                .expect("send");
+            drop(flist_tx);
+            let (plan_tx, mut plan_rx) = mpsc::unbounded_channel();
+            let in_flight = InFlightFiles::default();
+            let source = SourcePlan {
+                idx_offset: 0,
+                initial: vec![
+                    FileEntry::directory(".".into(), 1, UNIX_EPOCH, 0),
+                    FileEntry::regular("a".into(), 1, UNIX_EPOCH, 1),
+                ],
+                flist_rx,
+                plan_tx,
+                in_flight: in_flight.clone(),
+            };
+            let (copied, stat) = plan_incremental(
+                &namespace,
+                target_rev,
+                Compare::default(),
+                &pool,
+                vec![source],
+                ProgressDisplay::new(&namespace, &ProgressOpts::default()),
+            )
+            .await
+            .expect("plan");
+            assert_eq!(copied, 2); // . and a
+            assert_eq!(stat.total_count, 2);
+            assert_eq!(stat.total_bytes, 5);
+
+            let batch = plan_rx.recv().await.expect("batch");
+            assert_eq!(batch.flists, 2);
+            assert_eq!(
+                batch.items,
+                [TransferItem::new(2, None), TransferItem::new(3, None)]
+            );
+            assert!(plan_rx.recv().await.is_none());
+
+            // Only planned entries are in flight.
+            assert!(in_flight.get(1).is_err());
+            assert_eq!(in_flight.get(2).expect("in flight").name, b"d/b");
+            assert_eq!(in_flight.get(3).expect("in flight").name, b"d/c");
+        }
     }
 }
@@ -0,0 +1,93 @@
+//! Probe upstreams, e.g. when evaluating new upstreams or debugging auth.
+//!
+//! Nothing is uploaded or written to the database.
+
+use std::time::Instant;
+
+use bytesize::ByteSize;
+use chrono::{DateTime, Utc};
+use eyre::{Result, WrapErr};
+use url::Url;
+
+use crate::opts::RsyncOpts;
+use crate::rsync::{list_modules, start_handshake};
+
+/// Print the motd and module list of an rsync daemon if the url names no module, e.g.
+/// `rsync://host/`. Otherwise, probe the module.
+pub async fn probe(url: &Url, rsync: &RsyncOpts, trace_files: &[String]) -> Result<()> {
+    if url.scheme() == "rsync" && url.path().trim_matches('/').is_empty() {
+        list(url, rsync).await
+    } else {
+        probe_module(url, rsync, trace_files).await
+    }
+}
+
+async fn list(url: &Url, rsync: &RsyncOpts) -> Result<()> {
+    let (motd, modules) = list_modules(url, rsync).await?;
+    for line in motd {
+        println!("{line}");
+    }
+    println!("{} modules:", modules.len());
+    for module in modules {
+        println!("  {:<15}  {}", module.name, module.comment);
+    }
+    Ok(())
+}
+
+/// Print the protocol version, auth requirement, file count and total size of a module, and
+/// mtimes of the given trace files.
+async fn probe_module(url: &Url, rsync: &RsyncOpts, trace_files: &[String]) -> Result<()> {
+    // The whole file list is needed to count files.
+    let rsync = RsyncOpts {
+        inc_recursive: false,
+        ..rsync.clone()
+    };
+    let start = Instant::now();
+    let handshake = start_handshake(url, &rsync).await?;
+    let elapsed = start.elapsed();
+
+    for line in &handshake.motd {
+        println!("{line}");
+    }
+    if let Some(remote) = handshake.remote_version {
+        println!("protocol: {} (remote {remote})", handshake.protocol_version);
+    }
+    println!(
+        "auth: {}",
+        if handshake.auth_required {
+            "required"
+        } else {
+            "not required"
+        }
+    );
+    println!("handshake: {}ms", elapsed.as_millis());
+
+    let mut conn = handshake.finalize(&rsync).await?;
+    let file_list = conn
+        .recv_file_list()
+        .await
+        .wrap_err_with(|| format!("failed to receive file list of {url}"))?;
+    let (count, bytes) = file_list
+        .iter()
+        .filter(|entry| unix_mode::is_file(entry.mode))
+        .fold((0, 0), |(count, bytes), entry| {
+            (count + 1, bytes + entry.len)
+        });
+    println!(
+        "files: {count} ({}), {} entries in total",
+        ByteSize::b(bytes),
+        file_list.len()
+    );
+
+    for path in trace_files {
+        let name = path.trim_start_matches('/').as_bytes();
+        match file_list.iter().find(|entry| entry.name == name) {
+            Some(entry) => println!(
+                "trace file {path}: modified at {}",
+                DateTime::<Utc>::from(entry.modify_time)
+            ),
+            None => println!("trace file {path}: not found"),
+        }
+    }
+    Ok(())
+}
@@ -1,41 +1,69 @@
-use eyre::{bail, Context, ContextCompat, Result};
+use std::fmt::{Display, Formatter};
+
+use eyre::{bail, ensure, Context, ContextCompat, Result};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tokio_socks::tcp::Socks5Stream;
 use tracing::info;
 use url::Url;
 
+use crate::opts::RsyncOpts;
+use crate::plan::PlanBatch;
+use crate::rsync::compat::Protocol;
 use crate::rsync::downloader::Downloader;
 use crate::rsync::envelope::RsyncReadExt;
+use crate::rsync::file_list::FileEntry;
 use crate::rsync::generator::Generator;
-use crate::rsync::handshake::{Auth, HandshakeConn};
-use crate::rsync::progress_display::ProgressDisplay;
+use crate::rsync::handshake::{server_args, Auth, DaemonModule, HandshakeConn};
+use crate::rsync::ndx::{NdxCodec, NDX_DONE};
 use crate::rsync::receiver::Receiver;
 use crate::rsync::stats::Stats;
+use crate::rsync::transport::{spawn_server, url_path, Transport};
 use crate::rsync::uploader::Uploader;
 
+pub mod bwlimit;
 mod checksum;
+pub mod compat;
+mod compress;
 mod downloader;
 mod envelope;
 pub mod file_list;
 pub mod filter;
 mod generator;
-mod handshake;
-mod mux_conn;
-mod progress_display;
+pub mod handshake;
+pub mod multipart;
+pub mod mux_conn;
+mod ndx;
+pub mod progress_display;
 mod receiver;
 pub mod stats;
+mod transport;
 pub mod uploader;
 mod version;
 
-const BYE: i32 = -1;
+/// Error of the generator or receiver not caused by the upstream, e.g. of another task which has
+/// exited with its own error, so that it's not blamed on the upstream.
+#[derive(Debug)]
+pub struct LocalError(pub &'static str);
+
+impl Display for LocalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for LocalError {}
 
 pub struct TaskBuilders {
     pub downloader: Downloader,
     pub generator: Generator,
     pub receiver: Receiver,
     pub uploader: Uploader,
-    pub progress: ProgressDisplay,
+    /// Transfer plans to be executed by the generator.
+    pub plan_tx: mpsc::UnboundedSender<PlanBatch>,
+    /// File lists received by the receiver. Incremental recursion only.
+    pub flist_rx: mpsc::UnboundedReceiver<Vec<FileEntry>>,
 }
 
 async fn connect_with_proxy(target: &str) -> Result<TcpStream> {
@@ -86,12 +114,31 @@ async fn connect_with_proxy(target: &str) -> Result<TcpStream> {
     }
 }
 
-pub async fn start_handshake(url: &Url) -> Result<HandshakeConn> {
-    let port = url.port().unwrap_or(873);
-    let path = url.path().trim_start_matches('/');
-    let auth = Auth::from_url_and_env(url);
-    let module = path.split('/').next().context("empty remote path")?;
+pub async fn start_handshake(url: &Url, opts: &RsyncOpts) -> Result<HandshakeConn> {
+    match Transport::from_url(url, opts)? {
+        Transport::Daemon => start_daemon_handshake(url, opts).await,
+        Transport::RemoteShell { command, path } => {
+            // The server is spawned before versions are exchanged.
+            let args = server_args(
+                None,
+                opts.inc_recursive,
+                opts.compress,
+                opts.checksum,
+                opts.hard_links,
+                &path,
+            );
+            let (rx, tx) = spawn_server(&command, &args)?;
+
+            let mut handshake = HandshakeConn::new(rx, tx);
+            handshake.start_remote_shell_exchange(opts).await?;
 
+            Ok(handshake)
+        }
+    }
+}
+
+async fn connect_daemon(url: &Url) -> Result<HandshakeConn> {
+    let port = url.port().unwrap_or(873);
     let stream = connect_with_proxy(&format!(
         "{}:{}",
         url.host_str().context("missing remote host")?,
@@ -99,26 +146,76 @@ pub async fn start_handshake(url: &Url) -> Result<HandshakeConn> {
     ))
     .await?;
 
-    let mut handshake = HandshakeConn::new(stream);
-    handshake.start_inband_exchange(module, path, auth).await?;
+    let (rx, tx) = stream.into_split();
+    Ok(HandshakeConn::new(Box::new(rx), Box::new(tx)))
+}
+
+async fn start_daemon_handshake(url: &Url, opts: &RsyncOpts) -> Result<HandshakeConn> {
+    let path = url_path(url)?;
+    let path = path.trim_start_matches('/');
+    let auth = Auth::from_url_and_env(url, opts.password_file.as_deref())?;
+    let module = path
+        .split('/')
+        .next()
+        .filter(|module| !module.is_empty())
+        .context("empty remote path")?;
+
+    let mut handshake = connect_daemon(url).await?;
+    handshake
+        .start_inband_exchange(module, path, auth, opts)
+        .await?;
 
     Ok(handshake)
 }
 
+/// List modules of an rsync daemon, i.e. `rsync://host/`.
+///
+/// Returns the motd and the modules.
+pub async fn list_modules(url: &Url, opts: &RsyncOpts) -> Result<(Vec<String>, Vec<DaemonModule>)> {
+    ensure!(
+        Transport::from_url(url, opts)? == Transport::Daemon,
+        "modules can only be listed from rsync daemons"
+    );
+    let mut handshake = connect_daemon(url).await?;
+    let modules = handshake.list_modules().await?;
+    Ok((handshake.motd, modules))
+}
+
 pub async fn finalize(
+    protocol: &Protocol,
     mut tx: impl AsyncWrite + Unpin,
     mut rx: impl AsyncRead + Unpin + Send,
 ) -> Result<Stats> {
-    let read = rx.read_rsync_long().await?;
-    let written = rx.read_rsync_long().await?;
-    let size = rx.read_rsync_long().await?;
+    let version = protocol.version;
+    let read = rx.read_varlong30(version, 3).await?;
+    let written = rx.read_varlong30(version, 3).await?;
+    let size = rx.read_varlong30(version, 3).await?;
+    let (flist_build_time, flist_xfer_time) = if version >= 29 {
+        (
+            Some(rx.read_varlong30(version, 3).await?),
+            Some(rx.read_varlong30(version, 3).await?),
+        )
+    } else {
+        (None, None)
+    };
 
-    tx.write_i32_le(BYE).await?;
+    // Goodbye.
+    NdxCodec::new(version).write(&mut tx, NDX_DONE).await?;
+    tx.flush().await?;
+    if version >= 31 {
+        // Server echoes our goodbye.
+        let ndx = NdxCodec::new(version).read(&mut rx).await?;
+        if ndx != NDX_DONE {
+            bail!("unexpected goodbye from server: {}", ndx);
+        }
+    }
     tx.shutdown().await?;
 
     Ok(Stats {
         read,
         written,
         size,
+        flist_build_time,
+        flist_xfer_time,
     })
 }
@@ -0,0 +1,156 @@
+//! Bandwidth limiting.
+//!
+//! A `RateLimiter` is shared by all readers of a path (e.g. the rsync socket, or S3 transfers), and
+//! its rate can be changed at any time, which takes effect on the next read.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::Future;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Shared bandwidth limit in bytes per second. Zero means unlimited.
+///
+/// Clones share the same budget.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    rate: AtomicU64,
+    // When all bytes charged so far would have been transferred at the current rate.
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        let limiter = Self::default();
+        limiter.set_rate(rate);
+        limiter
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.0.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.0.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Account for `n` transferred bytes.
+    ///
+    /// Returns the instant until which the caller should pause, if the limit is exceeded.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn charge(&self, n: usize) -> Option<Instant> {
+        let rate = self.rate();
+        if rate == 0 || n == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut next = self.0.next.lock().expect("lock poisoned");
+        // Idle time doesn't build up credit.
+        let start = next.map_or(now, |next| next.max(now));
+        let end = start + Duration::from_secs_f64(n as f64 / rate as f64);
+        *next = Some(end);
+        (end > now).then_some(end)
+    }
+}
+
+/// Pauses a reader after it exceeds its rate limit.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    limiter: RateLimiter,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self {
+            limiter,
+            delay: None,
+        }
+    }
+
+    /// Wait for the pause caused by previous reads, if any.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Account for `n` bytes read.
+    pub fn consume(&mut self, n: usize) {
+        if let Some(deadline) = self.limiter.charge(n) {
+            self.delay = Some(Box::pin(sleep_until(deadline)));
+        }
+    }
+}
+
+/// An `AsyncRead` limited by a `RateLimiter`.
+pub struct RateLimitedRead<R> {
+    inner: R,
+    throttle: Throttle,
+}
+
+impl<R> RateLimitedRead<R> {
+    pub fn new(inner: R, limiter: RateLimiter) -> Self {
+        Self {
+            inner,
+            throttle: Throttle::new(limiter),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RateLimitedRead<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.throttle.poll_ready(cx));
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - filled;
+        self.throttle.consume(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::time::{Duration, Instant};
+
+    use crate::rsync::bwlimit::{RateLimitedRead, RateLimiter};
+
+    // The clock only advances while all tasks wait for timers, so elapsed time is exact.
+    #[tokio::test(start_paused = true)]
+    async fn must_limit_rate() {
+        let limiter = RateLimiter::new(16 * 1024);
+        let mut rd = RateLimitedRead::new(Cursor::new(vec![0u8; 4096]), limiter.clone());
+        let start = Instant::now();
+        let mut buf = vec![];
+        rd.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 4096);
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(250) && elapsed < Duration::from_millis(260),
+            "{elapsed:?}"
+        );
+
+        limiter.set_rate(0);
+        let mut rd = RateLimitedRead::new(Cursor::new(vec![0u8; 4096]), limiter);
+        let start = Instant::now();
+        rd.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -4,6 +4,7 @@ use std::io::Read;
 
 use eyre::Result;
 use md4::{Digest, Md4};
+use md5::Md5;
 use multiversion::multiversion;
 use num::integer::Roots;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -96,32 +97,140 @@ pub fn checksum_1(buf: &[u8]) -> u32 {
     (s1 & 0xffff) + (s2 << 16)
 }
 
-pub fn checksum_2(seed: i32, buf: &[u8]) -> Vec<u8> {
-    let mut hasher = Md4::default();
-    hasher.update(buf);
-    hasher.update(seed.to_le_bytes());
-    hasher.finalize().to_vec()
+/// Strong checksum algorithm used for block and whole-file checksums.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChecksumType {
+    /// MD4 used by protocol 27 - 29, with the seed prefixed to whole-file checksums.
+    Md4Old,
+    /// MD4 negotiated explicitly in protocol 30 and above.
+    Md4,
+    /// MD5, default since protocol 30.
+    Md5,
 }
 
-pub fn checksum_payload(sum_head: SumHead, seed: i32, file: &mut File, file_len: u64) -> Vec<u8> {
-    checksum_payload_(sum_head, seed, file, file_len, true)
+impl ChecksumType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "md4" => Some(Self::Md4),
+            "md5" => Some(Self::Md5),
+            _ => None,
+        }
+    }
+
+    /// Algorithm of whole-file checksums in the file list, as recorded in the database. They are
+    /// unseeded, so all MD4 variants compute the same checksums.
+    pub const fn file_checksum_name(self) -> &'static str {
+        match self {
+            Self::Md4Old | Self::Md4 => "md4",
+            Self::Md5 => "md5",
+        }
+    }
+}
+
+/// Strong checksum parameters negotiated with the server.
+#[derive(Debug, Copy, Clone)]
+pub struct StrongChecksum {
+    pub kind: ChecksumType,
+    pub seed: i32,
+    /// Whether the seed goes before the data in block checksums (`CF_CHKSUM_SEED_FIX`).
+    /// Only affects MD5.
+    pub proper_seed_order: bool,
+}
+
+impl StrongChecksum {
+    /// Checksum of a single block.
+    pub fn block_sum(&self, buf: &[u8]) -> Vec<u8> {
+        let seed = (self.seed != 0).then(|| self.seed.to_le_bytes());
+        match self.kind {
+            ChecksumType::Md4Old | ChecksumType::Md4 => {
+                let mut hasher = Md4::default();
+                hasher.update(buf);
+                if let Some(seed) = seed {
+                    hasher.update(seed);
+                }
+                hasher.finalize().to_vec()
+            }
+            ChecksumType::Md5 => {
+                let mut hasher = Md5::default();
+                match seed {
+                    Some(seed) if self.proper_seed_order => {
+                        hasher.update(seed);
+                        hasher.update(buf);
+                    }
+                    Some(seed) => {
+                        hasher.update(buf);
+                        hasher.update(seed);
+                    }
+                    None => hasher.update(buf),
+                }
+                hasher.finalize().to_vec()
+            }
+        }
+    }
+    /// Hasher for whole-file checksums.
+    pub fn file_hasher(&self) -> FileHasher {
+        match self.kind {
+            ChecksumType::Md4Old => {
+                let mut hasher = Md4::default();
+                hasher.update(self.seed.to_le_bytes());
+                FileHasher::Md4(hasher)
+            }
+            ChecksumType::Md4 => FileHasher::Md4(Md4::default()),
+            ChecksumType::Md5 => FileHasher::Md5(Md5::default()),
+        }
+    }
+    /// Whether block checksums can be computed by the SIMD md4 implementation.
+    const fn simd_compatible(&self) -> bool {
+        matches!(self.kind, ChecksumType::Md4Old | ChecksumType::Md4)
+    }
+}
+
+/// Whole-file checksum hasher.
+#[derive(Clone)]
+pub enum FileHasher {
+    Md4(Md4),
+    Md5(Md5),
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md4(h) => h.update(data),
+            Self::Md5(h) => h.update(data),
+        }
+    }
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Md4(h) => h.finalize().to_vec(),
+            Self::Md5(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+pub fn checksum_payload(
+    sum_head: SumHead,
+    checksum: &StrongChecksum,
+    file: &mut File,
+    file_len: u64,
+) -> Vec<u8> {
+    checksum_payload_(sum_head, checksum, file, file_len, true)
 }
 
 #[cfg(test)]
 pub fn checksum_payload_basic(
     sum_head: SumHead,
-    seed: i32,
+    checksum: &StrongChecksum,
     file: &mut File,
     file_len: u64,
 ) -> Vec<u8> {
-    checksum_payload_(sum_head, seed, file, file_len, false)
+    checksum_payload_(sum_head, checksum, file, file_len, false)
 }
 
 #[inline]
 #[allow(clippy::cast_sign_loss)] // block_len and checksum_count are always positive.
 fn checksum_payload_(
     sum_head: SumHead,
-    seed: i32,
+    checksum: &StrongChecksum,
     file: &mut File,
     file_len: u64,
     enable_simd: bool,
@@ -131,7 +240,9 @@ fn checksum_payload_(
     let mut buf_sum = Vec::with_capacity(sum_head.checksum_count as usize * 20);
     let mut block_remaining = sum_head.checksum_count as usize;
 
-    if enable_simd {
+    if enable_simd && checksum.simd_compatible() {
+        // Seed is appended to the block if not zero.
+        let seed_len = if checksum.seed == 0 { 0 } else { 4 };
         if let Some(simd_impl) = md4_simd::simd::Md4xN::select() {
             // Sqrt of usize can't be negative.
             let mut bufs: [_; md4_simd::simd::MAX_LANES] =
@@ -155,8 +266,10 @@ fn checksum_payload_(
                     file.read_exact(&mut buf[..n1]).expect("IO error");
 
                     file_remaining -= n1 as u64;
-                    let buf_slice = &mut buf[..n1 + 4];
-                    buf_slice[n1..].copy_from_slice(&seed.to_le_bytes());
+                    let buf_slice = &mut buf[..n1 + seed_len];
+                    if seed_len > 0 {
+                        buf_slice[n1..].copy_from_slice(&checksum.seed.to_le_bytes());
+                    }
 
                     datas[idx] = buf_slice;
                 }
@@ -165,7 +278,9 @@ fn checksum_payload_(
 
                 for (idx, block) in datas[..simd_impl.lanes()].iter().enumerate() {
                     // fast checksum (must remove seed from buffer)
-                    buf_sum.extend_from_slice(&checksum_1(&block[..block.len() - 4]).to_le_bytes());
+                    buf_sum.extend_from_slice(
+                        &checksum_1(&block[..block.len() - seed_len]).to_le_bytes(),
+                    );
                     // slow checksum
                     buf_sum.extend_from_slice(&md4_hashes[idx]);
                 }
@@ -189,7 +304,7 @@ fn checksum_payload_(
         file_remaining -= n1 as u64;
 
         buf_sum.extend_from_slice(&checksum_1(buf_slice).to_le_bytes());
-        buf_sum.extend_from_slice(&checksum_2(seed, buf_slice));
+        buf_sum.extend_from_slice(&checksum.block_sum(buf_slice));
     }
 
     buf_sum
@@ -204,10 +319,12 @@ mod tests {
     use tempfile::tempfile;
     use test_strategy::proptest;
 
-    use crate::rsync::checksum::{checksum_payload, checksum_payload_basic, SumHead};
+    use crate::rsync::checksum::{
+        checksum_payload, checksum_payload_basic, ChecksumType, StrongChecksum, SumHead,
+    };
 
     #[inline]
-    fn must_checksum_payload_basic_eq_simd_(data: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+    fn must_checksum_payload_basic_eq_simd_(data: Vec<u8>, seed: i32) -> (Vec<u8>, Vec<u8>) {
         let file_len = data.len() as u64;
 
         let mut f = tempfile().expect("tempfile");
@@ -216,22 +333,27 @@ mod tests {
 
         let sum_head = SumHead::sum_sizes_sqroot(file_len);
 
-        let chksum_simd = checksum_payload(sum_head, 0, &mut f, file_len);
+        let checksum = StrongChecksum {
+            kind: ChecksumType::Md4Old,
+            seed,
+            proper_seed_order: false,
+        };
+        let chksum_simd = checksum_payload(sum_head, &checksum, &mut f, file_len);
         f.seek(SeekFrom::Start(0)).expect("seek");
-        let chksum_basic = checksum_payload_basic(sum_head, 0, &mut f, file_len);
+        let chksum_basic = checksum_payload_basic(sum_head, &checksum, &mut f, file_len);
         (chksum_simd, chksum_basic)
     }
 
     #[proptest]
-    fn must_checksum_payload_basic_eq_simd(data: Vec<u8>) {
-        let (chksum_simd, chksum_basic) = must_checksum_payload_basic_eq_simd_(data);
+    fn must_checksum_payload_basic_eq_simd(data: Vec<u8>, seed: i32) {
+        let (chksum_simd, chksum_basic) = must_checksum_payload_basic_eq_simd_(data, seed);
         prop_assert_eq!(chksum_simd, chksum_basic);
     }
 
     #[test]
     fn checksum_payload_simd_regression_1() {
         let data = vec![0u8; 11199];
-        let (chksum_simd, chksum_basic) = must_checksum_payload_basic_eq_simd_(data);
+        let (chksum_simd, chksum_basic) = must_checksum_payload_basic_eq_simd_(data, 0);
         assert_eq!(chksum_simd, chksum_basic);
     }
 }
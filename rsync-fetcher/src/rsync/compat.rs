@@ -0,0 +1,171 @@
+//! Protocol setup after the options are sent.
+//!
+//! Since protocol 30, the server sends compat flags describing which extensions are enabled, and
+//! both sides may negotiate the checksum and compression algorithms. Then the server sends the checksum seed.
+
+use eyre::{bail, eyre, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, instrument};
+
+use crate::rsync::checksum::{ChecksumType, StrongChecksum};
+use crate::rsync::compress::{CompressionType, SUPPORTED_COMPRESSIONS};
+use crate::rsync::envelope::{RsyncReadExt, RsyncWriteExt};
+use crate::rsync::handshake::HandshakeConn;
+use crate::rsync::version::SUPPORTED_DIGESTS;
+
+/// Incremental recursion is enabled.
+pub const CF_INC_RECURSE: i32 = 1 << 0;
+/// The file list may end with an io error.
+pub const CF_SAFE_FLIST: i32 = 1 << 3;
+/// Seed is put before the data in MD5 block checksums.
+pub const CF_CHKSUM_SEED_FIX: i32 = 1 << 5;
+/// File list flags are sent as varint, and checksum and compression algorithms are negotiated.
+pub const CF_VARINT_FLIST_FLAGS: i32 = 1 << 7;
+
+/// Client capabilities sent as the argument of `-e` in protocol 30 and above.
+///
+/// `.` means no pre-release sub-protocol, `i` incremental recursion, `f` safe file list,
+/// `C` checksum seed fix and `v` varint file list flags & negotiated strings.
+pub fn client_info(inc_recursive: bool) -> String {
+    format!(".{}fCv", if inc_recursive { "i" } else { "" })
+}
+
+/// Parameters of the negotiated protocol.
+#[derive(Debug, Copy, Clone)]
+pub struct Protocol {
+    pub version: i32,
+    pub compat_flags: i32,
+    pub checksum: StrongChecksum,
+    /// Compression of the token stream, if enabled.
+    pub compression: Option<CompressionType>,
+    /// Whether whole-file checksums are sent in the file list.
+    pub always_checksum: bool,
+    /// Whether hard links are sent in the file list.
+    pub hard_links: bool,
+}
+
+impl Protocol {
+    /// Whether file list flags are sent as varint.
+    pub const fn varint_flist_flags(&self) -> bool {
+        self.compat_flags & CF_VARINT_FLIST_FLAGS != 0
+    }
+    /// Whether the file list may end with an io error.
+    pub const fn safe_flist(&self) -> bool {
+        self.compat_flags & CF_SAFE_FLIST != 0 || self.version >= 31
+    }
+    /// Whether incremental recursion is enabled.
+    pub const fn inc_recurse(&self) -> bool {
+        self.compat_flags & CF_INC_RECURSE != 0
+    }
+    /// Index of the first entry in the initial file list.
+    pub const fn first_ndx_start(&self) -> u32 {
+        if self.inc_recurse() {
+            1
+        } else {
+            0
+        }
+    }
+    /// Whether data sent to the server is multiplexed.
+    pub const fn multiplex_out(&self) -> bool {
+        self.version >= 30
+    }
+    /// The last transfer phase.
+    pub const fn max_phase(&self) -> i32 {
+        if self.version >= 29 {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+impl HandshakeConn {
+    #[instrument(skip(self))]
+    pub async fn setup_protocol(&mut self) -> Result<Protocol> {
+        let version = self.protocol_version;
+        let compat_flags = if version >= 30 {
+            self.rx.read_varint().await?
+        } else {
+            0
+        };
+        debug!(compat_flags);
+        if compat_flags & CF_INC_RECURSE != 0 && !self.inc_recursive {
+            bail!("server enabled incremental recursion, which is not requested");
+        }
+
+        let (kind, compression) = if compat_flags & CF_VARINT_FLIST_FLAGS != 0 {
+            self.negotiate_strings().await?
+        } else {
+            let kind = if version >= 30 {
+                ChecksumType::Md5
+            } else {
+                ChecksumType::Md4Old
+            };
+            (kind, self.compress.then_some(CompressionType::Zlib))
+        };
+        debug!(?compression);
+
+        let seed = self.rx.read_i32_le().await?;
+        debug!(seed, ?kind, "checksum");
+
+        Ok(Protocol {
+            version,
+            compat_flags,
+            checksum: StrongChecksum {
+                kind,
+                seed,
+                proper_seed_order: compat_flags & CF_CHKSUM_SEED_FIX != 0,
+            },
+            compression,
+            always_checksum: self.always_checksum,
+            hard_links: self.hard_links,
+        })
+    }
+
+    /// Negotiate checksum and compression algorithms.
+    ///
+    /// Compression is negotiated only if requested.
+    async fn negotiate_strings(&mut self) -> Result<(ChecksumType, Option<CompressionType>)> {
+        // Both sides send their lists before reading the other's.
+        self.tx
+            .write_vstring(SUPPORTED_DIGESTS.join(" ").as_bytes())
+            .await?;
+        if self.compress {
+            self.tx
+                .write_vstring(SUPPORTED_COMPRESSIONS.join(" ").as_bytes())
+                .await?;
+        }
+        self.tx.flush().await?;
+
+        let remote = self.rx.read_vstring().await?;
+        let remote = String::from_utf8_lossy(&remote);
+        debug!(%remote, "server checksums");
+        let checksum = choose(SUPPORTED_DIGESTS, &remote)
+            .and_then(ChecksumType::from_name)
+            .ok_or_else(|| eyre!("no common checksum algorithm with server: {}", remote))?;
+
+        let compression = if self.compress {
+            let remote = self.rx.read_vstring().await?;
+            let remote = String::from_utf8_lossy(&remote);
+            debug!(%remote, "server compressions");
+            Some(
+                choose(SUPPORTED_COMPRESSIONS, &remote)
+                    .and_then(CompressionType::from_name)
+                    .ok_or_else(|| {
+                        eyre!("no common compression algorithm with server: {}", remote)
+                    })?,
+            )
+        } else {
+            None
+        };
+
+        Ok((checksum, compression))
+    }
+}
+
+/// The first of the client's choices that the server also supports wins.
+fn choose<'a>(ours: &[&'a str], remote: &str) -> Option<&'a str> {
+    ours.iter()
+        .find(|name| remote.split_whitespace().any(|r| r == **name))
+        .copied()
+}
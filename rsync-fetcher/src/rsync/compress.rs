@@ -0,0 +1,214 @@
+//! Compressed token stream.
+//!
+//! When compression is enabled, literal data of a file is sent as a raw deflate stream. The sender
+//! does a sync flush at the end of each literal run and strips the trailing sync marker, so the
+//! receiver needs to feed it back before the next run.
+//!
+//! With `zlib` (but not `zlibx`), data of matched blocks is also added to the sender's window, so
+//! the receiver needs to feed them to the inflater too.
+
+use std::io;
+
+use flate2::{Decompress, FlushDecompress, Status};
+
+/// Marks the end of the file.
+pub const END_FLAG: u8 = 0;
+/// Followed by 32-bit token number and 16-bit run count. Without the lowest bit, no run count
+/// follows.
+pub const TOKENRUN_LONG: u8 = 0x21;
+/// Lower 6 bits are high bits of data length, followed by low byte of length.
+pub const DEFLATED_DATA: u8 = 0x40;
+/// Lower 6 bits are token number relative to the last token.
+pub const TOKEN_REL: u8 = 0x80;
+/// Lower 6 bits are relative token number, followed by 16-bit run count.
+pub const TOKENRUN_REL: u8 = 0xc0;
+
+/// Compression algorithms we support, in order of preference.
+pub const SUPPORTED_COMPRESSIONS: &[&str] = &["zlibx", "zlib"];
+
+const CHUNK_SIZE: usize = 32 * 1024;
+const SYNC_MARKER: [u8; 4] = [0, 0, 0xff, 0xff];
+const MAX_STORED_LEN: usize = 0xffff;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CompressionType {
+    /// Matched data is added to the deflate window.
+    Zlib,
+    /// Matched data is not added to the deflate window.
+    ZlibX,
+}
+
+impl CompressionType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "zlib" => Some(Self::Zlib),
+            "zlibx" => Some(Self::ZlibX),
+            _ => None,
+        }
+    }
+}
+
+/// Inflates literal data of a file.
+pub struct Inflater {
+    kind: CompressionType,
+    protocol_version: i32,
+    strm: Decompress,
+    /// Whether a literal run is not finished yet.
+    inflating: bool,
+}
+
+impl Inflater {
+    pub fn new(kind: CompressionType, protocol_version: i32) -> Self {
+        Self {
+            kind,
+            protocol_version,
+            strm: Decompress::new(false),
+            inflating: false,
+        }
+    }
+
+    /// Inflate a chunk of deflated data.
+    pub fn inflate(&mut self, mut input: &[u8]) -> io::Result<Vec<u8>> {
+        self.inflating = true;
+        let mut output = Vec::with_capacity(CHUNK_SIZE);
+        loop {
+            output.reserve(CHUNK_SIZE);
+            let (consumed, status) = self.decompress(input, &mut output, FlushDecompress::None)?;
+            input = &input[consumed..];
+            if status == Status::BufError || (input.is_empty() && output.len() < output.capacity())
+            {
+                break;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Finish the current literal run, if any.
+    ///
+    /// Returns remaining data of the run.
+    pub fn finish_run(&mut self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        if !self.inflating {
+            return Ok(output);
+        }
+        self.inflating = false;
+
+        loop {
+            output.reserve(CHUNK_SIZE);
+            let len = output.len();
+            let (_, status) = self.decompress(&[], &mut output, FlushDecompress::Sync)?;
+            if status == Status::BufError || output.len() == len {
+                break;
+            }
+        }
+
+        // Feed back the sync marker stripped by the sender.
+        self.feed(&SYNC_MARKER)?;
+        Ok(output)
+    }
+
+    /// Add data of a matched block to the window.
+    pub fn see(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.kind != CompressionType::Zlib {
+            return Ok(());
+        }
+
+        // Data is wrapped in fake stored blocks.
+        let mut offset = 0;
+        let mut remaining = data.len();
+        while remaining > 0 {
+            let len = remaining.min(MAX_STORED_LEN);
+            #[allow(clippy::cast_possible_truncation)] // len fits in u16
+            let [lo, hi] = (len as u16).to_le_bytes();
+            self.feed(&[0, lo, hi, !lo, !hi])?;
+            self.feed(&data[offset..offset + len])?;
+            // Older protocols feed the first block repeatedly due to a bug in the original
+            // implementation, and we need to do the same to keep in sync.
+            if self.protocol_version >= 31 {
+                offset += len;
+            }
+            remaining -= len;
+        }
+        Ok(())
+    }
+
+    /// Feed data to the inflater, and discard the output.
+    fn feed(&mut self, mut input: &[u8]) -> io::Result<()> {
+        let mut output = Vec::with_capacity(CHUNK_SIZE);
+        loop {
+            output.clear();
+            let (consumed, _) = self.decompress(input, &mut output, FlushDecompress::Sync)?;
+            input = &input[consumed..];
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn decompress(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        flush: FlushDecompress,
+    ) -> io::Result<(usize, Status)> {
+        let total_in = self.strm.total_in();
+        let status = self.strm.decompress_vec(input, output, flush)?;
+        #[allow(clippy::cast_possible_truncation)] // bounded by input length
+        let consumed = (self.strm.total_in() - total_in) as usize;
+        Ok((consumed, status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{Compress, Compression, FlushCompress};
+
+    use crate::rsync::compress::{CompressionType, Inflater};
+
+    /// Deflate a literal run as the sender does.
+    fn deflate_run(strm: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len() + 1024);
+        strm.compress_vec(data, &mut output, FlushCompress::Sync)
+            .unwrap();
+        assert_eq!(output[output.len() - 4..], [0, 0, 0xff, 0xff]);
+        output.truncate(output.len() - 4);
+        output
+    }
+
+    #[test]
+    fn must_inflate_runs() {
+        let mut strm = Compress::new(Compression::default(), false);
+        let run_1 = b"Package: rsync\nVersion: 3.2.7\n".repeat(10);
+        let run_2 = b"Package: rsync\nVersion: 3.2.3\n".repeat(10);
+        let deflated_1 = deflate_run(&mut strm, &run_1);
+        let deflated_2 = deflate_run(&mut strm, &run_2);
+
+        let mut inflater = Inflater::new(CompressionType::ZlibX, 31);
+        let (first, second) = deflated_1.split_at(deflated_1.len() / 2);
+        let mut output = inflater.inflate(first).unwrap();
+        output.extend(inflater.inflate(second).unwrap());
+        output.extend(inflater.finish_run().unwrap());
+        assert_eq!(output, run_1);
+
+        let mut output = inflater.inflate(&deflated_2).unwrap();
+        output.extend(inflater.finish_run().unwrap());
+        assert_eq!(output, run_2);
+    }
+
+    #[test]
+    fn must_see_matched_blocks() {
+        let mut strm = Compress::new(Compression::default(), false);
+        let matched = b"Filename: pool/main/r/rsync/rsync_3.2.7-1_amd64.deb\n".repeat(4);
+        let literal = b"Filename: pool/main/r/rsync/rsync_3.2.7-1_arm64.deb\n".repeat(4);
+        // Matched data only goes into the window of the sender.
+        deflate_run(&mut strm, &matched);
+        let deflated = deflate_run(&mut strm, &literal);
+
+        let mut inflater = Inflater::new(CompressionType::Zlib, 31);
+        inflater.see(&matched).unwrap();
+        let mut output = inflater.inflate(&deflated).unwrap();
+        output.extend(inflater.finish_run().unwrap());
+        assert_eq!(output, literal);
+    }
+}
@@ -4,11 +4,10 @@ use std::fmt::{Debug, Formatter};
 use std::io;
 use std::io::SeekFrom;
 use std::path::PathBuf;
-use std::sync::Arc;
 
-use eyre::{bail, eyre, Result};
+use eyre::{bail, Result};
 use futures::stream::FuturesUnordered;
-use futures::{FutureExt, TryStreamExt};
+use futures::TryStreamExt;
 use opendal::Operator;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncSeekExt;
@@ -17,36 +16,46 @@ use tracing::{debug, warn};
 
 use rsync_core::utils::ToHex;
 
-use crate::consts::{BASIS_BUFFER_LIMIT, DOWNLOAD_CONN};
 use crate::plan::TransferItem;
-use crate::rsync::file_list::FileEntry;
+use crate::rsync::bwlimit::{RateLimitedRead, RateLimiter};
+use crate::rsync::file_list::InFlightFiles;
 use crate::rsync::progress_display::ProgressDisplay;
-use crate::utils::{hash, ignore_mode};
+use crate::utils::hash;
 
 pub struct Downloader {
-    file_list: Arc<Vec<FileEntry>>,
+    in_flight: InFlightFiles,
     s3: Operator,
     s3_prefix: String,
     basis_dir: PathBuf,
+    rx: flume::Receiver<TransferItem>,
     basis_tx: mpsc::Sender<(u32, File)>,
+    bwlimit: RateLimiter,
+    conns: usize,
     pb: ProgressDisplay,
 }
 
 impl Downloader {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        file_list: Arc<Vec<FileEntry>>,
+        in_flight: InFlightFiles,
         s3: Operator,
         s3_prefix: String,
         basis_dir: PathBuf,
+        rx: flume::Receiver<TransferItem>,
         basis_tx: mpsc::Sender<(u32, File)>,
+        bwlimit: RateLimiter,
+        conns: usize,
         pb: ProgressDisplay,
     ) -> Self {
         Self {
-            file_list,
+            in_flight,
             s3,
             s3_prefix,
             basis_dir,
+            rx,
             basis_tx,
+            bwlimit,
+            conns,
             pb,
         }
     }
@@ -69,57 +78,26 @@ impl<'a> Debug for DownloadEntry<'a> {
 }
 
 impl Downloader {
-    pub async fn tasks(self, transfer_plan: &[TransferItem]) -> Result<()> {
-        let (tx, rx) = flume::bounded(BASIS_BUFFER_LIMIT);
-        let tasks: FuturesUnordered<_> = (0..DOWNLOAD_CONN)
-            .map(|id| self.download_task(id, rx.clone()).left_future())
-            .collect();
-        tasks.push(self.gen_task(transfer_plan, tx).right_future());
+    /// Download basis files requested by the generator.
+    pub async fn tasks(self) -> Result<()> {
+        let tasks: FuturesUnordered<_> = (0..self.conns).map(|id| self.download_task(id)).collect();
         tasks.try_collect::<()>().await?;
         Ok(())
     }
-    async fn gen_task<'a>(
-        &'a self,
-        transfer_plan: &'a [TransferItem],
-        tx: flume::Sender<DownloadEntry<'a>>,
-    ) -> Result<()> {
-        debug!("basis generator started");
+    async fn download_task(&self, id: usize) -> Result<()> {
+        debug!("basis downloader {} started", id);
 
-        for item in transfer_plan {
+        while let Ok(item) = self.rx.recv_async().await {
             let Some(blake2b_hash) = &item.blake2b else {
                 continue;
             };
-
-            let entry = &self.file_list[item.idx as usize];
-            if ignore_mode(entry.mode, None::<()>) {
-                warn!(
-                    name = %entry.name_lossy(),
-                    mode = entry.mode,
-                    "BUG: planner returns an entry with ignored mode"
-                );
-                continue;
-            }
-
-            tx.send_async(DownloadEntry {
-                idx: item.idx as u32,
+            let file = self.in_flight.get(item.idx as u32)?;
+            let entry = DownloadEntry {
+                idx: file.idx,
                 blake2b_hash,
-                path: &entry.name,
-            })
-            .await
-            .map_err(|e| eyre!(e.to_string()))?;
-        }
-
-        debug!("basis generator finished");
-        Ok(())
-    }
-    async fn download_task<'a>(
-        &'a self,
-        id: usize,
-        rx: flume::Receiver<DownloadEntry<'a>>,
-    ) -> Result<()> {
-        debug!("basis downloader {} started", id);
+                path: &file.name,
+            };
 
-        while let Ok(entry) = rx.recv_async().await {
             let permit = self.basis_tx.reserve().await?;
             self.pb.inc_basis(1);
             self.pb.inc_basis_downloading(1);
@@ -137,7 +115,8 @@ impl Downloader {
             let copy_result = {
                 let basis_mut = &mut basis_file;
                 async move {
-                    let mut rd = self.s3.reader(&key).await?;
+                    let rd = self.s3.reader(&key).await?;
+                    let mut rd = RateLimitedRead::new(rd, self.bwlimit.clone());
                     tokio::io::copy(&mut rd, basis_mut).await?;
                     Ok::<_, io::Error>(())
                 }
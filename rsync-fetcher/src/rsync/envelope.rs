@@ -1,16 +1,42 @@
 //! Rsync has a special multiplexed protocol, where each frame is prefixed with a 4-byte header,
 //! indicating the message type and the length of the frame.
 //!
-//! This is a wrapper around `AsyncRead` that strips the headers and returns the body.
+//! This is a wrapper around `AsyncRead` that strips the headers and returns the body, and a
+//! wrapper around `AsyncWrite` that adds them.
 //!
 //! Adopted from arrsync.
 
 use std::pin::Pin;
-use std::task::Poll;
+use std::task::{ready, Poll};
 
-use eyre::Result;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, ReadBuf};
-use tracing::{trace, warn};
+use eyre::{bail, Result};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{debug, info, trace, warn};
+
+use crate::rsync::bwlimit::{RateLimiter, Throttle};
+
+/// Tag of a frame is `MPLEX_BASE + message code`.
+const MPLEX_BASE: u8 = 7;
+
+const MSG_DATA: u8 = 0;
+const MSG_ERROR_XFER: u8 = 1;
+const MSG_INFO: u8 = 2;
+const MSG_ERROR: u8 = 3;
+const MSG_WARNING: u8 = 4;
+const MSG_ERROR_SOCKET: u8 = 5;
+const MSG_LOG: u8 = 6;
+const MSG_CLIENT: u8 = 7;
+const MSG_ERROR_UTF8: u8 = 8;
+const MSG_IO_ERROR: u8 = 22;
+const MSG_IO_TIMEOUT: u8 = 33;
+const MSG_NOOP: u8 = 42;
+const MSG_ERROR_EXIT: u8 = 86;
+const MSG_SUCCESS: u8 = 100;
+const MSG_DELETED: u8 = 101;
+const MSG_NO_SEND: u8 = 102;
+
+/// Max payload size of a frame written by `EnvelopeWrite`.
+const MAX_FRAME_SIZE: usize = 32 * 1024;
 
 /// Strips rsync data frame headers and prints non-data frames as warning messages
 #[derive(Debug)]
@@ -18,30 +44,38 @@ pub struct EnvelopeRead<T: AsyncBufRead + Unpin> {
     // TODO: rebuild with an enum of pending body, pending error, and pending header
     read: T,
     frame_remaining: usize,
-    pending_error: Option<(u8, Vec<u8>)>,
+    pending_msg: Option<(u8, Vec<u8>)>,
     pending_header: Option<([u8; 4], u8)>,
+    throttle: Throttle,
 }
 
 impl<T: AsyncBufRead + Unpin> EnvelopeRead<T> {
-    pub const fn new(t: T) -> Self {
+    pub fn new(t: T) -> Self {
         Self {
             read: t,
             frame_remaining: 0,
-            pending_error: None,
+            pending_msg: None,
             pending_header: None,
+            throttle: Throttle::default(),
         }
     }
 
-    fn poll_err(
+    /// Limit the rate of data read from this stream.
+    pub fn with_bwlimit(mut self, limiter: RateLimiter) -> Self {
+        self.throttle = Throttle::new(limiter);
+        self
+    }
+
+    fn poll_msg(
         mut self: Pin<&mut Self>,
         ctx: &mut std::task::Context<'_>,
         repoll_buf: &mut ReadBuf,
     ) -> Poll<Result<(), std::io::Error>> {
         while self.frame_remaining > 0 {
             let mut pe = self
-                .pending_error
+                .pending_msg
                 .as_ref()
-                .expect("Error expected, but not present")
+                .expect("Message expected, but not present")
                 .1
                 .clone();
             let pei = pe.len() - self.frame_remaining; // Ich check dich wek alter bowwochecka
@@ -54,24 +88,43 @@ impl<T: AsyncBufRead + Unpin> EnvelopeRead<T> {
                         break;
                     }
                     self.frame_remaining -= rb.filled().len();
-                    self.pending_error.as_mut().unwrap().1 = pe;
+                    self.pending_msg.as_mut().unwrap().1 = pe;
                 }
             };
         }
-        let (typ, msg) = self.pending_error.take().unwrap();
-        let msg = String::from_utf8_lossy(&msg);
+        let (tag, raw) = self.pending_msg.take().unwrap();
+        let msg = String::from_utf8_lossy(&raw);
         let msg = msg
             .strip_suffix('\n')
             .filter(|msg| msg.matches('\n').count() == 0)
             .unwrap_or(&msg)
             .to_owned();
-        let e = match typ {
-            8 => {
+        let e = match tag.wrapping_sub(MPLEX_BASE) {
+            MSG_ERROR_XFER | MSG_WARNING => {
                 warn!("Sender: {}", msg);
                 return self.poll_read(ctx, repoll_buf);
             }
-            1 => eyre::eyre!("Server error: {}", msg),
-            t => eyre::eyre!("Unknown error {}: {}", t, msg),
+            MSG_INFO | MSG_LOG | MSG_CLIENT => {
+                info!("Sender: {}", msg);
+                return self.poll_read(ctx, repoll_buf);
+            }
+            MSG_IO_ERROR => {
+                warn!("server reported IO errors: {}", msg_int(&raw));
+                return self.poll_read(ctx, repoll_buf);
+            }
+            MSG_NO_SEND => {
+                warn!("server failed to open file: idx {}", msg_int(&raw));
+                return self.poll_read(ctx, repoll_buf);
+            }
+            code @ (MSG_IO_TIMEOUT | MSG_NOOP | MSG_SUCCESS | MSG_DELETED) => {
+                debug!(code, "ignored message");
+                return self.poll_read(ctx, repoll_buf);
+            }
+            MSG_ERROR | MSG_ERROR_SOCKET | MSG_ERROR_UTF8 => {
+                eyre::eyre!("Server error: {}", msg)
+            }
+            MSG_ERROR_EXIT => eyre::eyre!("Server exited with error: {}", msg_int(&raw)),
+            _ => eyre::eyre!("Unknown error {}: {}", tag, msg),
         };
         Poll::Ready(Err(std::io::Error::new(
             std::io::ErrorKind::ConnectionAborted,
@@ -80,6 +133,12 @@ impl<T: AsyncBufRead + Unpin> EnvelopeRead<T> {
     }
 }
 
+/// Interpret the payload of a message as a little-endian int, as used by `MSG_IO_ERROR` and
+/// friends. Malformed payloads are read as -1.
+fn msg_int(raw: &[u8]) -> i32 {
+    raw.try_into().map_or(-1, i32::from_le_bytes)
+}
+
 impl<T: AsyncBufRead + Unpin> AsyncRead for EnvelopeRead<T> {
     #[allow(clippy::similar_names, clippy::cast_possible_truncation)]
     fn poll_read(
@@ -87,9 +146,10 @@ impl<T: AsyncBufRead + Unpin> AsyncRead for EnvelopeRead<T> {
         ctx: &mut std::task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        if self.pending_error.is_some() {
-            return self.poll_err(ctx, buf);
+        if self.pending_msg.is_some() {
+            return self.poll_msg(ctx, buf);
         }
+        ready!(self.throttle.poll_ready(ctx));
         while self.frame_remaining == 0 {
             let no_pending_header = self.pending_header.is_none();
             let pll = Pin::new(&mut self.read).poll_fill_buf(ctx);
@@ -124,11 +184,11 @@ impl<T: AsyncBufRead + Unpin> AsyncRead for EnvelopeRead<T> {
                         self.frame_remaining = b1 + b2 * 0x100 + b3 * 0x0001_0000_usize;
                         trace!("Frame {} {}", b4, self.frame_remaining);
                         match b4 {
-                            7 => (),
+                            t if t == MPLEX_BASE + MSG_DATA => (),
                             t => {
-                                let errbuf = vec![0; self.frame_remaining];
-                                self.pending_error = Some((t, errbuf));
-                                return self.poll_err(ctx, buf);
+                                let msgbuf = vec![0; self.frame_remaining];
+                                self.pending_msg = Some((t, msgbuf));
+                                return self.poll_msg(ctx, buf);
                             }
                         };
                     }
@@ -144,12 +204,124 @@ impl<T: AsyncBufRead + Unpin> AsyncRead for EnvelopeRead<T> {
                 let read = rb.filled().len();
                 self.frame_remaining -= read;
                 buf.advance(read);
+                self.throttle.consume(read);
                 r
             }
         }
     }
 }
 
+/// Wraps written data into rsync data frames once multiplexing is started.
+///
+/// Data is buffered until the frame is full or the writer is flushed, so remember to flush it
+/// before waiting for the server.
+#[derive(Debug)]
+pub struct EnvelopeWrite<T: AsyncWrite + Unpin> {
+    write: T,
+    multiplex: bool,
+    // Pending frame, with its first 4 bytes reserved for the header.
+    frame: Vec<u8>,
+    frame_written: usize,
+}
+
+impl<T: AsyncWrite + Unpin> EnvelopeWrite<T> {
+    pub const fn new(t: T) -> Self {
+        Self {
+            write: t,
+            multiplex: false,
+            frame: Vec::new(),
+            frame_written: 0,
+        }
+    }
+
+    /// Start multiplexing. All data written afterwards is wrapped in data frames.
+    pub fn start_multiplex(&mut self) {
+        self.multiplex = true;
+    }
+
+    fn poll_write_frame(
+        &mut self,
+        ctx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        if self.frame.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        if self.frame_written == 0 {
+            #[allow(clippy::cast_possible_truncation)] // frame size is bounded by MAX_FRAME_SIZE
+            let header = (u32::from(MPLEX_BASE + MSG_DATA) << 24) | (self.frame.len() - 4) as u32;
+            self.frame[..4].copy_from_slice(&header.to_le_bytes());
+        }
+        while self.frame_written < self.frame.len() {
+            match Pin::new(&mut self.write).poll_write(ctx, &self.frame[self.frame_written..]) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                }
+                Poll::Ready(Ok(n)) => self.frame_written += n,
+            }
+        }
+        self.frame.clear();
+        self.frame_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for EnvelopeWrite<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        ctx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        if !this.multiplex {
+            return Pin::new(&mut this.write).poll_write(ctx, buf);
+        }
+        if this.frame_written > 0 || this.frame.len() >= MAX_FRAME_SIZE + 4 {
+            match this.poll_write_frame(ctx) {
+                Poll::Ready(Ok(())) => (),
+                p => return p.map_ok(|()| 0),
+            }
+        }
+        if this.frame.is_empty() {
+            this.frame.extend_from_slice(&[0; 4]);
+        }
+        let n = buf.len().min(MAX_FRAME_SIZE + 4 - this.frame.len());
+        this.frame.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        ctx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+        match this.poll_write_frame(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.write).poll_flush(ctx),
+            p => p,
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        ctx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+        match this.poll_write_frame(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.write).poll_shutdown(ctx),
+            p => p,
+        }
+    }
+}
+
+/// Number of extra bytes following the first byte of a varint, indexed by `first_byte / 4`.
+const INT_BYTE_EXTRA: [u8; 64] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // (00 - 3F)/4
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // (40 - 7F)/4
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // (80 - BF)/4
+    2, 2, 2, 2, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 5, 6, // (C0 - FF)/4
+];
+
 #[async_trait::async_trait]
 pub trait RsyncReadExt: AsyncRead + Unpin {
     /// For reading rsync's variable length integers… quite an odd format.
@@ -161,6 +333,130 @@ pub trait RsyncReadExt: AsyncRead + Unpin {
             i64::from(v)
         })
     }
+    /// Varint used since protocol 30. The number of extra bytes is encoded in the high bits of
+    /// the first byte.
+    async fn read_varint(&mut self) -> Result<i32> {
+        let ch = self.read_u8().await?;
+        let extra = INT_BYTE_EXTRA[usize::from(ch / 4)] as usize;
+        let mut buf = [0u8; 5];
+        if extra == 0 {
+            buf[0] = ch;
+        } else {
+            if extra >= buf.len() {
+                bail!("overflow in read_varint");
+            }
+            let bit = 1u8 << (8 - extra);
+            self.read_exact(&mut buf[..extra]).await?;
+            buf[extra] = ch & (bit - 1);
+        }
+        Ok(i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
+    }
+    /// Varlong used since protocol 30. At least `min_bytes` bytes are always sent.
+    async fn read_varlong(&mut self, min_bytes: usize) -> Result<i64> {
+        let mut head = [0u8; 8];
+        self.read_exact(&mut head[..min_bytes]).await?;
+        let mut buf = [0u8; 9];
+        buf[..min_bytes - 1].copy_from_slice(&head[1..min_bytes]);
+        let extra = INT_BYTE_EXTRA[usize::from(head[0] / 4)] as usize;
+        if extra == 0 {
+            buf[min_bytes - 1] = head[0];
+        } else {
+            if min_bytes + extra > buf.len() {
+                bail!("overflow in read_varlong");
+            }
+            let bit = 1u8 << (8 - extra);
+            self.read_exact(&mut buf[min_bytes - 1..min_bytes - 1 + extra])
+                .await?;
+            buf[min_bytes + extra - 1] = head[0] & (bit - 1);
+        }
+        Ok(i64::from_le_bytes(*arrayref::array_ref![buf, 0, 8]))
+    }
+    /// Int before protocol 30, varint after.
+    async fn read_varint30(&mut self, protocol_version: i32) -> Result<i32> {
+        if protocol_version < 30 {
+            Ok(self.read_i32_le().await?)
+        } else {
+            self.read_varint().await
+        }
+    }
+    /// Long int before protocol 30, varlong after.
+    async fn read_varlong30(&mut self, protocol_version: i32, min_bytes: usize) -> Result<i64> {
+        if protocol_version < 30 {
+            self.read_rsync_long().await
+        } else {
+            self.read_varlong(min_bytes).await
+        }
+    }
+    /// Short string prefixed with its length in one or two bytes.
+    async fn read_vstring(&mut self) -> Result<Vec<u8>> {
+        let mut len = usize::from(self.read_u8().await?);
+        if len & 0x80 != 0 {
+            len = (len & !0x80) * 0x100 + usize::from(self.read_u8().await?);
+        }
+        let mut buf = vec![0; len];
+        self.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
 }
 
 impl<T: AsyncRead + Unpin> RsyncReadExt for T {}
+
+#[async_trait::async_trait]
+pub trait RsyncWriteExt: AsyncWrite + Unpin {
+    /// See `RsyncReadExt::read_vstring`.
+    async fn write_vstring(&mut self, s: &[u8]) -> Result<()> {
+        if s.len() > 0x7FFF {
+            bail!("string too long");
+        }
+        #[allow(clippy::cast_possible_truncation)] // checked above
+        if s.len() > 0x7F {
+            self.write_all(&[(s.len() / 0x100 + 0x80) as u8, s.len() as u8])
+                .await?;
+        } else {
+            self.write_u8(s.len() as u8).await?;
+        }
+        self.write_all(s).await?;
+        Ok(())
+    }
+}
+
+impl<T: AsyncWrite + Unpin> RsyncWriteExt for T {}
+
+#[cfg(test)]
+mod tests {
+    use crate::rsync::envelope::{RsyncReadExt, RsyncWriteExt};
+
+    #[tokio::test]
+    async fn must_read_varint() {
+        let cases: &[(&[u8], i32)] = &[
+            (&[0x00], 0),
+            (&[0x7F], 0x7F),
+            (&[0x80, 0x80], 0x80),
+            (&[0xC0, 0x00, 0x01], 0x100),
+            (&[0xF0, 0xFF, 0xFF, 0xFF, 0xFF], -1),
+        ];
+        for (buf, expected) in cases {
+            let mut rx = *buf;
+            assert_eq!(rx.read_varint().await.unwrap(), *expected);
+            assert!(rx.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn must_read_varlong() {
+        let mut rx: &[u8] = &[0x00, 0x10, 0x27];
+        assert_eq!(rx.read_varlong(3).await.unwrap(), 10000);
+        let mut rx: &[u8] = &[0x80, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(rx.read_varlong(4).await.unwrap(), 0x0100_0000);
+    }
+
+    #[tokio::test]
+    async fn must_vstring_roundtrip() {
+        for len in [0, 0x7F, 0x80, 0x7FFF] {
+            let s = vec![b'x'; len];
+            let mut buf = vec![];
+            buf.write_vstring(&s).await.unwrap();
+            assert_eq!(buf.as_slice().read_vstring().await.unwrap(), s);
+        }
+    }
+}
@@ -1,25 +1,41 @@
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt::{Debug, Formatter};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::Path;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use clean_path::Clean;
-use eyre::{bail, Result};
+use dashmap::DashMap;
+use eyre::{bail, eyre, Result};
 use indicatif::ProgressBar;
 use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::tcp::OwnedReadHalf;
 use tracing::{debug, info, warn};
 
+use rsync_core::utils::ToHex;
+
+use crate::rsync::checksum::ChecksumType;
+use crate::rsync::compat::Protocol;
 use crate::rsync::envelope::{EnvelopeRead, RsyncReadExt};
+use crate::rsync::transport::TransportRead;
 
-const XMIT_SAME_MODE: u8 = 1 << 1;
-const XMIT_SAME_NAME: u8 = 1 << 5;
-const XMIT_LONG_NAME: u8 = 1 << 6;
-const XMIT_SAME_TIME: u8 = 1 << 7;
+const XMIT_SAME_MODE: u32 = 1 << 1;
+const XMIT_EXTENDED_FLAGS: u32 = 1 << 2;
+const XMIT_SAME_NAME: u32 = 1 << 5;
+const XMIT_LONG_NAME: u32 = 1 << 6;
+const XMIT_SAME_TIME: u32 = 1 << 7;
+const XMIT_HLINKED: u32 = 1 << 9;
+const XMIT_SAME_DEV_PRE30: u32 = 1 << 10;
+const XMIT_HLINK_FIRST: u32 = 1 << 12;
+const XMIT_IO_ERROR_ENDLIST: u32 = 1 << 12;
+const XMIT_MOD_NSEC: u32 = 1 << 13;
 
 const PATH_MAX: u32 = 4096;
+/// Length of whole-file checksums in the file list. Both MD4 and MD5 are 16 bytes.
+const FILE_CHECKSUM_LEN: usize = 16;
 
 #[derive(Clone)]
 pub struct FileEntry {
@@ -28,6 +44,10 @@ pub struct FileEntry {
     pub modify_time: SystemTime,
     pub mode: u32,
     pub link_target: Option<Vec<u8>>,
+    /// Entries in the same group are hard links of each other.
+    pub hlink_group: Option<u32>,
+    /// Whole-file checksum of regular files, if requested with `--checksum`.
+    pub checksum: Option<FileChecksum>,
     // int32 in rsync, but it couldn't be negative yes?
     pub idx: u32,
 }
@@ -52,68 +72,194 @@ impl Debug for FileEntry {
                     .as_ref()
                     .map(|s| String::from_utf8_lossy(s))),
             )
+            .field("hlink_group", &self.hlink_group)
+            .field(
+                "checksum",
+                &self
+                    .checksum
+                    .map(|checksum| format!("{:?}:{:x}", checksum.kind, checksum.sum.as_hex())),
+            )
             .field("idx", &self.idx)
             .finish()
     }
 }
 
-impl EnvelopeRead<BufReader<OwnedReadHalf>> {
-    pub async fn recv_file_list(&mut self) -> Result<Vec<FileEntry>> {
-        let mut list = vec![];
+/// Whole-file checksum in the file list.
+///
+/// The algorithm depends on the protocol negotiated with the upstream, so it's kept with the
+/// checksum. Checksums of different algorithms are never compared.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FileChecksum {
+    pub kind: ChecksumType,
+    pub sum: [u8; FILE_CHECKSUM_LEN],
+}
+
+/// Entries of files being transferred, looked up by their index in the file list.
+///
+/// Entries are added once planned for transfer, and removed after uploaded, so that we don't need
+/// to keep the whole file list in memory.
+#[derive(Debug, Clone, Default)]
+pub struct InFlightFiles(Arc<DashMap<u32, FileEntry>>);
 
+impl InFlightFiles {
+    pub fn insert(&self, entry: FileEntry) {
+        self.0.insert(entry.idx, entry);
+    }
+    /// Get a copy of the entry.
+    ///
+    /// # Errors
+    /// Returns an error if the file is not in flight, e.g. the server sent an index we never
+    /// requested.
+    pub fn get(&self, idx: u32) -> Result<FileEntry> {
+        self.0
+            .get(&idx)
+            .map(|entry| entry.clone())
+            .ok_or_else(|| eyre!("unexpected ndx {idx}: file not in flight"))
+    }
+    pub fn remove(&self, idx: u32) {
+        self.0.remove(&idx);
+    }
+}
+
+impl EnvelopeRead<BufReader<TransportRead>> {
+    /// Receive the initial file list, with indices starting from `ndx_start`.
+    ///
+    /// Returns the entries and the number of indices they occupy.
+    pub async fn recv_file_list(
+        &mut self,
+        protocol: &Protocol,
+        ndx_start: u32,
+    ) -> Result<(Vec<FileEntry>, u32)> {
         let spinner = ProgressBar::new_spinner();
         spinner.enable_steady_tick(Duration::from_millis(50));
+        let result = self
+            .recv_file_list_(protocol, ndx_start, Some(&spinner))
+            .await;
+        spinner.finish_and_clear();
+
+        if let Ok((list, _)) = &result {
+            info!("{} files", list.len());
+        }
+        result
+    }
+
+    /// Receive a file list of a directory sent with incremental recursion.
+    pub async fn recv_sub_file_list(
+        &mut self,
+        protocol: &Protocol,
+        ndx_start: u32,
+    ) -> Result<(Vec<FileEntry>, u32)> {
+        let result = self.recv_file_list_(protocol, ndx_start, None).await;
+        if let Ok((list, _)) = &result {
+            debug!(ndx_start, len = list.len(), "recv sub file list");
+        }
+        result
+    }
+
+    async fn recv_file_list_(
+        &mut self,
+        protocol: &Protocol,
+        ndx_start: u32,
+        spinner: Option<&ProgressBar>,
+    ) -> Result<(Vec<FileEntry>, u32)> {
+        let mut list = vec![];
+
         let mut name_scratch = Vec::new();
+        let mut hlinks = DevInodes::default();
+        let mut io_errors = 0;
         loop {
-            let b = self.read_u8().await?;
-            if b == 0 {
-                break;
-            }
+            let flags = if protocol.varint_flist_flags() {
+                #[allow(clippy::cast_sign_loss)] // flags are bits
+                let flags = self.read_varint().await? as u32;
+                if flags == 0 {
+                    io_errors = self.read_varint().await?;
+                    break;
+                }
+                flags
+            } else {
+                let mut flags = u32::from(self.read_u8().await?);
+                if flags == 0 {
+                    break;
+                }
+                if protocol.version >= 28 && flags & XMIT_EXTENDED_FLAGS != 0 {
+                    flags |= u32::from(self.read_u8().await?) << 8;
+                }
+                if flags == XMIT_EXTENDED_FLAGS | XMIT_IO_ERROR_ENDLIST {
+                    if !protocol.safe_flist() {
+                        bail!("invalid file list end marker: safe file list not enabled");
+                    }
+                    io_errors = self.read_varint().await?;
+                    break;
+                }
+                flags
+            };
 
             let entry = self
-                .recv_file_entry(b, &mut name_scratch, list.last())
+                .recv_file_entry(
+                    protocol,
+                    flags,
+                    &mut name_scratch,
+                    &list,
+                    ndx_start,
+                    &mut hlinks,
+                )
                 .await?;
             debug!(?entry, "recv file entry");
             list.push(entry);
-            if list.len() % 100 == 0 {
-                spinner.set_message(format!("{} files", list.len()));
+            if let Some(spinner) = spinner {
+                if list.len() % 100 == 0 {
+                    spinner.set_message(format!("{} files", list.len()));
+                }
             }
         }
-        spinner.finish_and_clear();
 
-        list.sort_unstable_by(|x, y| x.name.cmp(&y.name));
-        list.dedup_by(|x, y| x.name == y.name);
+        if protocol.version < 30 {
+            // Old protocols send dev and inode of all candidates, including files that turn out
+            // to have no other links in the list.
+            hlinks.drop_singletons(&mut list);
+        }
 
-        info!("{} files", list.len());
+        list.sort_unstable_by(|x, y| f_name_cmp(x, y, protocol.version));
 
-        // Now we mark their idx
+        // Now we mark their idx. Duplicated entries still occupy their indices on server side.
+        let used = u32::try_from(list.len()).expect("file list too long");
         for (idx, entry) in list.iter_mut().enumerate() {
-            entry.idx = u32::try_from(idx).expect("file list too long");
+            entry.idx = ndx_start + u32::try_from(idx).expect("file list too long");
         }
+        list.dedup_by(|x, y| x.name == y.name);
 
         // enveloped_conn.consume_uid_mapping().await?;
-        let io_errors = self.read_i32_le().await?;
+        if protocol.version < 30 {
+            io_errors = self.read_i32_le().await?;
+        }
         if io_errors != 0 {
             warn!("server reported IO errors: {}", io_errors);
         }
 
-        Ok(list)
+        Ok((list, used))
     }
 
     async fn recv_file_entry(
         &mut self,
-        flags: u8,
+        protocol: &Protocol,
+        flags: u32,
         name_scratch: &mut Vec<u8>,
-        prev: Option<&FileEntry>,
+        list: &[FileEntry],
+        ndx_start: u32,
+        hlinks: &mut DevInodes,
     ) -> Result<FileEntry> {
+        let prev = list.last();
         let same_name = flags & XMIT_SAME_NAME != 0;
         let long_name = flags & XMIT_LONG_NAME != 0;
         let same_time = flags & XMIT_SAME_TIME != 0;
         let same_mode = flags & XMIT_SAME_MODE != 0;
+        let mod_nsec = flags & XMIT_MOD_NSEC != 0;
 
         let inherit_name_len = if same_name { self.read_u8().await? } else { 0 };
         let name_len = if long_name {
-            self.read_u32_le().await?
+            #[allow(clippy::cast_sign_loss)] // checked below
+            let len = self.read_varint30(protocol.version).await? as u32;
+            len
         } else {
             u32::from(self.read_u8().await?)
         };
@@ -135,19 +281,61 @@ impl EnvelopeRead<BufReader<OwnedReadHalf>> {
         if name != name_raw {
             warn!("path cleaned: {:?} -> {:?}", name_raw, name);
         }
+        let name = name.into_os_string().into_vec();
+
+        // Since protocol 30, hard links are sent as an index to the first entry of the group,
+        // which is the index of the first entry itself. Attributes are omitted if the first
+        // entry is in the same file list.
+        let mut hlink_group = None;
+        if protocol.version >= 30 && flags & XMIT_HLINKED != 0 {
+            let ndx = ndx_start + u32::try_from(list.len()).expect("file list too long");
+            if flags & XMIT_HLINK_FIRST != 0 {
+                hlink_group = Some(ndx);
+            } else {
+                let first = self.read_varint().await?;
+                let first = u32::try_from(first)
+                    .ok()
+                    .filter(|first| *first < ndx)
+                    .ok_or_else(|| eyre!("hard link to invalid index {}", first))?;
+                if first >= ndx_start {
+                    let first_entry = &list[(first - ndx_start) as usize];
+                    return Ok(FileEntry {
+                        name,
+                        len: first_entry.len,
+                        modify_time: first_entry.modify_time,
+                        mode: first_entry.mode,
+                        link_target: first_entry.link_target.clone(),
+                        hlink_group: Some(first),
+                        checksum: first_entry.checksum,
+                        idx: u32::MAX, // to be filled later
+                    });
+                }
+                hlink_group = Some(first);
+            }
+        }
 
         // File length should always be positive right?
         #[allow(clippy::cast_sign_loss)]
-        let len = self.read_rsync_long().await? as u64;
+        let len = self.read_varlong30(protocol.version, 3).await? as u64;
 
         let modify_time = if same_time {
             prev.expect("prev must exist").modify_time
+        } else if protocol.version >= 30 {
+            let secs = self.read_varlong(4).await?;
+            // Files before 1970 are clamped to epoch.
+            #[allow(clippy::cast_sign_loss)]
+            let secs = secs.max(0) as u64;
+            SystemTime::UNIX_EPOCH + Duration::new(secs, 0)
         } else {
             // To avoid Y2038 problem, newer versions of rsync daemon treat mtime as u32 when
-            // speaking protocol version 27.
+            // speaking protocol version < 30.
             let secs = self.read_u32_le().await?;
             SystemTime::UNIX_EPOCH + Duration::new(u64::from(secs), 0)
         };
+        if mod_nsec {
+            // We only keep mtime in seconds.
+            self.read_varint().await?;
+        }
 
         let mode = if same_mode {
             prev.expect("prev must exist").mode
@@ -163,7 +351,11 @@ impl EnvelopeRead<BufReader<OwnedReadHalf>> {
 
         // Preserve links
         let link_target = if is_link {
-            let len = self.read_u32_le().await?;
+            #[allow(clippy::cast_sign_loss)]
+            let len = self.read_varint30(protocol.version).await? as u32;
+            if len > PATH_MAX {
+                bail!("link target too long");
+            }
             let mut buf = vec![0u8; len as usize];
             self.read_exact(&mut buf).await?;
             Some(buf)
@@ -171,13 +363,416 @@ impl EnvelopeRead<BufReader<OwnedReadHalf>> {
             None
         };
 
+        // Before protocol 30, hard links are sent as dev and inode. Protocol 27 sends them for
+        // all regular files.
+        let hlinked = protocol.hard_links
+            && if protocol.version < 28 {
+                unix_mode::is_file(mode)
+            } else {
+                flags & XMIT_HLINKED != 0
+            };
+        if protocol.version < 30 && hlinked {
+            let dev = if flags & XMIT_SAME_DEV_PRE30 == 0 {
+                self.read_rsync_long().await?
+            } else {
+                hlinks.last_dev
+            };
+            let ino = self.read_rsync_long().await?;
+            hlink_group = Some(hlinks.group_of(dev, ino));
+        }
+
+        // Before protocol 28, a checksum of zeros is sent for other entries too.
+        let checksum =
+            if protocol.always_checksum && (unix_mode::is_file(mode) || protocol.version < 28) {
+                let mut sum = [0; FILE_CHECKSUM_LEN];
+                self.read_exact(&mut sum).await?;
+                unix_mode::is_file(mode).then_some(FileChecksum {
+                    kind: protocol.checksum.kind,
+                    sum,
+                })
+            } else {
+                None
+            };
+
         Ok(FileEntry {
-            name: name.into_os_string().into_vec(),
+            name,
             len,
             modify_time,
             mode,
             link_target,
+            hlink_group,
+            checksum,
             idx: u32::MAX, // to be filled later
         })
     }
 }
+
+/// Hard link groups identified by dev and inode, used before protocol 30.
+#[derive(Debug, Default)]
+struct DevInodes {
+    last_dev: i64,
+    groups: HashMap<(i64, i64), (u32, usize)>,
+}
+
+impl DevInodes {
+    /// Get the group id of given dev and inode.
+    fn group_of(&mut self, dev: i64, ino: i64) -> u32 {
+        self.last_dev = dev;
+        let next_id = u32::try_from(self.groups.len()).expect("too many hard links");
+        let (id, count) = self.groups.entry((dev, ino)).or_insert((next_id, 0));
+        *count += 1;
+        *id
+    }
+    /// Clear groups with only one entry, i.e. files that are not hard linked.
+    fn drop_singletons(&self, list: &mut [FileEntry]) {
+        let singletons: HashSet<u32> = self
+            .groups
+            .values()
+            .filter(|(_, count)| *count == 1)
+            .map(|(id, _)| *id)
+            .collect();
+        for entry in list {
+            if entry
+                .hlink_group
+                .is_some_and(|group| singletons.contains(&group))
+            {
+                entry.hlink_group = None;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum NameState {
+    Dir,
+    Slash,
+    Base,
+    Trailing,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum NameType {
+    Path,
+    Item,
+}
+
+/// Walks through a file name in the order rsync compares them: dirname, slash, basename, and a
+/// trailing slash for directories.
+struct NameWalker<'a> {
+    basename: &'a [u8],
+    is_dir: bool,
+    t_path: NameType,
+    state: NameState,
+    typ: NameType,
+    cur: &'a [u8],
+}
+
+impl<'a> NameWalker<'a> {
+    fn new(dirname: Option<&'a [u8]>, basename: &'a [u8], is_dir: bool, t_path: NameType) -> Self {
+        let mut walker = Self {
+            basename,
+            is_dir,
+            t_path,
+            state: NameState::Dir,
+            typ: t_path,
+            cur: dirname.unwrap_or_default(),
+        };
+        if dirname.is_none() {
+            walker.enter_base();
+        }
+        walker
+    }
+    fn enter_base(&mut self) {
+        self.typ = if self.is_dir {
+            self.t_path
+        } else {
+            NameType::Item
+        };
+        self.cur = self.basename;
+        if self.typ == NameType::Path && self.basename == b"." {
+            self.typ = NameType::Item;
+            self.state = NameState::Trailing;
+            self.cur = b"";
+        } else {
+            self.state = NameState::Base;
+        }
+    }
+    fn advance(&mut self) {
+        match self.state {
+            NameState::Dir => {
+                self.state = NameState::Slash;
+                self.cur = b"/";
+            }
+            NameState::Slash => self.enter_base(),
+            NameState::Base => {
+                self.state = NameState::Trailing;
+                if self.typ == NameType::Path {
+                    self.cur = b"/";
+                } else {
+                    self.typ = NameType::Item;
+                }
+            }
+            NameState::Trailing => self.typ = NameType::Item,
+        }
+    }
+    fn next_byte(&mut self) -> u8 {
+        self.cur.split_first().map_or(0, |(b, rest)| {
+            self.cur = rest;
+            *b
+        })
+    }
+}
+
+const fn type_order(typ: NameType) -> Ordering {
+    match typ {
+        NameType::Path => Ordering::Greater,
+        NameType::Item => Ordering::Less,
+    }
+}
+
+fn split_name(name: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    name.iter()
+        .rposition(|b| *b == b'/')
+        .map_or((None, name), |pos| (Some(&name[..pos]), &name[pos + 1..]))
+}
+
+/// Compare file names in the same order as the server does, so that file indices match.
+///
+/// Since protocol 29, files in a directory are sorted before subdirectories. Before that, it's a
+/// plain byte comparison.
+fn f_name_cmp(x: &FileEntry, y: &FileEntry, protocol_version: i32) -> Ordering {
+    let t_path = if protocol_version >= 29 {
+        NameType::Path
+    } else {
+        NameType::Item
+    };
+    let (mut dir_x, base_x) = split_name(&x.name);
+    let (mut dir_y, base_y) = split_name(&y.name);
+    if dir_x == dir_y {
+        (dir_x, dir_y) = (None, None);
+    }
+    let mut wx = NameWalker::new(dir_x, base_x, unix_mode::is_dir(x.mode), t_path);
+    let mut wy = NameWalker::new(dir_y, base_y, unix_mode::is_dir(y.mode), t_path);
+
+    if wx.typ != wy.typ {
+        return type_order(wx.typ);
+    }
+
+    loop {
+        if wx.cur.is_empty() {
+            wx.advance();
+            if !wy.cur.is_empty() && wx.typ != wy.typ {
+                return type_order(wx.typ);
+            }
+        }
+        if wy.cur.is_empty() {
+            wy.advance();
+            if !wx.cur.is_empty() && wx.typ != wy.typ {
+                return type_order(wx.typ);
+            }
+        }
+        if wx.cur.is_empty()
+            && wy.cur.is_empty()
+            && wx.state == NameState::Trailing
+            && wy.state == NameState::Trailing
+        {
+            return Ordering::Equal;
+        }
+        match wx.next_byte().cmp(&wy.next_byte()) {
+            Ordering::Equal => (),
+            ord => return ord,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::UNIX_EPOCH;
+
+    use tokio::io::BufReader;
+
+    use crate::rsync::checksum::{ChecksumType, StrongChecksum};
+    use crate::rsync::compat::Protocol;
+    use crate::rsync::envelope::EnvelopeRead;
+    use crate::rsync::file_list::{f_name_cmp, FileChecksum, FileEntry};
+    use crate::rsync::transport::TransportRead;
+
+    fn protocol(version: i32) -> Protocol {
+        Protocol {
+            version,
+            compat_flags: 0,
+            checksum: StrongChecksum {
+                kind: ChecksumType::Md5,
+                seed: 0,
+                proper_seed_order: true,
+            },
+            compression: None,
+            always_checksum: false,
+            hard_links: true,
+        }
+    }
+
+    fn envelope(data: &[u8]) -> EnvelopeRead<BufReader<TransportRead>> {
+        // Wrap data in a single multiplexed frame.
+        let len = u32::try_from(data.len()).unwrap();
+        let mut frame = (len | 7 << 24).to_le_bytes().to_vec();
+        frame.extend_from_slice(data);
+        let rx: TransportRead = Box::new(Cursor::new(frame));
+        EnvelopeRead::new(BufReader::new(rx))
+    }
+
+    async fn recv_list(protocol: &Protocol, data: &[u8]) -> Vec<FileEntry> {
+        let (list, used) = envelope(data)
+            .recv_file_list_(protocol, 0, None)
+            .await
+            .unwrap();
+        assert_eq!(used, 3);
+        list
+    }
+
+    async fn recv_hlink_groups(version: i32, data: &[u8]) -> Vec<(String, u64, Option<u32>)> {
+        recv_list(&protocol(version), data)
+            .await
+            .into_iter()
+            .map(|entry| (entry.name_lossy().to_string(), entry.len, entry.hlink_group))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn must_recv_hard_links() {
+        let data: &[u8] = &[
+            // a: XMIT_HLINKED | XMIT_HLINK_FIRST, len 5, mtime, mode 0o100644
+            0x04, 0x12, 1, b'a', 0x00, 0x05, 0x00, 0x00, 0x03, 0x02, 0x01, 0xa4, 0x81, 0, 0,
+            // b: XMIT_HLINKED, linked to index 0
+            0x04, 0x02, 1, b'b', 0x00, // c: XMIT_SAME_TIME | XMIT_SAME_MODE, len 7
+            0x82, 1, b'c', 0x00, 0x07, 0x00, // end of list
+            0x00,
+        ];
+        assert_eq!(
+            recv_hlink_groups(30, data).await,
+            [
+                ("a".to_string(), 5, Some(0)),
+                ("b".to_string(), 5, Some(0)),
+                ("c".to_string(), 7, None)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn must_recv_hard_links_pre30() {
+        let data: &[u8] = &[
+            // a: XMIT_HLINKED, len 5, mtime, mode 0o100644, dev 1, ino 10
+            0x04, 0x02, 1, b'a', 5, 0, 0, 0, 1, 0, 0, 0, 0xa4, 0x81, 0, 0, 1, 0, 0, 0, 10, 0, 0, 0,
+            // b: XMIT_HLINKED | XMIT_SAME_DEV_pre30, ino 10
+            0x04, 0x06, 1, b'b', 5, 0, 0, 0, 1, 0, 0, 0, 0xa4, 0x81, 0, 0, 10, 0, 0, 0,
+            // c: XMIT_HLINKED | XMIT_SAME_DEV_pre30, ino 11
+            0x04, 0x06, 1, b'c', 7, 0, 0, 0, 1, 0, 0, 0, 0xa4, 0x81, 0, 0, 11, 0, 0, 0,
+            // end of list, no io errors
+            0x00, 0, 0, 0, 0,
+        ];
+        assert_eq!(
+            recv_hlink_groups(29, data).await,
+            [
+                ("a".to_string(), 5, Some(0)),
+                ("b".to_string(), 5, Some(0)),
+                ("c".to_string(), 7, None)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn must_recv_checksums() {
+        let data: &[u8] = &[
+            // a: XMIT_HLINKED | XMIT_HLINK_FIRST, len 5, mtime, mode 0o100644, checksum
+            0x04, 0x12, 1, b'a', 0x00, 0x05, 0x00, 0x00, 0x03, 0x02, 0x01, 0xa4, 0x81, 0, 0, 1, 2,
+            3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+            // b: XMIT_HLINKED, linked to index 0, checksum not sent
+            0x04, 0x02, 1, b'b', 0x00, // d: XMIT_SAME_TIME, mode 0o40755, no checksum
+            0x80, 1, b'd', 0x00, 0x00, 0x00, 0xed, 0x41, 0, 0, // end of list
+            0x00,
+        ];
+        let protocol = Protocol {
+            always_checksum: true,
+            ..protocol(30)
+        };
+        let sums: Vec<_> = recv_list(&protocol, data)
+            .await
+            .into_iter()
+            .map(|entry| (entry.name_lossy().to_string(), entry.checksum))
+            .collect();
+        let expected = FileChecksum {
+            kind: ChecksumType::Md5,
+            sum: std::array::from_fn(|i| i as u8 + 1),
+        };
+        assert_eq!(
+            sums,
+            [
+                ("a".to_string(), Some(expected)),
+                ("b".to_string(), Some(expected)),
+                ("d".to_string(), None)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn must_recv_sub_file_list() {
+        let data: &[u8] = &[
+            // x: len 5, mtime, mode 0o100644
+            0x04, 0x00, 1, b'x', 0x00, 0x05, 0x00, 0x00, 0x03, 0x02, 0x01, 0xa4, 0x81, 0, 0,
+            // w: XMIT_SAME_TIME | XMIT_SAME_MODE, len 7
+            0x82, 1, b'w', 0x00, 0x07, 0x00,
+            // y: XMIT_SAME_TIME | XMIT_SAME_MODE | XMIT_HLINKED, linked to index 2 of a previous
+            // file list, len 9
+            0x86, 0x02, 1, b'y', 0x02, 0x00, 0x09, 0x00, // end of list
+            0x00,
+        ];
+        let (list, used) = envelope(data)
+            .recv_sub_file_list(&protocol(30), 5)
+            .await
+            .unwrap();
+        assert_eq!(used, 3);
+        let entries: Vec<_> = list
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.name_lossy().to_string(),
+                    entry.idx,
+                    entry.len,
+                    entry.hlink_group,
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("w".to_string(), 5, 7, None),
+                ("x".to_string(), 6, 5, None),
+                ("y".to_string(), 7, 9, Some(2))
+            ]
+        );
+    }
+
+    #[test]
+    fn must_sort_file_list() {
+        let dir = |name: &str| FileEntry::directory(name.to_string(), 0, UNIX_EPOCH, 0);
+        let file = |name: &str| FileEntry::regular(name.to_string(), 0, UNIX_EPOCH, 0);
+        let mut list = [
+            dir("a/b"),
+            file("a/x"),
+            dir("a"),
+            file("a.txt"),
+            file("b"),
+            dir("."),
+            file("a/b/c"),
+        ];
+
+        list.sort_by(|x, y| f_name_cmp(x, y, 31));
+        let names: Vec<_> = list.iter().map(|e| e.name_lossy().to_string()).collect();
+        assert_eq!(names, [".", "a.txt", "b", "a", "a/x", "a/b", "a/b/c"]);
+
+        list.sort_by(|x, y| f_name_cmp(x, y, 27));
+        let names: Vec<_> = list.iter().map(|e| e.name_lossy().to_string()).collect();
+        assert_eq!(names, [".", "a", "a.txt", "a/b", "a/b/c", "a/x", "b"]);
+    }
+}
@@ -1,46 +1,686 @@
-use std::ffi::OsString;
+//! Filter rules.
+//!
+//! Rules follow the grammar of rsync's `--filter`, including modifiers, merge files and list
+//! clearing. They are sent to the server, which does the actual filtering as the sender. Received
+//! file lists are not filtered again, because parts of the rules, e.g. dir-merge files, are only
+//! known to the server.
+
+use std::ffi::OsStr;
+use std::fs;
 use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 
-use eyre::Result;
+use eyre::{bail, ensure, eyre, Result, WrapErr};
 use tokio::io::AsyncWriteExt;
 
 use crate::rsync::handshake::HandshakeConn;
 
 const EXCLUSION_LIST_END: i32 = 0;
+const MAX_MERGE_DEPTH: usize = 16;
+
+/// Long names of rules and their short forms.
+const RULE_NAMES: &[(&[u8], u8)] = &[
+    (b"exclude", b'-'),
+    (b"include", b'+'),
+    (b"merge", b'.'),
+    (b"dir-merge", b':'),
+    (b"hide", b'H'),
+    (b"show", b'S'),
+    (b"protect", b'P'),
+    (b"risk", b'R'),
+    (b"clear", b'!'),
+];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    Include,
+    Exclude,
+}
+
+/// Modifiers of an include or exclude rule.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Modifiers {
+    /// `/`: match against the absolute path on the server.
+    pub abs_path: bool,
+    /// `!`: take effect if the pattern does not match.
+    pub negate: bool,
+    /// `s`: affect the sending side only.
+    pub sender_side: bool,
+    /// `r`: affect the receiving side only.
+    pub receiver_side: bool,
+    /// `p`: ignored in directories being deleted.
+    pub perishable: bool,
+}
+
+impl Modifiers {
+    const fn merge(self, other: Self) -> Self {
+        Self {
+            abs_path: self.abs_path || other.abs_path,
+            negate: self.negate || other.negate,
+            sender_side: self.sender_side || other.sender_side,
+            receiver_side: self.receiver_side || other.receiver_side,
+            perishable: self.perishable || other.perishable,
+        }
+    }
+    /// Whether the rule takes effect on the server, which is always the sender.
+    const fn on_sender(self) -> bool {
+        self.sender_side || !self.receiver_side
+    }
+    fn write(self, protocol_version: i32, buf: &mut Vec<u8>) {
+        if self.abs_path {
+            buf.push(b'/');
+        }
+        if self.negate {
+            buf.push(b'!');
+        }
+        if self.perishable && protocol_version >= 30 {
+            buf.push(b'p');
+        }
+        if self.sender_side {
+            buf.push(b's');
+        }
+        if self.receiver_side {
+            buf.push(b'r');
+        }
+    }
+}
+
+/// Modifiers of a merge or dir-merge rule.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MergeModifiers {
+    /// `+` or `-`: the file contains only include or exclude patterns.
+    pub only: Option<Action>,
+    /// `n`: rules are not inherited by subdirectories.
+    pub no_inherit: bool,
+    /// `w`: rules are separated by whitespace instead of newlines.
+    pub word_split: bool,
+    /// Modifiers applied to rules read from the file.
+    pub rule: Modifiers,
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Rule {
-    Exclude(OsString),
-    Include(OsString),
+    /// Include or exclude files matching the pattern.
+    Filter {
+        action: Action,
+        pattern: Vec<u8>,
+        mods: Modifiers,
+    },
+    /// Read per-directory rules from files of this name on the server.
+    DirMerge { file: Vec<u8>, mods: MergeModifiers },
 }
 
 impl Rule {
-    fn to_command(&self) -> OsString {
+    /// Encode the rule as sent to the server.
+    ///
+    /// Returns `None` if the rule only affects the receiving side, i.e. us.
+    fn to_command(&self, protocol_version: i32) -> Result<Option<Vec<u8>>> {
+        let mut cmd = Vec::new();
         match self {
-            Self::Exclude(path) => {
-                let mut cmd = OsString::from("- ");
-                cmd.push(path);
-                cmd
+            Self::Filter {
+                action,
+                pattern,
+                mods,
+            } => {
+                if !mods.on_sender() {
+                    return Ok(None);
+                }
+                cmd.push(match action {
+                    Action::Include => b'+',
+                    Action::Exclude => b'-',
+                });
+                mods.write(protocol_version, &mut cmd);
+                cmd.push(b' ');
+                cmd.extend_from_slice(pattern);
             }
-            Self::Include(path) => {
-                let mut cmd = OsString::from("+ ");
-                cmd.push(path);
-                cmd
+            Self::DirMerge { file, mods } => {
+                cmd.push(b':');
+                match mods.only {
+                    Some(Action::Include) => cmd.push(b'+'),
+                    Some(Action::Exclude) => cmd.push(b'-'),
+                    None => (),
+                }
+                if mods.no_inherit {
+                    cmd.push(b'n');
+                }
+                if mods.word_split {
+                    cmd.push(b'w');
+                }
+                mods.rule.write(protocol_version, &mut cmd);
+                cmd.push(b' ');
+                cmd.extend_from_slice(file);
             }
         }
+        // Servers before protocol 29 only understand plain include and exclude rules.
+        if protocol_version < 29 && !matches!(cmd.get(..2), Some(b"+ " | b"- ")) {
+            bail!(
+                "filter rule too modern for protocol {}: {}",
+                protocol_version,
+                String::from_utf8_lossy(&cmd)
+            );
+        }
+        Ok(Some(cmd))
+    }
+}
+
+/// How a line of rules is parsed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Syntax {
+    /// Full rule syntax, i.e. `--filter` and merge files.
+    Full,
+    /// Patterns of given action, with `- `, `+ ` and `!` recognized, i.e. `--exclude`.
+    OldPrefixes(Action),
+    /// Patterns of given action only, i.e. merge files with `+` or `-` modifier.
+    NoPrefixes(Action),
+}
+
+/// A parsed rule before being added to the list.
+#[derive(Debug, Eq, PartialEq)]
+enum Parsed<'a> {
+    Filter {
+        action: Action,
+        pattern: &'a [u8],
+        mods: Modifiers,
+    },
+    Merge {
+        file: &'a [u8],
+        mods: MergeModifiers,
+        exclude_self: bool,
+    },
+    DirMerge {
+        file: &'a [u8],
+        mods: MergeModifiers,
+        exclude_self: bool,
+    },
+    Clear,
+}
+
+/// Short form of the rule name, its modifiers, and the pattern if any.
+type RulePrefix<'a> = (u8, &'a [u8], Option<&'a [u8]>);
+
+/// Split the rule name and modifiers from the rest of the rule.
+fn split_rule(rule: &[u8]) -> Result<RulePrefix<'_>> {
+    let long = RULE_NAMES.iter().find(|(name, _)| {
+        rule.strip_prefix(*name)
+            .is_some_and(|rest| matches!(rest.first(), None | Some(b',' | b' ' | b'_')))
+    });
+    let (kind, rest) = if let Some((name, kind)) = long {
+        let rest = &rule[name.len()..];
+        (*kind, rest.strip_prefix(b",").unwrap_or(rest))
+    } else {
+        let (kind, rest) = rule
+            .split_first()
+            .ok_or_else(|| eyre!("empty filter rule"))?;
+        ensure!(
+            RULE_NAMES.iter().any(|(_, short)| short == kind),
+            "unknown filter rule: {}",
+            String::from_utf8_lossy(rule)
+        );
+        (*kind, rest)
+    };
+    Ok(match rest.iter().position(|c| matches!(c, b' ' | b'_')) {
+        Some(pos) => (kind, &rest[..pos], Some(&rest[pos + 1..])),
+        None => (kind, rest, None),
+    })
+}
+
+fn parse_rule(rule: &[u8], syntax: Syntax) -> Result<Parsed<'_>> {
+    let full = match syntax {
+        Syntax::Full => rule,
+        Syntax::OldPrefixes(action) => {
+            return Ok(if rule == b"!" {
+                Parsed::Clear
+            } else if let Some(pattern) = rule.strip_prefix(b"- ") {
+                parsed_pattern(Action::Exclude, pattern, Modifiers::default())?
+            } else if let Some(pattern) = rule.strip_prefix(b"+ ") {
+                parsed_pattern(Action::Include, pattern, Modifiers::default())?
+            } else {
+                parsed_pattern(action, rule, Modifiers::default())?
+            });
+        }
+        Syntax::NoPrefixes(action) => {
+            return parsed_pattern(action, rule, Modifiers::default());
+        }
+    };
+
+    let (kind, mod_chars, pattern) = split_rule(full)?;
+    if kind == b'!' {
+        ensure!(
+            mod_chars.is_empty() && pattern.unwrap_or_default().is_empty(),
+            "clear rule takes no pattern"
+        );
+        return Ok(Parsed::Clear);
+    }
+    let pattern = pattern.ok_or_else(|| {
+        eyre!(
+            "missing pattern in filter rule: {}",
+            String::from_utf8_lossy(rule)
+        )
+    })?;
+
+    let is_merge = matches!(kind, b'.' | b':');
+    let mut mods = Modifiers {
+        sender_side: matches!(kind, b'H' | b'S'),
+        receiver_side: matches!(kind, b'P' | b'R'),
+        ..Modifiers::default()
+    };
+    let mut merge_mods = MergeModifiers::default();
+    let mut exclude_self = false;
+    for c in mod_chars {
+        match c {
+            b'/' => mods.abs_path = true,
+            b'!' => mods.negate = true,
+            b's' => mods.sender_side = true,
+            b'r' => mods.receiver_side = true,
+            b'p' => mods.perishable = true,
+            b'+' | b'-' if is_merge => {
+                let only = if *c == b'+' {
+                    Action::Include
+                } else {
+                    Action::Exclude
+                };
+                ensure!(
+                    merge_mods.only.unwrap_or(only) == only,
+                    "conflicting merge modifiers"
+                );
+                merge_mods.only = Some(only);
+            }
+            b'e' if is_merge => exclude_self = true,
+            b'n' if is_merge => merge_mods.no_inherit = true,
+            b'w' if is_merge => merge_mods.word_split = true,
+            b'C' | b'x' => bail!("unsupported filter modifier: {}", char::from(*c)),
+            _ => bail!("invalid filter modifier: {}", char::from(*c)),
+        }
+    }
+
+    Ok(match kind {
+        b'.' | b':' => {
+            ensure!(!pattern.is_empty(), "empty merge file name");
+            merge_mods.rule = mods;
+            if kind == b'.' {
+                Parsed::Merge {
+                    file: pattern,
+                    mods: merge_mods,
+                    exclude_self,
+                }
+            } else {
+                Parsed::DirMerge {
+                    file: pattern,
+                    mods: merge_mods,
+                    exclude_self,
+                }
+            }
+        }
+        b'+' | b'S' | b'R' => parsed_pattern(Action::Include, pattern, mods)?,
+        _ => parsed_pattern(Action::Exclude, pattern, mods)?,
+    })
+}
+
+fn parsed_pattern(action: Action, pattern: &[u8], mods: Modifiers) -> Result<Parsed<'_>> {
+    ensure!(!pattern.is_empty(), "empty filter pattern");
+    Ok(Parsed::Filter {
+        action,
+        pattern,
+        mods,
+    })
+}
+
+/// An ordered list of filter rules. The first matching rule takes effect.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FilterRules {
+    rules: Vec<Rule>,
+}
+
+impl FilterRules {
+    /// Add a rule in full filter syntax, e.g. `- *.tmp`, `merge,- excludes.txt`.
+    pub fn add_rule(&mut self, rule: &[u8]) -> Result<()> {
+        self.add_line(rule, Syntax::Full, Modifiers::default(), 0)
+    }
+    /// Add an include or exclude pattern, i.e. `--include` and `--exclude`.
+    ///
+    /// Like rsync, `- ` and `+ ` prefixes override the action, and `!` clears the list.
+    pub fn add_pattern(&mut self, pattern: &[u8], action: Action) -> Result<()> {
+        self.add_line(
+            pattern,
+            Syntax::OldPrefixes(action),
+            Modifiers::default(),
+            0,
+        )
+    }
+    /// Add patterns from a file, one per line, i.e. `--include-from` and `--exclude-from`.
+    pub fn add_patterns_from(&mut self, path: &Path, action: Action) -> Result<()> {
+        self.add_file(
+            path,
+            Syntax::OldPrefixes(action),
+            false,
+            Modifiers::default(),
+            0,
+        )
+    }
+    /// Exclude a path relative to the transfer root, taking precedence over all other rules.
+    pub fn exclude_first(&mut self, path: &[u8]) {
+        self.rules.insert(
+            0,
+            Rule::Filter {
+                action: Action::Exclude,
+                pattern: [b"/", path].concat(),
+                mods: Modifiers::default(),
+            },
+        );
+    }
+
+    fn add_line(
+        &mut self,
+        line: &[u8],
+        syntax: Syntax,
+        inherit: Modifiers,
+        depth: usize,
+    ) -> Result<()> {
+        match parse_rule(line, syntax)? {
+            Parsed::Filter {
+                action,
+                pattern,
+                mods,
+            } => self.rules.push(Rule::Filter {
+                action,
+                pattern: pattern.to_vec(),
+                mods: mods.merge(inherit),
+            }),
+            Parsed::Merge {
+                file,
+                mods,
+                exclude_self,
+            } => {
+                ensure!(depth < MAX_MERGE_DEPTH, "merge files nested too deep");
+                if exclude_self {
+                    self.exclude_self(file);
+                }
+                let syntax = mods.only.map_or(Syntax::Full, Syntax::NoPrefixes);
+                let path = Path::new(OsStr::from_bytes(file));
+                self.add_file(
+                    path,
+                    syntax,
+                    mods.word_split,
+                    mods.rule.merge(inherit),
+                    depth + 1,
+                )?;
+            }
+            Parsed::DirMerge {
+                file,
+                mut mods,
+                exclude_self,
+            } => {
+                if exclude_self {
+                    self.exclude_self(file);
+                }
+                mods.rule = mods.rule.merge(inherit);
+                self.rules.push(Rule::DirMerge {
+                    file: file.to_vec(),
+                    mods,
+                });
+            }
+            Parsed::Clear => self.rules.clear(),
+        }
+        Ok(())
+    }
+
+    fn add_file(
+        &mut self,
+        path: &Path,
+        syntax: Syntax,
+        word_split: bool,
+        inherit: Modifiers,
+        depth: usize,
+    ) -> Result<()> {
+        let content = fs::read(path)
+            .wrap_err_with(|| format!("failed to read filter file {}", path.display()))?;
+        for line in content.split(|c| *c == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() || line.starts_with(b"#") || line.starts_with(b";") {
+                continue;
+            }
+            if word_split {
+                let mut words = line
+                    .split(u8::is_ascii_whitespace)
+                    .filter(|word| !word.is_empty());
+                while let Some(word) = words.next() {
+                    // A rule name and its pattern are separate words.
+                    let is_prefix = syntax == Syntax::Full
+                        && split_rule(word)
+                            .is_ok_and(|(kind, _, rest)| kind != b'!' && rest.is_none());
+                    if is_prefix {
+                        let pattern = words.next().ok_or_else(|| {
+                            eyre!("missing pattern after {}", String::from_utf8_lossy(word))
+                        })?;
+                        self.add_line(&[word, b" ", pattern].concat(), syntax, inherit, depth)?;
+                    } else {
+                        self.add_line(word, syntax, inherit, depth)?;
+                    }
+                }
+            } else {
+                self.add_line(line, syntax, inherit, depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Exclude the merge file itself.
+    fn exclude_self(&mut self, file: &[u8]) {
+        let name = file.rsplit(|c| *c == b'/').next().unwrap_or(file);
+        self.rules.push(Rule::Filter {
+            action: Action::Exclude,
+            pattern: name.to_vec(),
+            mods: Modifiers::default(),
+        });
+    }
+
+    /// Encode the rules as sent to the server, skipping rules which only affect the receiving side.
+    pub fn to_commands(&self, protocol_version: i32) -> Result<Vec<Vec<u8>>> {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.to_command(protocol_version).transpose())
+            .collect()
     }
 }
 
 impl HandshakeConn {
-    pub async fn send_filter_rules(&mut self, rules: &[Rule]) -> Result<()> {
-        for rule in rules {
-            let cmd = rule.to_command();
+    pub async fn send_filter_rules(&mut self, rules: &FilterRules) -> Result<()> {
+        for cmd in rules.to_commands(self.protocol_version)? {
             self.tx
                 .write_i32_le(i32::try_from(cmd.len()).expect("rule too long"))
                 .await?;
-            self.tx.write_all(cmd.as_bytes()).await?;
+            self.tx.write_all(&cmd).await?;
         }
         self.tx.write_i32_le(EXCLUSION_LIST_END).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use crate::rsync::filter::{Action, FilterRules, MergeModifiers, Modifiers, Rule};
+
+    fn rules(lines: &[&str]) -> FilterRules {
+        let mut rules = FilterRules::default();
+        for line in lines {
+            rules.add_rule(line.as_bytes()).unwrap();
+        }
+        rules
+    }
+
+    fn commands(rules: &FilterRules, protocol_version: i32) -> Vec<String> {
+        rules
+            .to_commands(protocol_version)
+            .unwrap()
+            .into_iter()
+            .map(|cmd| String::from_utf8(cmd).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn must_parse_rules() {
+        let rules = rules(&[
+            "- *.tmp",
+            "include,s foo/",
+            "H_.git",
+            "-! /keep",
+            "P /protected",
+            "dir-merge,-n .rsync-filter",
+            ":e .per-dir",
+        ]);
+        assert_eq!(
+            rules.rules,
+            [
+                Rule::Filter {
+                    action: Action::Exclude,
+                    pattern: b"*.tmp".to_vec(),
+                    mods: Modifiers::default(),
+                },
+                Rule::Filter {
+                    action: Action::Include,
+                    pattern: b"foo/".to_vec(),
+                    mods: Modifiers {
+                        sender_side: true,
+                        ..Modifiers::default()
+                    },
+                },
+                Rule::Filter {
+                    action: Action::Exclude,
+                    pattern: b".git".to_vec(),
+                    mods: Modifiers {
+                        sender_side: true,
+                        ..Modifiers::default()
+                    },
+                },
+                Rule::Filter {
+                    action: Action::Exclude,
+                    pattern: b"/keep".to_vec(),
+                    mods: Modifiers {
+                        negate: true,
+                        ..Modifiers::default()
+                    },
+                },
+                Rule::Filter {
+                    action: Action::Exclude,
+                    pattern: b"/protected".to_vec(),
+                    mods: Modifiers {
+                        receiver_side: true,
+                        ..Modifiers::default()
+                    },
+                },
+                Rule::DirMerge {
+                    file: b".rsync-filter".to_vec(),
+                    mods: MergeModifiers {
+                        only: Some(Action::Exclude),
+                        no_inherit: true,
+                        ..MergeModifiers::default()
+                    },
+                },
+                Rule::Filter {
+                    action: Action::Exclude,
+                    pattern: b".per-dir".to_vec(),
+                    mods: Modifiers::default(),
+                },
+                Rule::DirMerge {
+                    file: b".per-dir".to_vec(),
+                    mods: MergeModifiers::default(),
+                },
+            ]
+        );
+        assert_eq!(
+            commands(&rules, 31),
+            [
+                "- *.tmp",
+                "+s foo/",
+                "-s .git",
+                "-! /keep",
+                ":-n .rsync-filter",
+                "- .per-dir",
+                ": .per-dir"
+            ]
+        );
+    }
+
+    #[test]
+    fn must_reject_invalid_rules() {
+        for rule in ["", "x foo", "-", "- ", "-C foo", "+e foo", "! foo", "merge"] {
+            assert!(
+                FilterRules::default().add_rule(rule.as_bytes()).is_err(),
+                "{rule:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn must_reject_modern_rules_for_old_protocol() {
+        let rules = rules(&["- *.tmp", "+ foo/"]);
+        assert_eq!(commands(&rules, 28), ["- *.tmp", "+ foo/"]);
+        let rules = self::rules(&["H .git"]);
+        assert!(rules.rules[0].to_command(28).is_err());
+    }
+
+    #[test]
+    fn must_clear_rules() {
+        let rules = rules(&["- a", "!", "- b"]);
+        assert_eq!(commands(&rules, 31), ["- b"]);
+
+        let mut rules = FilterRules::default();
+        rules.add_pattern(b"a", Action::Exclude).unwrap();
+        rules.add_pattern(b"!", Action::Exclude).unwrap();
+        rules.add_pattern(b"+ b", Action::Exclude).unwrap();
+        rules.add_pattern(b"c", Action::Include).unwrap();
+        assert_eq!(commands(&rules, 31), ["+ b", "+ c"]);
+    }
+
+    #[test]
+    fn must_exclude_first() {
+        let mut rules = rules(&["+ security/***", "- *.iso"]);
+        rules.exclude_first(b"security");
+        assert_eq!(
+            commands(&rules, 31),
+            ["- /security", "+ security/***", "- *.iso"]
+        );
+    }
+
+    #[test]
+    fn must_read_merge_files() {
+        let mut nested = NamedTempFile::new().unwrap();
+        writeln!(nested, "*.iso\n*.img").unwrap();
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "# comment\n; comment\n\n+ */\nmerge,-s {}\n- *",
+            nested.path().display()
+        )
+        .unwrap();
+
+        let rules = rules(&[&format!("merge {}", file.path().display())]);
+        assert_eq!(
+            commands(&rules, 31),
+            ["+ */", "-s *.iso", "-s *.img", "- *"]
+        );
+
+        let mut words = NamedTempFile::new().unwrap();
+        writeln!(words, "- a + b\nmerge,- {}", nested.path().display()).unwrap();
+        let rules = self::rules(&[&format!(".w {}", words.path().display())]);
+        assert_eq!(commands(&rules, 31), ["- a", "+ b", "- *.iso", "- *.img"]);
+
+        let mut invalid = NamedTempFile::new().unwrap();
+        writeln!(invalid, "- a b").unwrap();
+        assert!(FilterRules::default()
+            .add_rule(format!(".w {}", invalid.path().display()).as_bytes())
+            .is_err());
+
+        let mut patterns = NamedTempFile::new().unwrap();
+        writeln!(patterns, "*.tmp\n+ keep.tmp").unwrap();
+        let mut rules = FilterRules::default();
+        rules
+            .add_patterns_from(patterns.path(), Action::Exclude)
+            .unwrap();
+        assert_eq!(commands(&rules, 31), ["- *.tmp", "+ keep.tmp"]);
+    }
+}
@@ -9,40 +9,58 @@ use eyre::Result;
 use futures::{stream, StreamExt};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::OwnedWriteHalf;
+
 use tokio::sync::{mpsc, Semaphore};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use crate::plan::TransferItem;
+use crate::plan::{PlanBatch, TransferItem};
 use crate::rsync::checksum::{checksum_payload, SumHead};
-use crate::rsync::file_list::FileEntry;
+use crate::rsync::compat::Protocol;
+use crate::rsync::envelope::EnvelopeWrite;
+use crate::rsync::file_list::InFlightFiles;
+use crate::rsync::ndx::{NdxCodec, ITEM_TRANSFER, NDX_DONE};
 use crate::rsync::progress_display::ProgressDisplay;
+use crate::rsync::transport::TransportWrite;
+use crate::rsync::LocalError;
 use crate::utils::ignore_mode;
 
 /// Generator sends requests to rsync server.
 pub struct Generator {
-    tx: OwnedWriteHalf,
-    file_list: Arc<Vec<FileEntry>>,
-    seed: i32,
+    tx: EnvelopeWrite<TransportWrite>,
+    in_flight: InFlightFiles,
+    protocol: Protocol,
+    ndx: NdxCodec,
     permits: Arc<Semaphore>,
+    plan_rx: mpsc::UnboundedReceiver<PlanBatch>,
+    download_tx: Option<flume::Sender<TransferItem>>,
+    redo_rx: mpsc::UnboundedReceiver<u32>,
     basis_rx: mpsc::Receiver<(u32, File)>,
     pb: ProgressDisplay,
 }
 
 impl Generator {
+    // We are fine with this because it's a private constructor.
+    #[allow(clippy::too_many_arguments)]
     pub const fn new(
-        tx: OwnedWriteHalf,
-        file_list: Arc<Vec<FileEntry>>,
-        seed: i32,
+        tx: EnvelopeWrite<TransportWrite>,
+        in_flight: InFlightFiles,
+        protocol: Protocol,
         permits: Arc<Semaphore>,
+        plan_rx: mpsc::UnboundedReceiver<PlanBatch>,
+        download_tx: flume::Sender<TransferItem>,
+        redo_rx: mpsc::UnboundedReceiver<u32>,
         basis_rx: mpsc::Receiver<(u32, File)>,
         pb: ProgressDisplay,
     ) -> Self {
         Self {
             tx,
-            file_list,
-            seed,
+            in_flight,
+            protocol,
+            ndx: NdxCodec::new(protocol.version),
             permits,
+            plan_rx,
+            download_tx: Some(download_tx),
+            redo_rx,
             basis_rx,
             pb,
         }
@@ -50,7 +68,7 @@ impl Generator {
 }
 
 impl Deref for Generator {
-    type Target = OwnedWriteHalf;
+    type Target = EnvelopeWrite<TransportWrite>;
 
     fn deref(&self) -> &Self::Target {
         &self.tx
@@ -64,60 +82,121 @@ impl DerefMut for Generator {
 }
 
 impl Generator {
-    pub async fn generate_task(mut self, transfer_plan: &[TransferItem]) -> Result<Self> {
-        self.generate_task_mut(transfer_plan).await?;
+    pub async fn generate_task(mut self) -> Result<Self> {
+        self.generate_task_mut().await?;
         Ok(self)
     }
-    pub async fn generate_task_mut(&mut self, transfer_plan: &[TransferItem]) -> Result<()> {
+    pub async fn generate_task_mut(&mut self) -> Result<()> {
         info!("generator started.");
 
         info!("generate file phase 1");
-        self.recv_generator(transfer_plan).await?;
+        // With incremental recursion, the sender ends phase 1 once all file lists it has sent are
+        // marked done, and only sends more file lists after finished ones are marked done. Like
+        // rsync, finished file lists of a batch are marked done at once, and only the current
+        // one is held back until more file lists arrive.
+        let mut held_back = false;
+        while let Some(batch) = self.plan_rx.recv().await {
+            if held_back && batch.flists > 0 {
+                self.write_ndx(NDX_DONE).await?;
+                held_back = false;
+            }
+            self.flush().await?;
+            self.recv_generator(&batch.items).await?;
+            if batch.flists > 0 {
+                for _ in 1..batch.flists {
+                    self.write_ndx(NDX_DONE).await?;
+                }
+                self.flush().await?;
+                held_back = true;
+            }
+        }
+        // The NDX_DONE of the current file list also ends phase 1.
+        self.write_ndx(NDX_DONE).await?;
+        drop(self.download_tx.take());
 
-        self.write_i32_le(-1).await?;
+        self.flush().await?;
 
-        // TODO phase 2: re-do failed files
+        // Files failed checksum verification are known after the receiver sees the end of
+        // phase 1, and they are requested again without delta transfer.
         info!("generate file phase 2");
-        self.write_i32_le(-1).await?;
+        while let Some(idx) = self.redo_rx.recv().await {
+            // NOTE: permits are refilled after the corresponding files are received.
+            self.permits.acquire().await?.forget();
+
+            let entry = self.in_flight.get(idx)?;
+            let path = Path::new(OsStr::from_bytes(&entry.name));
+            debug!(?path, idx, "re-requesting full file");
+            self.request_full_file(idx).await?;
+        }
+        self.write_ndx(NDX_DONE).await?;
+
+        if self.protocol.version >= 29 {
+            // Phase 3 is for delayed updates, which we don't use.
+            self.write_ndx(NDX_DONE).await?;
+        }
+        self.flush().await?;
 
         info!("generator finish");
         Ok(())
     }
     async fn recv_generator(&mut self, transfer_plan: &[TransferItem]) -> Result<()> {
-        let file_list = self.file_list.clone();
-        let it = transfer_plan.iter().filter(|item| {
+        let download_tx = self
+            .download_tx
+            .as_ref()
+            .expect("task can be run only once");
+        let mut full_items = vec![];
+        let mut pending_basis = 0usize;
+        for item in transfer_plan {
             #[allow(clippy::cast_sign_loss)]
-            let entry = &file_list[item.idx as usize];
-            item.blake2b.is_none() && !ignore_mode(entry.mode, None::<()>)
-        });
-        let mut async_it = stream::iter(it);
+            let entry = self.in_flight.get(item.idx as u32)?;
+            if ignore_mode(entry.mode, None::<()>) {
+                if item.blake2b.is_some() {
+                    warn!(
+                        name = %entry.name_lossy(),
+                        mode = entry.mode,
+                        "BUG: planner returns an entry with ignored mode"
+                    );
+                }
+                // Never requested, so nothing else would remove it.
+                self.in_flight.remove(entry.idx);
+                continue;
+            }
+            if item.blake2b.is_some() {
+                download_tx
+                    .send(item.clone())
+                    .map_err(|_| LocalError("download tasks exited"))?;
+                pending_basis += 1;
+            } else {
+                full_items.push(entry.idx);
+            }
+        }
+        let mut async_it = stream::iter(full_items);
 
         loop {
             // NOTE: permits are refilled after the corresponding files are received.
-            self.permits.acquire().await?.forget();
+            let permit = self.permits.clone().acquire_owned().await?;
 
             tokio::select! { biased;
-                Some((idx, file)) = self.basis_rx.recv() => {
-                    let entry = &self.file_list[idx as usize];
+                Some((idx, file)) = self.basis_rx.recv(), if pending_basis > 0 => {
+                    permit.forget();
+                    pending_basis -= 1;
+                    let entry = self.in_flight.get(idx)?;
                     let path = Path::new(OsStr::from_bytes(&entry.name));
 
                     debug!(?path, idx, "requesting partial file");
-                    self.write_u32_le(idx).await?;
+                    self.write_request(idx).await?;
                     self.generate_and_send_sums(file).await?;
+                    self.flush().await?;
 
                     self.pb.inc_pending(1);
                 }
-                Some(item) = async_it.next() => {
-                    #[allow(clippy::cast_sign_loss)]
-                    let entry = &self.file_list[item.idx as usize];
-                    let idx = entry.idx;
+                Some(idx) = async_it.next() => {
+                    permit.forget();
+                    let entry = self.in_flight.get(idx)?;
                     let path = Path::new(OsStr::from_bytes(&entry.name));
 
                     debug!(?path, idx, "requesting full file");
-                    self.write_u32_le(idx).await?;
-                    SumHead::default().write_to(&mut **self).await?;
-
-                    self.pb.inc_pending(1);
+                    self.request_full_file(idx).await?;
                 }
                 else => {
                     break;
@@ -128,16 +207,43 @@ impl Generator {
         Ok(())
     }
 
+    async fn write_ndx(&mut self, ndx: i32) -> Result<()> {
+        let mut codec = self.ndx;
+        codec.write(&mut self.tx, ndx).await?;
+        self.ndx = codec;
+        Ok(())
+    }
+
+    /// Request a file from server. Sum head must follow.
+    async fn write_request(&mut self, idx: u32) -> Result<()> {
+        self.write_ndx(i32::try_from(idx).expect("file list too long"))
+            .await?;
+        if self.protocol.version >= 29 {
+            self.write_u16_le(ITEM_TRANSFER).await?;
+        }
+        Ok(())
+    }
+
+    /// Request a file without delta transfer.
+    async fn request_full_file(&mut self, idx: u32) -> Result<()> {
+        self.write_request(idx).await?;
+        SumHead::default().write_to(&mut **self).await?;
+        self.flush().await?;
+
+        self.pb.inc_pending(1);
+        Ok(())
+    }
+
     async fn generate_and_send_sums(&mut self, file: File) -> Result<()> {
         let file_len = file.metadata().await?.size();
         let sum_head = SumHead::sum_sizes_sqroot(file_len);
         sum_head.write_to(&mut **self).await?;
 
         let mut file = file.into_std().await;
-        let seed = self.seed;
+        let checksum = self.protocol.checksum;
 
         let sum_bytes = tokio::task::spawn_blocking(move || {
-            checksum_payload(sum_head, seed, &mut file, file_len)
+            checksum_payload(sum_head, &checksum, &mut file, file_len)
         })
         .await?;
 
@@ -146,3 +252,93 @@ impl Generator {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{duplex, AsyncReadExt};
+    use tokio::sync::{mpsc, Semaphore};
+
+    use crate::plan::PlanBatch;
+    use crate::rsync::checksum::{ChecksumType, StrongChecksum};
+    use crate::rsync::compat::{Protocol, CF_INC_RECURSE};
+    use crate::rsync::envelope::EnvelopeWrite;
+    use crate::rsync::file_list::InFlightFiles;
+    use crate::rsync::generator::Generator;
+    use crate::rsync::progress_display::{ProgressDisplay, ProgressOpts};
+    use crate::rsync::transport::TransportWrite;
+
+    #[tokio::test]
+    async fn must_mark_finished_file_lists_done() {
+        let protocol = Protocol {
+            version: 31,
+            compat_flags: CF_INC_RECURSE,
+            checksum: StrongChecksum {
+                kind: ChecksumType::Md5,
+                seed: 0,
+                proper_seed_order: true,
+            },
+            compression: None,
+            always_checksum: false,
+            hard_links: false,
+        };
+        let (tx, mut rx) = duplex(1024);
+        let tx: TransportWrite = Box::new(tx);
+        let (plan_tx, plan_rx) = mpsc::unbounded_channel();
+        let (download_tx, _download_rx) = flume::unbounded();
+        let (redo_tx, redo_rx) = mpsc::unbounded_channel();
+        let (_basis_tx, basis_rx) = mpsc::channel(1);
+        let generator = Generator::new(
+            EnvelopeWrite::new(tx),
+            InFlightFiles::default(),
+            protocol,
+            Arc::new(Semaphore::new(1)),
+            plan_rx,
+            download_tx,
+            redo_rx,
+            basis_rx,
+            ProgressDisplay::new("test", &ProgressOpts::default()),
+        );
+        let task = tokio::spawn(generator.generate_task());
+
+        // Two of three file lists are finished, and the sender must be told so before it sends
+        // more file lists.
+        let mut buf = [0xff; 2];
+        plan_tx
+            .send(PlanBatch {
+                flists: 3,
+                items: vec![],
+            })
+            .unwrap();
+        rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0]);
+
+        // The held back file list is done once more file lists arrive.
+        let mut buf = [0xff; 1];
+        plan_tx
+            .send(PlanBatch {
+                flists: 1,
+                items: vec![],
+            })
+            .unwrap();
+        rx.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0]);
+
+        // Batches without file lists don't change anything.
+        plan_tx
+            .send(PlanBatch {
+                flists: 0,
+                items: vec![],
+            })
+            .unwrap();
+
+        // End of phase 1 (the current file list), phase 2 and phase 3.
+        drop(plan_tx);
+        drop(redo_tx);
+        drop(task.await.unwrap().unwrap());
+        let mut rest = vec![];
+        rx.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, [0, 0, 0]);
+    }
+}
@@ -2,25 +2,65 @@
 //!
 //! In this stage, the client and server exchange information about the protocol version, server
 //! sends the motd message, and client sends the module name, path name, options, and filter rules.
+//!
+//! If the client sends an empty module name instead, the daemon sends its module list and closes
+//! the connection.
 
 use std::fmt::{Debug, Formatter};
+use std::path::Path;
 
 use base64::engine::general_purpose;
 use base64::Engine;
 use digest::Digest;
-use eyre::{bail, Result};
+use eyre::{bail, ensure, eyre, Result, WrapErr};
 use md4::Md4;
+use md5::Md5;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
 use url::Url;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use crate::rsync::envelope::EnvelopeRead;
-use crate::rsync::filter::Rule;
+use crate::opts::RsyncOpts;
+use crate::rsync::compat::client_info;
+use crate::rsync::envelope::{EnvelopeRead, EnvelopeWrite};
 use crate::rsync::mux_conn::MuxConn;
-use crate::rsync::version::{Version, SUPPORTED_VERSION};
+use crate::rsync::transport::{TransportRead, TransportWrite};
+use crate::rsync::version::{
+    Greeting, Version, MIN_SUPPORTED_VERSION, SUPPORTED_DIGESTS, SUPPORTED_VERSION,
+};
+
+/// Digest used to answer the daemon auth challenge.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum AuthDigest {
+    Md5,
+    Md4,
+    /// MD4 with a zero seed prefixed, used before protocol 30.
+    Md4Old,
+}
+
+impl AuthDigest {
+    /// Pick the digest to use.
+    ///
+    /// If the server lists its supported digests, the first of ours it supports is used.
+    /// Otherwise the default of the protocol version is used.
+    fn negotiate(protocol_version: i32, remote: Option<&[String]>) -> Result<Self> {
+        let Some(remote) = remote else {
+            return Ok(if protocol_version >= 30 {
+                Self::Md5
+            } else {
+                Self::Md4Old
+            });
+        };
+        SUPPORTED_DIGESTS
+            .iter()
+            .find(|name| remote.iter().any(|r| r == **name))
+            .map(|name| match *name {
+                "md5" => Self::Md5,
+                _ => Self::Md4,
+            })
+            .ok_or_else(|| eyre!("no common auth digest with server: {:?}", remote))
+    }
+}
 
 #[derive(Zeroize, ZeroizeOnDrop)]
 pub struct Auth {
@@ -38,91 +78,220 @@ impl Debug for Auth {
 }
 
 impl Auth {
-    pub fn from_url_and_env(url: &Url) -> Self {
+    /// Credentials from the url, the password file, or `RSYNC_PASSWORD`, in that order.
+    pub fn from_url_and_env(url: &Url, password_file: Option<&Path>) -> Result<Self> {
         let username = if url.username().is_empty() {
             "nobody".to_string()
         } else {
             url.username().to_string()
         };
-        let password = url
-            .password()
-            .map(ToString::to_string)
-            .or_else(|| std::env::var("RSYNC_PASSWORD").ok())
-            .unwrap_or_default();
-        Self { username, password }
-    }
-    fn challenge(&self, challenge: &str) -> String {
-        let mut hasher = Md4::default();
-        hasher.update([0; 4]);
-        hasher.update(&self.password);
-        hasher.update(challenge);
-        let hash = hasher.finalize();
+        let password = match (url.password(), password_file) {
+            (Some(password), _) => password.to_string(),
+            (None, Some(path)) => {
+                let content = std::fs::read_to_string(path)
+                    .wrap_err_with(|| format!("failed to read password file {}", path.display()))?;
+                // Only the first line is used, like rsync does.
+                content.lines().next().unwrap_or_default().to_string()
+            }
+            (None, None) => std::env::var("RSYNC_PASSWORD").unwrap_or_default(),
+        };
+        Ok(Self { username, password })
+    }
+    fn challenge(&self, challenge: &str, digest: AuthDigest) -> String {
+        let hash = match digest {
+            AuthDigest::Md5 => {
+                let mut hasher = Md5::default();
+                hasher.update(&self.password);
+                hasher.update(challenge);
+                hasher.finalize().to_vec()
+            }
+            AuthDigest::Md4 | AuthDigest::Md4Old => {
+                let mut hasher = Md4::default();
+                if digest == AuthDigest::Md4Old {
+                    hasher.update([0; 4]);
+                }
+                hasher.update(&self.password);
+                hasher.update(challenge);
+                hasher.finalize().to_vec()
+            }
+        };
         let response = general_purpose::STANDARD_NO_PAD.encode(hash);
         format!("{} {}", self.username, response)
     }
 }
 
+/// A module listed by a daemon.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DaemonModule {
+    pub name: String,
+    pub comment: String,
+}
+
+impl DaemonModule {
+    /// Parse a line of the module list, i.e. the name padded to 15 chars, a tab, and the comment.
+    ///
+    /// The padding is required, so that MOTD lines with tabs aren't taken as modules.
+    fn parse(line: &str) -> Option<Self> {
+        let (padded, comment) = line.split_once('\t')?;
+        let name = padded.trim_end_matches(' ');
+        let valid = !name.is_empty()
+            && !name.contains(char::is_whitespace)
+            && padded.len() == name.len().max(15);
+        valid.then(|| Self {
+            name: name.to_string(),
+            comment: comment.to_string(),
+        })
+    }
+}
+
+/// Arguments to start an rsync server sending `path`.
+///
+/// `protocol_version` is `None` if it's not negotiated yet, i.e. for servers spawned over a remote
+/// shell. Client info is sent anyway then, like rsync does: servers older than protocol 30 take
+/// `-e` as `--rsh`, which they ignore.
+pub fn server_args(
+    protocol_version: Option<i32>,
+    inc_recursive: bool,
+    compress: bool,
+    always_checksum: bool,
+    hard_links: bool,
+    path: &str,
+) -> Vec<String> {
+    // -l preserve_links -t preserve_times -r recursive -p perms -H hard_links -z compress
+    // -c checksum -e client info (protocol 30+)
+    let mut flags = "-ltpr".to_string();
+    if hard_links {
+        flags.push('H');
+    }
+    if compress {
+        flags.push('z');
+    }
+    if always_checksum {
+        flags.push('c');
+    }
+    if protocol_version.map_or(true, |version| version >= 30) {
+        flags.push('e');
+        flags.push_str(&client_info(inc_recursive));
+    }
+    ["--server", "--sender", &flags, ".", path]
+        .map(ToString::to_string)
+        .to_vec()
+}
+
 /// Represents a connection that is in the handshake phase.
 ///
 /// Note that in this stage no multiplexing is done.
-#[derive(Debug)]
 pub struct HandshakeConn {
-    pub tx: OwnedWriteHalf,
-    pub rx: BufReader<OwnedReadHalf>,
+    pub tx: EnvelopeWrite<TransportWrite>,
+    pub rx: BufReader<TransportRead>,
+    /// Negotiated protocol version.
+    pub protocol_version: i32,
+    /// Whether incremental recursion is requested.
+    pub inc_recursive: bool,
+    /// Whether compression is requested.
+    pub compress: bool,
+    /// Whether whole-file checksums are requested in the file list.
+    pub always_checksum: bool,
+    /// Whether hard links are requested in the file list.
+    pub hard_links: bool,
+    /// Protocol version of the remote.
+    pub remote_version: Option<Version>,
+    /// Message of the day sent by the daemon.
+    pub motd: Vec<String>,
+    /// Whether the daemon asked for authentication.
+    pub auth_required: bool,
 }
 
 impl HandshakeConn {
-    pub fn new(stream: TcpStream) -> Self {
-        let (rx, tx) = stream.into_split();
+    pub fn new(rx: TransportRead, tx: TransportWrite) -> Self {
         Self {
-            tx,
+            tx: EnvelopeWrite::new(tx),
             rx: BufReader::with_capacity(256 * 1024, rx),
+            protocol_version: SUPPORTED_VERSION.major,
+            inc_recursive: false,
+            compress: false,
+            always_checksum: false,
+            hard_links: false,
+            remote_version: None,
+            motd: vec![],
+            auth_required: false,
         }
     }
 
+    /// Exchange greetings with a daemon, and negotiate the protocol version.
+    async fn exchange_greeting(&mut self) -> Result<Greeting> {
+        debug!("negotiate protocol version");
+        let greeting = Greeting {
+            version: SUPPORTED_VERSION,
+            auth_digests: Some(SUPPORTED_DIGESTS.iter().map(ToString::to_string).collect()),
+        };
+        greeting.write_to(&mut self.tx).await?;
+
+        let remote = Greeting::read_from(&mut self.rx).await?;
+        let remote_protocol = remote.version;
+        if remote_protocol.major < MIN_SUPPORTED_VERSION {
+            bail!("server protocol version too old: {}", remote_protocol);
+        }
+        self.protocol_version = SUPPORTED_VERSION.negotiate(remote_protocol);
+        self.remote_version = Some(remote_protocol);
+
+        debug!(%remote_protocol, local_protocol = self.protocol_version, "protocol negotiated");
+        Ok(remote)
+    }
+
+    /// Read a line sent by the daemon, without the line ending.
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        let read = (&mut self.rx).take(1024).read_line(&mut line).await?;
+        ensure!(read > 0, "connection closed by server");
+        // Trailing tabs are significant in the module list.
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
     #[instrument(skip(self))]
     pub async fn start_inband_exchange(
         &mut self,
         module: &str,
         path: &str,
         auth: Auth,
+        opts: &RsyncOpts,
     ) -> Result<()> {
-        debug!("negotiate protocol version");
-        SUPPORTED_VERSION.write_to(&mut self.tx).await?;
-
-        let remote_protocol = Version::read_from(&mut self.rx).await?;
-        if remote_protocol.major < 27 {
-            bail!("server protocol version too old: {}", remote_protocol);
-        }
-
-        debug!(%remote_protocol, local_protocol = 27, "protocol negotiated");
+        let remote = self.exchange_greeting().await?;
 
         debug!(module, "send module name");
         self.tx.write_all(format!("{module}\n").as_bytes()).await?;
 
         debug!("reading motd");
         loop {
-            let mut line = String::new();
-            (&mut self.rx).take(1024).read_line(&mut line).await?;
+            let line = self.read_line().await?;
 
             if line.starts_with("@ERROR") {
                 bail!("server error: {}", line);
-            } else if line.starts_with("@RSYNCD: AUTHREQD ") {
-                let challenge = line
-                    .strip_prefix("@RSYNCD: AUTHREQD ")
-                    .expect("challenge")
-                    .trim();
-                let resp = auth.challenge(challenge);
+            } else if let Some(challenge) = line.strip_prefix("@RSYNCD: AUTHREQD ") {
+                self.auth_required = true;
+                let digest =
+                    AuthDigest::negotiate(self.protocol_version, remote.auth_digests.as_deref())?;
+                let resp = auth.challenge(challenge.trim(), digest);
                 self.tx.write_all(format!("{resp}\n").as_bytes()).await?;
             } else if line.starts_with("@RSYNCD: OK") {
                 break;
             } else {
-                println!("{}", line.trim_end());
+                self.motd.push(line);
             }
         }
 
-        // -l preserve_links -t preserve_times -r recursive -p perms
-        let options = ["--server", "--sender", "-ltpr", ".", path];
+        self.set_inc_recursive(opts.inc_recursive);
+        self.compress = opts.compress;
+        self.always_checksum = opts.checksum;
+        self.hard_links = opts.hard_links;
+        let options = server_args(
+            Some(self.protocol_version),
+            self.inc_recursive,
+            self.compress,
+            self.always_checksum,
+            self.hard_links,
+            path,
+        );
         debug!(?options, "send options");
         for opt in options {
             self.tx.write_all(format!("{opt}\n").as_bytes()).await?;
@@ -132,15 +301,153 @@ impl HandshakeConn {
         Ok(())
     }
 
+    /// Request the module list of a daemon.
+    ///
+    /// Lines before the first module are the motd.
+    #[instrument(skip(self))]
+    pub async fn list_modules(&mut self) -> Result<Vec<DaemonModule>> {
+        self.exchange_greeting().await?;
+
+        debug!("request module list");
+        self.tx.write_all(b"\n").await?;
+
+        let mut modules = vec![];
+        loop {
+            let line = self.read_line().await?;
+
+            if line.starts_with("@ERROR") {
+                bail!("server error: {}", line);
+            } else if line.starts_with("@RSYNCD: EXIT") {
+                break;
+            } else if let Some(module) = DaemonModule::parse(&line) {
+                modules.push(module);
+            } else if modules.is_empty() {
+                self.motd.push(line);
+            } else {
+                warn!(line, "unrecognized line in module list");
+            }
+        }
+        Ok(modules)
+    }
+
+    /// Exchange protocol versions with a server spawned over a remote shell.
+    ///
+    /// Server arguments are passed on its command line, so only versions are exchanged here.
+    #[instrument(skip(self))]
+    pub async fn start_remote_shell_exchange(&mut self, opts: &RsyncOpts) -> Result<()> {
+        debug!("negotiate protocol version");
+        self.tx.write_i32_le(SUPPORTED_VERSION.major).await?;
+        self.tx.flush().await?;
+
+        let remote_protocol = self.rx.read_i32_le().await?;
+        if remote_protocol < MIN_SUPPORTED_VERSION {
+            bail!("server protocol version too old: {}", remote_protocol);
+        }
+        self.protocol_version = SUPPORTED_VERSION.major.min(remote_protocol);
+        self.remote_version = Some(Version {
+            major: remote_protocol,
+            minor: None,
+        });
+        debug!(
+            remote_protocol,
+            local_protocol = self.protocol_version,
+            "protocol negotiated"
+        );
+
+        self.set_inc_recursive(opts.inc_recursive);
+        self.compress = opts.compress;
+        self.always_checksum = opts.checksum;
+        self.hard_links = opts.hard_links;
+        Ok(())
+    }
+
+    fn set_inc_recursive(&mut self, requested: bool) {
+        if requested && self.protocol_version < 30 {
+            warn!(
+                protocol = self.protocol_version,
+                "incremental recursion requires protocol 30, disabled"
+            );
+        }
+        self.inc_recursive = requested && self.protocol_version >= 30;
+    }
+
     #[instrument(skip(self))]
-    pub async fn finalize(mut self, rules: &[Rule]) -> Result<MuxConn> {
-        let seed = self.rx.read_i32_le().await?;
-        debug!(seed);
+    pub async fn finalize(mut self, opts: &RsyncOpts) -> Result<MuxConn> {
+        let protocol = self.setup_protocol().await?;
 
-        self.send_filter_rules(rules).await?;
+        if protocol.multiplex_out() {
+            self.tx.start_multiplex();
+        }
+        self.send_filter_rules(&opts.filters).await?;
+        self.tx.flush().await?;
+
+        let rx = EnvelopeRead::new(self.rx).with_bwlimit(opts.bwlimit.clone());
+
+        Ok(MuxConn::new(self.tx, rx, protocol))
+    }
+}
 
-        let rx = EnvelopeRead::new(self.rx);
+#[cfg(test)]
+mod tests {
+    use crate::rsync::handshake::{server_args, DaemonModule, HandshakeConn};
+
+    #[test]
+    fn must_build_server_args() {
+        assert_eq!(
+            server_args(Some(31), true, false, false, true, "debian/"),
+            ["--server", "--sender", "-ltprHe.ifCv", ".", "debian/"]
+        );
+        assert_eq!(
+            server_args(Some(29), false, true, true, false, "debian/"),
+            ["--server", "--sender", "-ltprzc", ".", "debian/"]
+        );
+        // Not negotiated yet over a remote shell.
+        assert_eq!(
+            server_args(None, false, false, false, false, "/srv/mirror/"),
+            ["--server", "--sender", "-ltpre.fCv", ".", "/srv/mirror/"]
+        );
+    }
+
+    #[tokio::test]
+    async fn must_list_modules() {
+        let reply = b"@RSYNCD: 31.0 md5 md4\n\
+            Welcome to the mirror.\n\
+            Contact:\tmirror@example.org\n\
+            \n\
+            debian         \tDebian GNU/Linux\n\
+            ubuntu         \t\n\
+            debian-security\tDebian security updates\n\
+            @RSYNCD: EXIT\n";
+        let mut handshake = HandshakeConn::new(Box::new(&reply[..]), Box::new(tokio::io::sink()));
+        let modules = handshake.list_modules().await.unwrap();
+        assert_eq!(handshake.protocol_version, 31);
+        assert_eq!(
+            handshake.motd,
+            ["Welcome to the mirror.", "Contact:\tmirror@example.org", ""]
+        );
+        assert_eq!(
+            modules,
+            [
+                DaemonModule {
+                    name: "debian".to_string(),
+                    comment: "Debian GNU/Linux".to_string(),
+                },
+                DaemonModule {
+                    name: "ubuntu".to_string(),
+                    comment: String::new(),
+                },
+                DaemonModule {
+                    name: "debian-security".to_string(),
+                    comment: "Debian security updates".to_string(),
+                },
+            ]
+        );
+    }
 
-        Ok(MuxConn::new(self.tx, rx, seed))
+    #[tokio::test]
+    async fn must_fail_on_closed_connection() {
+        let reply = b"@RSYNCD: 31.0\nWelcome to the mirror.\n";
+        let mut handshake = HandshakeConn::new(Box::new(&reply[..]), Box::new(tokio::io::sink()));
+        assert!(handshake.list_modules().await.is_err());
     }
 }
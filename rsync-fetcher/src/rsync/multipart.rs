@@ -0,0 +1,452 @@
+//! Resumable multipart uploads of large objects.
+//!
+//! Upload ids are recorded in Postgres, so that an upload interrupted by a crash or a network
+//! failure is resumed by the next sync uploading the same object. Uploaded parts are only reused if
+//! their etags match the MD5 of the data to upload, since temporary objects are not content
+//! addressed. Uploads never completed are aborted, so that their parts don't accrue storage cost.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use aws_config::environment::EnvironmentVariableCredentialsProvider;
+use aws_sdk_s3::config::{BehaviorVersion, Region};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use chrono::Utc;
+use digest::Digest;
+use eyre::{eyre, Result};
+use md5::Md5;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sqlx::PgPool;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{sleep, sleep_until};
+use tracing::{debug, info, warn};
+
+use rsync_core::s3::S3Opts;
+use rsync_core::utils::ToHex;
+
+use crate::pg::{
+    claim_multipart_upload, delete_multipart_upload, insert_multipart_upload,
+    list_multipart_uploads, multipart_upload_exists,
+};
+use crate::rsync::bwlimit::RateLimiter;
+
+/// Objects larger than this are uploaded in parts.
+pub const MULTIPART_THRESHOLD: u64 = PART_SIZE;
+/// Size of each part, unless the object is too large to fit in `MAX_PARTS` parts.
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+/// Max number of parts of an object allowed by S3.
+const MAX_PARTS: u64 = 10000;
+/// Characters escaped in the copy source of a part, which is the url encoded `bucket/key`.
+const KEY_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+/// Max number of attempts to upload a part.
+const MAX_ATTEMPTS: u32 = 5;
+/// Unrecorded uploads initiated earlier than this many hours ago are considered abandoned.
+///
+/// Recent ones may belong to a running sync of another namespace sharing the same prefix.
+const UNRECORDED_TTL_HOURS: i64 = 24;
+
+/// Size of parts of an object. Must be the same for the same object to resume its upload.
+fn part_size(len: u64) -> u64 {
+    PART_SIZE.max(len.div_ceil(MAX_PARTS))
+}
+
+/// Whether the etag of an uploaded part is the MD5 of `data`. Etags of buckets encrypted with KMS
+/// keys are not, so their parts are uploaded again.
+fn e_tag_matches(e_tag: &str, data: &[u8]) -> bool {
+    e_tag.trim_matches('"') == format!("{:x}", Md5::digest(data).as_hex())
+}
+
+/// Retry an S3 operation with exponential backoff.
+async fn retry<T, F, Fut>(what: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match f().await {
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                warn!(what, attempt, ?e, "S3 operation failed, retrying");
+                sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MultipartUploader {
+    client: Client,
+    bucket: String,
+    namespace: String,
+    pool: Arc<PgPool>,
+    bwlimit: RateLimiter,
+}
+
+/// Build an S3 client talking to the same endpoint as the operator built from `S3Opts`.
+///
+/// Credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, the variables
+/// documented for the operator, instead of the full AWS credential chain. Buckets are addressed
+/// by path, as opendal does unless virtual host style is enabled, which `build_operator` never
+/// does.
+fn build_client(s3: &S3Opts) -> Client {
+    let config = aws_sdk_s3::config::Builder::new()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new(s3.region.clone()))
+        .endpoint_url(&s3.url)
+        .credentials_provider(EnvironmentVariableCredentialsProvider::new())
+        .force_path_style(true)
+        .build();
+    Client::from_conf(config)
+}
+
+impl MultipartUploader {
+    pub fn new(s3: &S3Opts, namespace: &str, pool: Arc<PgPool>, bwlimit: RateLimiter) -> Self {
+        Self {
+            client: build_client(s3),
+            bucket: s3.bucket.clone(),
+            namespace: namespace.to_string(),
+            pool,
+            bwlimit,
+        }
+    }
+
+    /// Upload an object of `len` bytes in parts, resuming a previous upload of the same key if
+    /// there is one.
+    ///
+    /// Returns the number of bytes uploaded, excluding resumed parts.
+    pub async fn upload(
+        &self,
+        key: &str,
+        content_disposition: Option<&str>,
+        len: u64,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<u64> {
+        let part_size = part_size(len);
+        let (upload_id, mut done) = match self.resumable(key).await? {
+            Some(resumable) => resumable,
+            None => (self.create(key, content_disposition).await?, HashMap::new()),
+        };
+
+        #[allow(clippy::cast_possible_truncation)]
+        let mut buf = Vec::with_capacity(part_size as usize);
+        let mut parts = vec![];
+        let mut uploaded = 0;
+        for part_number in 1.. {
+            buf.clear();
+            (&mut reader).take(part_size).read_to_end(&mut buf).await?;
+            if buf.is_empty() && part_number > 1 {
+                break;
+            }
+
+            let e_tag = match done.remove(&part_number) {
+                Some((size, e_tag)) if size == buf.len() as u64 && e_tag_matches(&e_tag, &buf) => {
+                    e_tag
+                }
+                _ => {
+                    if let Some(deadline) = self.bwlimit.charge(buf.len()) {
+                        sleep_until(deadline).await;
+                    }
+                    let (buf, upload_id) = (&buf, &upload_id);
+                    let e_tag = retry("upload_part", || async move {
+                        let output = self
+                            .client
+                            .upload_part()
+                            .bucket(&self.bucket)
+                            .key(key)
+                            .upload_id(upload_id)
+                            .part_number(part_number)
+                            .body(ByteStream::from(buf.clone()))
+                            .send()
+                            .await?;
+                        output
+                            .e_tag()
+                            .map(ToString::to_string)
+                            .ok_or_else(|| eyre!("missing etag of uploaded part"))
+                    })
+                    .await?;
+                    uploaded += buf.len() as u64;
+                    e_tag
+                }
+            };
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .e_tag(e_tag)
+                    .build(),
+            );
+            if (buf.len() as u64) < part_size {
+                break;
+            }
+        }
+
+        self.complete(key, &upload_id, parts).await?;
+        Ok(uploaded)
+    }
+
+    /// Copy an object of `len` bytes on the server side in parts, for objects too large to be
+    /// copied at once.
+    pub async fn copy(
+        &self,
+        src: &str,
+        key: &str,
+        content_disposition: Option<&str>,
+        len: u64,
+    ) -> Result<()> {
+        let part_size = part_size(len);
+        let upload_id = self.create(key, content_disposition).await?;
+        let copy_source =
+            utf8_percent_encode(&format!("{}/{src}", self.bucket), KEY_CHAR).to_string();
+
+        let mut parts = vec![];
+        let (mut start, mut part_number) = (0, 1);
+        while start < len {
+            let end = (start + part_size).min(len) - 1;
+            let (copy_source, upload_id) = (&copy_source, &upload_id);
+            let e_tag = retry("upload_part_copy", || async move {
+                let output = self
+                    .client
+                    .upload_part_copy()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .copy_source(copy_source)
+                    .copy_source_range(format!("bytes={start}-{end}"))
+                    .send()
+                    .await?;
+                output
+                    .copy_part_result()
+                    .and_then(|result| result.e_tag())
+                    .map(ToString::to_string)
+                    .ok_or_else(|| eyre!("missing etag of copied part"))
+            })
+            .await?;
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .e_tag(e_tag)
+                    .build(),
+            );
+            start = end + 1;
+            part_number += 1;
+        }
+
+        self.complete(key, &upload_id, parts).await
+    }
+
+    /// Complete a multipart upload and forget it.
+    async fn complete(&self, key: &str, upload_id: &str, parts: Vec<CompletedPart>) -> Result<()> {
+        let completed = CompletedMultipartUpload::builder()
+            .set_parts(Some(parts))
+            .build();
+        let completed = &completed;
+        retry("complete_multipart_upload", || async move {
+            self.client
+                .complete_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(completed.clone())
+                .send()
+                .await?;
+            Ok(())
+        })
+        .await?;
+        delete_multipart_upload(upload_id, &*self.pool).await?;
+        Ok(())
+    }
+
+    /// Start a new multipart upload and record it.
+    async fn create(&self, key: &str, content_disposition: Option<&str>) -> Result<String> {
+        let output = retry("create_multipart_upload", || async move {
+            Ok(self
+                .client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .set_content_disposition(content_disposition.map(ToString::to_string))
+                .send()
+                .await?)
+        })
+        .await?;
+        let upload_id = output
+            .upload_id()
+            .ok_or_else(|| eyre!("missing upload id"))?;
+        insert_multipart_upload(&self.namespace, key, upload_id, &*self.pool).await?;
+        debug!(key, upload_id, "multipart upload created");
+        Ok(upload_id.to_string())
+    }
+
+    /// Claim a recorded upload of the key and find its uploaded parts, as
+    /// `part number => (size, etag)`.
+    ///
+    /// The upload is recorded again once claimed, so that it's resumed if this sync is interrupted
+    /// too.
+    async fn resumable(&self, key: &str) -> Result<Option<(String, HashMap<i32, (u64, String)>)>> {
+        let Some(upload_id) = claim_multipart_upload(&self.namespace, key, &*self.pool).await?
+        else {
+            return Ok(None);
+        };
+
+        let mut parts = HashMap::new();
+        let mut marker = None;
+        loop {
+            let output = match self
+                .client
+                .list_parts()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .set_part_number_marker(marker)
+                .send()
+                .await
+            {
+                Ok(output) => output,
+                Err(e) => {
+                    // Most likely aborted or expired by lifecycle rules. It's already forgotten.
+                    warn!(key, upload_id, ?e, "failed to list parts, starting over");
+                    return Ok(None);
+                }
+            };
+            for part in output.parts() {
+                if let (Some(number), Some(size), Some(e_tag)) =
+                    (part.part_number(), part.size(), part.e_tag())
+                {
+                    #[allow(clippy::cast_sign_loss)]
+                    parts.insert(number, (size as u64, e_tag.to_string()));
+                }
+            }
+            if output.is_truncated() != Some(true) {
+                break;
+            }
+            marker = output.next_part_number_marker().map(ToString::to_string);
+        }
+        insert_multipart_upload(&self.namespace, key, &upload_id, &*self.pool).await?;
+
+        info!(
+            key,
+            upload_id,
+            parts = parts.len(),
+            "resuming multipart upload"
+        );
+        Ok(Some((upload_id, parts)))
+    }
+
+    async fn abort(&self, key: &str, upload_id: &str) -> Result<()> {
+        if let Err(e) = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            // Unrecorded uploads are aborted later anyway.
+            warn!(key, upload_id, ?e, "failed to abort multipart upload");
+        }
+        delete_multipart_upload(upload_id, &*self.pool).await?;
+        Ok(())
+    }
+
+    /// Abort multipart uploads which will never be completed. Must be called with the namespace
+    /// locked, after the sync succeeded or failed.
+    ///
+    /// These are
+    /// 1. uploads recorded by the namespace, but not resumed by this sync if it succeeded,
+    /// 2. uploads of temporary objects of the namespace not recorded,
+    /// 3. uploads under the prefix not recorded by any namespace, and initiated long ago.
+    ///
+    /// Uploads recorded by a failed sync are kept, so that the next sync can resume them.
+    pub async fn abort_abandoned(
+        &self,
+        prefix: &str,
+        temp_prefix: &str,
+        succeeded: bool,
+    ) -> Result<()> {
+        let mut aborted = 0;
+        if succeeded {
+            for (key, upload_id) in list_multipart_uploads(&self.namespace, &*self.pool).await? {
+                self.abort(&key, &upload_id).await?;
+                aborted += 1;
+            }
+        }
+
+        let expire_before = Utc::now().timestamp() - UNRECORDED_TTL_HOURS * 3600;
+        let (mut key_marker, mut upload_id_marker) = (None, None);
+        loop {
+            let output = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await?;
+            for upload in output.uploads() {
+                let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) else {
+                    continue;
+                };
+                let abandoned = (key.starts_with(temp_prefix)
+                    || upload
+                        .initiated()
+                        .map_or(false, |initiated| initiated.secs() < expire_before))
+                    && !multipart_upload_exists(upload_id, &*self.pool).await?;
+                if abandoned {
+                    self.abort(key, upload_id).await?;
+                    aborted += 1;
+                }
+            }
+            if output.is_truncated() != Some(true) {
+                break;
+            }
+            key_marker = output.next_key_marker().map(ToString::to_string);
+            upload_id_marker = output.next_upload_id_marker().map(ToString::to_string);
+        }
+
+        if aborted > 0 {
+            info!(aborted, "aborted abandoned multipart uploads");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rsync::multipart::{e_tag_matches, part_size, MAX_PARTS, PART_SIZE};
+
+    #[test]
+    fn must_match_e_tag() {
+        // MD5 of "hello".
+        let e_tag = "\"5d41402abc4b2a76b9719d911017c592\"";
+        assert!(e_tag_matches(e_tag, b"hello"));
+        assert!(!e_tag_matches(e_tag, b"world"));
+        // Etags of KMS encrypted objects.
+        assert!(!e_tag_matches("\"0123\"", b"hello"));
+    }
+
+    #[test]
+    fn must_compute_part_size() {
+        assert_eq!(part_size(PART_SIZE), PART_SIZE);
+        assert_eq!(part_size(100 * PART_SIZE), PART_SIZE);
+        assert_eq!(part_size(MAX_PARTS * PART_SIZE), PART_SIZE);
+        // Objects too large for default part size.
+        let len = MAX_PARTS * PART_SIZE + 1;
+        assert!(part_size(len) > PART_SIZE);
+        assert!(len.div_ceil(part_size(len)) <= MAX_PARTS);
+    }
+}
@@ -5,79 +5,153 @@ use eyre::{Context, Result};
 use opendal::Operator;
 use tempfile::TempDir;
 use tokio::io::BufReader;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
 use tokio::sync::{mpsc, Semaphore};
 
 use rsync_core::metadata::Metadata;
 
-use crate::consts::{BASIS_BUFFER_LIMIT, UPLOAD_CONN};
+use crate::opts::Concurrency;
+use crate::rsync::bwlimit::RateLimiter;
+use crate::rsync::compat::Protocol;
 use crate::rsync::downloader::Downloader;
-use crate::rsync::envelope::EnvelopeRead;
-use crate::rsync::file_list::FileEntry;
+use crate::rsync::envelope::{EnvelopeRead, EnvelopeWrite};
+use crate::rsync::file_list::{FileEntry, InFlightFiles};
 use crate::rsync::generator::Generator;
+use crate::rsync::multipart::MultipartUploader;
 use crate::rsync::progress_display::ProgressDisplay;
-use crate::rsync::receiver::Receiver;
-use crate::rsync::uploader::Uploader;
+use crate::rsync::receiver::{Receiver, StreamTarget};
+use crate::rsync::transport::{TransportRead, TransportWrite};
+use crate::rsync::uploader::{KnownBlobs, Uploader};
 use crate::rsync::TaskBuilders;
+use crate::source::mount_entries;
 
 pub struct MuxConn {
-    tx: OwnedWriteHalf,
-    rx: EnvelopeRead<BufReader<OwnedReadHalf>>,
-    seed: i32,
+    tx: EnvelopeWrite<TransportWrite>,
+    rx: EnvelopeRead<BufReader<TransportRead>>,
+    protocol: Protocol,
+    /// Index of the first entry of the next file list.
+    next_ndx_start: u32,
+    /// Path of the namespace the source is mounted at.
+    mount: String,
 }
 
 impl MuxConn {
-    pub fn new(tx: OwnedWriteHalf, rx: EnvelopeRead<BufReader<OwnedReadHalf>>, seed: i32) -> Self {
-        Self { tx, rx, seed }
+    pub const fn new(
+        tx: EnvelopeWrite<TransportWrite>,
+        rx: EnvelopeRead<BufReader<TransportRead>>,
+        protocol: Protocol,
+    ) -> Self {
+        Self {
+            tx,
+            rx,
+            protocol,
+            next_ndx_start: protocol.first_ndx_start(),
+            mount: String::new(),
+        }
+    }
+    /// Mount the source at a path of the namespace. Names of received entries are prefixed with
+    /// it.
+    pub fn set_mount(&mut self, path: &str) {
+        self.mount = path.to_string();
+    }
+    pub const fn protocol(&self) -> Protocol {
+        self.protocol
     }
+    /// Receive the initial file list.
+    ///
+    /// With incremental recursion, file lists of subdirectories are received by the receiver
+    /// during transfer.
     pub async fn recv_file_list(&mut self) -> Result<Vec<FileEntry>> {
-        self.rx.recv_file_list().await
+        let (mut list, used) = self
+            .rx
+            .recv_file_list(&self.protocol, self.next_ndx_start)
+            .await?;
+        self.next_ndx_start += used + 1;
+        mount_entries(&self.mount, &mut list);
+        Ok(list)
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn into_task_builders(
         self,
         s3: Operator,
         s3_prefix: String,
+        temp_prefix: String,
+        multipart: MultipartUploader,
+        known: KnownBlobs,
         pg_tx: mpsc::Sender<(Vec<u8>, Metadata)>,
-        file_list: Arc<Vec<FileEntry>>,
+        in_flight: InFlightFiles,
         temp_dir: &Path,
+        s3_bwlimit: RateLimiter,
+        concurrency: Concurrency,
+        progress: &ProgressDisplay,
     ) -> Result<TaskBuilders> {
         let basis_dir = TempDir::new_in(temp_dir).context("failed to create temp dir")?;
-        let permits = Arc::new(Semaphore::new(BASIS_BUFFER_LIMIT));
-        let (basis_tx, basis_rx) = mpsc::channel(BASIS_BUFFER_LIMIT);
-        let (upload_tx, upload_rx) = flume::bounded(UPLOAD_CONN * 2);
-        let progress = ProgressDisplay::new();
+        let permits = Arc::new(Semaphore::new(concurrency.basis_buffer_limit));
+        let (basis_tx, basis_rx) = mpsc::channel(concurrency.basis_buffer_limit);
+        let (download_tx, download_rx) = flume::unbounded();
+        let (upload_tx, upload_rx) = flume::bounded(concurrency.upload_conn * 2);
+        let (plan_tx, plan_rx) = mpsc::unbounded_channel();
+        let (flist_tx, flist_rx) = mpsc::unbounded_channel();
+        let (redo_tx, redo_rx) = mpsc::unbounded_channel();
         let downloader = Downloader::new(
-            file_list.clone(),
+            in_flight.clone(),
             s3.clone(),
             s3_prefix.clone(),
             basis_dir.path().to_path_buf(),
+            download_rx,
             basis_tx,
+            s3_bwlimit.clone(),
+            concurrency.download_conn,
             progress.clone(),
         );
         let generator = Generator::new(
             self.tx,
-            file_list.clone(),
-            self.seed,
+            in_flight.clone(),
+            self.protocol,
             permits.clone(),
+            plan_rx,
+            download_tx,
+            redo_rx,
             basis_rx,
             progress.clone(),
         );
         let receiver = Receiver::new(
             self.rx,
             upload_tx,
-            file_list.clone(),
-            self.seed,
+            in_flight.clone(),
+            self.protocol,
+            self.protocol
+                .inc_recurse()
+                .then_some((flist_tx, self.next_ndx_start)),
+            redo_tx,
+            self.mount,
             basis_dir,
+            StreamTarget {
+                s3: s3.clone(),
+                temp_prefix,
+            },
             permits,
             progress.clone(),
         );
-        let uploader = Uploader::new(upload_rx, file_list, s3, s3_prefix, pg_tx, progress.clone());
+        let uploader = Uploader::new(
+            upload_rx,
+            in_flight,
+            s3,
+            s3_prefix,
+            pg_tx,
+            s3_bwlimit,
+            multipart,
+            known,
+            concurrency.upload_conn,
+            progress.clone(),
+        );
         Ok(TaskBuilders {
             downloader,
             generator,
             receiver,
             uploader,
-            progress,
+            plan_tx,
+            flist_rx,
         })
     }
 }
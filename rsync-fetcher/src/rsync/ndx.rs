@@ -0,0 +1,168 @@
+//! File index encoding.
+//!
+//! Before protocol 30 file indices are sent as plain ints. Since protocol 30 they are sent as the
+//! difference to the previous index of the same sign, which usually fits into a single byte.
+
+use eyre::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Marks the end of a phase, and the end of the whole transfer.
+pub const NDX_DONE: i32 = -1;
+/// All file lists have been sent. Incremental recursion only.
+pub const NDX_FLIST_EOF: i32 = -2;
+/// A file list of directory `NDX_FLIST_OFFSET - ndx` follows. Incremental recursion only.
+pub const NDX_FLIST_OFFSET: i32 = -101;
+
+/// The file should be transferred.
+pub const ITEM_TRANSFER: u16 = 1 << 15;
+/// A basis file type byte follows the item flags.
+pub const ITEM_BASIS_TYPE_FOLLOWS: u16 = 1 << 11;
+/// An alternative basis name follows the item flags.
+pub const ITEM_XNAME_FOLLOWS: u16 = 1 << 12;
+
+/// Stateful codec of file indices. Each direction of a connection needs its own codec.
+#[derive(Debug, Copy, Clone)]
+pub struct NdxCodec {
+    protocol_version: i32,
+    prev_positive: i32,
+    prev_negative: i32,
+}
+
+impl NdxCodec {
+    pub const fn new(protocol_version: i32) -> Self {
+        Self {
+            protocol_version,
+            prev_positive: -1,
+            prev_negative: 1,
+        }
+    }
+
+    pub async fn read(&mut self, mut rx: impl AsyncRead + Unpin) -> Result<i32> {
+        if self.protocol_version < 30 {
+            return Ok(rx.read_i32_le().await?);
+        }
+
+        let mut b = rx.read_u8().await?;
+        let negative = if b == 0xFF {
+            b = rx.read_u8().await?;
+            true
+        } else if b == 0 {
+            return Ok(NDX_DONE);
+        } else {
+            false
+        };
+        let prev = if negative {
+            self.prev_negative
+        } else {
+            self.prev_positive
+        };
+
+        let num = if b == 0xFE {
+            let mut buf = [0u8; 4];
+            rx.read_exact(&mut buf[..2]).await?;
+            if buf[0] & 0x80 == 0 {
+                (i32::from(buf[0]) << 8) + i32::from(buf[1]) + prev
+            } else {
+                // Absolute value, most significant byte first, the rest in little endian.
+                let high = buf[0] & !0x80;
+                buf[0] = buf[1];
+                rx.read_exact(&mut buf[1..3]).await?;
+                buf[3] = high;
+                i32::from_le_bytes(buf)
+            }
+        } else {
+            i32::from(b) + prev
+        };
+
+        if negative {
+            self.prev_negative = num;
+            Ok(-num)
+        } else {
+            self.prev_positive = num;
+            Ok(num)
+        }
+    }
+
+    pub async fn write(&mut self, mut tx: impl AsyncWrite + Unpin, ndx: i32) -> Result<()> {
+        if self.protocol_version < 30 {
+            tx.write_i32_le(ndx).await?;
+            return Ok(());
+        }
+
+        let mut buf = Vec::with_capacity(6);
+        let (ndx, diff) = if ndx >= 0 {
+            let diff = ndx - self.prev_positive;
+            self.prev_positive = ndx;
+            (ndx, diff)
+        } else if ndx == NDX_DONE {
+            tx.write_u8(0).await?;
+            return Ok(());
+        } else {
+            buf.push(0xFF);
+            let ndx = -ndx;
+            let diff = ndx - self.prev_negative;
+            self.prev_negative = ndx;
+            (ndx, diff)
+        };
+
+        let bytes = ndx.to_le_bytes();
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // range checked
+        if diff > 0 && diff < 0xFE {
+            buf.push(diff as u8);
+        } else if !(0..=0x7FFF).contains(&diff) {
+            buf.extend_from_slice(&[0xFE, bytes[3] | 0x80, bytes[0], bytes[1], bytes[2]]);
+        } else {
+            buf.extend_from_slice(&[0xFE, (diff >> 8) as u8, diff as u8]);
+        }
+        tx.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rsync::ndx::{NdxCodec, NDX_DONE, NDX_FLIST_EOF, NDX_FLIST_OFFSET};
+
+    #[tokio::test]
+    async fn must_ndx_roundtrip() {
+        let ndxs = [
+            0,
+            1,
+            2,
+            300,
+            299,
+            100_000,
+            7,
+            NDX_DONE,
+            NDX_FLIST_OFFSET,
+            NDX_FLIST_OFFSET - 1,
+            NDX_FLIST_EOF,
+            5,
+            i32::MAX,
+        ];
+        for version in [29, 30, 31] {
+            let mut buf = vec![];
+            let mut codec = NdxCodec::new(version);
+            for ndx in ndxs {
+                codec.write(&mut buf, ndx).await.unwrap();
+            }
+
+            let mut rx = &buf[..];
+            let mut codec = NdxCodec::new(version);
+            for ndx in ndxs {
+                assert_eq!(codec.read(&mut rx).await.unwrap(), ndx);
+            }
+            assert!(rx.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn must_write_compact_ndx() {
+        let mut buf = vec![];
+        let mut codec = NdxCodec::new(31);
+        codec.write(&mut buf, 0).await.unwrap();
+        codec.write(&mut buf, 1).await.unwrap();
+        codec.write(&mut buf, NDX_DONE).await.unwrap();
+        assert_eq!(buf, [1, 1, 0]);
+    }
+}
@@ -1,12 +1,58 @@
 use std::fmt::{Display, Formatter};
 use std::ops::Deref;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+use clap::ValueEnum;
 use eyre::Result;
 use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
 use tokio::sync::mpsc;
+use tracing::warn;
+
+/// How to report progress.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, ValueEnum)]
+pub enum ProgressFormat {
+    /// Progress bar on the terminal.
+    #[default]
+    Bar,
+    /// Periodic JSON lines.
+    Json,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProgressOpts {
+    pub format: ProgressFormat,
+    /// Unix socket to write JSON lines to, instead of stderr.
+    pub socket: Option<PathBuf>,
+}
+
+/// Phase of a sync, as reported in JSON progress.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Phase {
+    /// Generating transfer plan. Nothing is transferred yet.
+    Plan,
+    /// Transferring files. With incremental recursion, planning continues in this phase.
+    Transfer,
+    /// All files are transferred, and the revision is being committed.
+    Commit,
+}
+
+impl Phase {
+    const fn from_u8(v: u8) -> Self {
+        match v {
+            0 => Self::Plan,
+            1 => Self::Transfer,
+            _ => Self::Commit,
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 struct Ctx {
@@ -14,6 +60,26 @@ struct Ctx {
     basis_buf: AtomicUsize,
     pending: AtomicUsize,
     uploading: AtomicUsize,
+    phase: AtomicU8,
+    files_total: AtomicU64,
+    files_done: AtomicU64,
+    bytes_uploaded: AtomicU64,
+}
+
+/// A line of JSON progress.
+#[derive(Debug, Serialize)]
+struct ProgressLine<'a> {
+    namespace: &'a str,
+    phase: Phase,
+    files_done: u64,
+    files_total: u64,
+    /// File data received from the rsync server, including data reconstructed from basis files.
+    bytes_downloaded: u64,
+    bytes_total: Option<u64>,
+    bytes_uploaded: u64,
+    uploading: usize,
+    elapsed_secs: u64,
+    eta_secs: u64,
 }
 
 impl Display for Ctx {
@@ -50,6 +116,23 @@ impl Deref for ProgressDisplay {
     }
 }
 
+impl ProgressLine<'_> {
+    fn new<'a>(namespace: &'a str, pb: &ProgressBar, ctx: &Ctx) -> ProgressLine<'a> {
+        ProgressLine {
+            namespace,
+            phase: Phase::from_u8(ctx.phase.load(Ordering::Relaxed)),
+            files_done: ctx.files_done.load(Ordering::Relaxed),
+            files_total: ctx.files_total.load(Ordering::Relaxed),
+            bytes_downloaded: pb.position(),
+            bytes_total: pb.length(),
+            bytes_uploaded: ctx.bytes_uploaded.load(Ordering::Relaxed),
+            uploading: ctx.uploading.load(Ordering::Relaxed),
+            elapsed_secs: pb.elapsed().as_secs(),
+            eta_secs: pb.eta().as_secs(),
+        }
+    }
+}
+
 async fn progress_task(pb: ProgressBar, ctx: Arc<Ctx>, mut stop_rx: mpsc::Receiver<()>) {
     let mut interval = tokio::time::interval(Duration::from_millis(500));
     loop {
@@ -64,9 +147,74 @@ async fn progress_task(pb: ProgressBar, ctx: Arc<Ctx>, mut stop_rx: mpsc::Receiv
     pb.finish_with_message("done");
 }
 
+async fn json_progress_task(
+    namespace: String,
+    socket: Option<PathBuf>,
+    pb: ProgressBar,
+    ctx: Arc<Ctx>,
+    mut stop_rx: mpsc::Receiver<()>,
+) {
+    let mut stream = match socket {
+        Some(path) => match UnixStream::connect(&path).await {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                warn!(
+                    ?path,
+                    ?e,
+                    "failed to connect to progress socket, progress not reported"
+                );
+                stop_rx.recv().await;
+                return;
+            }
+        },
+        None => None,
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let finalized = loop {
+        tokio::select! {
+            stop = stop_rx.recv() => break stop.is_some(),
+            _ = interval.tick() => {
+                emit_json(&mut stream, &ProgressLine::new(&namespace, &pb, &ctx)).await;
+            }
+        }
+    };
+    // Otherwise the sync failed, and there's nothing more to report.
+    if finalized {
+        ctx.phase.store(Phase::Commit as u8, Ordering::Relaxed);
+        emit_json(&mut stream, &ProgressLine::new(&namespace, &pb, &ctx)).await;
+    }
+}
+
+async fn emit_json(stream: &mut Option<UnixStream>, line: &ProgressLine<'_>) {
+    let mut line = serde_json::to_string(line).expect("serializable");
+    line.push('\n');
+    match stream {
+        Some(stream) => {
+            if let Err(e) = stream.write_all(line.as_bytes()).await {
+                warn!(?e, "failed to write to progress socket");
+            }
+        }
+        None => eprint!("{line}"),
+    }
+}
+
 impl ProgressDisplay {
-    pub fn new() -> Self {
+    pub fn new(namespace: &str, opts: &ProgressOpts) -> Self {
         let ctx = Arc::new(Ctx::default());
+        let (stop_tx, stop_rx) = mpsc::channel(1);
+        if opts.format == ProgressFormat::Json {
+            let pb = ProgressBar::hidden();
+            pb.set_length(0);
+            tokio::spawn(json_progress_task(
+                namespace.to_string(),
+                opts.socket.clone(),
+                pb.clone(),
+                ctx.clone(),
+                stop_rx,
+            ));
+            return Self { ctx, pb, stop_tx };
+        }
+
         let pb = ProgressBar::new(0);
         pb.set_style(
             ProgressStyle::with_template(
@@ -77,7 +225,6 @@ impl ProgressDisplay {
         );
         pb.enable_steady_tick(Duration::from_secs(u64::MAX)); // disable ticking
         pb.set_message(format!("{ctx}"));
-        let (stop_tx, stop_rx) = mpsc::channel(1);
         tokio::spawn(progress_task(pb.clone(), ctx.clone(), stop_rx));
         Self { ctx, pb, stop_tx }
     }
@@ -105,6 +252,20 @@ impl ProgressDisplay {
     pub fn dec_uploading(&self, i: usize) {
         self.ctx.uploading.fetch_sub(i, Ordering::SeqCst);
     }
+    pub fn set_phase(&self, phase: Phase) {
+        self.ctx.phase.store(phase as u8, Ordering::SeqCst);
+    }
+    /// Add planned files and their total size.
+    pub fn inc_planned(&self, files: u64, bytes: u64) {
+        self.ctx.files_total.fetch_add(files, Ordering::SeqCst);
+        self.pb.inc_length(bytes);
+    }
+    pub fn inc_files_done(&self, i: u64) {
+        self.ctx.files_done.fetch_add(i, Ordering::SeqCst);
+    }
+    pub fn inc_uploaded(&self, bytes: u64) {
+        self.ctx.bytes_uploaded.fetch_add(bytes, Ordering::SeqCst);
+    }
     pub async fn finalize(&self) -> Result<()> {
         self.stop_tx.send(()).await?;
         Ok(())
@@ -4,56 +4,108 @@ use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use blake2::Blake2b;
 use digest::consts::U20;
 use digest::Digest;
-use eyre::{ensure, Result};
-use md4::Md4;
+use eyre::{bail, ensure, Result, WrapErr};
+use opendal::Operator;
 use tempfile::{tempfile_in, TempDir, TempPath};
 use tokio::fs;
 use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::sync::{mpsc, Semaphore};
-use tracing::{debug, info, instrument};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tracing::{debug, error, info, instrument, warn};
 
 use rsync_core::utils::ToHex;
 
+use crate::consts::{IN_MEMORY_LIMIT, STREAM_CHUNK_LIMIT};
 use crate::rsync::checksum::SumHead;
-use crate::rsync::envelope::EnvelopeRead;
-use crate::rsync::file_list::FileEntry;
+use crate::rsync::compat::Protocol;
+use crate::rsync::compress::{
+    Inflater, DEFLATED_DATA, END_FLAG, TOKENRUN_LONG, TOKENRUN_REL, TOKEN_REL,
+};
+use crate::rsync::envelope::{EnvelopeRead, RsyncReadExt};
+use crate::rsync::file_list::{FileEntry, InFlightFiles};
+use crate::rsync::ndx::{
+    NdxCodec, ITEM_BASIS_TYPE_FOLLOWS, ITEM_TRANSFER, ITEM_XNAME_FOLLOWS, NDX_DONE, NDX_FLIST_EOF,
+    NDX_FLIST_OFFSET,
+};
 use crate::rsync::progress_display::ProgressDisplay;
-use crate::rsync::uploader::UploadTask;
+use crate::rsync::transport::TransportRead;
+use crate::rsync::uploader::{entry_filename, UploadSource, UploadTask};
+use crate::rsync::LocalError;
+use crate::source::mount_entries;
 use crate::utils::hash;
 
 pub struct Receiver {
-    rx: EnvelopeRead<BufReader<OwnedReadHalf>>,
+    rx: EnvelopeRead<BufReader<TransportRead>>,
     upload_tx: Option<flume::Sender<UploadTask>>,
-    file_list: Arc<Vec<FileEntry>>,
-    seed: i32,
+    in_flight: InFlightFiles,
+    protocol: Protocol,
+    ndx: NdxCodec,
+    /// Channel of file lists received during transfer. Incremental recursion only.
+    flist_tx: Option<mpsc::UnboundedSender<Vec<FileEntry>>>,
+    /// Index of the first entry of the next file list.
+    next_ndx_start: u32,
+    /// Number of file lists not yet marked done by the sender.
+    live_flists: usize,
+    /// Files failed checksum verification in phase 1, to be requested again by the generator.
+    redo_tx: Option<mpsc::UnboundedSender<u32>>,
+    /// Number of files failed checksum verification again after redo, left out of the revision.
+    failed: u64,
+    /// Path of the namespace the source is mounted at, prefixed to names of received entries.
+    mount: String,
+    /// Last block index received in the compressed token stream.
+    last_token: i32,
+    /// Remaining blocks of a run in the compressed token stream.
+    token_run: u16,
     basis_dir: TempDir,
+    stream: StreamTarget,
     permits: Arc<Semaphore>,
     pb: ProgressDisplay,
 }
 
+/// Where to stream files transferred in full.
+pub struct StreamTarget {
+    pub s3: Operator,
+    /// Prefix of temporary objects, see `uploader::temp_prefix`.
+    pub temp_prefix: String,
+}
+
 impl Receiver {
     // We are fine with this because it's a private constructor.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        rx: EnvelopeRead<BufReader<OwnedReadHalf>>,
+        rx: EnvelopeRead<BufReader<TransportRead>>,
         upload_tx: flume::Sender<UploadTask>,
-        file_list: Arc<Vec<FileEntry>>,
-        seed: i32,
+        in_flight: InFlightFiles,
+        protocol: Protocol,
+        inc_recurse: Option<(mpsc::UnboundedSender<Vec<FileEntry>>, u32)>,
+        redo_tx: mpsc::UnboundedSender<u32>,
+        mount: String,
         basis_dir: TempDir,
+        stream: StreamTarget,
         permits: Arc<Semaphore>,
         pb: ProgressDisplay,
     ) -> Self {
+        let (flist_tx, next_ndx_start) = inc_recurse.unzip();
         Self {
             rx,
             upload_tx: Some(upload_tx),
-            file_list,
-            seed,
+            in_flight,
+            protocol,
+            ndx: NdxCodec::new(protocol.version),
+            flist_tx,
+            next_ndx_start: next_ndx_start.unwrap_or_default(),
+            live_flists: 1,
+            redo_tx: Some(redo_tx),
+            failed: 0,
+            mount,
+            last_token: 0,
+            token_run: 0,
             basis_dir,
+            stream,
             permits,
             pb,
         }
@@ -61,7 +113,7 @@ impl Receiver {
 }
 
 impl Deref for Receiver {
-    type Target = EnvelopeRead<BufReader<OwnedReadHalf>>;
+    type Target = EnvelopeRead<BufReader<TransportRead>>;
 
     fn deref(&self) -> &Self::Target {
         &self.rx
@@ -76,19 +128,75 @@ impl DerefMut for Receiver {
 
 enum FileToken {
     Data(Vec<u8>),
+    Deflated(Vec<u8>),
     Copied(u32),
     Done,
 }
 
 enum IOChunk {
     Data(Vec<u8>),
+    Deflated(Vec<u8>),
     Copied { offset: u64, data_len: i32 },
 }
 
-#[derive(Debug)]
+/// Where received file data is written.
+enum Target {
+    File(File),
+    Memory(Vec<u8>),
+    /// Handed to an upload task in chunks, which uploads it to a temporary object.
+    Stream {
+        key: String,
+        tx: mpsc::Sender<Vec<u8>>,
+        uploaded: oneshot::Receiver<()>,
+    },
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Memory(data) => data.write(buf),
+            Self::Stream { tx, .. } => {
+                tx.blocking_send(buf.to_vec()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        LocalError("upload task of stream exited"),
+                    )
+                })?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Memory(data) => data.flush(),
+            Self::Stream { .. } => Ok(()),
+        }
+    }
+}
+
+impl Target {
+    /// Finish writing, and hand the data over to the uploader. Blocking.
+    fn finish(self) -> io::Result<UploadSource> {
+        Ok(match self {
+            Self::File(mut file) => {
+                file.seek(SeekFrom::Start(0))?;
+                UploadSource::File(fs::File::from_std(file))
+            }
+            Self::Memory(data) => UploadSource::Memory(data),
+            // Dropping the sender marks the end of the stream.
+            Self::Stream { key, uploaded, .. } => UploadSource::Temp { key, uploaded },
+        })
+    }
+}
+
 struct RecvResult {
-    target_file: File,
+    source: UploadSource,
     blake2b_hash: [u8; 20],
+    /// Whether the whole-file checksum matches the one sent by the server.
+    checksum_ok: bool,
 }
 
 struct BasisFile {
@@ -118,6 +226,10 @@ impl Drop for BasisFile {
 }
 
 impl Receiver {
+    /// Number of files left out of the revision because they failed checksum verification twice.
+    pub const fn failed(&self) -> u64 {
+        self.failed
+    }
     pub async fn recv_task(mut self) -> Result<Self> {
         self.recv_task_mut().await?;
         Ok(self)
@@ -126,31 +238,112 @@ impl Receiver {
         info!("receiver started.");
         let mut phase = 0;
         loop {
-            let idx = self.read_i32_le().await?;
+            let idx = self.read_ndx().await?;
 
-            if idx == -1 {
-                if phase == 0 {
-                    phase += 1;
-                    info!("recv file phase {}", phase);
+            if idx == NDX_DONE {
+                // With incremental recursion, each file list is marked done separately, and the
+                // last one also ends the phase.
+                if self.live_flists > 1 {
+                    self.live_flists -= 1;
                     continue;
                 }
-                break;
+                self.live_flists = 0;
+                phase += 1;
+                // All files failed in phase 1 are known now.
+                self.redo_tx.take();
+                if phase > self.protocol.max_phase() {
+                    break;
+                }
+                info!("recv file phase {}", phase);
+                continue;
+            }
+
+            if idx == NDX_FLIST_EOF {
+                ensure!(
+                    self.flist_tx.take().is_some(),
+                    "unexpected end of file lists"
+                );
+                debug!("all file lists received");
+                continue;
+            }
+
+            if idx <= NDX_FLIST_OFFSET {
+                self.recv_sub_file_list(NDX_FLIST_OFFSET - idx).await?;
+                continue;
+            }
+
+            ensure!(idx >= 0, "unexpected ndx {}", idx);
+
+            if self.protocol.version >= 29 {
+                self.read_item_attrs(idx).await?;
             }
 
             // Intended sign loss.
             #[allow(clippy::cast_sign_loss)]
-            let idx = idx as usize;
+            let idx = idx as u32;
             self.recv_file(idx).await?;
         }
 
         info!("recv finish");
+        self.flist_tx.take();
         self.upload_tx.take().unwrap();
         Ok(())
     }
 
+    /// Receive the file list of a subdirectory, and pass it to the planner.
+    async fn recv_sub_file_list(&mut self, dir_ndx: i32) -> Result<()> {
+        let protocol = self.protocol;
+        let ndx_start = self.next_ndx_start;
+        let (mut list, used) = self.rx.recv_sub_file_list(&protocol, ndx_start).await?;
+        mount_entries(&self.mount, &mut list);
+        debug!(
+            dir_ndx,
+            ndx_start,
+            len = list.len(),
+            "received sub file list"
+        );
+
+        self.next_ndx_start += used + 1;
+        self.live_flists += 1;
+        let Some(flist_tx) = &self.flist_tx else {
+            bail!("unexpected file list of dir {}", dir_ndx);
+        };
+        flist_tx
+            .send(list)
+            .map_err(|_| LocalError("planner exited"))?;
+        Ok(())
+    }
+
+    async fn read_ndx(&mut self) -> Result<i32> {
+        let mut codec = self.ndx;
+        let ndx = codec.read(&mut self.rx).await?;
+        self.ndx = codec;
+        Ok(ndx)
+    }
+
+    /// Read item flags and their trailing fields following a file index.
+    async fn read_item_attrs(&mut self, idx: i32) -> Result<()> {
+        let iflags = self.read_u16_le().await?;
+        if iflags & ITEM_BASIS_TYPE_FOLLOWS != 0 {
+            self.read_u8().await?;
+        }
+        if iflags & ITEM_XNAME_FOLLOWS != 0 {
+            let xname = self.read_vstring().await?;
+            warn!(idx, xname=%String::from_utf8_lossy(&xname), "unexpected alternative basis name");
+        }
+        if iflags & ITEM_TRANSFER == 0 {
+            bail!(
+                "server sent non-transfer item {} (flags {:#x})",
+                idx,
+                iflags
+            );
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
-    async fn recv_file(&mut self, idx: usize) -> Result<()> {
-        let entry = &self.file_list[idx];
+    async fn recv_file(&mut self, idx: u32) -> Result<()> {
+        let entry = self.in_flight.get(idx)?;
         debug!(file=%entry.name_lossy(), "receive file");
 
         // Get basis file if exists. It should be created by generator if delta transfer is
@@ -158,29 +351,102 @@ impl Receiver {
         let basis_file = self.try_get_basis_file(&entry.name)?;
 
         // Receive file data.
+        let target = self.open_target(&entry, basis_file.is_some()).await?;
         let RecvResult {
-            target_file,
+            source,
             blake2b_hash,
-        } = self.recv_data(basis_file).await?;
+            checksum_ok,
+        } = self.recv_data(basis_file, target).await?;
 
         // Release permit.
         self.permits.add_permits(1);
         self.pb.dec_pending(1);
 
+        if !checksum_ok {
+            // The file might have changed during transfer, or the basis file is corrupted.
+            // Discard it and let the generator request it again without delta transfer.
+            if let UploadSource::Temp { key, uploaded } = source {
+                if uploaded.await.is_ok() {
+                    self.stream.s3.delete(&key).await?;
+                }
+            }
+            let Some(redo_tx) = &self.redo_tx else {
+                // Like rsync, report the file and go on with the rest of the transfer.
+                error!(
+                    file=%entry.name_lossy(),
+                    "checksum mismatch after redo, file left out of the revision"
+                );
+                self.in_flight.remove(idx);
+                self.failed += 1;
+                return Ok(());
+            };
+            warn!(file=%entry.name_lossy(), "checksum mismatch, will redo");
+            self.pb.inc_length(entry.len);
+            redo_tx.send(idx)?;
+            return Ok(());
+        }
+
         self.upload_tx
             .as_ref()
             .expect("task can be run only once")
-            .send_async(UploadTask {
+            .send_async(UploadTask::Blob {
                 idx,
                 blake2b_hash,
-                file: fs::File::from_std(target_file),
+                source,
             })
-            .await?;
+            .await
+            .map_err(|_| LocalError("upload tasks exited"))?;
         self.pb.inc_uploading(1);
 
         Ok(())
     }
 
+    /// Open where to write the received file.
+    ///
+    /// Files transferred in full are received in memory if small, or streamed to a temporary
+    /// object otherwise, so that they don't fill the temp dir. Streams are uploaded by upload
+    /// tasks, so that slow uploads only stall the receiver when the chunk buffer is full. Delta
+    /// transfers are received in temp files.
+    async fn open_target(&self, entry: &FileEntry, has_basis: bool) -> Result<Target> {
+        if has_basis {
+            let file =
+                tempfile_in(&self.basis_dir).wrap_err(LocalError("failed to create temp file"))?;
+            return Ok(Target::File(file));
+        }
+        if entry.len <= IN_MEMORY_LIMIT {
+            #[allow(clippy::cast_possible_truncation)]
+            return Ok(Target::Memory(Vec::with_capacity(entry.len as usize)));
+        }
+
+        // Named by the file, so that an interrupted upload of the same file is resumed.
+        let mtime = entry
+            .modify_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let key = format!(
+            "{}{:x}-{}-{mtime}",
+            self.stream.temp_prefix,
+            hash(&entry.name).as_hex(),
+            entry.len
+        );
+        let (tx, chunks) = mpsc::channel(STREAM_CHUNK_LIMIT);
+        let (uploaded_tx, uploaded) = oneshot::channel();
+        self.upload_tx
+            .as_ref()
+            .expect("task can be run only once")
+            .send_async(UploadTask::Stream {
+                key: key.clone(),
+                filename: entry_filename(&entry.name).map(<[u8]>::to_vec),
+                len: entry.len,
+                chunks,
+                uploaded: uploaded_tx,
+            })
+            .await
+            .map_err(|_| LocalError("upload tasks exited"))?;
+        Ok(Target::Stream { key, tx, uploaded })
+    }
+
     fn try_get_basis_file(&self, path: &[u8]) -> Result<Option<BasisFile>> {
         let basis_path = self
             .basis_dir
@@ -203,7 +469,11 @@ impl Receiver {
             })?)
     }
 
-    async fn recv_data(&mut self, mut local_basis: Option<BasisFile>) -> Result<RecvResult> {
+    async fn recv_data(
+        &mut self,
+        mut local_basis: Option<BasisFile>,
+        mut target: Target,
+    ) -> Result<RecvResult> {
         let SumHead {
             checksum_count,
             block_len,
@@ -211,27 +481,46 @@ impl Receiver {
             remainder_len,
         } = SumHead::read_from(&mut **self).await?;
 
-        let mut target_file = tempfile_in(&self.basis_dir)?;
-
         // Hasher for final file consistency check.
-        let mut md4_hasher = Md4::default();
-        md4_hasher.update(self.seed.to_le_bytes());
+        let mut file_hasher = self.protocol.checksum.file_hasher();
         // Hasher for content addressing. Hash function is blake2b-160.
         let mut blake2b_hasher = Blake2b::<U20>::default();
 
+        let mut inflater = self
+            .protocol
+            .compression
+            .map(|kind| Inflater::new(kind, self.protocol.version));
+
         let (tx, mut rx) = mpsc::channel(1024);
 
         #[allow(clippy::read_zero_byte_vec)] // false positive
         let io_task = tokio::task::spawn_blocking(move || {
             let mut buf = vec![];
+            let mut literal = 0u64;
+            let mut write = |data: &[u8]| {
+                file_hasher.update(data);
+                blake2b_hasher.update(data);
+                target.write_all(data)
+            };
             while let Some(token) = rx.blocking_recv() {
                 match token {
                     IOChunk::Data(data) => {
-                        md4_hasher.update(&data);
-                        blake2b_hasher.update(&data);
-                        target_file.write_all(&data)?;
+                        literal += data.len() as u64;
+                        write(&data)?;
+                    }
+                    IOChunk::Deflated(data) => {
+                        let inflater = inflater.as_mut().expect("compressed");
+                        let data = inflater.inflate(&data)?;
+                        literal += data.len() as u64;
+                        write(&data)?;
                     }
                     IOChunk::Copied { offset, data_len } => {
+                        if let Some(inflater) = &mut inflater {
+                            let data = inflater.finish_run()?;
+                            literal += data.len() as u64;
+                            write(&data)?;
+                        }
+
                         #[allow(clippy::cast_sign_loss)] // data_len is always positive.
                         if buf.len() != data_len as usize {
                             buf.resize(data_len as usize, 0);
@@ -241,18 +530,24 @@ impl Receiver {
                         local_basis.seek(SeekFrom::Start(offset))?;
                         local_basis.read_exact(&mut buf)?;
 
-                        md4_hasher.update(&buf);
-                        blake2b_hasher.update(&buf);
-                        target_file.write_all(&buf)?;
+                        write(&buf)?;
+                        if let Some(inflater) = &mut inflater {
+                            inflater.see(&buf)?;
+                        }
                     }
                 }
             }
+            if let Some(inflater) = &mut inflater {
+                let data = inflater.finish_run()?;
+                literal += data.len() as u64;
+                write(&data)?;
+            }
 
-            let md4: [u8; 16] = md4_hasher.finalize().into();
+            let checksum = file_hasher.finalize();
             let blake2b: [u8; 20] = blake2b_hasher.finalize().into();
 
-            target_file.seek(SeekFrom::Start(0))?;
-            Ok::<_, io::Error>((md4, blake2b, target_file))
+            let source = target.finish()?;
+            Ok::<_, io::Error>((checksum, blake2b, source, literal))
         });
 
         let (mut transferred, mut copied) = (0u64, 0u64);
@@ -269,6 +564,12 @@ impl Receiver {
 
                     tx.send(IOChunk::Data(data)).await?;
                 }
+                FileToken::Deflated(data) => {
+                    // Size of literal data is known after inflated, so progress is updated later.
+                    transferred += data.len() as u64;
+
+                    tx.send(IOChunk::Deflated(data)).await?;
+                }
                 // We interpret sum head values as unsigned ints anyway.
                 #[allow(clippy::cast_sign_loss)]
                 FileToken::Copied(block_offset) => {
@@ -289,28 +590,35 @@ impl Receiver {
         }
 
         drop(tx);
-        let (md4, blake2b, mut target_file) = io_task.await??;
+        let (checksum, blake2b, source, literal) = io_task.await??;
+        if self.protocol.compression.is_some() {
+            self.pb.inc(literal);
+        }
 
-        let mut remote_md4 = [0; 16];
+        let mut remote_checksum = vec![0; checksum.len()];
 
-        self.read_exact(&mut remote_md4).await?;
-        ensure!(md4 == remote_md4, "checksum mismatch");
+        self.read_exact(&mut remote_checksum).await?;
+        let checksum_ok = checksum == remote_checksum;
 
         // A debug log anyway.
         #[allow(clippy::cast_precision_loss)]
-        let (transferred, total) = (transferred as f64, (transferred + copied) as f64);
+        let (transferred, total) = (transferred as f64, (literal + copied) as f64);
         debug!(ratio = transferred / total, "transfer ratio");
 
         // No need to set perms because we'll upload it to s3.
 
-        target_file.seek(SeekFrom::Start(0))?;
         Ok(RecvResult {
-            target_file,
+            source,
             blake2b_hash: blake2b,
+            checksum_ok,
         })
     }
 
     async fn recv_token(&mut self) -> Result<FileToken> {
+        if self.protocol.compression.is_some() {
+            return self.recv_deflated_token().await;
+        }
+
         let token = self.read_i32_le().await?;
         match token.cmp(&0) {
             Ordering::Equal => Ok(FileToken::Done),
@@ -326,4 +634,170 @@ impl Receiver {
             Ordering::Less => Ok(FileToken::Copied(-(token + 1) as u32)),
         }
     }
+
+    /// Read a token from the compressed token stream.
+    async fn recv_deflated_token(&mut self) -> Result<FileToken> {
+        if self.token_run > 0 {
+            self.token_run -= 1;
+            self.last_token += 1;
+            #[allow(clippy::cast_sign_loss)]
+            return Ok(FileToken::Copied(self.last_token as u32));
+        }
+
+        let flag = self.read_u8().await?;
+        if flag & 0xc0 == DEFLATED_DATA {
+            let len = (usize::from(flag & 0x3f) << 8) + usize::from(self.read_u8().await?);
+            let mut buf = vec![0; len];
+            self.read_exact(&mut buf).await?;
+            return Ok(FileToken::Deflated(buf));
+        }
+        if flag == END_FLAG {
+            // Block indices are relative to the previous one in the same file.
+            self.last_token = 0;
+            return Ok(FileToken::Done);
+        }
+
+        let has_run = if flag & TOKEN_REL != 0 {
+            self.last_token += i32::from(flag & 0x3f);
+            flag & TOKENRUN_REL == TOKENRUN_REL
+        } else {
+            self.last_token = self.read_i32_le().await?;
+            flag & TOKENRUN_LONG == TOKENRUN_LONG
+        };
+        if has_run {
+            self.token_run = self.read_u16_le().await?;
+        }
+        ensure!(
+            self.last_token >= 0,
+            "invalid block index {}",
+            self.last_token
+        );
+        #[allow(clippy::cast_sign_loss)]
+        Ok(FileToken::Copied(self.last_token as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    use digest::Digest;
+    use md5::Md5;
+    use tokio::io::{AsyncWriteExt, BufReader};
+    use tokio::sync::{mpsc, Semaphore};
+
+    use rsync_core::s3::{build_operator, S3Opts};
+
+    use crate::rsync::checksum::{ChecksumType, StrongChecksum};
+    use crate::rsync::compat::Protocol;
+    use crate::rsync::envelope::EnvelopeRead;
+    use crate::rsync::file_list::{FileEntry, InFlightFiles};
+    use crate::rsync::ndx::{NdxCodec, ITEM_TRANSFER, NDX_DONE};
+    use crate::rsync::progress_display::{ProgressDisplay, ProgressOpts};
+    use crate::rsync::receiver::{Receiver, StreamTarget};
+    use crate::rsync::transport::TransportRead;
+    use crate::rsync::uploader::UploadTask;
+
+    /// Data of a file sent in full, followed by its whole-file checksum.
+    async fn send_file(data: &mut Vec<u8>, ndx: &mut NdxCodec, idx: i32, content: &[u8], ok: bool) {
+        ndx.write(&mut *data, idx).await.unwrap();
+        data.write_u16_le(ITEM_TRANSFER).await.unwrap();
+        // Sum head without blocks.
+        data.extend_from_slice(&[0; 16]);
+        data.write_i32_le(i32::try_from(content.len()).unwrap())
+            .await
+            .unwrap();
+        data.extend_from_slice(content);
+        data.write_i32_le(0).await.unwrap();
+        if ok {
+            data.extend_from_slice(&Md5::digest(content));
+        } else {
+            data.extend_from_slice(&[0; 16]);
+        }
+    }
+
+    #[tokio::test]
+    async fn must_redo_once_and_skip_failed_files() {
+        let protocol = Protocol {
+            version: 31,
+            compat_flags: 0,
+            checksum: StrongChecksum {
+                kind: ChecksumType::Md5,
+                seed: 0,
+                proper_seed_order: true,
+            },
+            compression: None,
+            always_checksum: false,
+            hard_links: false,
+        };
+
+        let mut data = vec![];
+        let mut ndx = NdxCodec::new(protocol.version);
+        // Phase 1: a fails checksum verification, and b is fine.
+        send_file(&mut data, &mut ndx, 0, b"hello", false).await;
+        send_file(&mut data, &mut ndx, 1, b"world", true).await;
+        ndx.write(&mut data, NDX_DONE).await.unwrap();
+        // Phase 2: a fails again.
+        send_file(&mut data, &mut ndx, 0, b"hello", false).await;
+        ndx.write(&mut data, NDX_DONE).await.unwrap();
+        ndx.write(&mut data, NDX_DONE).await.unwrap();
+
+        // Wrap data in a single multiplexed frame.
+        let len = u32::try_from(data.len()).unwrap();
+        let mut frame = (len | 7 << 24).to_le_bytes().to_vec();
+        frame.extend_from_slice(&data);
+        let rx: TransportRead = Box::new(Cursor::new(frame));
+
+        let in_flight = InFlightFiles::default();
+        for (idx, name) in ["a", "b"].into_iter().enumerate() {
+            in_flight.insert(FileEntry::regular(
+                name.into(),
+                5,
+                UNIX_EPOCH,
+                u32::try_from(idx).unwrap(),
+            ));
+        }
+        let (upload_tx, upload_rx) = flume::unbounded();
+        let (redo_tx, mut redo_rx) = mpsc::unbounded_channel();
+        let s3 = build_operator(&S3Opts {
+            region: "us-east-1".to_string(),
+            url: "http://localhost:9000".to_string(),
+            bucket: "test".to_string(),
+        })
+        .unwrap();
+        let receiver = Receiver::new(
+            EnvelopeRead::new(BufReader::new(rx)),
+            upload_tx,
+            in_flight.clone(),
+            protocol,
+            None,
+            redo_tx,
+            String::new(),
+            tempfile::tempdir().unwrap(),
+            StreamTarget {
+                s3,
+                temp_prefix: "tmp/".to_string(),
+            },
+            Arc::new(Semaphore::new(0)),
+            ProgressDisplay::new("test", &ProgressOpts::default()),
+        );
+        let receiver = receiver.recv_task().await.unwrap();
+
+        // a is requested again only once.
+        assert_eq!(redo_rx.recv().await, Some(0));
+        assert_eq!(redo_rx.recv().await, None);
+        // Only b is uploaded, and a is left out.
+        let uploaded: Vec<_> = upload_rx
+            .drain()
+            .map(|task| match task {
+                UploadTask::Blob { idx, .. } => idx,
+                UploadTask::Stream { .. } => panic!("unexpected stream"),
+            })
+            .collect();
+        assert_eq!(uploaded, [1]);
+        assert_eq!(receiver.failed(), 1);
+        assert!(in_flight.get(0).is_err());
+    }
 }
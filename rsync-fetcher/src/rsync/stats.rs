@@ -1,3 +1,5 @@
+use std::ops::Add;
+
 /// Stats returned by server at the end of transmission.
 #[derive(Debug, Copy, Clone)]
 pub struct Stats {
@@ -7,4 +9,24 @@ pub struct Stats {
     pub written: i64,
     /// Total size of files.
     pub size: i64,
+    /// Time spent building the file list in milliseconds. Protocol 29+.
+    pub flist_build_time: Option<i64>,
+    /// Time spent transferring the file list in milliseconds. Protocol 29+.
+    pub flist_xfer_time: Option<i64>,
+}
+
+impl Add for Stats {
+    type Output = Self;
+
+    /// Stats of connections to multiple sources.
+    fn add(self, rhs: Self) -> Self::Output {
+        let sum = |a: Option<i64>, b: Option<i64>| a.zip(b).map(|(a, b)| a + b);
+        Self {
+            read: self.read + rhs.read,
+            written: self.written + rhs.written,
+            size: self.size + rhs.size,
+            flist_build_time: sum(self.flist_build_time, rhs.flist_build_time),
+            flist_xfer_time: sum(self.flist_xfer_time, rhs.flist_xfer_time),
+        }
+    }
 }
@@ -0,0 +1,246 @@
+//! Transports to reach an rsync server.
+//!
+//! Besides an rsync daemon over TCP, a server can be spawned over a remote shell (e.g. ssh), or by
+//! any local command. In the latter two cases, we talk the remote-shell protocol over the stdin and
+//! stdout of the spawned process.
+
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use eyre::{bail, ensure, ContextCompat, Result, WrapErr};
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdout, Command};
+use tracing::info;
+use url::Url;
+
+use crate::opts::RsyncOpts;
+
+/// Read half of a connection to the server.
+pub type TransportRead = Box<dyn AsyncRead + Send + Unpin>;
+/// Write half of a connection to the server.
+pub type TransportWrite = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Transport {
+    /// Rsync daemon over TCP, i.e. `rsync://[user@]host[:port]/module/path`.
+    Daemon,
+    /// Rsync server spawned by a command, i.e. `ssh://[user@]host[:port]/path`, or any url with
+    /// `--command` given.
+    ///
+    /// Server arguments are appended to `command`, and `path` is passed as the source path. The
+    /// path is quoted for the remote shell if there's one.
+    RemoteShell { command: Vec<String>, path: String },
+}
+
+impl Transport {
+    pub fn from_url(url: &Url, opts: &RsyncOpts) -> Result<Self> {
+        if let Some(command) = &opts.command {
+            let command: Vec<_> = command
+                .split_whitespace()
+                .map(ToString::to_string)
+                .collect();
+            ensure!(!command.is_empty(), "empty command");
+            return Ok(Self::RemoteShell {
+                command,
+                path: url_path(url)?,
+            });
+        }
+
+        match url.scheme() {
+            "rsync" => Ok(Self::Daemon),
+            "ssh" => {
+                let mut command: Vec<_> = opts
+                    .rsh
+                    .split_whitespace()
+                    .map(ToString::to_string)
+                    .collect();
+                ensure!(!command.is_empty(), "empty remote shell command");
+                if let Some(port) = url.port() {
+                    command.extend(["-p".to_string(), port.to_string()]);
+                }
+                if !url.username().is_empty() {
+                    command.extend(["-l".to_string(), url.username().to_string()]);
+                }
+                command.push(url.host_str().context("missing remote host")?.to_string());
+                command.push(opts.rsync_path.clone());
+                // The remote shell joins the command line and runs it in a shell.
+                Ok(Self::RemoteShell {
+                    command,
+                    path: shell_quote(&url_path(url)?),
+                })
+            }
+            scheme => bail!("unsupported url scheme: {}", scheme),
+        }
+    }
+}
+
+/// Percent-decoded path of the url.
+pub fn url_path(url: &Url) -> Result<String> {
+    Ok(percent_decode_str(url.path())
+        .decode_utf8()
+        .wrap_err_with(|| format!("path of {url} is not valid utf-8"))?
+        .into_owned())
+}
+
+/// Quote a word for a POSIX shell, unless it only contains characters safe in shell words.
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Spawn the server with given command and arguments.
+///
+/// The server is killed when the read half is dropped.
+pub fn spawn_server(
+    command: &[String],
+    args: &[String],
+) -> Result<(TransportRead, TransportWrite)> {
+    let (program, program_args) = command.split_first().context("empty command")?;
+    info!(?command, ?args, "spawning rsync server");
+    let mut child = Command::new(program)
+        .args(program_args)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .wrap_err_with(|| format!("failed to spawn {program}"))?;
+
+    let stdin = child.stdin.take().expect("piped stdin");
+    let stdout = child.stdout.take().expect("piped stdout");
+    Ok((
+        Box::new(ChildRead {
+            stdout,
+            _child: child,
+        }),
+        Box::new(stdin),
+    ))
+}
+
+/// Stdout of a spawned server, which owns the process.
+struct ChildRead {
+    stdout: ChildStdout,
+    _child: Child,
+}
+
+impl AsyncRead for ChildRead {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use crate::opts::RsyncOpts;
+    use crate::rsync::bwlimit::RateLimiter;
+    use crate::rsync::filter::FilterRules;
+    use crate::rsync::transport::{shell_quote, Transport};
+
+    fn opts(command: Option<&str>) -> RsyncOpts {
+        RsyncOpts {
+            filters: FilterRules::default(),
+            bwlimit: RateLimiter::default(),
+            reload_bwlimit: false,
+            inc_recursive: false,
+            compress: false,
+            hard_links: false,
+            checksum: false,
+            rsh: "ssh -o BatchMode=yes".to_string(),
+            rsync_path: "rsync".to_string(),
+            command: command.map(ToString::to_string),
+            password_file: None,
+        }
+    }
+
+    #[test]
+    fn must_parse_daemon() {
+        let url = Url::parse("rsync://mirror.example.com/module/path").unwrap();
+        assert_eq!(
+            Transport::from_url(&url, &opts(None)).unwrap(),
+            Transport::Daemon
+        );
+    }
+
+    #[test]
+    fn must_parse_ssh() {
+        let url = Url::parse("ssh://alice@mirror.example.com:2222/srv/mirror/").unwrap();
+        assert_eq!(
+            Transport::from_url(&url, &opts(None)).unwrap(),
+            Transport::RemoteShell {
+                command: [
+                    "ssh",
+                    "-o",
+                    "BatchMode=yes",
+                    "-p",
+                    "2222",
+                    "-l",
+                    "alice",
+                    "mirror.example.com",
+                    "rsync"
+                ]
+                .map(ToString::to_string)
+                .to_vec(),
+                path: "/srv/mirror/".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn must_quote_ssh_path() {
+        let url = Url::parse("ssh://mirror.example.com/srv/my mirror/it's;rm -rf ~/").unwrap();
+        let Transport::RemoteShell { path, .. } = Transport::from_url(&url, &opts(None)).unwrap()
+        else {
+            panic!("not a remote shell");
+        };
+        assert_eq!(path, r"'/srv/my mirror/it'\''s;rm -rf ~/'");
+
+        // No shell is involved when spawning a command.
+        let url = Url::parse("file:///srv/my%20mirror/").unwrap();
+        let Transport::RemoteShell { path, .. } =
+            Transport::from_url(&url, &opts(Some("rsync"))).unwrap()
+        else {
+            panic!("not a remote shell");
+        };
+        assert_eq!(path, "/srv/my mirror/");
+    }
+
+    #[test]
+    fn must_quote_shell_words() {
+        assert_eq!(shell_quote("/srv/mirror/"), "/srv/mirror/");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$(id)"), "'$(id)'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn must_parse_command() {
+        let url = Url::parse("file:///srv/mirror/").unwrap();
+        assert_eq!(
+            Transport::from_url(&url, &opts(Some("rsync"))).unwrap(),
+            Transport::RemoteShell {
+                command: vec!["rsync".to_string()],
+                path: "/srv/mirror/".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn must_reject_unknown_scheme() {
+        let url = Url::parse("http://mirror.example.com/").unwrap();
+        assert!(Transport::from_url(&url, &opts(None)).is_err());
+    }
+}
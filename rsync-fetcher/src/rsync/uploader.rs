@@ -1,68 +1,178 @@
 use std::ffi::OsStr;
-use std::io::SeekFrom;
+use std::io;
+use std::io::{Cursor, SeekFrom};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::sync::Arc;
 
-use dashmap::DashSet;
-use eyre::Result;
+use dashmap::DashMap;
+use eyre::{eyre, Result};
 use futures::stream::FuturesUnordered;
-use futures::TryStreamExt;
-use opendal::{ErrorKind, Operator};
+use futures::{stream, TryStreamExt};
+use opendal::{ErrorKind, Operator, Writer};
+use sqlx::PgPool;
 use tap::TapOptional;
 use tokio::fs::File;
-use tokio::io::AsyncSeekExt;
-use tokio::sync::mpsc;
+use tokio::io::{AsyncRead, AsyncSeekExt};
+use tokio::sync::{mpsc, oneshot, Mutex, OwnedMutexGuard};
 use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::io::StreamReader;
 use tracing::{debug, info, warn};
 
 use rsync_core::metadata::{MetaExtra, Metadata};
 use rsync_core::utils::{ToHex, ATTR_CHAR};
 
-use crate::consts::UPLOAD_CONN;
-use crate::rsync::file_list::FileEntry;
+use crate::pg::blob_exists;
+use crate::rsync::bwlimit::{RateLimitedRead, RateLimiter};
+use crate::rsync::file_list::InFlightFiles;
+use crate::rsync::multipart::{MultipartUploader, MULTIPART_THRESHOLD};
 use crate::rsync::progress_display::ProgressDisplay;
+use crate::utils::namespace_as_table;
 
 const UPLOAD_CHUNK_SIZE: usize = 10 * 1024 * 1024;
+/// Max size of objects copied on the server side at once. Larger objects are copied in parts.
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
 
 pub struct Uploader {
     rx: flume::Receiver<UploadTask>,
-    file_list: Arc<Vec<FileEntry>>,
+    in_flight: InFlightFiles,
     s3: Operator,
     s3_prefix: String,
     pg_tx: mpsc::Sender<(Vec<u8>, Metadata)>,
-    uploaded: DashSet<[u8; 20]>,
+    bwlimit: RateLimiter,
+    multipart: MultipartUploader,
+    known: KnownBlobs,
+    conns: usize,
     pb: ProgressDisplay,
 }
 
-pub struct UploadTask {
-    pub idx: usize,
-    pub blake2b_hash: [u8; 20],
-    pub file: File,
+/// Where to look up blobs already in storage.
+pub struct KnownBlobs {
+    pub namespace: String,
+    pub pool: Arc<PgPool>,
+    /// Also check known blobs exist in S3 before skipping their uploads.
+    pub stat: bool,
+    /// Blobs uploaded by this sync, shared by uploaders of all sources.
+    pub uploaded: UploadedBlobs,
+}
+
+/// Blobs uploaded by this sync, keyed by blake2b hash.
+///
+/// A blob is uploaded by one task at a time. Other tasks uploading the same content, e.g. a
+/// duplicated or hard linked file, wait for it and skip the upload once it's done.
+#[derive(Clone, Default)]
+pub struct UploadedBlobs(Arc<DashMap<[u8; 20], Arc<Mutex<bool>>>>);
+
+impl UploadedBlobs {
+    /// Claim the blob, waiting for the task holding it if there's one. The guard tells whether
+    /// the blob is already uploaded.
+    async fn claim(&self, blake2b_hash: [u8; 20]) -> OwnedMutexGuard<bool> {
+        let entry = self.0.entry(blake2b_hash).or_default().clone();
+        entry.lock_owned().await
+    }
+}
+
+pub enum UploadTask {
+    /// Upload a received file to its content address.
+    Blob {
+        idx: u32,
+        blake2b_hash: [u8; 20],
+        source: UploadSource,
+    },
+    /// Upload a file being received to a temporary object. The receiver sends it before the
+    /// `Blob` task of the same file, so that it's taken by an upload task first.
+    Stream {
+        key: String,
+        filename: Option<Vec<u8>>,
+        len: u64,
+        /// Received data, closed at the end of the file.
+        chunks: mpsc::Receiver<Vec<u8>>,
+        /// Notified once the temporary object is complete.
+        uploaded: oneshot::Sender<()>,
+    },
+}
+
+/// Where the content of a received file is.
+pub enum UploadSource {
+    /// A temporary file.
+    File(File),
+    /// Small files are received in memory.
+    Memory(Vec<u8>),
+    /// Uploaded to this temporary key while being received, to be moved to its content address
+    /// once the upload completes.
+    Temp {
+        key: String,
+        uploaded: oneshot::Receiver<()>,
+    },
+}
+
+/// Prefix of temporary objects of the namespace.
+///
+/// Objects under it are only used during a sync, so they can be removed when the namespace is
+/// locked by a new sync.
+pub fn temp_prefix(s3_prefix: &str, namespace: &str) -> String {
+    format!("{s3_prefix}_tmp/{}/", namespace_as_table(namespace))
+}
+
+fn content_disposition(filename: Option<&[u8]>) -> Option<String> {
+    filename.map(|filename| {
+        let encoded_name = percent_encoding::percent_encode(filename, ATTR_CHAR);
+        format!("attachment; filename=\"{encoded_name}\"; filename*=UTF-8''{encoded_name}")
+    })
+}
+
+/// Open a writer of the object, with its content disposition set to the given filename.
+pub async fn object_writer(s3: &Operator, key: &str, filename: Option<&[u8]>) -> Result<Writer> {
+    Ok(content_disposition(filename)
+        .map_or_else(
+            || s3.writer_with(key).buffer(UPLOAD_CHUNK_SIZE),
+            |content_disposition| {
+                s3.writer_with(key)
+                    .buffer(UPLOAD_CHUNK_SIZE)
+                    .content_disposition(&content_disposition)
+            },
+        )
+        .await?)
+}
+
+/// Filename of the entry, used in content disposition.
+pub fn entry_filename(name: &[u8]) -> Option<&[u8]> {
+    let key = Path::new(OsStr::from_bytes(name));
+    key.file_name()
+        .tap_none(|| warn!(?key, "missing filename of entry"))
+        .map(OsStrExt::as_bytes)
 }
 
 impl Uploader {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         rx: flume::Receiver<UploadTask>,
-        file_list: Arc<Vec<FileEntry>>,
+        in_flight: InFlightFiles,
         s3: Operator,
         s3_prefix: String,
         pg_tx: mpsc::Sender<(Vec<u8>, Metadata)>,
+        bwlimit: RateLimiter,
+        multipart: MultipartUploader,
+        known: KnownBlobs,
+        conns: usize,
         pb: ProgressDisplay,
     ) -> Self {
         Self {
             rx,
-            file_list,
+            in_flight,
             s3,
             s3_prefix,
             pg_tx,
-            uploaded: DashSet::new(),
+            bwlimit,
+            multipart,
+            known,
+            conns,
             pb,
         }
     }
 
     pub async fn upload_tasks(self) -> Result<()> {
-        let futs: FuturesUnordered<_> = (0..UPLOAD_CONN).map(|id| self.upload_task(id)).collect();
+        let futs: FuturesUnordered<_> = (0..self.conns).map(|id| self.upload_task(id)).collect();
         futs.try_collect().await?;
         Ok(())
     }
@@ -71,89 +181,196 @@ impl Uploader {
         debug!("upload task {} started", id);
 
         while let Ok(task) = self.rx.recv_async().await {
-            let UploadTask {
-                idx,
-                blake2b_hash,
-                mut file,
-            } = task;
-
-            // Avoid repeatedly uploading the same file to address
-            // https://stackoverflow.com/questions/63238344/amazon-s3-how-parallel-puts-to-the-same-key-are-resolved-in-versioned-buckets
-            if !self.uploaded.contains(&blake2b_hash) {
-                // Upload file to S3.
-                let entry = &self.file_list[idx];
-                let key = Path::new(OsStr::from_bytes(&entry.name));
-                let filename = key
-                    .file_name()
-                    .tap_none(|| warn!(?key, "missing filename of entry"))
-                    .map(OsStrExt::as_bytes);
-
-                // REMARK: If a file is soft/hard linked, users may see a content-disposition with a
-                // different filename instead of the one they expect.
-                // TODO since we no longer need static file path, we can use presign on the gateway.
-                file.seek(SeekFrom::Start(0))
-                    .await
-                    .expect("unable to seek file");
-                self.upload_s3(filename, file, &blake2b_hash).await?;
-                self.uploaded.insert(blake2b_hash);
+            match task {
+                UploadTask::Blob {
+                    idx,
+                    blake2b_hash,
+                    source,
+                } => self.upload_blob(idx, blake2b_hash, source).await?,
+                UploadTask::Stream {
+                    key,
+                    filename,
+                    len,
+                    chunks,
+                    uploaded,
+                } => {
+                    self.upload_stream(&key, filename.as_deref(), len, chunks)
+                        .await?;
+                    // Nobody waits for it if the receiver failed.
+                    let _ = uploaded.send(());
+                }
             }
-            self.pb.dec_uploading(1);
-
-            // Update metadata in pg.
-            self.update_metadata(idx, blake2b_hash).await?;
         }
 
         debug!("upload task {} finished", id);
         Ok(())
     }
 
+    async fn upload_blob(
+        &self,
+        idx: u32,
+        blake2b_hash: [u8; 20],
+        mut source: UploadSource,
+    ) -> Result<()> {
+        let temp_key = if let UploadSource::Temp { key, uploaded } = &mut source {
+            // The temporary object is uploaded by another upload task.
+            uploaded
+                .await
+                .map_err(|_| eyre!("failed to upload temporary object {key}"))?;
+            Some(key.clone())
+        } else {
+            None
+        };
+
+        // Avoid repeatedly uploading the same file to address
+        // https://stackoverflow.com/questions/63238344/amazon-s3-how-parallel-puts-to-the-same-key-are-resolved-in-versioned-buckets
+        // Concurrent uploads of the same key would also share its multipart upload.
+        let mut uploaded = self.known.uploaded.claim(blake2b_hash).await;
+        if !*uploaded {
+            // Upload file to S3.
+            let entry = self.in_flight.get(idx)?;
+            let filename = entry_filename(&entry.name);
+
+            // REMARK: If a file is soft/hard linked, users may see a content-disposition with a
+            // different filename instead of the one they expect.
+            // TODO since we no longer need static file path, we can use presign on the gateway.
+            self.upload_s3(filename, source, entry.len, &blake2b_hash)
+                .await?;
+            *uploaded = true;
+        }
+        drop(uploaded);
+        if let Some(temp_key) = temp_key {
+            self.s3.delete(&temp_key).await?;
+        }
+        self.pb.dec_uploading(1);
+
+        // Update metadata in pg.
+        self.update_metadata(idx, blake2b_hash).await?;
+        self.in_flight.remove(idx);
+        self.pb.inc_files_done(1);
+        Ok(())
+    }
+
+    /// Upload a file to a temporary object while it's being received.
+    ///
+    /// Streams are always uploaded in parts, so that failed parts are retried from memory, and an
+    /// interrupted upload is resumed by the next sync receiving the same file.
+    async fn upload_stream(
+        &self,
+        key: &str,
+        filename: Option<&[u8]>,
+        len: u64,
+        chunks: mpsc::Receiver<Vec<u8>>,
+    ) -> Result<()> {
+        let chunks = stream::unfold(chunks, |mut chunks| async move {
+            let chunk = chunks.recv().await?;
+            Some((Ok::<_, io::Error>(Cursor::new(chunk)), chunks))
+        });
+        let content_disposition = content_disposition(filename);
+        let uploaded = self
+            .multipart
+            .upload(
+                key,
+                content_disposition.as_deref(),
+                len,
+                StreamReader::new(Box::pin(chunks)),
+            )
+            .await?;
+        self.pb.inc_uploaded(uploaded);
+        Ok(())
+    }
+
     async fn upload_s3(
         &self,
         filename: Option<&[u8]>,
-        target_file: File,
+        source: UploadSource,
+        len: u64,
         blake2b_hash: &[u8; 20],
     ) -> Result<()> {
         // File is content addressed by its blake2b hash.
         let key = format!("{}{:x}", self.s3_prefix, blake2b_hash.as_hex());
 
         // Check if file already exists. Might happen if the file is the same between two syncs,
-        // or it's hard linked.
-        let exists = self
-            .s3
-            .stat(&key)
-            .await
-            .map(|_| true)
-            .or_else(|e| match e.kind() {
-                ErrorKind::NotFound => Ok(false),
-                _ => Err(e),
-            })?;
+        // it's moved, or it's hard linked. Blobs referenced by previous revisions are known to
+        // exist without asking S3.
+        let known = blob_exists(&self.known.namespace, blake2b_hash, &*self.known.pool).await?;
+        let exists = if known && !self.known.stat {
+            true
+        } else {
+            self.s3
+                .stat(&key)
+                .await
+                .map(|_| true)
+                .or_else(|e| match e.kind() {
+                    ErrorKind::NotFound => Ok(false),
+                    _ => Err(e),
+                })?
+        };
+        if known && !exists {
+            warn!(
+                key,
+                "blob referenced by previous revisions is missing, uploading again"
+            );
+        }
 
         // Only upload if it doesn't exist.
         if !exists {
-            let content_disposition = filename.map(|filename| {
-                let encoded_name = percent_encoding::percent_encode(filename, ATTR_CHAR);
-                format!("attachment; filename=\"{encoded_name}\"; filename*=UTF-8''{encoded_name}")
-            });
-
-            let mut writer = content_disposition
-                .map_or_else(
-                    || self.s3.writer_with(&key).buffer(UPLOAD_CHUNK_SIZE),
-                    |content_disposition| {
-                        self.s3
-                            .writer_with(&key)
-                            .buffer(UPLOAD_CHUNK_SIZE)
-                            .content_disposition(&content_disposition)
-                    },
-                )
+            match source {
+                UploadSource::File(mut file) => {
+                    file.seek(SeekFrom::Start(0))
+                        .await
+                        .expect("unable to seek file");
+                    self.write_object(&key, filename, len, file).await?;
+                }
+                UploadSource::Memory(data) => {
+                    self.write_object(&key, filename, len, Cursor::new(data))
+                        .await?;
+                }
+                UploadSource::Temp { key: temp_key, .. } => {
+                    // The file may have changed size since the file list was sent.
+                    let len = self.s3.stat(&temp_key).await?.content_length();
+                    if len <= MAX_COPY_SIZE {
+                        // Metadata including content disposition is kept by the copy.
+                        self.s3.copy(&temp_key, &key).await?;
+                    } else {
+                        let content_disposition = content_disposition(filename);
+                        self.multipart
+                            .copy(&temp_key, &key, content_disposition.as_deref(), len)
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_object(
+        &self,
+        key: &str,
+        filename: Option<&[u8]>,
+        len: u64,
+        reader: impl AsyncRead + Unpin,
+    ) -> Result<()> {
+        if len > MULTIPART_THRESHOLD {
+            let content_disposition = content_disposition(filename);
+            let uploaded = self
+                .multipart
+                .upload(key, content_disposition.as_deref(), len, reader)
                 .await?;
-            writer.copy(target_file.compat()).await?;
-            writer.close().await?;
+            self.pb.inc_uploaded(uploaded);
+            return Ok(());
         }
+
+        let mut writer = object_writer(&self.s3, key, filename).await?;
+        let reader = RateLimitedRead::new(reader, self.bwlimit.clone());
+        let uploaded = writer.copy(reader.compat()).await?;
+        writer.close().await?;
+        self.pb.inc_uploaded(uploaded);
         Ok(())
     }
 
-    async fn update_metadata(&self, idx: usize, blake2b_hash: [u8; 20]) -> Result<()> {
-        let entry = &self.file_list[idx];
+    async fn update_metadata(&self, idx: u32, blake2b_hash: [u8; 20]) -> Result<()> {
+        let entry = self.in_flight.get(idx)?;
         let metadata = Metadata {
             len: entry.len,
             modify_time: entry.modify_time,
@@ -161,7 +378,7 @@ impl Uploader {
         };
 
         // Queue metadata insertion to Postgres.
-        self.pg_tx.send((entry.name.clone(), metadata)).await?;
+        self.pg_tx.send((entry.name, metadata)).await?;
         Ok(())
     }
 }
@@ -171,3 +388,38 @@ impl Drop for Uploader {
         info!("uploader dropped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::rsync::uploader::UploadedBlobs;
+
+    #[tokio::test]
+    async fn must_claim_blob_once() {
+        let uploaded = UploadedBlobs::default();
+        let mut first = uploaded.claim([1; 20]).await;
+        assert!(!*first);
+
+        // Other blobs are not blocked.
+        assert!(!*uploaded.clone().claim([2; 20]).await);
+
+        // The same blob waits for the first claim.
+        let waiting = tokio::spawn({
+            let uploaded = uploaded.clone();
+            async move { *uploaded.claim([1; 20]).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        *first = true;
+        drop(first);
+        let done = timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("claimed")
+            .unwrap();
+        assert!(done);
+    }
+}
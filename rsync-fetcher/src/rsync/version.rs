@@ -5,12 +5,18 @@ use scan_fmt::scan_fmt;
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::debug;
 
-/// The version of the rsync protocol that is supported by this implementation.
+/// The newest version of the rsync protocol that is supported by this implementation.
 pub const SUPPORTED_VERSION: Version = Version {
-    major: 27,
-    minor: None,
+    major: 31,
+    minor: Some(0),
 };
 
+/// The oldest version of the rsync protocol that is supported by this implementation.
+pub const MIN_SUPPORTED_VERSION: i32 = 27;
+
+/// Digests we support for daemon auth and strong checksums, in order of preference.
+pub const SUPPORTED_DIGESTS: &[&str] = &["md5", "md4"];
+
 /// Version of the rsync protocol.
 #[derive(Debug, Copy, Clone)]
 pub struct Version {
@@ -29,6 +35,33 @@ impl Display for Version {
 }
 
 impl Version {
+    /// Protocol version to speak with a remote of the given version.
+    pub fn negotiate(self, remote: Self) -> i32 {
+        let negotiated = self.major.min(remote.major);
+        let our_minor = if negotiated == self.major {
+            self.minor.unwrap_or(0)
+        } else {
+            0
+        };
+        // A mismatched sub-protocol means one side is a pre-release build. Fall back to the
+        // previous protocol version which is considered stable.
+        if remote.major == negotiated && remote.minor.unwrap_or(0) != our_minor {
+            negotiated - 1
+        } else {
+            negotiated
+        }
+    }
+}
+
+/// Greeting message exchanged at the beginning of a daemon connection.
+#[derive(Debug, Clone)]
+pub struct Greeting {
+    pub version: Version,
+    /// Auth digests supported by the peer. Only sent by rsync 3.2.0 and above.
+    pub auth_digests: Option<Vec<String>>,
+}
+
+impl Greeting {
     pub async fn read_from(mut rx: impl AsyncBufRead + Unpin) -> Result<Self> {
         let mut greeting = String::new();
         (&mut rx).take(128).read_line(&mut greeting).await?;
@@ -39,21 +72,73 @@ impl Version {
             .strip_prefix("@RSYNCD: ")
             .ok_or_else(|| eyre!("invalid greeting"))?
             .to_string();
+        let (protocol_header, auth_digests) = protocol_header.split_once(' ').map_or(
+            (protocol_header.as_str(), None),
+            |(version, digests)| {
+                (
+                    version,
+                    Some(
+                        digests
+                            .split_whitespace()
+                            .map(ToString::to_string)
+                            .collect(),
+                    ),
+                )
+            },
+        );
 
-        let (major, minor) = scan_fmt!(&protocol_header, "{}.{}", i32, i32)
+        let (major, minor) = scan_fmt!(protocol_header, "{}.{}", i32, i32)
             .map(|(protocol, sub)| (protocol, Some(sub)))
-            .or_else(|_| scan_fmt!(&protocol_header, "{}", i32).map(|protocol| (protocol, None)))
+            .or_else(|_| scan_fmt!(protocol_header, "{}", i32).map(|protocol| (protocol, None)))
             .context("invalid greeting: no server version")?;
 
-        Ok(Self { major, minor })
+        Ok(Self {
+            version: Version { major, minor },
+            auth_digests,
+        })
     }
-    pub async fn write_to(self, mut tx: impl AsyncWrite + Unpin) -> Result<()> {
-        let msg = self.minor.map_or_else(
-            || format!("@RSYNCD: {}\n", self.major),
-            |minor| format!("@RSYNCD: {}.{}\n", self.major, minor),
-        );
+    pub async fn write_to(&self, mut tx: impl AsyncWrite + Unpin) -> Result<()> {
+        let mut msg = format!("@RSYNCD: {}", self.version);
+        if let Some(digests) = &self.auth_digests {
+            msg.push(' ');
+            msg.push_str(&digests.join(" "));
+        }
+        msg.push('\n');
 
         tx.write_all(msg.as_bytes()).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::rsync::version::{Greeting, SUPPORTED_VERSION};
+
+    #[tokio::test]
+    async fn must_parse_greeting() {
+        let greeting = Greeting::read_from(&b"@RSYNCD: 31.0 sha512 sha256 md5 md4\n"[..])
+            .await
+            .unwrap();
+        assert_eq!(greeting.version.major, 31);
+        assert_eq!(greeting.version.minor, Some(0));
+        assert_eq!(
+            greeting.auth_digests.unwrap(),
+            ["sha512", "sha256", "md5", "md4"]
+        );
+
+        let greeting = Greeting::read_from(&b"@RSYNCD: 29\n"[..]).await.unwrap();
+        assert_eq!(greeting.version.major, 29);
+        assert_eq!(greeting.version.minor, None);
+        assert!(greeting.auth_digests.is_none());
+    }
+
+    #[tokio::test]
+    async fn must_negotiate_version() {
+        let greeting = Greeting::read_from(&b"@RSYNCD: 30.0\n"[..]).await.unwrap();
+        assert_eq!(SUPPORTED_VERSION.negotiate(greeting.version), 30);
+        let greeting = Greeting::read_from(&b"@RSYNCD: 32.0\n"[..]).await.unwrap();
+        assert_eq!(SUPPORTED_VERSION.negotiate(greeting.version), 31);
+        let greeting = Greeting::read_from(&b"@RSYNCD: 31.14\n"[..]).await.unwrap();
+        assert_eq!(SUPPORTED_VERSION.negotiate(greeting.version), 30);
+    }
+}
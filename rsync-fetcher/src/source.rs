@@ -0,0 +1,244 @@
+//! Upstream sources merged into one namespace.
+//!
+//! Each source is synced over its own connection, and names of its entries are prefixed with the
+//! path it's mounted at. Indices of file lists are only unique within a connection, so entries of
+//! each source occupy a disjoint range of indices in the file list table.
+//!
+//! A source may have multiple equivalent urls, which are tried in order of their health, see
+//! `health`.
+
+use doku::Document;
+use eyre::{ensure, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize};
+use url::Url;
+
+use crate::rsync::file_list::FileEntry;
+use crate::rsync::filter::FilterRules;
+
+/// Indices of the n-th source start from `n * SOURCE_IDX_STRIDE` in the file list table.
+pub const SOURCE_IDX_STRIDE: u32 = 1 << 28;
+/// Max number of sources of a namespace, so that indices fit in `i32`.
+pub const MAX_SOURCES: usize = 8;
+
+/// An upstream source, mounted at a path of the namespace.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Document)]
+pub struct Source {
+    /// Path of the namespace the source is mounted at.
+    #[doku(example = "security")]
+    pub path: String,
+    /// Rsync remote url, or a list of equivalent urls.
+    #[serde(deserialize_with = "de_urls")]
+    #[doku(
+        as = "String",
+        example = "rsync://security.debian.org/debian-security/"
+    )]
+    pub src: Vec<Url>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(Url),
+    Many(Vec<Url>),
+}
+
+/// Deserialize a url or a list of urls.
+pub fn de_urls<'de, D>(de: D) -> Result<Vec<Url>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match OneOrMany::deserialize(de)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
+}
+
+impl Source {
+    /// Source mounted at the root of the namespace.
+    pub fn root(src: Vec<Url>) -> Self {
+        Self {
+            path: String::new(),
+            src,
+        }
+    }
+    /// Parse a `path=url[,url...]` pair, i.e. `--merge`.
+    pub fn parse_merge(s: &str) -> Result<Self, String> {
+        let (path, urls) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PATH=URL, got {s}"))?;
+        Ok(Self {
+            path: path.to_string(),
+            src: urls
+                .split(',')
+                .map(|url| url.parse().map_err(|e| format!("invalid url {url}: {e}")))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+    /// Mount path without leading or trailing slashes. Empty for the root.
+    pub fn mount_path(&self) -> &str {
+        self.path.trim_matches('/')
+    }
+    /// Path of `other` relative to this source, if `other` is mounted inside it.
+    fn relative<'a>(&self, other: &'a Self) -> Option<&'a str> {
+        let (path, other_path) = (self.mount_path(), other.mount_path());
+        if path.is_empty() {
+            return (!other_path.is_empty()).then_some(other_path);
+        }
+        other_path
+            .strip_prefix(path)
+            .and_then(|rest| rest.strip_prefix('/'))
+    }
+    /// Filter rules of the source. Entries where other sources are mounted are excluded, so that
+    /// they are not synced twice.
+    pub fn filters(&self, sources: &[Self], base: &FilterRules) -> FilterRules {
+        let mut filters = base.clone();
+        for path in sources.iter().filter_map(|other| self.relative(other)) {
+            filters.exclude_first(path.as_bytes());
+        }
+        filters
+    }
+}
+
+/// Offset of indices of the n-th source in the file list table.
+pub fn idx_offset(n: usize) -> u32 {
+    u32::try_from(n).expect("too many sources") * SOURCE_IDX_STRIDE
+}
+
+/// Make sure sources can be merged. The first source must be mounted at the root.
+pub fn validate_sources(sources: &[Source]) -> Result<()> {
+    ensure!(
+        sources.len() <= MAX_SOURCES,
+        "at most {MAX_SOURCES} sources can be merged"
+    );
+    for (n, source) in sources.iter().enumerate() {
+        let path = source.mount_path();
+        ensure!(
+            !source.src.is_empty(),
+            "no url of source at {:?}",
+            source.path
+        );
+        if n == 0 {
+            ensure!(path.is_empty(), "the first source must be mounted at root");
+            continue;
+        }
+        ensure!(
+            path.split('/').all(|part| !matches!(part, "" | "." | "..")),
+            "invalid mount path {:?}",
+            source.path
+        );
+        // Mount paths are used as patterns of filter rules.
+        ensure!(
+            !path.contains(['*', '?', '[']),
+            "mount path {:?} can't contain wildcards",
+            source.path
+        );
+    }
+    ensure!(
+        sources.iter().map(Source::mount_path).all_unique(),
+        "sources can't be mounted at the same path"
+    );
+    Ok(())
+}
+
+/// Index of the source a path of the namespace belongs to, i.e. the one mounted closest to it.
+pub fn source_of(sources: &[Source], path: &str) -> usize {
+    let path = path.trim_matches('/');
+    sources
+        .iter()
+        .enumerate()
+        .filter(|(_, source)| {
+            let mount = source.mount_path();
+            mount.is_empty()
+                || path
+                    .strip_prefix(mount)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(_, source)| source.mount_path().len())
+        .map_or(0, |(n, _)| n)
+}
+
+/// Prefix names of entries with the mount path. The root entry `.` becomes the mount point.
+pub fn mount_entries(path: &str, list: &mut [FileEntry]) {
+    if path.is_empty() {
+        return;
+    }
+    for entry in list {
+        entry.name = if entry.name == b"." {
+            path.as_bytes().to_vec()
+        } else {
+            [path.as_bytes(), b"/", &entry.name].concat()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rsync::filter::FilterRules;
+    use crate::source::{idx_offset, source_of, validate_sources, Source, MAX_SOURCES};
+
+    fn source(path: &str) -> Source {
+        Source {
+            path: path.to_string(),
+            src: vec!["rsync://example.com/module/".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn must_parse_merge() {
+        let merged = Source::parse_merge("security/=rsync://b/debian-security/").unwrap();
+        assert_eq!(merged.mount_path(), "security");
+        assert_eq!(merged.src.len(), 1);
+        assert_eq!(merged.src[0].as_str(), "rsync://b/debian-security/");
+        let mirrors = Source::parse_merge("security=rsync://b/a/,rsync://c/a/").unwrap();
+        assert_eq!(mirrors.src.len(), 2);
+        assert!(Source::parse_merge("rsync://b/debian-security/").is_err());
+        assert!(Source::parse_merge("security=not a url").is_err());
+    }
+
+    #[test]
+    fn must_validate_sources() {
+        validate_sources(&[source(""), source("security"), source("a/b/")]).unwrap();
+        assert!(validate_sources(&[source("security")]).is_err());
+        assert!(validate_sources(&[source(""), source("/")]).is_err());
+        assert!(validate_sources(&[source(""), source("a/../b")]).is_err());
+        assert!(validate_sources(&[source(""), source("a/*")]).is_err());
+        assert!(validate_sources(&[source(""), source("a"), source("/a/")]).is_err());
+        assert!(validate_sources(&vec![source(""); MAX_SOURCES + 1]).is_err());
+        let mut no_url = source("security");
+        no_url.src.clear();
+        assert!(validate_sources(&[source(""), no_url]).is_err());
+        assert!(i32::try_from(idx_offset(MAX_SOURCES - 1)).is_ok());
+    }
+
+    #[test]
+    fn must_exclude_nested_mounts() {
+        let sources = [source(""), source("a"), source("a/b"), source("ab")];
+        let mut base = FilterRules::default();
+        base.add_rule(b"- *.iso").unwrap();
+        let commands = |n: usize| -> Vec<String> {
+            sources[n]
+                .filters(&sources, &base)
+                .to_commands(31)
+                .unwrap()
+                .into_iter()
+                .map(|cmd| String::from_utf8(cmd).unwrap())
+                .collect()
+        };
+
+        assert_eq!(commands(0), ["- /ab", "- /a/b", "- /a", "- *.iso"]);
+        assert_eq!(commands(1), ["- /b", "- *.iso"]);
+        assert_eq!(commands(2), ["- *.iso"]);
+        assert_eq!(commands(3), ["- *.iso"]);
+    }
+
+    #[test]
+    fn must_find_source_of_path() {
+        let sources = [source(""), source("a"), source("a/b")];
+        assert_eq!(source_of(&sources, "project/trace/master"), 0);
+        assert_eq!(source_of(&sources, "ab/lastsync"), 0);
+        assert_eq!(source_of(&sources, "a/lastsync"), 1);
+        assert_eq!(source_of(&sources, "a/b/lastsync"), 2);
+        assert_eq!(source_of(&sources, "/a/b"), 2);
+    }
+}
@@ -0,0 +1,851 @@
+//! Sync a repository.
+
+use std::fmt::{Display, Formatter};
+use std::future::ready;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytesize::ByteSize;
+use chrono::{Duration, Utc};
+use eyre::{Result, WrapErr};
+use futures::future::try_join_all;
+use futures::{FutureExt, TryFutureExt};
+use opendal::Operator;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tracing::{info, instrument, warn};
+use url::Url;
+
+use rsync_core::pg::{
+    change_revision_status, create_revision, ensure_repository, insert_task, RevisionStatus,
+};
+use rsync_core::pg_lock::PgLock;
+use rsync_core::utils::AbortJoinHandle;
+
+use crate::health::rank_urls;
+use crate::opts::{RsyncOpts, SyncOpts};
+use crate::pg::{
+    analyse_objects, create_fl_table, delete_removed_objects, drop_fl_table, finish_sync_run,
+    insert_file_list_to_db, latest_live_revision, record_handshake, record_upstream_failure,
+    record_upstream_success, resumable_revision, resume_revision, revision_size, start_sync_run,
+    update_checksums, update_hard_links, update_parent_ids, upstream_health, RunStats, SyncResult,
+};
+use crate::plan::{
+    diff_and_apply, diff_dry_run, items_of_source, plan_incremental, Compare, PlanBatch, SourcePlan,
+};
+use crate::rsync::compat::Protocol;
+use crate::rsync::file_list::{FileEntry, InFlightFiles};
+use crate::rsync::multipart::MultipartUploader;
+use crate::rsync::mux_conn::MuxConn;
+use crate::rsync::progress_display::{Phase, ProgressDisplay};
+use crate::rsync::uploader::{temp_prefix, KnownBlobs, UploadedBlobs};
+use crate::rsync::{finalize, start_handshake, LocalError, TaskBuilders};
+use crate::source::{idx_offset, source_of, Source};
+use crate::trace::{check_freshness, check_trace_mtimes};
+use crate::utils::{
+    check_shrink, flatten_err, plan_stat, top_changed_dirs, PlannedTransfer, Shutdown,
+};
+
+/// Error returned when a sync is interrupted by SIGINT or SIGTERM.
+#[derive(Debug)]
+pub struct Aborted;
+
+impl Display for Aborted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sync aborted by signal")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Context of errors caused by an upstream during transfer, so that the sync can be retried with
+/// another url of the source.
+#[derive(Debug, Clone)]
+struct UpstreamFailed {
+    source: usize,
+    url: Url,
+}
+
+impl Display for UpstreamFailed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream {} failed", self.url)
+    }
+}
+
+/// Blame an error of the generator or receiver on the upstream, unless it's caused by S3, the
+/// database, or another task.
+fn blame_upstream(e: eyre::Report, upstream: &UpstreamFailed) -> eyre::Report {
+    let local = e.downcast_ref::<LocalError>().is_some()
+        || e.chain().any(|e| {
+            e.is::<opendal::Error>()
+                || e.is::<sqlx::Error>()
+                || e.downcast_ref::<io::Error>()
+                    .and_then(io::Error::get_ref)
+                    .is_some_and(|e| e.is::<LocalError>())
+        });
+    if local {
+        e
+    } else {
+        e.wrap_err(upstream.clone())
+    }
+}
+
+/// Sync a repository to S3, and publish it as a new live revision.
+///
+/// Waits if another write operation is running on the namespace.
+pub async fn sync(
+    opts: &SyncOpts,
+    pool: &Arc<PgPool>,
+    s3: &Operator,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    sync_with_lock(opts, pool, s3, shutdown, true).await?;
+    Ok(())
+}
+
+/// Like `sync`, but returns `false` without syncing if another write operation is running on the
+/// namespace.
+pub async fn try_sync(
+    opts: &SyncOpts,
+    pool: &Arc<PgPool>,
+    s3: &Operator,
+    shutdown: &Shutdown,
+) -> Result<bool> {
+    sync_with_lock(opts, pool, s3, shutdown, false).await
+}
+
+#[instrument(skip_all, fields(namespace = %opts.namespace))]
+async fn sync_with_lock(
+    opts: &SyncOpts,
+    pool: &Arc<PgPool>,
+    s3: &Operator,
+    shutdown: &Shutdown,
+    wait: bool,
+) -> Result<bool> {
+    let mut shutdown = shutdown.clone();
+    // Shared lock table structure to prevent schema changes.
+    let system_lock = PgLock::new_shared("_system");
+    let system_guard = system_lock.lock(pool.acquire().await?).await?;
+    // Exclusively lock namespace to ensure only one write operation(fetch, migrate, gc) is running
+    // on the namespace.
+    let ns_lock = PgLock::new_exclusive(&opts.namespace);
+    let ns_guard = if wait {
+        let conn = pool.acquire().await?;
+        tokio::select! {
+            guard = ns_lock.lock(conn) => guard?,
+            () = shutdown.recv() => {
+                system_guard.unlock().await?;
+                return Err(Aborted.into());
+            }
+        }
+    } else if let Some(guard) = ns_lock.try_lock(pool.acquire().await?).await? {
+        guard
+    } else {
+        system_guard.unlock().await?;
+        return Ok(false);
+    };
+
+    // Locks are released even if the sync fails, so that pooled connections don't keep them.
+    let result = if opts.dry_run {
+        // Dry runs leave no trace in the database.
+        tokio::select! {
+            result = sync_locked(opts, pool, s3, &mut RunStats::default()) => result,
+            () = shutdown.recv() => Err(Aborted.into()),
+        }
+    } else {
+        sync_recorded(opts, pool, s3, &mut shutdown).await
+    };
+    system_guard.unlock().await?;
+    ns_guard.unlock().await?;
+    result.map(|()| true)
+}
+
+/// Sync and record the run in `sync_runs`.
+async fn sync_recorded(
+    opts: &SyncOpts,
+    pool: &Arc<PgPool>,
+    s3: &Operator,
+    shutdown: &mut Shutdown,
+) -> Result<()> {
+    let id = start_sync_run(&opts.namespace, Utc::now(), &**pool).await?;
+
+    let mut stats = RunStats::default();
+    let (result, status) = tokio::select! {
+        result = sync_locked(opts, pool, s3, &mut stats) => {
+            let status = if result.is_ok() {
+                SyncResult::Success
+            } else {
+                SyncResult::Failed
+            };
+            (result, status)
+        }
+        () = shutdown.recv() => (Err(Aborted.into()), SyncResult::Aborted),
+    };
+
+    let error = result.as_ref().err().map(|e| format!("{e:#}"));
+    if let Err(e) = finish_sync_run(id, status, error.as_deref(), &stats, &**pool).await {
+        warn!(?e, "failed to record sync run");
+    }
+    result
+}
+
+/// Sync with urls of each source tried in order of their health.
+///
+/// If an upstream fails during transfer, the partial revision is resumed with the next url of its
+/// source.
+async fn sync_locked(
+    opts: &SyncOpts,
+    pool: &Arc<PgPool>,
+    s3: &Operator,
+    run: &mut RunStats,
+) -> Result<()> {
+    let all_urls: Vec<_> = opts
+        .sources
+        .iter()
+        .flat_map(|source| source.src.iter().map(Url::to_string))
+        .collect();
+    let health = upstream_health(&all_urls, &**pool).await?;
+    let failures_since = Utc::now() - Duration::hours(i64::from(opts.failure_window));
+    let candidates: Vec<_> = opts
+        .sources
+        .iter()
+        .map(|source| rank_urls(&source.src, &health, failures_since))
+        .collect();
+
+    let multipart = MultipartUploader::new(
+        &opts.s3,
+        &opts.namespace,
+        pool.clone(),
+        opts.s3_bwlimit.clone(),
+    );
+    let result = sync_attempts(opts, candidates, pool, s3, &multipart, run).await;
+    if !opts.dry_run {
+        let temp_prefix = temp_prefix(&opts.s3_prefix, &opts.namespace);
+        if let Err(e) = multipart
+            .abort_abandoned(&opts.s3_prefix, &temp_prefix, result.is_ok())
+            .await
+        {
+            warn!(?e, "failed to abort abandoned multipart uploads");
+        }
+    }
+    result
+}
+
+/// Retry the sync with the next url of a source whose upstream failed.
+async fn sync_attempts(
+    opts: &SyncOpts,
+    mut candidates: Vec<Vec<Url>>,
+    pool: &Arc<PgPool>,
+    s3: &Operator,
+    multipart: &MultipartUploader,
+    run: &mut RunStats,
+) -> Result<()> {
+    let mut resume = opts.resume;
+    loop {
+        let Err(e) = sync_attempt(opts, &candidates, resume, pool, s3, multipart, run).await else {
+            return Ok(());
+        };
+        let Some(UpstreamFailed { source, url }) = e.downcast_ref::<UpstreamFailed>().cloned()
+        else {
+            return Err(e);
+        };
+        if !opts.dry_run {
+            if let Err(e) = record_upstream_failure(url.as_str(), &format!("{e:#}"), &**pool).await
+            {
+                warn!(?e, %url, "failed to record upstream health");
+            }
+        }
+        // Urls before the failed one have failed to connect in this attempt.
+        let urls = &mut candidates[source];
+        let failed = urls
+            .iter()
+            .position(|u| u == &url)
+            .map_or(urls.len(), |pos| pos + 1);
+        urls.drain(..failed);
+        if urls.is_empty() {
+            return Err(e);
+        }
+        warn!(?e, %url, "upstream failed during transfer, retrying with the next url.");
+        resume = true;
+    }
+}
+
+async fn sync_attempt(
+    opts: &SyncOpts,
+    candidates: &[Vec<Url>],
+    resume: bool,
+    pool: &Arc<PgPool>,
+    s3: &Operator,
+    multipart: &MultipartUploader,
+    run: &mut RunStats,
+) -> Result<()> {
+    let namespace = &opts.namespace;
+    let pool = pool.clone();
+    let mut db_conn = pool.acquire().await?;
+
+    // File lists are received concurrently, so that no connection idles for long.
+    // Dry runs leave no trace in the database.
+    let record = (!opts.dry_run).then_some(&*pool);
+    let (urls, conns): (Vec<_>, Vec<_>) =
+        try_join_all(opts.sources.iter().zip(candidates).map(|(source, urls)| {
+            connect_source(source, urls, &opts.sources, &opts.rsync, record)
+        }))
+        .await?
+        .into_iter()
+        .map(|(url, conn, file_list)| (url, (conn, file_list)))
+        .unzip();
+
+    if opts.dry_run {
+        return dry_run(namespace, conns, opts.compare, &pool).await;
+    }
+
+    let live = latest_live_revision(namespace, &mut db_conn).await?;
+    let trace_max_age = opts
+        .trace_max_age
+        .map(|hours| Duration::hours(i64::from(hours)));
+    if !opts.trace_files.is_empty() {
+        info!("checking upstream freshness before transfer.");
+        let file_lists: Vec<_> = conns.iter().map(|(_, file_list)| &file_list[..]).collect();
+        check_trace_mtimes(
+            &opts.trace_files,
+            &file_lists,
+            trace_max_age,
+            live,
+            &mut db_conn,
+        )
+        .await?;
+    }
+
+    create_fl_table(namespace, &mut db_conn).await?;
+
+    ensure_repository(namespace, &mut db_conn).await?;
+    let resumed = if resume {
+        resumable_revision(namespace, &mut db_conn).await?
+    } else {
+        None
+    };
+    let revision = if let Some(revision) = resumed {
+        info!(revision, "resuming partial revision.");
+        resume_revision(revision, &mut db_conn).await?;
+        revision
+    } else {
+        create_revision(namespace, RevisionStatus::Partial, &mut db_conn).await?
+    };
+    run.revision = Some(revision);
+
+    let (pg_tx, pg_rx) = mpsc::channel(10240);
+    let insert_handle = AbortJoinHandle::new(tokio::spawn({
+        let pool = pool.clone();
+        async move { insert_task(revision, pg_rx, &*pool).await }
+    }));
+
+    // Temporary objects of interrupted syncs are never used.
+    let temp_prefix = temp_prefix(&opts.s3_prefix, namespace);
+    s3.remove_all(&temp_prefix).await?;
+
+    // Start the transfer of each source. The transfer model is basically the same as the original
+    // rsync impl.
+    let progress = ProgressDisplay::new(namespace, &opts.progress);
+    let uploaded = UploadedBlobs::default();
+    let mut protocols = vec![];
+    let mut sources = vec![];
+    let mut transfers = vec![];
+    for (n, (conn, file_list)) in conns.into_iter().enumerate() {
+        protocols.push(conn.protocol());
+        let in_flight = InFlightFiles::default();
+        let TaskBuilders {
+            downloader,
+            generator,
+            receiver,
+            uploader,
+            plan_tx,
+            flist_rx,
+        } = conn.into_task_builders(
+            s3.clone(),
+            opts.s3_prefix.clone(),
+            // Temporary objects of each source are kept apart.
+            format!("{temp_prefix}{n}/"),
+            multipart.clone(),
+            KnownBlobs {
+                namespace: namespace.clone(),
+                pool: pool.clone(),
+                stat: opts.stat_known_blobs,
+                uploaded: uploaded.clone(),
+            },
+            pg_tx.clone(),
+            in_flight.clone(),
+            &opts.tmp_path,
+            opts.s3_bwlimit.clone(),
+            opts.concurrency,
+            &progress,
+        )?;
+        sources.push(SourcePlan {
+            idx_offset: idx_offset(n),
+            initial: file_list,
+            flist_rx,
+            plan_tx,
+            in_flight,
+        });
+        // Generator and receiver talk to the upstream, so their transport and protocol errors are
+        // blamed on it. Tasks are aborted if any of them fails.
+        let upstream = UpstreamFailed {
+            source: n,
+            url: urls[n].clone(),
+        };
+        transfers.push(async move {
+            let ((), generator, receiver, ()) = tokio::try_join!(
+                AbortJoinHandle::new(tokio::spawn(downloader.tasks())).map(flatten_err),
+                AbortJoinHandle::new(tokio::spawn(generator.generate_task()))
+                    .map(flatten_err)
+                    .map_err(|e| blame_upstream(e, &upstream)),
+                AbortJoinHandle::new(tokio::spawn(receiver.recv_task()))
+                    .map(flatten_err)
+                    .map_err(|e| blame_upstream(e, &upstream)),
+                AbortJoinHandle::new(tokio::spawn(uploader.upload_tasks())).map(flatten_err),
+            )?;
+            Ok::<_, eyre::Report>((generator, receiver))
+        });
+    }
+    drop(pg_tx);
+
+    let planner = if protocols.iter().any(Protocol::inc_recurse) {
+        // File lists of subdirectories are received during transfer, so the plan is generated
+        // incrementally.
+        info!("generating transfer plan incrementally.");
+        progress.set_phase(Phase::Transfer);
+        plan_incremental(
+            namespace,
+            revision,
+            opts.compare,
+            &pool,
+            sources,
+            progress.clone(),
+        )
+        .left_future()
+    } else {
+        for source in &sources {
+            insert_file_list_to_db(namespace, &source.initial, source.idx_offset, &mut db_conn)
+                .await?;
+        }
+
+        info!("generating transfer plan.");
+        // Run queries to
+        // 1. diff files to be transferred
+        // 2. copy unchanged entries from live indices to partial index
+        // 3. copy directories and symlinks from file list to partial index.
+        let (copied, transfer_items) =
+            diff_and_apply(namespace, revision, opts.compare, &*pool).await?;
+        let mut stat = PlannedTransfer::default();
+        for source in sources {
+            let items = items_of_source(&transfer_items, source.idx_offset);
+            let mut entries = source.initial.into_iter();
+            for item in &items {
+                #[allow(clippy::cast_sign_loss)]
+                let idx = item.idx as u32;
+                // Both entries and items are sorted by idx.
+                let entry = entries
+                    .find(|entry| entry.idx == idx)
+                    .expect("planned entry");
+                source.in_flight.insert(entry);
+            }
+
+            let source_stat = plan_stat(&source.in_flight, &items);
+            progress.inc_planned(source_stat.total_count, source_stat.total_bytes);
+            stat = stat + source_stat;
+            source.plan_tx.send(PlanBatch { flists: 1, items })?;
+        }
+        progress.set_phase(Phase::Transfer);
+        ready(Ok((copied, stat))).right_future()
+    };
+
+    let (conns, (copied, stat)) = match tokio::try_join!(try_join_all(transfers), planner) {
+        Ok(done) => done,
+        Err(e) => {
+            // Record files uploaded before the failure, so that a retry doesn't transfer them
+            // again.
+            if let Err(e) = flatten_err(insert_handle.await) {
+                warn!(?e, "failed to insert uploaded files");
+            }
+            return Err(e);
+        }
+    };
+    progress.finalize().await?;
+    info!(copied, ?stat, "transfer plan done.");
+    run.copied = Some(copied);
+    run.plan = Some(stat);
+    let failed = conns.iter().map(|(_, receiver)| receiver.failed()).sum();
+    if failed > 0 {
+        warn!(
+            failed,
+            "files failed checksum verification twice, left out of the revision."
+        );
+    }
+    run.failed = Some(failed);
+
+    info!("waiting for database insertion to finish.");
+    insert_handle.await??;
+
+    if resumed.is_some() {
+        // The partial revision may contain files removed upstream since it was interrupted.
+        delete_removed_objects(namespace, revision, &mut db_conn).await?;
+    }
+
+    info!("generating index for directory listing.");
+    analyse_objects(&mut db_conn).await?;
+    update_parent_ids(revision, &mut db_conn).await?;
+    update_hard_links(namespace, revision, &mut db_conn).await?;
+    if opts.rsync.checksum {
+        update_checksums(namespace, revision, &mut db_conn).await?;
+    }
+
+    if let Some(live) = live {
+        let live = revision_size(live, &mut db_conn).await?;
+        let new = revision_size(revision, &mut db_conn).await?;
+        info!(?live, ?new, "comparing with live revision.");
+        check_shrink(live, new, opts.max_shrink)?;
+    }
+    let mut timestamps = vec![];
+    if !opts.trace_files.is_empty() {
+        info!("checking upstream freshness.");
+        timestamps = check_freshness(
+            &opts.trace_files,
+            trace_max_age,
+            revision,
+            live,
+            s3,
+            &opts.s3_prefix,
+            &mut db_conn,
+        )
+        .await?;
+    }
+
+    info!("committing transfer.");
+    change_revision_status(
+        revision,
+        RevisionStatus::Live,
+        Some(Utc::now()),
+        &mut db_conn,
+    )
+    .await?;
+
+    // The oldest trace timestamp of a source is the freshness of its upstream.
+    for (n, url) in urls.iter().enumerate() {
+        let freshness = opts
+            .trace_files
+            .iter()
+            .zip(&timestamps)
+            .filter(|(path, _)| source_of(&opts.sources, path) == n)
+            .map(|(_, timestamp)| *timestamp)
+            .min();
+        if let Err(e) = record_upstream_success(url.as_str(), freshness, &mut db_conn).await {
+            warn!(?e, %url, "failed to record upstream health");
+        }
+    }
+
+    // Finalize rsync connections.
+    for ((mut generator, mut receiver), protocol) in conns.into_iter().zip(protocols) {
+        let stats = finalize(&protocol, &mut *generator, &mut *receiver).await?;
+        info!(?stats, "transfer stats");
+        run.rsync = Some(run.rsync.map_or(stats, |total| total + stats));
+    }
+
+    // Finalize db.
+    drop_fl_table(namespace, &mut db_conn).await?;
+
+    Ok(())
+}
+
+/// Connect to a source, and receive its initial file list.
+///
+/// Urls are tried in the given order until one succeeds. Health of urls is recorded if `record` is
+/// given.
+async fn connect_source(
+    source: &Source,
+    urls: &[Url],
+    sources: &[Source],
+    rsync: &RsyncOpts,
+    record: Option<&PgPool>,
+) -> Result<(Url, MuxConn, Vec<FileEntry>)> {
+    let rsync = RsyncOpts {
+        filters: source.filters(sources, &rsync.filters),
+        ..rsync.clone()
+    };
+    let mut last_err = None;
+    for url in urls {
+        match connect_url(url, source.mount_path(), &rsync, record).await {
+            Ok((conn, file_list)) => return Ok((url.clone(), conn, file_list)),
+            Err(e) => {
+                warn!(?e, %url, "failed to connect to upstream.");
+                if let Some(pool) = record {
+                    if let Err(e) =
+                        record_upstream_failure(url.as_str(), &format!("{e:#}"), pool).await
+                    {
+                        warn!(?e, %url, "failed to record upstream health");
+                    }
+                }
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.expect("sources have at least one url"))
+}
+
+async fn connect_url(
+    url: &Url,
+    mount: &str,
+    rsync: &RsyncOpts,
+    record: Option<&PgPool>,
+) -> Result<(MuxConn, Vec<FileEntry>)> {
+    let start = Instant::now();
+    let handshake = start_handshake(url, rsync).await?;
+    if !handshake.motd.is_empty() {
+        info!(src = %url, motd = handshake.motd.join("\n"), "message of the day.");
+    }
+    let mut conn = handshake.finalize(rsync).await?;
+    if let Some(pool) = record {
+        let elapsed = i32::try_from(start.elapsed().as_millis()).unwrap_or(i32::MAX);
+        if let Err(e) = record_handshake(url.as_str(), elapsed, pool).await {
+            warn!(?e, %url, "failed to record upstream health");
+        }
+    }
+    conn.set_mount(mount);
+
+    info!(src = %url, "fetching file list from rsync server.");
+    let file_list = conn
+        .recv_file_list()
+        .await
+        .wrap_err_with(|| format!("failed to receive file list of {url}"))?;
+    Ok((conn, file_list))
+}
+
+/// Plan the transfer and print what would be done.
+async fn dry_run(
+    namespace: &str,
+    conns: Vec<(MuxConn, Vec<FileEntry>)>,
+    compare: Compare,
+    pool: &PgPool,
+) -> Result<()> {
+    // Nothing is transferred, so indices and hard link groups of all sources can be offset in
+    // place, as `append_file_list_to_db` does.
+    let file_list: Vec<_> = conns
+        .into_iter()
+        .enumerate()
+        .flat_map(|(n, (_, file_list))| {
+            let offset = idx_offset(n);
+            file_list.into_iter().map(move |mut entry| {
+                entry.idx += offset;
+                entry.hlink_group = entry.hlink_group.map(|group| group + offset);
+                entry
+            })
+        })
+        .collect();
+
+    info!("generating transfer plan (dry run).");
+    let (copied, transfer_items) = diff_dry_run(namespace, &file_list, compare, pool).await?;
+
+    let in_flight = InFlightFiles::default();
+    for entry in file_list {
+        in_flight.insert(entry);
+    }
+    let stat = plan_stat(&in_flight, &transfer_items);
+
+    println!(
+        "{} files ({}) to transfer, {copied} entries unchanged",
+        stat.total_count,
+        ByteSize::b(stat.total_bytes)
+    );
+    println!(
+        "  delta: {} files ({})",
+        stat.partial_count,
+        ByteSize::b(stat.partial_bytes)
+    );
+    println!(
+        "  full:  {} files ({})",
+        stat.full_count,
+        ByteSize::b(stat.full_bytes)
+    );
+    let top_dirs = top_changed_dirs(&in_flight, &transfer_items, 10);
+    if !top_dirs.is_empty() {
+        println!("top changed directories:");
+        for (dir, count, bytes) in top_dirs {
+            println!(
+                "  {:>10}  {count:>6} files  {dir}",
+                ByteSize::b(bytes).to_string()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use eyre::{eyre, WrapErr};
+    use url::Url;
+
+    use crate::rsync::LocalError;
+    use crate::sync::{blame_upstream, UpstreamFailed};
+
+    #[test]
+    fn must_blame_only_upstream_errors() {
+        let upstream = UpstreamFailed {
+            source: 0,
+            url: Url::parse("rsync://a/debian/").unwrap(),
+        };
+        let blamed = |e: eyre::Report| {
+            blame_upstream(e, &upstream)
+                .downcast_ref::<UpstreamFailed>()
+                .is_some()
+        };
+
+        assert!(blamed(eyre!("unexpected ndx 42")));
+        assert!(blamed(
+            io::Error::new(io::ErrorKind::UnexpectedEof, "early eof").into()
+        ));
+
+        let s3 = opendal::Error::new(opendal::ErrorKind::Unexpected, "s3 is down");
+        assert!(!blamed(Err::<(), _>(s3).wrap_err("delete").unwrap_err()));
+        assert!(!blamed(LocalError("upload tasks exited").into()));
+        assert!(!blamed(
+            io::Error::new(io::ErrorKind::BrokenPipe, LocalError("stream exited")).into()
+        ));
+    }
+
+    mod db_required {
+        use std::fs;
+        use std::os::unix::fs::symlink;
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
+
+        use sqlx::PgPool;
+        use tempfile::TempDir;
+        use url::Url;
+
+        use rsync_core::s3::{build_operator, S3Opts};
+        use rsync_core::tests::generate_random_namespace;
+        use rsync_core::utils::ToHex;
+
+        use crate::opts::{Concurrency, RsyncOpts, SyncOpts};
+        use crate::pg::{latest_live_revision, regular_file_hash, regular_file_mtime};
+        use crate::plan::Compare;
+        use crate::rsync::bwlimit::RateLimiter;
+        use crate::rsync::filter::FilterRules;
+        use crate::rsync::progress_display::ProgressOpts;
+        use crate::source::Source;
+        use crate::sync::sync;
+        use crate::utils::Shutdown;
+
+        fn env(key: &str) -> String {
+            std::env::var(key).unwrap_or_else(|_| panic!("{key} not set"))
+        }
+
+        /// Sync a small tree from a local `rsync --server --sender`, spawned by the command
+        /// transport.
+        ///
+        /// Needs `rsync` in `PATH`, and an S3 bucket given by `S3_URL`, `S3_REGION` and
+        /// `S3_BUCKET`, with credentials in `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+        #[sqlx::test(migrations = "../tests/migrations")]
+        #[ignore = "needs rsync and an S3 bucket"]
+        async fn must_sync_local_tree(pool: PgPool) {
+            let src = TempDir::new().unwrap();
+            let files: &[(&str, Vec<u8>)] = &[
+                ("a/b/c.txt", b"hello".to_vec()),
+                ("a/d.txt", b"world".to_vec()),
+                ("e.bin", vec![42; 100_000]),
+            ];
+            for (name, content) in files {
+                let path = src.path().join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+            symlink("a/b/c.txt", src.path().join("link")).unwrap();
+
+            let namespace = generate_random_namespace();
+            let s3 = S3Opts {
+                region: env("S3_REGION"),
+                url: env("S3_URL"),
+                bucket: env("S3_BUCKET"),
+            };
+            let s3_prefix = format!("{namespace}/");
+            let opts = SyncOpts {
+                sources: vec![Source::root(vec![
+                    Url::from_directory_path(src.path()).unwrap()
+                ])],
+                namespace: namespace.clone(),
+                s3: s3.clone(),
+                s3_prefix: s3_prefix.clone(),
+                s3_bwlimit: RateLimiter::default(),
+                reload_s3_bwlimit: false,
+                concurrency: Concurrency::default(),
+                stat_known_blobs: false,
+                compare: Compare::Mtime { modify_window: 0 },
+                resume: false,
+                dry_run: false,
+                max_shrink: 100,
+                trace_files: vec![],
+                trace_max_age: None,
+                failure_window: 0,
+                tmp_path: std::env::temp_dir(),
+                progress: ProgressOpts::default(),
+                rsync: RsyncOpts {
+                    filters: FilterRules::default(),
+                    bwlimit: RateLimiter::default(),
+                    reload_bwlimit: false,
+                    inc_recursive: false,
+                    compress: false,
+                    hard_links: false,
+                    checksum: false,
+                    rsh: "ssh".to_string(),
+                    rsync_path: "rsync".to_string(),
+                    command: Some("rsync".to_string()),
+                    password_file: None,
+                },
+            };
+            let operator = build_operator(&s3).unwrap();
+            let shutdown = Shutdown::install().unwrap();
+            sync(&opts, &Arc::new(pool.clone()), &operator, &shutdown)
+                .await
+                .expect("sync");
+
+            let mut conn = pool.acquire().await.unwrap();
+            let rev = latest_live_revision(&namespace, &mut conn)
+                .await
+                .unwrap()
+                .expect("live revision");
+            for (name, content) in files {
+                let hash = regular_file_hash(rev, name.as_bytes(), &mut conn)
+                    .await
+                    .unwrap()
+                    .unwrap_or_else(|| panic!("{name} not synced"));
+                let blob = operator
+                    .read(&format!("{s3_prefix}{:x}", hash.as_hex()))
+                    .await
+                    .unwrap();
+                assert_eq!(&blob, content, "{name}");
+
+                let mtime = regular_file_mtime(rev, name.as_bytes(), &mut conn)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let expected = fs::metadata(src.path().join(name))
+                    .unwrap()
+                    .modified()
+                    .unwrap()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap();
+                assert_eq!(
+                    u64::try_from(mtime.timestamp()).unwrap(),
+                    expected.as_secs(),
+                    "{name}"
+                );
+            }
+            // Symlinks are not regular files.
+            assert!(regular_file_hash(rev, b"link", &mut conn)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+}
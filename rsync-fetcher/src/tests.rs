@@ -17,6 +17,8 @@ impl FileEntry {
             modify_time,
             mode: 0o0_100_777,
             link_target: None,
+            hlink_group: None,
+            checksum: None,
             idx,
         }
     }
@@ -27,6 +29,8 @@ impl FileEntry {
             modify_time,
             mode: 0o0_040_777,
             link_target: None,
+            hlink_group: None,
+            checksum: None,
             idx,
         }
     }
@@ -43,6 +47,8 @@ impl FileEntry {
             modify_time,
             mode: 0o0_120_777,
             link_target: Some(link_target.into_bytes()),
+            hlink_group: None,
+            checksum: None,
             idx,
         }
     }
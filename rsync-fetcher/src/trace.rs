@@ -0,0 +1,260 @@
+//! Upstream freshness validation via trace files.
+//!
+//! Many mirrors publish a trace file (e.g. Debian `project/trace/<master>`) or a `lastsync` file
+//! containing the time of their last update. Comparing it with the live revision prevents us from
+//! regressing to a lagging upstream.
+//!
+//! Mtimes of trace files in the received file lists are checked before planning, so that a stale
+//! upstream is rejected before anything is transferred. Their contents are checked again before
+//! publishing.
+
+use chrono::{DateTime, Duration, NaiveDateTime, SubsecRound, TimeZone, Utc};
+use eyre::{bail, ensure, eyre, Result, WrapErr};
+use opendal::Operator;
+use sqlx::{Acquire, Postgres};
+use tracing::{info, warn};
+
+use rsync_core::utils::ToHex;
+
+use crate::pg::{regular_file_hash, regular_file_mtime};
+use crate::rsync::file_list::FileEntry;
+
+/// Formats of the first line of trace files, besides unix timestamps, RFC 3339 and RFC 2822.
+const DATE_FORMATS: &[&str] = &[
+    // Output of `date -u`, used by Debian ftpsync.
+    "%a %b %e %H:%M:%S UTC %Y",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+];
+
+/// Parse the timestamp from the first line of a trace file.
+pub fn parse_timestamp(content: &[u8]) -> Option<DateTime<Utc>> {
+    let content = String::from_utf8_lossy(content);
+    let line = content.lines().next()?.trim();
+    if let Ok(secs) = line.parse::<i64>() {
+        return Utc.timestamp_opt(secs, 0).single();
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(line) {
+        return Some(date.into());
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(line) {
+        return Some(date.into());
+    }
+    DATE_FORMATS.iter().find_map(|format| {
+        NaiveDateTime::parse_from_str(line, format)
+            .ok()
+            .map(|date| date.and_utc())
+    })
+}
+
+/// Read and parse the timestamp of a trace file in the given revision.
+///
+/// Returns `None` if the file doesn't exist in the revision.
+async fn trace_timestamp<'a>(
+    rev: i32,
+    path: &str,
+    s3: &Operator,
+    s3_prefix: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Option<DateTime<Utc>>> {
+    let Some(hash) = regular_file_hash(rev, path.as_bytes(), conn).await? else {
+        return Ok(None);
+    };
+    let key = format!("{s3_prefix}{:x}", hash.as_hex());
+    let content = s3
+        .read(&key)
+        .await
+        .wrap_err_with(|| format!("failed to read trace file {path}"))?;
+    match parse_timestamp(&content) {
+        Some(timestamp) => Ok(Some(timestamp)),
+        None => bail!("unrecognized timestamp in trace file {path}"),
+    }
+}
+
+/// Ensure the upstream is not older than the live revision, or older than `max_age`, according to
+/// mtimes of the given trace files in the received file lists.
+///
+/// Trace files not in the file lists are skipped, e.g. ones in subdirectories that are received
+/// during transfer with incremental recursion.
+pub async fn check_trace_mtimes<'a>(
+    trace_files: &[String],
+    file_lists: &[&[FileEntry]],
+    max_age: Option<Duration>,
+    live: Option<i32>,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<()> {
+    let mut conn = conn.acquire().await?;
+    for path in trace_files {
+        let name = path.trim_start_matches('/').as_bytes();
+        let Some(entry) = file_lists
+            .iter()
+            .flat_map(|file_list| file_list.iter())
+            .find(|entry| entry.name == name && unix_mode::is_file(entry.mode))
+        else {
+            continue;
+        };
+        // Mtimes are stored in seconds.
+        let new = DateTime::<Utc>::from(entry.modify_time).round_subsecs(0);
+        info!(path, %new, "upstream trace mtime.");
+
+        if let Some(live) = live {
+            if let Some(old) = regular_file_mtime(live, name, &mut *conn).await? {
+                ensure!(
+                    new >= old,
+                    "upstream is stale: trace file {path} was modified at {new}, before {old} of \
+                    the live revision, refusing to sync."
+                );
+            }
+        }
+
+        if let Some(max_age) = max_age {
+            let age = Utc::now() - new;
+            ensure!(
+                age <= max_age,
+                "upstream is stale: trace file {path} was modified at {new}, more than {} hours \
+                ago, refusing to sync.",
+                max_age.num_hours()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Ensure the new revision is not older than the live one, or older than `max_age`, according to
+/// the given trace files.
+///
+/// Returns timestamps of the trace files in the new revision, in the given order.
+#[allow(clippy::too_many_arguments)]
+pub async fn check_freshness<'a>(
+    trace_files: &[String],
+    max_age: Option<Duration>,
+    revision: i32,
+    live: Option<i32>,
+    s3: &Operator,
+    s3_prefix: &str,
+    conn: impl Acquire<'a, Database = Postgres>,
+) -> Result<Vec<DateTime<Utc>>> {
+    let mut conn = conn.acquire().await?;
+    let mut timestamps = Vec::with_capacity(trace_files.len());
+    for path in trace_files {
+        let new = trace_timestamp(revision, path, s3, s3_prefix, &mut *conn)
+            .await?
+            .ok_or_else(|| eyre!("trace file {path} not found in upstream"))?;
+        info!(path, %new, "upstream trace timestamp.");
+
+        if let Some(live) = live {
+            match trace_timestamp(live, path, s3, s3_prefix, &mut *conn).await {
+                Ok(Some(old)) => ensure!(
+                    new >= old,
+                    "upstream is stale: trace file {path} is at {new}, older than {old} of the \
+                    live revision, refusing to publish."
+                ),
+                Ok(None) => {}
+                Err(e) => warn!(path, ?e, "failed to read trace file of live revision"),
+            }
+        }
+
+        if let Some(max_age) = max_age {
+            let age = Utc::now() - new;
+            ensure!(
+                age <= max_age,
+                "upstream is stale: trace file {path} is at {new}, older than {} hours, \
+                refusing to publish.",
+                max_age.num_hours()
+            );
+        }
+        timestamps.push(new);
+    }
+    Ok(timestamps)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::trace::parse_timestamp;
+
+    #[test]
+    fn must_parse_timestamp() {
+        let expected = Utc.with_ymd_and_hms(2023, 10, 6, 4, 46, 48).unwrap();
+        for content in [
+            "1696567608\n",
+            "2023-10-06T04:46:48Z",
+            "2023-10-06T12:46:48+08:00\n",
+            "Fri, 06 Oct 2023 04:46:48 +0000",
+            "Fri Oct  6 04:46:48 UTC 2023\nDate: Fri, 06 Oct 2023 04:46:48 +0000\n",
+            "2023-10-06 04:46:48",
+        ] {
+            assert_eq!(
+                parse_timestamp(content.as_bytes()),
+                Some(expected),
+                "{content}"
+            );
+        }
+        assert_eq!(parse_timestamp(b"not a date"), None);
+        assert_eq!(parse_timestamp(b""), None);
+    }
+
+    mod db_required {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use sqlx::PgPool;
+
+        use rsync_core::metadata::Metadata;
+        use rsync_core::pg::{create_revision, ensure_repository, RevisionStatus};
+        use rsync_core::tests::{generate_random_namespace, insert_to_revision};
+
+        use crate::rsync::file_list::FileEntry;
+        use crate::trace::check_trace_mtimes;
+
+        #[sqlx::test(migrations = "../tests/migrations")]
+        async fn must_check_trace_mtimes(pool: PgPool) {
+            let mut conn = pool.acquire().await.expect("acquire");
+            let namespace = generate_random_namespace();
+            ensure_repository(&namespace, &mut conn)
+                .await
+                .expect("create repo");
+            let live = create_revision(&namespace, RevisionStatus::Partial, &mut conn)
+                .await
+                .expect("create rev");
+            insert_to_revision(
+                live,
+                &[(
+                    b"project/trace/master".to_vec(),
+                    Metadata::regular(0, UNIX_EPOCH + Duration::from_secs(1000), [0; 20]),
+                )],
+                &mut conn,
+            )
+            .await;
+
+            let trace_files = ["project/trace/master".to_string()];
+            let trace = |secs| {
+                vec![FileEntry::regular(
+                    "project/trace/master".into(),
+                    0,
+                    UNIX_EPOCH + Duration::from_secs(secs),
+                    0,
+                )]
+            };
+            let check = |file_list: Vec<FileEntry>, max_age| {
+                let pool = pool.clone();
+                let trace_files = trace_files.clone();
+                async move {
+                    check_trace_mtimes(&trace_files, &[&file_list], max_age, Some(live), &pool)
+                        .await
+                }
+            };
+
+            assert!(check(trace(1000), None).await.is_ok());
+            assert!(check(trace(2000), None).await.is_ok());
+            assert!(check(trace(999), None).await.is_err());
+            assert!(check(trace(2000), Some(chrono::Duration::hours(48)))
+                .await
+                .is_err());
+            // Trace files not received yet are checked before publishing instead.
+            assert!(check(vec![], Some(chrono::Duration::hours(48)))
+                .await
+                .is_ok());
+        }
+    }
+}
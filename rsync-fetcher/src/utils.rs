@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::ops::Add;
 
@@ -5,12 +6,16 @@ use blake2::Blake2b;
 use bytesize::ByteSize;
 use digest::consts::U20;
 use digest::Digest;
+use eyre::bail;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tracing::warn;
 
 use rsync_core::utils::ToHex;
 
+use crate::pg::RevisionSize;
 use crate::plan::TransferItem;
-use crate::rsync::file_list::FileEntry;
+use crate::rsync::file_list::InFlightFiles;
 
 pub fn hash(data: &[u8]) -> [u8; 20] {
     let mut hasher = Blake2b::<U20>::default();
@@ -78,12 +83,12 @@ impl Add for PlannedTransfer {
     }
 }
 
-pub fn plan_stat(files: &[FileEntry], transfer_items: &[TransferItem]) -> PlannedTransfer {
+pub fn plan_stat(files: &InFlightFiles, transfer_items: &[TransferItem]) -> PlannedTransfer {
     transfer_items
         .iter()
         .filter_map(|item| {
             #[allow(clippy::cast_sign_loss)]
-            let entry = &files[item.idx as usize];
+            let entry = files.get(item.idx as u32).expect("planned entry");
             let path = entry.name_lossy();
             (!ignore_mode(entry.mode, Some(path))).then(|| {
                 if item.blake2b.is_some() {
@@ -111,6 +116,54 @@ pub fn plan_stat(files: &[FileEntry], transfer_items: &[TransferItem]) -> Planne
         .unwrap_or_default()
 }
 
+/// Directories with the most bytes to transfer, as `(directory, count, bytes)`.
+pub fn top_changed_dirs(
+    files: &InFlightFiles,
+    transfer_items: &[TransferItem],
+    n: usize,
+) -> Vec<(String, u64, u64)> {
+    let mut dirs: HashMap<String, (u64, u64)> = HashMap::new();
+    for item in transfer_items {
+        #[allow(clippy::cast_sign_loss)]
+        let entry = files.get(item.idx as u32).expect("planned entry");
+        if ignore_mode(entry.mode, None::<()>) {
+            continue;
+        }
+        let name = entry.name_lossy();
+        let dir = name.rsplit_once('/').map_or(".", |(dir, _)| dir);
+        let (count, bytes) = dirs.entry(dir.to_string()).or_default();
+        *count += 1;
+        *bytes += entry.len;
+    }
+    let mut dirs: Vec<_> = dirs
+        .into_iter()
+        .map(|(dir, (count, bytes))| (dir, count, bytes))
+        .collect();
+    dirs.sort_unstable_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    dirs.truncate(n);
+    dirs
+}
+
+/// Ensure the new revision didn't shrink by more than `max_shrink` percent from the live one.
+///
+/// Upstreams may serve a half-empty module during maintenance, which must not be published.
+pub fn check_shrink(live: RevisionSize, new: RevisionSize, max_shrink: u8) -> eyre::Result<()> {
+    let shrank = |live: u64, new: u64| {
+        u128::from(new) * 100 < u128::from(live) * u128::from(100 - max_shrink.min(100))
+    };
+    if shrank(live.count, new.count) || shrank(live.bytes, new.bytes) {
+        bail!(
+            "new revision shrank by more than {max_shrink}% ({} files, {} -> {} files, {}), \
+            refusing to publish. Increase --max-shrink if this is expected.",
+            live.count,
+            ByteSize::b(live.bytes),
+            new.count,
+            ByteSize::b(new.bytes)
+        );
+    }
+    Ok(())
+}
+
 pub fn flatten_err<T, E1, E2>(t: Result<Result<T, E1>, E2>) -> eyre::Result<T>
 where
     E1: Into<eyre::Report> + Send + Sync + 'static,
@@ -119,7 +172,86 @@ where
     t.map_err(Into::into)?.map_err(Into::into)
 }
 
+/// Listener of SIGINT and SIGTERM.
+///
+/// Handlers are installed once per process, and every clone receives the same signal, so that
+/// syncs started later can still be interrupted.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Install handlers of both signals. Signals are only received after this is called.
+    pub fn install() -> std::io::Result<Self> {
+        let mut int = signal(SignalKind::interrupt())?;
+        let mut term = signal(SignalKind::terminate())?;
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = int.recv() => {}
+                _ = term.recv() => {}
+            }
+            tx.send_replace(true);
+        });
+        Ok(Self(rx))
+    }
+
+    /// Wait for either signal. Returns immediately if one was received before.
+    pub async fn recv(&mut self) {
+        // The sender is only dropped after sending, or when the runtime shuts down.
+        let _ = self.0.wait_for(|received| *received).await;
+    }
+}
+
 /// Clean namespace name as a valid SQL table name.
 pub fn namespace_as_table(namespace: &str) -> String {
     format!("h{:x}", hash(namespace.as_bytes()).as_hex())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::pg::RevisionSize;
+    use crate::plan::TransferItem;
+    use crate::rsync::file_list::{FileEntry, InFlightFiles};
+    use crate::utils::{check_shrink, top_changed_dirs};
+
+    #[test]
+    fn must_check_shrink() {
+        let size = |count, bytes| RevisionSize { count, bytes };
+        let live = size(100, 1000);
+        assert!(check_shrink(live, size(60, 1000), 50).is_ok());
+        assert!(check_shrink(live, size(50, 500), 50).is_ok());
+        assert!(check_shrink(live, size(49, 1000), 50).is_err());
+        assert!(check_shrink(live, size(100, 499), 50).is_err());
+        assert!(check_shrink(live, size(0, 0), 100).is_ok());
+        assert!(check_shrink(live, size(99, 1000), 0).is_err());
+        assert!(check_shrink(RevisionSize::default(), size(0, 0), 0).is_ok());
+    }
+
+    #[test]
+    fn must_rank_changed_dirs_by_bytes() {
+        let files = InFlightFiles::default();
+        let entries: &[(&[u8], u64)] = &[(b"a/x", 10), (b"a/y", 10), (b"b/c/z", 30), (b"w", 1)];
+        for (idx, (name, len)) in entries.iter().enumerate() {
+            files.insert(FileEntry {
+                name: name.to_vec(),
+                len: *len,
+                modify_time: UNIX_EPOCH,
+                mode: 0o100_644,
+                link_target: None,
+                hlink_group: None,
+                checksum: None,
+                idx: idx as u32,
+            });
+        }
+        let items: Vec<_> = (0..4)
+            .map(|idx| TransferItem { idx, blake2b: None })
+            .collect();
+
+        assert_eq!(
+            top_changed_dirs(&files, &items, 2),
+            vec![("b/c".to_string(), 1, 30), ("a".to_string(), 2, 20)]
+        );
+    }
+}
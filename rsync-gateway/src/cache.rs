@@ -270,6 +270,8 @@ impl MaybeCompressed {
             Self::Compressed { data, len } => {
                 let decompressed =
                     zstd::bulk::decompress(data, *len).expect("incorrect decompress capacity");
+                // Archives never leave the process that wrote them, so the layout of `Resolved`
+                // can change between versions without a format version.
                 let archived = unsafe { rkyv::archived_root::<Resolved>(&decompressed) };
                 let deserialized =
                     <ArchivedResolved as Deserialize<Resolved, Infallible>>::deserialize(
@@ -116,4 +116,6 @@ pub struct ListingEntry {
     )]
     pub modify_time: Option<DateTime<Utc>>,
     pub is_dir: bool,
+    /// Number of hard links, if the entry is hard linked.
+    pub nlink: Option<u64>,
 }
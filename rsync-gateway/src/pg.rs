@@ -134,6 +134,7 @@ struct RawResolveEntry {
     len: i64,
     r#type: FileType,
     modify_time: DateTime<Utc>,
+    nlink: Option<i64>,
 }
 
 impl From<RawResolveEntry> for ListingEntry {
@@ -143,6 +144,7 @@ impl From<RawResolveEntry> for ListingEntry {
             len,
             r#type,
             modify_time,
+            nlink,
         }: RawResolveEntry,
     ) -> Self {
         Self {
@@ -159,6 +161,8 @@ impl From<RawResolveEntry> for ListingEntry {
             },
             modify_time: Some(modify_time),
             is_dir: r#type == FileType::Directory,
+            #[allow(clippy::cast_sign_loss)]
+            nlink: nlink.map(|nlink| nlink as u64),
         }
     }
 }
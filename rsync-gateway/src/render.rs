@@ -48,6 +48,7 @@ impl Resolved {
                     len: None,
                     modify_time: None,
                     is_dir: true,
+                    nlink: None,
                 };
                 let root = req_path.is_empty() || req_path == b"/";
                 let entries = if root {
@@ -0,0 +1,7 @@
+SELECT EXISTS(SELECT 1
+              FROM objects
+                       JOIN revisions ON objects.revision = revisions.revision
+                       JOIN repositories ON revisions.repository = repositories.id
+              WHERE repositories.name = $1
+                AND revisions.status IN ('live', 'partial')
+                AND objects.blake2b = $2) AS "exists!";
@@ -0,0 +1,5 @@
+DELETE
+FROM multipart_uploads
+WHERE namespace = $1
+  AND key = $2
+RETURNING upload_id;
@@ -5,5 +5,8 @@ CREATE UNLOGGED TABLE rsync_filelist
     len         BIGINT         NOT NULL,
     modify_time TIMESTAMPTZ(0) NOT NULL,
     mode        INTEGER        NOT NULL,
-    target      bytea
+    target      bytea,
+    hlink_group BIGINT,
+    checksum    bytea,
+    checksum_type checksum_type
 );
\ No newline at end of file
@@ -0,0 +1,3 @@
+DELETE
+FROM multipart_uploads
+WHERE upload_id = $1;
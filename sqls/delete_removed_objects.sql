@@ -0,0 +1,4 @@
+DELETE
+FROM objects
+WHERE revision = $1
+  AND NOT EXISTS (SELECT 1 FROM rsync_filelist AS fl WHERE fl.filename = objects.filename);
@@ -11,12 +11,22 @@ SELECT fl.idx,
         ORDER BY o.revision DESC
         LIMIT 1) as blake2b
 FROM rsync_filelist as fl
-         -- left join (exclude) fl rows with existing filename, matching mtime and len in objects
+         -- left join (exclude) fl rows with existing filename (or checksum), matching len and mtime (or checksum) in objects
          LEFT JOIN objects AS o
                    ON o.revision IN (SELECT revision FROM local_revs)
-                       AND fl.filename = o.filename
-                       AND fl.modify_time = o.modify_time
+                       -- keep in sync with diff_unchanged_and_copy.sql
+                       AND (fl.filename = o.filename
+                           -- content is known by checksum regardless of filename, e.g. renamed files
+                           OR $4 = 'checksum' AND fl.checksum = o.checksum AND fl.checksum_type = o.checksum_type)
+                       AND CASE $4
+                               -- checksums of different algorithms, e.g. after failover to an upstream
+                               -- of another protocol version, never match
+                               WHEN 'checksum' THEN fl.checksum = o.checksum AND fl.checksum_type = o.checksum_type
+                               WHEN 'size_only' THEN TRUE
+                               ELSE abs(extract(EPOCH FROM fl.modify_time - o.modify_time)) <= $5
+                           END
                        AND fl.len = o.len
                        AND o.type = 'regular'
 WHERE is_regular(mode)
+  AND fl.idx BETWEEN $2 AND $3
   AND o.filename IS NULL;
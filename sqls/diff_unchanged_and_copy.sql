@@ -3,20 +3,39 @@ WITH ns AS
      local_revs AS
          (SELECT revision from revisions WHERE repository IN (SELECT id FROM ns) AND status in ('live', 'partial'))
 INSERT
-INTO objects (revision, filename, len, modify_time, type, blake2b, target)
-SELECT DISTINCT ON (o.filename) $2,
-                                o.filename,
-                                o.len,
-                                o.modify_time,
-                                o.type,
-                                o.blake2b,
-                                o.target
+INTO objects (revision, filename, len, modify_time, type, blake2b, target, checksum, checksum_type)
+SELECT DISTINCT ON (fl.filename) $2,
+                                  fl.filename,
+                                  o.len,
+                                  -- mtime may differ unless compared exactly
+                                  fl.modify_time,
+                                  o.type,
+                                  o.blake2b,
+                                  o.target,
+                                  o.checksum,
+                                  o.checksum_type
 FROM rsync_filelist AS fl
          INNER JOIN objects AS o
                     ON o.revision IN (SELECT revision FROM local_revs)
-                        AND fl.filename = o.filename
+                        -- keep in sync with diff_changed_or_remote_only.sql
+                        AND (fl.filename = o.filename
+                            OR $3 = 'checksum' AND fl.checksum = o.checksum AND fl.checksum_type = o.checksum_type)
 WHERE is_regular(mode)
   AND o.type = 'regular'
-  AND fl.modify_time = o.modify_time
+  AND CASE $3
+          WHEN 'checksum' THEN fl.checksum = o.checksum AND fl.checksum_type = o.checksum_type
+          WHEN 'size_only' THEN TRUE
+          ELSE abs(extract(EPOCH FROM fl.modify_time - o.modify_time)) <= $4
+      END
   AND fl.len = o.len
-ORDER BY o.filename, o.revision DESC;
+-- prefer the object with the same filename
+ORDER BY fl.filename, fl.filename = o.filename DESC, o.revision DESC
+-- files kept in a resumed revision are copied onto themselves
+ON CONFLICT (revision, filename) DO UPDATE SET len         = EXCLUDED.len,
+                                              modify_time = EXCLUDED.modify_time,
+                                              type        = EXCLUDED.type,
+                                              blake2b     = EXCLUDED.blake2b,
+                                              target      = EXCLUDED.target,
+                                              hlink_group = EXCLUDED.hlink_group,
+                                              checksum    = EXCLUDED.checksum,
+                                              checksum_type = EXCLUDED.checksum_type;
@@ -0,0 +1,14 @@
+UPDATE sync_runs
+SET finished_at    = $2,
+    result         = $3,
+    error          = $4,
+    revision       = $5,
+    bytes_read     = $6,
+    bytes_written  = $7,
+    transfer_count = $8,
+    transfer_bytes = $9,
+    delta_count    = $10,
+    delta_bytes    = $11,
+    copied_count   = $12,
+    failed_count   = $13
+WHERE id = $1;
@@ -1,3 +1,4 @@
-INSERT INTO rsync_filelist(filename, len, modify_time, mode, target, idx)
+INSERT INTO rsync_filelist(filename, len, modify_time, mode, target, hlink_group, checksum, checksum_type, idx)
 SELECT *
-FROM UNNEST($1::bytea[], $2::BIGINT[], $3::TIMESTAMPTZ[], $4::INT[], $5::bytea[], $6::INT[])
+FROM UNNEST($1::bytea[], $2::BIGINT[], $3::TIMESTAMPTZ[], $4::INT[], $5::bytea[], $6::BIGINT[], $7::bytea[],
+            $8::checksum_type[], $9::INT[])
\ No newline at end of file
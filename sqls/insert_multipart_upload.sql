@@ -0,0 +1,3 @@
+INSERT
+INTO multipart_uploads (upload_id, namespace, key, created_at)
+VALUES ($1, $2, $3, now());
@@ -1,4 +1,13 @@
 INSERT INTO objects(revision, filename, len, modify_time, type, blake2b, target)
 SELECT *
 FROM UNNEST(array_fill($1::INTEGER, $2), $3::BYTEA[], $4::BIGINT[], $5::TIMESTAMPTZ[], $6::filetype[], $7::BYTEA[],
-            $8::BYTEA[])
\ No newline at end of file
+            $8::BYTEA[])
+-- files kept in a resumed revision are replaced if they changed since
+ON CONFLICT (revision, filename) DO UPDATE SET len         = EXCLUDED.len,
+                                              modify_time = EXCLUDED.modify_time,
+                                              type        = EXCLUDED.type,
+                                              blake2b     = EXCLUDED.blake2b,
+                                              target      = EXCLUDED.target,
+                                              hlink_group = EXCLUDED.hlink_group,
+                                              checksum    = EXCLUDED.checksum,
+                                              checksum_type = EXCLUDED.checksum_type
\ No newline at end of file
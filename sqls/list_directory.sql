@@ -1,5 +1,14 @@
 WITH parent AS (SELECT id FROM objects WHERE filename = NULLIF($1, ''::bytea) AND revision = $2)
-SELECT filename, len, type as "type: _", modify_time
+SELECT filename,
+       len,
+       type as "type: _",
+       modify_time,
+       CASE
+           WHEN hlink_group IS NOT NULL THEN (SELECT count(*)
+                                              FROM objects AS links
+                                              WHERE links.revision = objects.revision
+                                                AND links.hlink_group = objects.hlink_group)
+           END AS nlink
 FROM objects
 WHERE revision = $2
   AND objects.parent = (SELECT id FROM parent)
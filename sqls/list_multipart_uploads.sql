@@ -0,0 +1,3 @@
+SELECT key, upload_id
+FROM multipart_uploads
+WHERE namespace = $1;
@@ -1,4 +1,13 @@
-SELECT filename, len, type as "type: _", modify_time
+SELECT filename,
+       len,
+       type as "type: _",
+       modify_time,
+       CASE
+           WHEN hlink_group IS NOT NULL THEN (SELECT count(*)
+                                              FROM objects AS links
+                                              WHERE links.revision = objects.revision
+                                                AND links.hlink_group = objects.hlink_group)
+           END AS nlink
 FROM objects
 WHERE revision = $1
   AND objects.parent IS NULL
@@ -0,0 +1 @@
+SELECT EXISTS(SELECT 1 FROM multipart_uploads WHERE upload_id = $1) AS "exists!";
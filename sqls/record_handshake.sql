@@ -0,0 +1,3 @@
+INSERT INTO upstream_health (url, handshake_ms)
+VALUES ($1, $2)
+ON CONFLICT (url) DO UPDATE SET handshake_ms = EXCLUDED.handshake_ms;
@@ -0,0 +1,4 @@
+INSERT INTO upstream_health (url, last_failure, last_error)
+VALUES ($1, now(), $2)
+ON CONFLICT (url) DO UPDATE SET last_failure = EXCLUDED.last_failure,
+                                last_error   = EXCLUDED.last_error;
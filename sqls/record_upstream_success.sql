@@ -0,0 +1,5 @@
+INSERT INTO upstream_health (url, last_success, freshness)
+VALUES ($1, now(), $2)
+-- keep the last known freshness if no trace file is configured
+ON CONFLICT (url) DO UPDATE SET last_success = EXCLUDED.last_success,
+                                freshness    = COALESCE(EXCLUDED.freshness, upstream_health.freshness);
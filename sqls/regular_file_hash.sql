@@ -0,0 +1,5 @@
+SELECT blake2b AS "blake2b!"
+FROM objects
+WHERE revision = $1
+  AND filename = $2
+  AND type = 'regular';
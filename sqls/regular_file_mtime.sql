@@ -0,0 +1,5 @@
+SELECT modify_time
+FROM objects
+WHERE revision = $1
+  AND filename = $2
+  AND type = 'regular';
@@ -0,0 +1,12 @@
+WITH ns AS
+         (SELECT id FROM repositories WHERE name = $1)
+SELECT revision
+FROM revisions
+WHERE repository IN (SELECT id FROM ns)
+  AND status = 'partial'
+  AND revision > (SELECT coalesce(max(revision), 0)
+                  FROM revisions
+                  WHERE repository IN (SELECT id FROM ns)
+                    AND status = 'live')
+ORDER BY revision DESC
+LIMIT 1;
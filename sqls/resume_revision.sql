@@ -0,0 +1,5 @@
+-- directories and symlinks are copied from the file list again
+DELETE
+FROM objects
+WHERE revision = $1
+  AND type <> 'regular';
@@ -0,0 +1,3 @@
+SELECT count(filename) AS "count!", coalesce(sum(len), 0)::bigint AS "bytes!"
+FROM objects
+WHERE revision = $1;
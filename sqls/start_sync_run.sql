@@ -0,0 +1,9 @@
+WITH stale AS (
+    -- The namespace is locked, so any running run must have been interrupted.
+    UPDATE sync_runs
+        SET result = 'aborted', finished_at = now(), error = 'interrupted'
+        WHERE namespace = $1 AND result = 'running')
+INSERT
+INTO sync_runs (namespace, started_at, result)
+VALUES ($1, $2, 'running')
+RETURNING id;
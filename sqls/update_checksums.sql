@@ -0,0 +1,7 @@
+UPDATE objects
+SET checksum      = fl.checksum,
+    checksum_type = fl.checksum_type
+FROM rsync_filelist AS fl
+WHERE objects.revision = $1
+  AND objects.filename = fl.filename
+  AND fl.checksum IS NOT NULL;
@@ -0,0 +1,6 @@
+UPDATE objects
+SET hlink_group = fl.hlink_group
+FROM rsync_filelist AS fl
+WHERE objects.revision = $1
+  AND objects.filename = fl.filename
+  AND fl.hlink_group IS NOT NULL;
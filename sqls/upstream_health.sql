@@ -0,0 +1,3 @@
+SELECT url, handshake_ms, last_success, last_failure, freshness
+FROM upstream_health
+WHERE url = ANY ($1);
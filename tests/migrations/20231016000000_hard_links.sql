@@ -0,0 +1,4 @@
+ALTER TABLE objects
+    ADD COLUMN hlink_group BIGINT; -- entries sharing the same group in a revision are hard links of each other
+
+CREATE INDEX objects_hlink_group_idx ON objects (revision, hlink_group) WHERE hlink_group IS NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE objects
+    ADD COLUMN checksum bytea; -- whole-file checksum sent by upstream in checksum mode, compared instead of mtime
@@ -0,0 +1,9 @@
+CREATE TABLE upstream_health
+(
+    url          TEXT PRIMARY KEY,
+    handshake_ms INTEGER,        -- latency of the last successful handshake
+    last_success timestamptz(0), -- last revision synced from the upstream was published
+    last_failure timestamptz(0),
+    last_error   TEXT,
+    freshness    timestamptz(0)  -- oldest trace timestamp of the last revision
+);
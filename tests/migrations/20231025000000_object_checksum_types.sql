@@ -0,0 +1,3 @@
+CREATE TYPE checksum_type AS ENUM ('md4', 'md5');
+ALTER TABLE objects
+    ADD COLUMN checksum_type checksum_type; -- algorithm of checksum, only checksums of the same type are compared